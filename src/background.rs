@@ -0,0 +1,104 @@
+use gloo_events::EventListener;
+use js_sys::{Array, Object, Reflect, Uint8Array};
+use wasm_bindgen::JsCast;
+use web_sys::{ErrorEvent, MessageEvent, Worker};
+use yew::Callback;
+
+use ppm::jobs::{run_job, Job, Reply};
+
+// Script trunk builds from the ppm-worker binary.
+const WORKER_URL: &str = "./ppm-worker.js";
+
+// A reply to a job, with its id, or None when the worker itself failed and
+// every job sent to it is lost.
+pub type JobReply = (Option<usize>, Reply, Vec<u8>);
+
+// Jobs run on a worker so the page stays responsive. Without one they run
+// right away on the main thread.
+pub struct Background {
+    worker: Option<(Worker, [EventListener; 2])>,
+    next_id: usize,
+    callback: Callback<JobReply>,
+}
+
+impl Background {
+    pub fn new(callback: Callback<JobReply>) -> Self {
+        Background {
+            worker: spawn(&callback),
+            next_id: 0,
+            callback,
+        }
+    }
+
+    // Returns the id the replies to the job come with.
+    pub fn start(&mut self, job: &Job, data: &[u8]) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let worker = match &self.worker {
+            Some((worker, _)) => worker,
+            None => {
+                let (reply, data) = run_job(job, data, &mut |_| {});
+                self.callback.emit((Some(id), reply, data));
+                return id;
+            }
+        };
+
+        let data = Uint8Array::from(data);
+        let message = Object::new();
+        Reflect::set(&message, &"id".into(), &(id as f64).into()).unwrap();
+        Reflect::set(&message, &"job".into(), &serde_json::to_string(job).unwrap().into()).unwrap();
+        Reflect::set(&message, &"data".into(), &data).unwrap();
+        if let Err(err) = worker.post_message_with_transfer(&message, &Array::of1(&data.buffer())) {
+            let error = format!("Couldn't start job: {:?}", err);
+            self.callback.emit((Some(id), Reply::Failed { error }, Vec::new()));
+        }
+
+        return id;
+    }
+
+    // Stops every running job. The worker can't be interrupted, so it's
+    // replaced with a new one.
+    pub fn cancel(&mut self) {
+        if let Some((worker, _)) = self.worker.take() {
+            worker.terminate();
+        }
+        self.worker = spawn(&self.callback);
+    }
+}
+
+fn spawn(callback: &Callback<JobReply>) -> Option<(Worker, [EventListener; 2])> {
+    let worker = match Worker::new(WORKER_URL) {
+        Ok(worker) => worker,
+        Err(err) => {
+            log::error!("Couldn't start worker, jobs run on the main thread: {:?}", err);
+            return None;
+        }
+    };
+
+    let replies = callback.clone();
+    let message = EventListener::new(&worker, "message", move |event| {
+        let message = event.dyn_ref::<MessageEvent>().unwrap().data();
+        let id = Reflect::get(&message, &"id".into()).ok().and_then(|id| id.as_f64());
+        let reply = Reflect::get(&message, &"reply".into())
+            .ok()
+            .and_then(|reply| reply.as_string())
+            .and_then(|reply| serde_json::from_str::<Reply>(&reply).ok());
+        let data = Reflect::get(&message, &"data".into()).map(|data| Uint8Array::new(&data).to_vec());
+
+        match (id, reply, data) {
+            (Some(id), Some(reply), Ok(data)) => replies.emit((Some(id as usize), reply, data)),
+            _ => log::error!("Invalid message from worker."),
+        }
+    });
+    let failures = callback.clone();
+    let error = EventListener::new(&worker, "error", move |event| {
+        let error = match event.dyn_ref::<ErrorEvent>() {
+            Some(event) => event.message(),
+            None => "The worker failed.".to_string(),
+        };
+        failures.emit((None, Reply::Failed { error }, Vec::new()));
+    });
+
+    return Some((worker, [message, error]));
+}
@@ -0,0 +1,153 @@
+use std::error::Error;
+
+use serde::Serialize;
+
+use ppm::image::load_from_file;
+use ppm::metrics::{compare, ChannelMetrics, Metrics};
+
+#[derive(Clone, Copy)]
+enum Metric {
+    Mse,
+    Psnr,
+    Ssim,
+    MsSsim,
+    MaxError,
+}
+
+impl Metric {
+    fn parse(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name {
+            "mse" => Ok(Metric::Mse),
+            "psnr" => Ok(Metric::Psnr),
+            "ssim" => Ok(Metric::Ssim),
+            "ms-ssim" => Ok(Metric::MsSsim),
+            "max-error" => Ok(Metric::MaxError),
+            _ => Err(format!("Unknown metric \"{}\", use mse, psnr, ssim, ms-ssim or max-error.", name).into()),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Metric::Mse => "mse",
+            Metric::Psnr => "psnr",
+            Metric::Ssim => "ssim",
+            Metric::MsSsim => "ms-ssim",
+            Metric::MaxError => "max-error",
+        }
+    }
+
+    fn value(&self, metrics: &Metrics) -> f64 {
+        match self {
+            Metric::Mse => metrics.overall.mse,
+            Metric::Psnr => metrics.overall.psnr,
+            Metric::Ssim => metrics.overall.ssim,
+            Metric::MsSsim => metrics.ms_ssim,
+            Metric::MaxError => metrics.overall.max_error as f64,
+        }
+    }
+
+    // Errors pass below the threshold, similarities above it.
+    fn passes(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Metric::Mse | Metric::MaxError => value <= threshold,
+            Metric::Psnr | Metric::Ssim | Metric::MsSsim => value >= threshold,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    a: &'a str,
+    b: &'a str,
+    #[serde(flatten)]
+    metrics: &'a Metrics,
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut files = Vec::new();
+    let mut metric = None;
+    let mut threshold = None;
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--metric" => metric = Some(Metric::parse(args.next().ok_or("--metric needs a name.")?)?),
+            "--threshold" => {
+                let value = args.next().ok_or("--threshold needs a value.")?;
+                threshold = Some(value.parse::<f64>().map_err(|_| format!("Invalid threshold \"{}\".", value))?);
+            }
+            "--json" => json = true,
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option \"{}\".", arg).into())
+            }
+            _ => files.push(arg.as_str()),
+        }
+    }
+
+    let (a, b) = match files[..] {
+        [a, b] => (a, b),
+        _ => return Err("Expected two files to compare.".into()),
+    };
+    if threshold.is_some() && metric.is_none() {
+        return Err("--threshold needs a --metric.".into());
+    }
+
+    let image_a = load_from_file(a)?;
+    let image_b = load_from_file(b)?;
+    let metrics = compare(image_a.as_ref(), image_b.as_ref())?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&Report { a, b, metrics: &metrics })?);
+    } else if let Some(metric) = metric {
+        println!("{} {}", metric.name(), format_value(metric.value(&metrics)));
+    } else {
+        println!(
+            "{} and {}: {}x{}, max value {}",
+            a,
+            b,
+            image_a.get_width(),
+            image_a.get_height(),
+            metrics.max_value
+        );
+        for (name, channel) in ["r", "g", "b"].iter().zip(metrics.channels.iter()) {
+            print_channel(name, channel);
+        }
+        print_channel("all", &metrics.overall);
+        println!("  ms-ssim {:.6}", metrics.ms_ssim);
+    }
+
+    if let (Some(metric), Some(threshold)) = (metric, threshold) {
+        let value = metric.value(&metrics);
+        if !metric.passes(value, threshold) {
+            return Err(format!(
+                "{} {} doesn't meet the threshold {}.",
+                metric.name(),
+                format_value(value),
+                threshold
+            )
+            .into());
+        }
+    }
+
+    return Ok(());
+}
+
+fn print_channel(name: &str, channel: &ChannelMetrics) {
+    println!(
+        "  {:>3}: mse {:.8}, psnr {} dB, ssim {:.6}, max error {}",
+        name,
+        channel.mse,
+        format_value(channel.psnr),
+        channel.ssim,
+        channel.max_error
+    );
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_infinite() {
+        return "inf".to_string();
+    }
+
+    return format!("{:.6}", value);
+}
@@ -0,0 +1,240 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+use ppm::image::{load_from_file, Image};
+use ppm::jpeg::{parse_quantization_tables, JpegOptions, Subsampling};
+use ppm::ops::Operation;
+use ppm::recipe::{OutputFormat, Recipe};
+
+use crate::files::{expand_inputs, output_paths};
+
+#[derive(Debug, Clone, Copy)]
+enum Resize {
+    Exact(usize, usize),
+    // A single dimension keeps the aspect ratio.
+    Width(usize),
+    Height(usize),
+    Percent(f64),
+}
+
+impl Resize {
+    fn parse(spec: &str) -> Result<Self, Box<dyn Error>> {
+        let invalid = || format!("Invalid size \"{}\", expected WxH, Wx, xH or N%.", spec);
+
+        if let Some(percent) = spec.strip_suffix('%') {
+            let percent: f64 = percent.parse().map_err(|_| invalid())?;
+            if percent <= 0.0 {
+                return Err(invalid().into());
+            }
+            return Ok(Resize::Percent(percent));
+        }
+
+        let (width, height) = spec.split_once(['x', 'X']).ok_or_else(invalid)?;
+        let dimension = |val: &str| -> Result<Option<usize>, String> {
+            if val.is_empty() {
+                return Ok(None);
+            }
+            match val.parse::<usize>() {
+                Ok(val) if val > 0 => Ok(Some(val)),
+                _ => Err(invalid()),
+            }
+        };
+
+        match (dimension(width)?, dimension(height)?) {
+            (Some(width), Some(height)) => Ok(Resize::Exact(width, height)),
+            (Some(width), None) => Ok(Resize::Width(width)),
+            (None, Some(height)) => Ok(Resize::Height(height)),
+            (None, None) => Err(invalid().into()),
+        }
+    }
+
+    fn target(&self, width: usize, height: usize) -> (usize, usize) {
+        let scaled = |val: usize, factor: f64| ((val as f64 * factor).round() as usize).max(1);
+
+        match *self {
+            Resize::Exact(width, height) => (width, height),
+            Resize::Width(new_width) => (new_width, scaled(height, new_width as f64 / width.max(1) as f64)),
+            Resize::Height(new_height) => (scaled(width, new_height as f64 / height.max(1) as f64), new_height),
+            Resize::Percent(percent) => (scaled(width, percent / 100.0), scaled(height, percent / 100.0)),
+        }
+    }
+}
+
+fn parse_format(name: &str) -> Result<OutputFormat, Box<dyn Error>> {
+    match name.to_ascii_lowercase().as_str() {
+        "ppm" | "p6" => Ok(OutputFormat::Ppm { binary: true }),
+        "ppm-ascii" | "p3" => Ok(OutputFormat::Ppm { binary: false }),
+        "pgm" | "p5" => Ok(OutputFormat::Pgm { binary: true }),
+        "pgm-ascii" | "p2" => Ok(OutputFormat::Pgm { binary: false }),
+        "pbm" | "p4" => Ok(OutputFormat::Pbm { binary: true }),
+        "pbm-ascii" | "p1" => Ok(OutputFormat::Pbm { binary: false }),
+        "jpeg" | "jpg" => Ok(OutputFormat::Jpeg(JpegOptions::default())),
+        _ => Err(format!("Unknown output format \"{}\".", name).into()),
+    }
+}
+
+struct Job {
+    format: OutputFormat,
+    resize: Option<Resize>,
+    recipe: Option<Recipe>,
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut format_name = None;
+    // Overrides for the JPEG encoder, also applied to a recipe's output.
+    let mut quality = None;
+    let mut subsampling = None;
+    let mut progressive = false;
+    let mut optimize_huffman = false;
+    let mut restart_interval = None;
+    let mut tables = None;
+    let mut strip_metadata = false;
+    let mut resize = None;
+    let mut recipe = None;
+    let mut out_dir = None;
+    let mut jobs = thread::available_parallelism().map(|jobs| jobs.get()).unwrap_or(1);
+    let mut inputs = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value.", name));
+        match arg.as_str() {
+            "-f" | "--format" => format_name = Some(value("--format")?.as_str()),
+            "-q" | "--quality" => {
+                let val: u8 = value("--quality")?.parse()?;
+                if !(1..=100).contains(&val) {
+                    return Err("Quality must be between 1 and 100.".into());
+                }
+                quality = Some(val);
+            }
+            "--subsampling" => subsampling = Some(value("--subsampling")?.parse::<Subsampling>()?),
+            "--progressive" => progressive = true,
+            "--optimize" => optimize_huffman = true,
+            "--restart" => restart_interval = Some(value("--restart")?.parse::<u16>()?),
+            "--quant-tables" => {
+                let values = fs::read_to_string(value("--quant-tables")?)?;
+                tables = Some(parse_quantization_tables(&values)?);
+            }
+            "--strip-metadata" => strip_metadata = true,
+            "--resize" => resize = Some(Resize::parse(value("--resize")?)?),
+            "--recipe" => recipe = Some(Recipe::from_file(value("--recipe")?)?),
+            "-o" | "--out-dir" => out_dir = Some(PathBuf::from(value("--out-dir")?)),
+            "-j" | "--jobs" => jobs = value("--jobs")?.parse::<usize>()?.max(1),
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option \"{}\".", arg).into())
+            }
+            _ => inputs.push(arg.as_str()),
+        }
+    }
+
+    // The format given on the command line wins over the one of the recipe.
+    let recipe_format = recipe.as_ref().and_then(|recipe: &Recipe| recipe.output.clone());
+    let mut format = match (format_name, recipe_format) {
+        (Some(name), _) => parse_format(name)?,
+        (None, Some(format)) => format,
+        (None, None) => return Err("Missing output format, pass --format.".into()),
+    };
+    if let OutputFormat::Jpeg(options) = &mut format {
+        options.quality = quality.unwrap_or(options.quality);
+        options.subsampling = subsampling.unwrap_or(options.subsampling);
+        options.progressive |= progressive;
+        options.optimize_huffman |= optimize_huffman;
+        options.restart_interval = restart_interval.unwrap_or(options.restart_interval);
+        if let Some((luma, chroma)) = tables {
+            options.luma_table = Some(luma);
+            options.chroma_table = chroma;
+        }
+        options.keep_metadata &= !strip_metadata;
+    }
+
+    let files = expand_inputs(&inputs)?;
+    if files.is_empty() {
+        return Err("No input files.".into());
+    }
+    if let Some(dir) = &out_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Named up front, so workers never write the same file.
+    let outputs = output_paths(&files, out_dir.as_deref(), format.extension());
+    let job = Job {
+        format,
+        resize,
+        recipe,
+    };
+
+    // Workers pick the next file until none are left, results are reported
+    // as they finish.
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut failed = 0;
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(files.len()) {
+            let sender = sender.clone();
+            let (files, outputs, job, next) = (&files, &outputs, &job, &next);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let file = match files.get(index) {
+                    Some(file) => file,
+                    None => return,
+                };
+                let result = convert(job, file, &outputs[index]).map_err(|err| err.to_string());
+                sender.send((file, result)).unwrap();
+            });
+        }
+        drop(sender);
+
+        for (file, result) in receiver {
+            match result {
+                Ok(output) => println!("{} -> {}", file.display(), output.display()),
+                Err(err) => {
+                    eprintln!("{}: {}", file.display(), err);
+                    failed += 1;
+                }
+            }
+        }
+    });
+
+    if failed > 0 {
+        return Err(format!("{} of {} files failed.", failed, files.len()).into());
+    }
+
+    return Ok(());
+}
+
+fn convert(job: &Job, file: &Path, output: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let image = load_from_file(file.to_str().ok_or("Invalid file name.")?)?;
+
+    let mut result = None;
+    if let Some(recipe) = &job.recipe {
+        result = Some(recipe.apply(image.as_ref()));
+    }
+    if let Some(resize) = job.resize {
+        let source: &dyn Image = match &result {
+            Some(result) => result,
+            None => image.as_ref(),
+        };
+        let (width, height) = resize.target(source.get_width(), source.get_height());
+        let resize = Operation::Resize { width, height };
+        resize.validate()?;
+        result = Some(resize.apply(source));
+    }
+
+    let mut vec = Vec::new();
+    match &result {
+        Some(result) => job.format.encode(result, &mut vec)?,
+        None => job.format.encode(image.as_ref(), &mut vec)?,
+    }
+
+    fs::write(output, vec)?;
+
+    return Ok(output.to_path_buf());
+}
@@ -0,0 +1,141 @@
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+
+use serde::Serialize;
+
+use ppm::hash::{group_duplicates, hamming_distance, HashKind};
+use ppm::image::load_from_file;
+
+use crate::files::expand_inputs;
+
+// Out of 64 bits.
+const DEFAULT_DISTANCE: u32 = 8;
+
+#[derive(Serialize)]
+struct Member {
+    file: String,
+    hash: String,
+    // From the first file of the group.
+    distance: u32,
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut kind = HashKind::Perceptual;
+    let mut max_distance = DEFAULT_DISTANCE;
+    let mut json = false;
+    let mut jobs = thread::available_parallelism().map(|jobs| jobs.get()).unwrap_or(1);
+    let mut inputs = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value.", name));
+        match arg.as_str() {
+            "--hash" => kind = value("--hash")?.parse::<HashKind>()?,
+            "-d" | "--distance" => {
+                let val: u32 = value("--distance")?.parse()?;
+                if val > 64 {
+                    return Err("Distance must be between 0 and 64.".into());
+                }
+                max_distance = val;
+            }
+            "--json" => json = true,
+            "-j" | "--jobs" => jobs = value("--jobs")?.parse::<usize>()?.max(1),
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option \"{}\".", arg).into())
+            }
+            _ => inputs.push(arg.as_str()),
+        }
+    }
+
+    let files = expand_inputs(&inputs)?;
+    if files.is_empty() {
+        return Err("No input files.".into());
+    }
+
+    // Hashed in parallel like convert does, kept in input order.
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut hashes = vec![None; files.len()];
+    let mut failed = 0;
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(files.len()) {
+            let sender = sender.clone();
+            let (files, next) = (&files, &next);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let file = match files.get(index) {
+                    Some(file) => file,
+                    None => return,
+                };
+                let result = file
+                    .to_str()
+                    .ok_or_else(|| "Invalid file name.".to_string())
+                    .and_then(|name| load_from_file(name).map_err(|err| err.to_string()))
+                    .map(|image| kind.hash(image.as_ref()));
+                sender.send((index, result)).unwrap();
+            });
+        }
+        drop(sender);
+
+        for (index, result) in receiver {
+            match result {
+                Ok(hash) => hashes[index] = Some(hash),
+                Err(err) => {
+                    eprintln!("{}: {}", files[index].display(), err);
+                    failed += 1;
+                }
+            }
+        }
+    });
+
+    let hashed: Vec<(usize, u64)> = hashes
+        .iter()
+        .enumerate()
+        .filter_map(|(index, hash)| hash.map(|hash| (index, hash)))
+        .collect();
+    let values: Vec<u64> = hashed.iter().map(|(_, hash)| *hash).collect();
+    let groups: Vec<Vec<Member>> = group_duplicates(&values, max_distance)
+        .iter()
+        .map(|group| {
+            let first = values[group[0]];
+            group
+                .iter()
+                .map(|&member| Member {
+                    file: files[hashed[member].0].display().to_string(),
+                    hash: format!("{:016x}", values[member]),
+                    distance: hamming_distance(first, values[member]),
+                })
+                .collect()
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&groups)?);
+    } else {
+        for (number, group) in groups.iter().enumerate() {
+            println!("group {} ({} files)", number + 1, group.len());
+            for member in group {
+                println!("  {}  {:>2}  {}", member.hash, member.distance, member.file);
+            }
+        }
+        println!(
+            "{} groups of near duplicates among {} files ({}, distance {}).",
+            groups.len(),
+            hashed.len(),
+            kind,
+            max_distance
+        );
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} files failed.", failed, files.len()).into());
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,108 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const IMAGE_EXTENSIONS: [&str; 6] = ["ppm", "pgm", "pbm", "pnm", "jpg", "jpeg"];
+
+// Expands directories and glob patterns into the image files they contain.
+// Shells usually expand globs themselves, quoted patterns end up here.
+pub fn expand_inputs(inputs: &[&str]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut files = Vec::new();
+
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file() && is_image(path))
+                .collect();
+            entries.sort();
+            files.extend(entries);
+        } else if input.contains(['*', '?', '[']) {
+            let matches: Vec<PathBuf> = glob::glob(input)?
+                .filter_map(Result::ok)
+                .filter(|path| path.is_file())
+                .collect();
+            if matches.is_empty() {
+                return Err(format!("No files match \"{}\".", input).into());
+            }
+            files.extend(matches);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    return Ok(files);
+}
+
+fn is_image(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()),
+        None => false,
+    }
+}
+
+// Same names with the new extension, one per input. Outputs never overwrite
+// an input or each other: names that are taken get the input's extension
+// and then a number appended, e.g. x.jpeg, x-pgm.jpeg, x-pgm-2.jpeg.
+pub fn output_paths(inputs: &[PathBuf], out_dir: Option<&Path>, extension: &str) -> Vec<PathBuf> {
+    let mut used: HashSet<PathBuf> = inputs.iter().cloned().collect();
+    let mut outputs = Vec::with_capacity(inputs.len());
+
+    for input in inputs {
+        let dir = match out_dir {
+            Some(dir) => dir.to_path_buf(),
+            None => input.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        let stem = input.file_stem().and_then(|stem| stem.to_str()).unwrap_or("image");
+        let source = input.extension().and_then(|ext| ext.to_str()).unwrap_or("processed");
+
+        let mut output = dir.join(format!("{}.{}", stem, extension));
+        let mut count = 1;
+        while used.contains(&output) {
+            let name = match count {
+                1 => format!("{}-{}.{}", stem, source, extension),
+                _ => format!("{}-{}-{}.{}", stem, source, count, extension),
+            };
+            output = dir.join(name);
+            count += 1;
+        }
+
+        used.insert(output.clone());
+        outputs.push(output);
+    }
+
+    return outputs;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        return names.iter().map(PathBuf::from).collect();
+    }
+
+    #[test]
+    fn outputs_never_collide() {
+        let inputs = paths(&["dir/x.ppm", "dir/x.pgm", "dir/y.jpeg"]);
+        assert_eq!(output_paths(&inputs, None, "jpeg"), paths(&["dir/x.jpeg", "dir/x-pgm.jpeg", "dir/y-jpeg.jpeg"]));
+
+        let inputs = paths(&["a/x.ppm", "b/x.ppm", "c/x.ppm"]);
+        let out_dir = Path::new("out");
+        assert_eq!(
+            output_paths(&inputs, Some(out_dir), "jpeg"),
+            paths(&["out/x.jpeg", "out/x-ppm.jpeg", "out/x-ppm-2.jpeg"])
+        );
+    }
+
+    // Another input with the output's name isn't overwritten either.
+    #[test]
+    fn outputs_never_overwrite_inputs() {
+        let inputs = paths(&["x.pgm", "x.ppm"]);
+        assert_eq!(output_paths(&inputs, None, "ppm"), paths(&["x-pgm.ppm", "x-ppm.ppm"]));
+    }
+}
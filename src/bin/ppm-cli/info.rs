@@ -0,0 +1,97 @@
+use std::error::Error;
+
+use serde::Serialize;
+
+use ppm::info::{file_info, ImageInfo};
+
+use crate::files::expand_inputs;
+
+#[derive(Serialize)]
+struct Entry {
+    file: String,
+    #[serde(flatten)]
+    info: ImageInfo,
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut json = false;
+    let mut with_stats = true;
+    let mut inputs = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--no-stats" => with_stats = false,
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option \"{}\".", arg).into())
+            }
+            _ => inputs.push(arg.as_str()),
+        }
+    }
+
+    let files = expand_inputs(&inputs)?;
+    if files.is_empty() {
+        return Err("Missing input file.".into());
+    }
+
+    let mut entries = Vec::new();
+    let mut failed = 0;
+    for file in &files {
+        let name = file.display().to_string();
+        match file_info(&name, with_stats) {
+            Ok(info) => entries.push(Entry { file: name, info }),
+            Err(err) => {
+                eprintln!("{}: {}", name, err);
+                failed += 1;
+            }
+        }
+    }
+
+    if json {
+        // A single object for one file, an array otherwise.
+        let text = match &entries[..] {
+            [entry] if files.len() == 1 => serde_json::to_string_pretty(entry)?,
+            _ => serde_json::to_string_pretty(&entries)?,
+        };
+        println!("{}", text);
+    } else {
+        for entry in &entries {
+            print_info(entry);
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} files failed.", failed, files.len()).into());
+    }
+
+    return Ok(());
+}
+
+fn print_info(entry: &Entry) {
+    let info = &entry.info;
+    println!("{}", entry.file);
+    match &info.variant {
+        Some(variant) => println!("  format:     {} ({})", info.format, variant),
+        None => println!("  format:     {}", info.format),
+    }
+    println!("  size:       {}x{}", info.width, info.height);
+    println!("  max value:  {}", info.max_value);
+    println!("  bit depth:  {}", info.bit_depth);
+    println!("  channels:   {}", info.channels);
+    println!("  file size:  {} bytes", info.file_size);
+    for comment in &info.comments {
+        println!("  comment:    {}", comment);
+    }
+    for field in &info.exif {
+        println!("  exif:       {} = {}", field.tag, field.value);
+    }
+
+    if let Some(stats) = &info.stats {
+        for (channel, name) in ["r", "g", "b"].iter().enumerate() {
+            println!(
+                "  {}:          mean {:.3}, stddev {:.3}, min {}, max {}",
+                name, stats.mean[channel], stats.stddev[channel], stats.min[channel], stats.max[channel]
+            );
+        }
+    }
+}
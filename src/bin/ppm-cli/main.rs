@@ -0,0 +1,93 @@
+#![allow(clippy::needless_return)]
+
+mod compare;
+mod convert;
+mod duplicates;
+mod files;
+mod info;
+mod markers;
+mod run;
+mod stats;
+mod transform;
+
+use std::{env, error::Error, process};
+
+const USAGE: &str = "Usage: ppm-cli <command> [arguments]
+
+Commands:
+    info <file>... [--json] [--no-stats]  Print format, header fields and statistics
+    stats <file> [--roi <selection>]...   Print per-channel statistics
+    markers <file>... [--json] [--tables] List the segments of JPEG files
+    run <recipe> <file>... [-o <dir>]     Apply a JSON or TOML recipe to each file
+    convert <input>... [options]          Convert files, directories or globs
+    transform <input>... [options]        Rotate, flip or crop JPEG files without re-encoding
+    compare <a> <b> [options]             Print MSE, PSNR, SSIM, MS-SSIM and max error
+    duplicates <input>... [options]       Group near-duplicate images by perceptual hash
+
+Convert options:
+    -f, --format <format>   ppm, ppm-ascii, pgm, pgm-ascii, pbm, pbm-ascii or jpeg
+    -q, --quality <1-100>   JPEG quality (default 90)
+    --subsampling <mode>    JPEG chroma subsampling: 4:4:4, 4:2:2 or 4:2:0 (default)
+    --progressive           Write progressive instead of baseline JPEG
+    --optimize              Optimise the JPEG Huffman tables
+    --restart <mcus>        JPEG restart interval, 0 disables it
+    --quant-tables <file>   64 luma and optionally 64 chroma quantisation values
+    --strip-metadata        Leave EXIF data and comments out of JPEG files
+    --resize <size>         WxH, Wx or xH keeping the aspect ratio, or N%
+    --recipe <file>         Apply a recipe before resizing
+    -o, --out-dir <dir>     Write next to the inputs by default
+    -j, --jobs <count>      Files converted in parallel (default: all cores)
+
+Transform options, applied in the order given:
+    --rotate <90|180|270>   Rotate clockwise
+    --flip <h|v>            Flip horizontally or vertically
+    --transpose             Swap rows and columns
+    --crop <x,y,w,h>        Crop, the origin moves to the closest MCU boundary
+    -o, --out-dir <dir>     Write next to the inputs by default
+Edges that aren't whole MCUs are trimmed when they would end up at the top or
+left. EXIF orientation is applied first and reset.
+
+Compare options:
+    --metric <name>         Print only mse, psnr, ssim, ms-ssim or max-error
+    --threshold <value>     Fail unless the metric is at least the value, or at
+                            most for mse and max-error
+    --json                  Print all metrics as JSON
+
+Duplicates options:
+    --hash <kind>           ahash, dhash or phash (default)
+    -d, --distance <bits>   Largest Hamming distance between duplicates (default 8)
+    --json                  Print the groups as JSON
+    -j, --jobs <count>      Files hashed in parallel (default: all cores)
+
+Selections:
+    rect:x,y,width,height
+    ellipse:cx,cy,rx,ry
+    poly:x,y;x,y;x,y[;...]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let result: Result<(), Box<dyn Error>> = match args.first().map(|arg| arg.as_str()) {
+        Some("info") => info::run(&args[1..]),
+        Some("stats") => stats::run(&args[1..]),
+        Some("markers") => markers::run(&args[1..]),
+        Some("run") => run::run(&args[1..]),
+        Some("convert") => convert::run(&args[1..]),
+        Some("transform") => transform::run(&args[1..]),
+        Some("compare") => compare::run(&args[1..]),
+        Some("duplicates") => duplicates::run(&args[1..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,153 @@
+use std::error::Error;
+
+use serde::Serialize;
+
+use ppm::markers::{file_markers, Details, MarkerReport};
+
+use crate::files::expand_inputs;
+
+#[derive(Serialize)]
+struct Entry {
+    file: String,
+    #[serde(flatten)]
+    report: MarkerReport,
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut json = false;
+    let mut tables = false;
+    let mut inputs = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--tables" => tables = true,
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option \"{}\".", arg).into())
+            }
+            _ => inputs.push(arg.as_str()),
+        }
+    }
+
+    let files = expand_inputs(&inputs)?;
+    if files.is_empty() {
+        return Err("Missing input file.".into());
+    }
+
+    let mut entries = Vec::new();
+    let mut failed = 0;
+    for file in &files {
+        let name = file.display().to_string();
+        match file_markers(&name) {
+            Ok(report) => entries.push(Entry { file: name, report }),
+            Err(err) => {
+                eprintln!("{}: {}", name, err);
+                failed += 1;
+            }
+        }
+    }
+
+    if json {
+        let text = match &entries[..] {
+            [entry] if files.len() == 1 => serde_json::to_string_pretty(entry)?,
+            _ => serde_json::to_string_pretty(&entries)?,
+        };
+        println!("{}", text);
+    } else {
+        for entry in &entries {
+            print_report(entry, tables);
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} files failed.", failed, files.len()).into());
+    }
+
+    return Ok(());
+}
+
+fn print_report(entry: &Entry, tables: bool) {
+    let report = &entry.report;
+    println!("{}", entry.file);
+    for segment in &report.segments {
+        if segment.length > 0 {
+            println!("  {:>8}  {:<5} length {}", segment.offset, segment.name, segment.length);
+        } else {
+            println!("  {:>8}  {}", segment.offset, segment.name);
+        }
+
+        match &segment.details {
+            Details::None => {}
+            Details::Application { identifier } => println!("            identifier \"{}\"", identifier),
+            Details::Comment { text } => println!("            \"{}\"", text),
+            Details::QuantizationTables { tables: quantization_tables } => {
+                for table in quantization_tables {
+                    println!(
+                        "            table {}, {}-bit, closest to quality {}",
+                        table.id, table.precision, table.estimated_quality
+                    );
+                    if tables {
+                        for row in table.values.chunks(8) {
+                            let row: Vec<String> = row.iter().map(|val| format!("{:3}", val)).collect();
+                            println!("              {}", row.join(" "));
+                        }
+                    }
+                }
+            }
+            Details::HuffmanTables { tables: huffman_tables } => {
+                for table in huffman_tables {
+                    println!("            {} table {}, {} codes", table.class, table.id, table.symbols.len());
+                    if tables {
+                        let counts: Vec<String> = table.counts.iter().map(|val| val.to_string()).collect();
+                        let symbols: Vec<String> = table.symbols.iter().map(|val| format!("{:02X}", val)).collect();
+                        println!("              counts  {}", counts.join(" "));
+                        println!("              symbols {}", symbols.join(" "));
+                    }
+                }
+            }
+            Details::Frame {
+                process,
+                precision,
+                width,
+                height,
+                components,
+            } => {
+                println!("            {}, {}-bit, {}x{}", process, precision, width, height);
+                for component in components {
+                    println!(
+                        "            component {}: sampling {}x{}, quantization table {}",
+                        component.id, component.horizontal, component.vertical, component.table
+                    );
+                }
+            }
+            Details::Scan {
+                components,
+                spectral_start,
+                spectral_end,
+                approximation_high,
+                approximation_low,
+                data_length,
+                restart_markers,
+            } => {
+                let components: Vec<String> = components
+                    .iter()
+                    .map(|component| format!("{} (DC {}, AC {})", component.id, component.dc_table, component.ac_table))
+                    .collect();
+                println!("            components {}", components.join(", "));
+                println!(
+                    "            spectral {}..{}, approximation {}/{}",
+                    spectral_start, spectral_end, approximation_high, approximation_low
+                );
+                println!("            {} bytes of data, {} restart markers", data_length, restart_markers);
+            }
+            Details::RestartInterval { mcus } => println!("            every {} MCUs", mcus),
+        }
+    }
+
+    if let Some(quality) = report.estimated_quality {
+        println!("  estimated quality: {}", quality);
+    }
+    for problem in &report.problems {
+        println!("  problem: {}", problem);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ppm::image::load_from_file;
+use ppm::recipe::{OutputFormat, Recipe};
+
+use crate::files::{expand_inputs, output_paths};
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut recipe_path = None;
+    let mut out_dir = None;
+    let mut files = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--out-dir" => out_dir = Some(args.next().ok_or("--out-dir needs a directory.")?),
+            _ if recipe_path.is_none() => recipe_path = Some(arg),
+            _ => files.push(arg.as_str()),
+        }
+    }
+
+    let recipe = Recipe::from_file(recipe_path.ok_or("Missing recipe file.")?)?;
+    let files = expand_inputs(&files)?;
+    if files.is_empty() {
+        return Err("No input files.".into());
+    }
+
+    if let Some(dir) = out_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let format = recipe.output.clone().unwrap_or(OutputFormat::Ppm { binary: true });
+    let outputs = output_paths(&files, out_dir.map(Path::new), format.extension());
+    let mut failed = 0;
+    for (file, output) in files.iter().zip(&outputs) {
+        match process(&recipe, &format, file, output) {
+            Ok(output) => println!("{} -> {}", file.display(), output.display()),
+            Err(err) => {
+                eprintln!("{}: {}", file.display(), err);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} files failed.", failed, files.len()).into());
+    }
+
+    return Ok(());
+}
+
+fn process(
+    recipe: &Recipe,
+    format: &OutputFormat,
+    file: &Path,
+    output: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let image = load_from_file(file.to_str().ok_or("Invalid file name.")?)?;
+    let result = recipe.apply(image.as_ref());
+
+    let mut vec = Vec::new();
+    format.encode(&result, &mut vec)?;
+
+    fs::write(output, vec)?;
+
+    return Ok(output.to_path_buf());
+}
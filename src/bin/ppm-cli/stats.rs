@@ -0,0 +1,51 @@
+use std::error::Error;
+
+use ppm::image::load_from_file;
+use ppm::selection::{region_stats, RegionStats, Selection};
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut file = None;
+    let mut selections = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--roi" => {
+                let spec = args.next().ok_or("--roi needs a selection.")?;
+                selections.push(spec.parse::<Selection>()?);
+            }
+            _ if file.is_none() => file = Some(arg.as_str()),
+            _ => return Err(format!("Unexpected argument \"{}\".", arg).into()),
+        }
+    }
+
+    let file = file.ok_or("Missing input file.")?;
+    let image = load_from_file(file)?;
+    println!(
+        "{}: {}x{}, max value {}",
+        file,
+        image.get_width(),
+        image.get_height(),
+        image.get_max_value()
+    );
+
+    if selections.is_empty() {
+        print_stats("image", &region_stats(image.as_ref(), None));
+    }
+
+    for selection in &selections {
+        print_stats(&selection.to_string(), &region_stats(image.as_ref(), Some(selection)));
+    }
+
+    return Ok(());
+}
+
+fn print_stats(label: &str, stats: &RegionStats) {
+    println!("{} ({} pixels)", label, stats.count);
+    for (channel, name) in ["r", "g", "b"].iter().enumerate() {
+        println!(
+            "  {}: mean {:.3}, stddev {:.3}, min {}, max {}",
+            name, stats.mean[channel], stats.stddev[channel], stats.min[channel], stats.max[channel]
+        );
+    }
+}
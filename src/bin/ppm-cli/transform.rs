@@ -0,0 +1,82 @@
+use std::{error::Error, fs, path::PathBuf};
+
+use ppm::lossless::{transform_jpeg, Transform};
+
+use crate::files::{expand_inputs, output_paths};
+
+fn parse_crop(text: &str) -> Result<Transform, Box<dyn Error>> {
+    let values = text
+        .split(',')
+        .map(|val| val.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()?;
+
+    match values[..] {
+        [x, y, width, height] => Ok(Transform::Crop { x, y, width, height }),
+        _ => Err("Crop needs x,y,width,height.".into()),
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut transforms = Vec::new();
+    let mut out_dir = None;
+    let mut inputs = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value.", name));
+        match arg.as_str() {
+            "--rotate" => transforms.push(match value("--rotate")?.as_str() {
+                "90" => Transform::Rotate90,
+                "180" => Transform::Rotate180,
+                "270" => Transform::Rotate270,
+                angle => return Err(format!("Can't rotate by {}, use 90, 180 or 270.", angle).into()),
+            }),
+            "--flip" => transforms.push(match value("--flip")?.as_str() {
+                "h" | "horizontal" => Transform::FlipHorizontal,
+                "v" | "vertical" => Transform::FlipVertical,
+                direction => return Err(format!("Unknown flip direction \"{}\".", direction).into()),
+            }),
+            "--transpose" => transforms.push(Transform::Transpose),
+            "--crop" => transforms.push(parse_crop(value("--crop")?)?),
+            "-o" | "--out-dir" => out_dir = Some(PathBuf::from(value("--out-dir")?)),
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("Unknown option \"{}\".", arg).into())
+            }
+            _ => inputs.push(arg.as_str()),
+        }
+    }
+
+    let files = expand_inputs(&inputs)?;
+    if files.is_empty() {
+        return Err("No input files.".into());
+    }
+    if let Some(dir) = &out_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let outputs = output_paths(&files, out_dir.as_deref(), "jpg");
+    let mut failed = 0;
+    for (file, output) in files.iter().zip(&outputs) {
+        let result = fs::read(file)
+            .map_err(|err| err.into())
+            .and_then(|buffer| transform_jpeg(&buffer, &transforms))
+            .and_then(|vec| {
+                fs::write(output, vec)?;
+                Ok(output)
+            });
+
+        match result {
+            Ok(output) => println!("{} -> {}", file.display(), output.display()),
+            Err(err) => {
+                eprintln!("{}: {}", file.display(), err);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} files failed.", failed, files.len()).into());
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,48 @@
+#![allow(clippy::needless_return)]
+
+use gloo_events::EventListener;
+use js_sys::{Array, Object, Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent};
+
+use ppm::jobs::{run_job, Job, Reply};
+
+// Runs the jobs the viewer sends, one at a time. Messages both ways are
+// objects of an id, the job or reply as JSON and the bytes that go with it.
+fn main() {
+    wasm_logger::init(wasm_logger::Config::default());
+
+    let scope = js_sys::global().dyn_into::<DedicatedWorkerGlobalScope>().unwrap();
+    EventListener::new(&scope.clone(), "message", move |event| {
+        let message = event.dyn_ref::<MessageEvent>().unwrap().data();
+        let id = Reflect::get(&message, &"id".into()).unwrap();
+        let job = Reflect::get(&message, &"job".into())
+            .ok()
+            .and_then(|job| job.as_string())
+            .ok_or_else(|| "Missing job.".to_string())
+            .and_then(|job| serde_json::from_str::<Job>(&job).map_err(|err| err.to_string()));
+        let data = Uint8Array::new(&Reflect::get(&message, &"data".into()).unwrap()).to_vec();
+
+        let (reply, data) = match job {
+            Ok(job) => run_job(&job, &data, &mut |fraction| {
+                post(&scope, &id, &Reply::Progress { fraction }, &[]);
+            }),
+            Err(error) => (Reply::Failed { error }, Vec::new()),
+        };
+        post(&scope, &id, &reply, &data);
+    })
+    .forget();
+}
+
+// The buffer of the data is handed over rather than copied.
+fn post(scope: &DedicatedWorkerGlobalScope, id: &JsValue, reply: &Reply, data: &[u8]) {
+    let data = Uint8Array::from(data);
+    let message = Object::new();
+    Reflect::set(&message, &"id".into(), id).unwrap();
+    Reflect::set(&message, &"reply".into(), &serde_json::to_string(reply).unwrap().into()).unwrap();
+    Reflect::set(&message, &"data".into(), &data).unwrap();
+
+    if let Err(err) = scope.post_message_with_transfer(&message, &Array::of1(&data.buffer())) {
+        log::error!("Couldn't post reply: {:?}", err);
+    }
+}
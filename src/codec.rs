@@ -0,0 +1,1049 @@
+use std::{error::Error, f32::consts::PI};
+
+use crate::image::{Bitmap, BitmapData, Image};
+use crate::jpeg::{read_segments, write_segment, Subsampling, STANDARD_CHROMA_TABLE, STANDARD_LUMA_TABLE};
+
+// Baseline JPEG codec written out stage by stage for teaching: colour
+// conversion, chroma subsampling, 8x8 DCT, quantisation, zig-zag ordering,
+// run-length and Huffman coding. Every intermediate result is kept so it can
+// be shown in the viewer. It is slow on purpose; use `write_to_jpeg` for real
+// work.
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const APP0: u8 = 0xE0;
+const DQT: u8 = 0xDB;
+const SOF0: u8 = 0xC0;
+const SOF1: u8 = 0xC1;
+const DHT: u8 = 0xC4;
+const SOS: u8 = 0xDA;
+const DRI: u8 = 0xDD;
+
+// Natural (row-major) index of every position along the zig-zag scan.
+pub const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10,
+    17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Huffman tables from Annex K.3: code counts per length 1..=16, then symbols.
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_LUMA_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_CHROMA_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plane {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+impl Plane {
+    pub fn new(width: usize, height: usize) -> Self {
+        Plane {
+            width,
+            height,
+            data: vec![0.0; width * height],
+        }
+    }
+
+    // Sample with coordinates clamped to the plane, which pads it by
+    // repeating the last row and column.
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        let x = x.min(self.width.saturating_sub(1));
+        let y = y.min(self.height.saturating_sub(1));
+
+        return self.data.get(y * self.width + x).copied().unwrap_or(0.0);
+    }
+}
+
+// One colour component on its way through the encoder.
+#[derive(Debug, Clone)]
+pub struct Component {
+    pub name: &'static str,
+    // Samples per MCU horizontally and vertically.
+    pub sampling: (usize, usize),
+    // Full resolution plane, then after subsampling.
+    pub full: Plane,
+    pub plane: Plane,
+    // Block grid covering the plane padded to whole MCUs.
+    pub blocks_wide: usize,
+    pub blocks_high: usize,
+    // Per block in row-major order, coefficients in natural order.
+    pub dct: Vec<[f32; 64]>,
+    pub quantized: Vec<[i16; 64]>,
+    // Huffman coded size of every block in bits.
+    pub bits: Vec<u32>,
+    pub table: [u16; 64],
+}
+
+#[derive(Debug, Clone)]
+pub struct Encoded {
+    pub width: usize,
+    pub height: usize,
+    pub quality: u8,
+    pub subsampling: Subsampling,
+    pub components: Vec<Component>,
+    pub bytes: Vec<u8>,
+}
+
+// Standard table scaled like the IJG encoder does for the given quality.
+pub fn scaled_table(table: &[u16; 64], quality: u8) -> [u16; 64] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+
+    return table.map(|val| ((val as u32 * scale + 50) / 100).clamp(1, 255) as u16);
+}
+
+pub fn rgb_to_ycbcr(r: f32, g: f32, b: f32) -> [f32; 3] {
+    [
+        0.299 * r + 0.587 * g + 0.114 * b,
+        128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b,
+        128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b,
+    ]
+}
+
+pub fn ycbcr_to_rgb(y: f32, cb: f32, cr: f32) -> [f32; 3] {
+    [
+        y + 1.402 * (cr - 128.0),
+        y - 0.344136 * (cb - 128.0) - 0.714136 * (cr - 128.0),
+        y + 1.772 * (cb - 128.0),
+    ]
+}
+
+fn sampling_factors(subsampling: Subsampling) -> (usize, usize) {
+    match subsampling {
+        Subsampling::S444 => (1, 1),
+        Subsampling::S422 => (2, 1),
+        Subsampling::S420 => (2, 2),
+    }
+}
+
+// Averages factor_x x factor_y boxes, partial boxes at the edges included.
+fn subsample(plane: &Plane, factor_x: usize, factor_y: usize) -> Plane {
+    let mut out = Plane::new(plane.width.div_ceil(factor_x), plane.height.div_ceil(factor_y));
+    for y in 0..out.height {
+        for x in 0..out.width {
+            let mut sum = 0.0;
+            let mut count = 0.0;
+            for sy in y * factor_y..((y + 1) * factor_y).min(plane.height) {
+                for sx in x * factor_x..((x + 1) * factor_x).min(plane.width) {
+                    sum += plane.data[sy * plane.width + sx];
+                    count += 1.0;
+                }
+            }
+            out.data[y * out.width + x] = sum / count;
+        }
+    }
+
+    return out;
+}
+
+fn cosine_table() -> [[f32; 8]; 8] {
+    let mut table = [[0.0; 8]; 8];
+    for (x, row) in table.iter_mut().enumerate() {
+        for (u, val) in row.iter_mut().enumerate() {
+            *val = ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+        }
+    }
+
+    return table;
+}
+
+fn scale_factor(u: usize) -> f32 {
+    if u == 0 {
+        std::f32::consts::FRAC_1_SQRT_2
+    } else {
+        1.0
+    }
+}
+
+// Two dimensional DCT-II of a level shifted block, written as two passes of
+// the one dimensional transform.
+pub fn forward_dct(block: &[f32; 64]) -> [f32; 64] {
+    let cos = cosine_table();
+    let mut rows = [0.0; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            let sum: f32 = (0..8).map(|x| block[y * 8 + x] * cos[x][u]).sum();
+            rows[y * 8 + u] = sum * scale_factor(u) / 2.0;
+        }
+    }
+
+    let mut out = [0.0; 64];
+    for u in 0..8 {
+        for v in 0..8 {
+            let sum: f32 = (0..8).map(|y| rows[y * 8 + u] * cos[y][v]).sum();
+            out[v * 8 + u] = sum * scale_factor(v) / 2.0;
+        }
+    }
+
+    return out;
+}
+
+pub fn inverse_dct(coefficients: &[f32; 64]) -> [f32; 64] {
+    let cos = cosine_table();
+    let mut columns = [0.0; 64];
+    for u in 0..8 {
+        for y in 0..8 {
+            let sum: f32 = (0..8)
+                .map(|v| scale_factor(v) * coefficients[v * 8 + u] * cos[y][v])
+                .sum();
+            columns[y * 8 + u] = sum / 2.0;
+        }
+    }
+
+    let mut out = [0.0; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            let sum: f32 = (0..8)
+                .map(|u| scale_factor(u) * columns[y * 8 + u] * cos[x][u])
+                .sum();
+            out[y * 8 + x] = sum / 2.0;
+        }
+    }
+
+    return out;
+}
+
+pub fn quantize(coefficients: &[f32; 64], table: &[u16; 64]) -> [i16; 64] {
+    let mut out = [0; 64];
+    for i in 0..64 {
+        out[i] = (coefficients[i] / table[i] as f32).round() as i16;
+    }
+
+    return out;
+}
+
+pub fn zigzag(block: &[i16; 64]) -> [i16; 64] {
+    ZIGZAG.map(|index| block[index])
+}
+
+// Number of bits needed for the magnitude of a value, the JPEG "size".
+fn magnitude_bits(val: i32) -> u8 {
+    (32 - val.unsigned_abs().leading_zeros()) as u8
+}
+
+// Symbols of one block after zig-zag ordering: the DC difference, then
+// (zero run, size) pairs with their extra bits, ZRL for 16 zeros and EOB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbol {
+    Dc { size: u8, value: i32 },
+    Ac { run: u8, size: u8, value: i32 },
+    ZeroRun,
+    EndOfBlock,
+}
+
+pub fn run_length(block: &[i16; 64], previous_dc: i16) -> Vec<Symbol> {
+    let scan = zigzag(block);
+    let diff = scan[0] as i32 - previous_dc as i32;
+    let mut symbols = vec![Symbol::Dc {
+        size: magnitude_bits(diff),
+        value: diff,
+    }];
+
+    let mut run = 0;
+    for val in scan[1..].iter().map(|val| *val as i32) {
+        if val == 0 {
+            run += 1;
+            continue;
+        }
+
+        while run > 15 {
+            symbols.push(Symbol::ZeroRun);
+            run -= 16;
+        }
+        symbols.push(Symbol::Ac {
+            run,
+            size: magnitude_bits(val),
+            value: val,
+        });
+        run = 0;
+    }
+
+    if run > 0 {
+        symbols.push(Symbol::EndOfBlock);
+    }
+
+    return symbols;
+}
+
+// Canonical Huffman codes from the code counts per length (Annex C).
+struct HuffmanTable {
+    // (code, length) per symbol value.
+    codes: [(u16, u8); 256],
+    // Per length: smallest code, largest code and index of its first symbol.
+    min_code: [i32; 17],
+    max_code: [i32; 17],
+    first: [usize; 17],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    fn new(bits: &[u8], values: &[u8]) -> Self {
+        let mut table = HuffmanTable {
+            codes: [(0, 0); 256],
+            min_code: [0; 17],
+            max_code: [-1; 17],
+            first: [0; 17],
+            values: values.to_vec(),
+        };
+
+        let mut code = 0i32;
+        let mut index = 0;
+        for length in 1..=16 {
+            let count = bits[length - 1] as usize;
+            table.first[length] = index;
+            table.min_code[length] = code;
+            for _ in 0..count {
+                if let Some(val) = values.get(index) {
+                    table.codes[*val as usize] = (code as u16, length as u8);
+                }
+                code += 1;
+                index += 1;
+            }
+            table.max_code[length] = if count > 0 { code - 1 } else { -1 };
+            code <<= 1;
+        }
+
+        return table;
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, bits: u32, length: u8) {
+        for i in (0..length).rev() {
+            self.buffer = (self.buffer << 1) | ((bits >> i) & 1);
+            self.count += 1;
+            if self.count == 8 {
+                let byte = self.buffer as u8;
+                self.bytes.push(byte);
+                // A literal 0xFF is stuffed with a zero so it isn't read as a marker.
+                if byte == 0xFF {
+                    self.bytes.push(0);
+                }
+                self.buffer = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    // Pads the last byte with ones.
+    fn flush(&mut self) {
+        while self.count != 0 {
+            self.write(1, 1);
+        }
+    }
+}
+
+// Writes the symbols of one block and returns the number of bits used.
+fn write_symbols(writer: &mut BitWriter, symbols: &[Symbol], dc: &HuffmanTable, ac: &HuffmanTable) -> u32 {
+    let mut bits = 0;
+    let mut put = |writer: &mut BitWriter, (code, length): (u16, u8), size: u8, value: i32| {
+        writer.write(code as u32, length);
+        // Negative values are stored as value - 1 in size bits.
+        let extra = if value < 0 { value - 1 } else { value };
+        writer.write(extra as u32 & ((1u32 << size) - 1), size);
+        bits += length as u32 + size as u32;
+    };
+
+    for symbol in symbols {
+        match *symbol {
+            Symbol::Dc { size, value } => put(writer, dc.codes[size as usize], size, value),
+            Symbol::Ac { run, size, value } => put(writer, ac.codes[(run << 4 | size) as usize], size, value),
+            Symbol::ZeroRun => put(writer, ac.codes[0xF0], 0, 0),
+            Symbol::EndOfBlock => put(writer, ac.codes[0x00], 0, 0),
+        }
+    }
+
+    return bits;
+}
+
+pub fn encode(image: &dyn Image, quality: u8, subsampling: Subsampling) -> Encoded {
+    let (width, height) = (image.get_width(), image.get_height());
+    let max_value = image.get_max_value().max(1) as f32;
+
+    // Colour conversion.
+    let mut full = [Plane::new(width, height), Plane::new(width, height), Plane::new(width, height)];
+    for y in 0..height {
+        for x in 0..width {
+            let (r, g, b) = image.get_pixel_value(x, y);
+            let scale = 255.0 / max_value;
+            let ycbcr = rgb_to_ycbcr(r as f32 * scale, g as f32 * scale, b as f32 * scale);
+            for (plane, val) in full.iter_mut().zip(ycbcr) {
+                plane.data[y * width + x] = val;
+            }
+        }
+    }
+
+    // Chroma subsampling, luma keeps every sample.
+    let (max_h, max_v) = sampling_factors(subsampling);
+    let mcus_wide = width.div_ceil(8 * max_h).max(1);
+    let mcus_high = height.div_ceil(8 * max_v).max(1);
+    let luma_table = scaled_table(&STANDARD_LUMA_TABLE, quality);
+    let chroma_table = scaled_table(&STANDARD_CHROMA_TABLE, quality);
+
+    let mut components: Vec<Component> = full
+        .into_iter()
+        .zip(["Y", "Cb", "Cr"])
+        .enumerate()
+        .map(|(index, (full, name))| {
+            let (sampling, plane, table) = if index == 0 {
+                ((max_h, max_v), full.clone(), luma_table)
+            } else {
+                ((1, 1), subsample(&full, max_h, max_v), chroma_table)
+            };
+
+            Component {
+                name,
+                sampling,
+                full,
+                plane,
+                blocks_wide: mcus_wide * sampling.0,
+                blocks_high: mcus_high * sampling.1,
+                dct: Vec::new(),
+                quantized: Vec::new(),
+                bits: Vec::new(),
+                table,
+            }
+        })
+        .collect();
+
+    // Level shift, DCT and quantisation of every block.
+    for component in components.iter_mut() {
+        for block_y in 0..component.blocks_high {
+            for block_x in 0..component.blocks_wide {
+                let mut block = [0.0; 64];
+                for (i, val) in block.iter_mut().enumerate() {
+                    *val = component.plane.get(block_x * 8 + i % 8, block_y * 8 + i / 8) - 128.0;
+                }
+
+                let dct = forward_dct(&block);
+                component.quantized.push(quantize(&dct, &component.table));
+                component.dct.push(dct);
+            }
+        }
+    }
+
+    // Entropy coding.
+    let coefficients = Coefficients {
+        width,
+        height,
+        planes: components
+            .iter()
+            .enumerate()
+            .map(|(index, component)| CoefficientPlane {
+                id: index as u8 + 1,
+                sampling: component.sampling,
+                table_id: index.min(1) as u8,
+                table: component.table,
+                blocks_wide: component.blocks_wide,
+                blocks_high: component.blocks_high,
+                blocks: component.quantized.clone(),
+            })
+            .collect(),
+        segments: vec![(APP0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0".to_vec())],
+    };
+    let (bytes, bits) = write_coefficients(&coefficients);
+    for (component, bits) in components.iter_mut().zip(bits) {
+        component.bits = bits;
+    }
+
+    return Encoded {
+        width,
+        height,
+        quality,
+        subsampling,
+        components,
+        bytes,
+    };
+}
+
+// Quantised DCT blocks of one component as stored in a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoefficientPlane {
+    pub id: u8,
+    pub sampling: (usize, usize),
+    pub table_id: u8,
+    pub table: [u16; 64],
+    // Block grid padded to whole MCUs, blocks in row-major order with the
+    // coefficients in natural order.
+    pub blocks_wide: usize,
+    pub blocks_high: usize,
+    pub blocks: Vec<[i16; 64]>,
+}
+
+// A baseline JPEG file before entropy decoding, enough to write it back
+// without touching the pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coefficients {
+    pub width: usize,
+    pub height: usize,
+    pub planes: Vec<CoefficientPlane>,
+    // APPn and COM segments, copied as they are.
+    pub segments: Vec<(u8, Vec<u8>)>,
+}
+
+impl Coefficients {
+    // Largest sampling factors, an MCU covers 8 times that many pixels.
+    pub fn max_sampling(&self) -> (usize, usize) {
+        let h = self.planes.iter().map(|plane| plane.sampling.0).max().unwrap_or(1);
+        let v = self.planes.iter().map(|plane| plane.sampling.1).max().unwrap_or(1);
+
+        return (h, v);
+    }
+}
+
+// Writes a baseline file with the standard Huffman tables. Returns it with
+// the coded size of every block in bits.
+pub fn write_coefficients(coefficients: &Coefficients) -> (Vec<u8>, Vec<Vec<u32>>) {
+    let planes = &coefficients.planes;
+    let tables = [
+        (HuffmanTable::new(&DC_LUMA_BITS, &DC_LUMA_VALUES), HuffmanTable::new(&AC_LUMA_BITS, &AC_LUMA_VALUES)),
+        (HuffmanTable::new(&DC_CHROMA_BITS, &DC_CHROMA_VALUES), HuffmanTable::new(&AC_CHROMA_BITS, &AC_CHROMA_VALUES)),
+    ];
+    let mut writer = BitWriter {
+        bytes: Vec::new(),
+        buffer: 0,
+        count: 0,
+    };
+    let mut bits: Vec<Vec<u32>> = planes.iter().map(|plane| vec![0; plane.blocks.len()]).collect();
+    let mut previous_dc = vec![0i16; planes.len()];
+
+    // MCU by MCU, each holding h x v blocks of every component.
+    let (max_h, max_v) = coefficients.max_sampling();
+    let mcus_wide = coefficients.width.div_ceil(8 * max_h).max(1);
+    let mcus_high = coefficients.height.div_ceil(8 * max_v).max(1);
+    for mcu_y in 0..mcus_high {
+        for mcu_x in 0..mcus_wide {
+            for (index, plane) in planes.iter().enumerate() {
+                let (dc, ac) = &tables[index.min(1)];
+                let (h, v) = plane.sampling;
+                for block_y in mcu_y * v..(mcu_y + 1) * v {
+                    for block_x in mcu_x * h..(mcu_x + 1) * h {
+                        let block = block_y * plane.blocks_wide + block_x;
+                        let symbols = run_length(&plane.blocks[block], previous_dc[index]);
+                        previous_dc[index] = plane.blocks[block][0];
+                        bits[index][block] = write_symbols(&mut writer, &symbols, dc, ac);
+                    }
+                }
+            }
+        }
+    }
+    writer.flush();
+
+    let mut vec = vec![0xFF, SOI];
+    for (marker, payload) in &coefficients.segments {
+        write_segment(&mut vec, *marker, payload);
+    }
+
+    let mut written = Vec::new();
+    for plane in planes {
+        if written.contains(&plane.table_id) {
+            continue;
+        }
+        written.push(plane.table_id);
+
+        let mut payload = vec![plane.table_id];
+        payload.extend(ZIGZAG.iter().map(|index| plane.table[*index].min(255) as u8));
+        write_segment(&mut vec, DQT, &payload);
+    }
+
+    let mut frame = vec![8];
+    frame.extend_from_slice(&(coefficients.height as u16).to_be_bytes());
+    frame.extend_from_slice(&(coefficients.width as u16).to_be_bytes());
+    frame.push(planes.len() as u8);
+    for plane in planes {
+        let (h, v) = plane.sampling;
+        frame.extend_from_slice(&[plane.id, (h << 4 | v) as u8, plane.table_id]);
+    }
+    write_segment(&mut vec, SOF0, &frame);
+
+    let huffman_tables: [(u8, &[u8], &[u8]); 4] = [
+        (0x00, &DC_LUMA_BITS, &DC_LUMA_VALUES),
+        (0x10, &AC_LUMA_BITS, &AC_LUMA_VALUES),
+        (0x01, &DC_CHROMA_BITS, &DC_CHROMA_VALUES),
+        (0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+    ];
+    for (class_id, counts, values) in huffman_tables {
+        let mut payload = vec![class_id];
+        payload.extend_from_slice(counts);
+        payload.extend_from_slice(values);
+        write_segment(&mut vec, DHT, &payload);
+    }
+
+    let mut header = vec![planes.len() as u8];
+    for (index, plane) in planes.iter().enumerate() {
+        let table = index.min(1) as u8;
+        header.extend_from_slice(&[plane.id, table << 4 | table]);
+    }
+    header.extend_from_slice(&[0, 63, 0]);
+    write_segment(&mut vec, SOS, &header);
+
+    vec.extend_from_slice(&writer.bytes);
+    vec.extend_from_slice(&[0xFF, EOI]);
+
+    return (vec, bits);
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u8,
+}
+
+impl BitReader<'_> {
+    fn bit(&mut self) -> u32 {
+        if self.count == 0 {
+            let mut byte = 0;
+            // A marker ends the scan, missing bits read as zero.
+            if self.pos < self.data.len() && !(self.data[self.pos] == 0xFF && self.data.get(self.pos + 1) != Some(&0)) {
+                byte = self.data[self.pos];
+                self.pos += if byte == 0xFF { 2 } else { 1 };
+            }
+            self.buffer = byte as u32;
+            self.count = 8;
+        }
+
+        self.count -= 1;
+        return (self.buffer >> self.count) & 1;
+    }
+
+    fn bits(&mut self, length: u8) -> u32 {
+        (0..length).fold(0, |val, _| (val << 1) | self.bit())
+    }
+
+    fn decode(&mut self, table: &HuffmanTable) -> Result<u8, Box<dyn Error>> {
+        let mut code = 0i32;
+        for length in 1..=16 {
+            code = (code << 1) | self.bit() as i32;
+            if code <= table.max_code[length] {
+                let index = table.first[length] + (code - table.min_code[length]) as usize;
+                return table.values.get(index).copied().ok_or_else(|| "Invalid Huffman code.".into());
+            }
+        }
+
+        return Err("Invalid Huffman code.".into());
+    }
+
+    // Skips to the next byte and past a restart marker.
+    fn restart(&mut self) {
+        self.count = 0;
+        if self.data.get(self.pos) == Some(&0xFF) && matches!(self.data.get(self.pos + 1), Some(0xD0..=0xD7)) {
+            self.pos += 2;
+        }
+    }
+}
+
+// Value stored in size extra bits, the inverse of the encoder's value - 1
+// trick for negative numbers.
+fn extend(bits: u32, size: u8) -> i32 {
+    if size == 0 {
+        return 0;
+    }
+    if bits < 1 << (size - 1) {
+        return bits as i32 - (1 << size) + 1;
+    }
+
+    return bits as i32;
+}
+
+// Entropy decodes a baseline, Huffman coded file with 8-bit samples and a
+// single interleaved scan, which includes everything `encode` writes.
+pub fn read_coefficients(buffer: &[u8]) -> Result<Coefficients, Box<dyn Error>> {
+    if !buffer.starts_with(&[0xFF, SOI]) {
+        return Err("Not a jpeg file.".into());
+    }
+
+    let mut quant_tables = [[1u16; 64]; 4];
+    let mut dc_tables: [Option<HuffmanTable>; 4] = [None, None, None, None];
+    let mut ac_tables: [Option<HuffmanTable>; 4] = [None, None, None, None];
+    let mut frame: Option<(usize, usize, Vec<CoefficientPlane>)> = None;
+    let mut scan_tables = Vec::new();
+    let mut restart_interval = 0;
+    let mut segments = Vec::new();
+    let mut scan_start = None;
+
+    let mut offset = 2;
+    for (marker, payload) in read_segments(buffer) {
+        offset += 4 + payload.len();
+        match marker {
+            DQT => {
+                let mut pos = 0;
+                while pos < payload.len() {
+                    let (precision, id) = (payload[pos] >> 4, (payload[pos] & 0x0F) as usize % 4);
+                    let size = if precision == 0 { 1 } else { 2 };
+                    let values = payload.get(pos + 1..pos + 1 + 64 * size).ok_or("Truncated DQT segment.")?;
+                    for (i, index) in ZIGZAG.iter().enumerate() {
+                        quant_tables[id][*index] = if size == 1 {
+                            values[i] as u16
+                        } else {
+                            u16::from_be_bytes([values[2 * i], values[2 * i + 1]])
+                        };
+                    }
+                    pos += 1 + 64 * size;
+                }
+            }
+            DHT => {
+                let mut pos = 0;
+                while pos + 17 <= payload.len() {
+                    let (class, id) = (payload[pos] >> 4, (payload[pos] & 0x0F) as usize % 4);
+                    let bits = &payload[pos + 1..pos + 17];
+                    let count: usize = bits.iter().map(|val| *val as usize).sum();
+                    let values = payload.get(pos + 17..pos + 17 + count).ok_or("Truncated DHT segment.")?;
+                    let table = HuffmanTable::new(bits, values);
+                    if class == 0 {
+                        dc_tables[id] = Some(table);
+                    } else {
+                        ac_tables[id] = Some(table);
+                    }
+                    pos += 17 + count;
+                }
+            }
+            SOF0 | SOF1 => {
+                if payload.len() < 6 || payload[0] != 8 {
+                    return Err("Only 8-bit samples are supported.".into());
+                }
+                let height = u16::from_be_bytes([payload[1], payload[2]]) as usize;
+                let width = u16::from_be_bytes([payload[3], payload[4]]) as usize;
+                let mut planes = Vec::new();
+                for chunk in payload[6..].chunks_exact(3).take(payload[5] as usize) {
+                    planes.push(CoefficientPlane {
+                        id: chunk[0],
+                        sampling: ((chunk[1] >> 4).max(1) as usize, (chunk[1] & 0x0F).max(1) as usize),
+                        table_id: chunk[2] % 4,
+                        table: [1; 64],
+                        blocks_wide: 0,
+                        blocks_high: 0,
+                        blocks: Vec::new(),
+                    });
+                }
+                frame = Some((width, height, planes));
+            }
+            0xC2..=0xCF if marker != DHT && marker != 0xC8 && marker != 0xCC => {
+                return Err("Only baseline jpeg files are supported.".into());
+            }
+            DRI if payload.len() >= 2 => restart_interval = u16::from_be_bytes([payload[0], payload[1]]) as usize,
+            0xE0..=0xEF | 0xFE => segments.push((marker, payload.to_vec())),
+            SOS => {
+                let (_, _, planes) = frame.as_ref().ok_or("Scan before frame header.")?;
+                if payload.first().map(|count| *count as usize) != Some(planes.len()) {
+                    return Err("Only files with a single interleaved scan are supported.".into());
+                }
+                for chunk in payload[1..].chunks_exact(2).take(planes.len()) {
+                    let index = planes.iter().position(|plane| plane.id == chunk[0]).ok_or("Scan of an unknown component.")?;
+                    scan_tables.push((index, (chunk[1] >> 4) as usize % 4, (chunk[1] & 0x0F) as usize % 4));
+                }
+                scan_start = Some(offset);
+            }
+            _ => {}
+        }
+    }
+
+    let (width, height, mut planes) = frame.ok_or("Missing frame header.")?;
+    let scan_start = scan_start.ok_or("Missing scan.")?;
+    if planes.is_empty() || width == 0 || height == 0 {
+        return Err("Empty frame.".into());
+    }
+
+    let max_h = planes.iter().map(|plane| plane.sampling.0).max().unwrap();
+    let max_v = planes.iter().map(|plane| plane.sampling.1).max().unwrap();
+    let mcus_wide = width.div_ceil(8 * max_h);
+    let mcus_high = height.div_ceil(8 * max_v);
+    for plane in planes.iter_mut() {
+        plane.table = quant_tables[plane.table_id as usize];
+        plane.blocks_wide = mcus_wide * plane.sampling.0;
+        plane.blocks_high = mcus_high * plane.sampling.1;
+        plane.blocks = vec![[0; 64]; plane.blocks_wide * plane.blocks_high];
+    }
+
+    let mut reader = BitReader {
+        data: &buffer[scan_start..],
+        pos: 0,
+        buffer: 0,
+        count: 0,
+    };
+    let mut previous_dc = vec![0i32; planes.len()];
+    for mcu in 0..mcus_wide * mcus_high {
+        if restart_interval > 0 && mcu > 0 && mcu % restart_interval == 0 {
+            reader.restart();
+            previous_dc.iter_mut().for_each(|dc| *dc = 0);
+        }
+
+        let (mcu_x, mcu_y) = (mcu % mcus_wide, mcu / mcus_wide);
+        for (index, dc_table, ac_table) in scan_tables.iter().copied() {
+            let dc_table = dc_tables[dc_table].as_ref().ok_or("Missing DC Huffman table.")?;
+            let ac_table = ac_tables[ac_table].as_ref().ok_or("Missing AC Huffman table.")?;
+            let plane = &mut planes[index];
+            let (h, v) = plane.sampling;
+            for block_y in mcu_y * v..(mcu_y + 1) * v {
+                for block_x in mcu_x * h..(mcu_x + 1) * h {
+                    let mut scan = [0i32; 64];
+                    let size = reader.decode(dc_table)?;
+                    previous_dc[index] += extend(reader.bits(size), size);
+                    scan[0] = previous_dc[index];
+
+                    let mut position = 1;
+                    while position < 64 {
+                        let symbol = reader.decode(ac_table)?;
+                        let (run, size) = ((symbol >> 4) as usize, symbol & 0x0F);
+                        if size == 0 {
+                            if run == 15 {
+                                position += 16;
+                                continue;
+                            }
+                            break;
+                        }
+
+                        position += run;
+                        if position > 63 {
+                            return Err("Corrupt scan data.".into());
+                        }
+                        scan[position] = extend(reader.bits(size), size);
+                        position += 1;
+                    }
+
+                    let block = &mut plane.blocks[block_y * plane.blocks_wide + block_x];
+                    for (i, index) in ZIGZAG.iter().enumerate() {
+                        block[*index] = scan[i] as i16;
+                    }
+                }
+            }
+        }
+    }
+
+    return Ok(Coefficients {
+        width,
+        height,
+        planes,
+        segments,
+    });
+}
+
+// Dequantises and transforms the blocks back, then upsamples by repetition
+// and converts to RGB.
+pub fn reconstruct(coefficients: &Coefficients) -> Bitmap {
+    let (width, height) = (coefficients.width, coefficients.height);
+    let (max_h, max_v) = coefficients.max_sampling();
+
+    let planes: Vec<Plane> = coefficients
+        .planes
+        .iter()
+        .map(|component| {
+            let mut plane = Plane::new(component.blocks_wide * 8, component.blocks_high * 8);
+            for (block, values) in component.blocks.iter().enumerate() {
+                let (block_x, block_y) = (block % component.blocks_wide, block / component.blocks_wide);
+                let mut dequantized = [0.0; 64];
+                for i in 0..64 {
+                    dequantized[i] = values[i] as f32 * component.table[i] as f32;
+                }
+
+                for (i, val) in inverse_dct(&dequantized).iter().enumerate() {
+                    let (x, y) = (block_x * 8 + i % 8, block_y * 8 + i / 8);
+                    plane.data[y * plane.width + x] = val + 128.0;
+                }
+            }
+
+            plane
+        })
+        .collect();
+
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let sample = |index: usize| {
+                let (h, v) = coefficients.planes[index].sampling;
+                planes[index].get(x * h / max_h, y * v / max_v)
+            };
+
+            let rgb = if planes.len() >= 3 {
+                ycbcr_to_rgb(sample(0), sample(1), sample(2))
+            } else {
+                [sample(0); 3]
+            };
+            data.extend(rgb.map(|val| val.round().clamp(0.0, 255.0) as u8));
+        }
+    }
+
+    return Bitmap::new(width, height, u8::MAX as usize, BitmapData::U8(data));
+}
+
+pub fn decode(buffer: &[u8]) -> Result<Bitmap, Box<dyn Error>> {
+    let coefficients = read_coefficients(buffer)?;
+
+    return Ok(reconstruct(&coefficients));
+}
+
+// Maps 0..=1 to black, purple, red, orange and pale yellow.
+pub fn heat_color(t: f32) -> [u8; 3] {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [90.0, 0.0, 140.0],
+        [220.0, 40.0, 40.0],
+        [250.0, 160.0, 0.0],
+        [255.0, 255.0, 200.0],
+    ];
+
+    let t = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let index = (t.floor() as usize).min(STOPS.len() - 2);
+    let fraction = t - index as f32;
+    let mut color = [0; 3];
+    for channel in 0..3 {
+        let (from, to) = (STOPS[index][channel], STOPS[index + 1][channel]);
+        color[channel] = (from + (to - from) * fraction).round() as u8;
+    }
+
+    return color;
+}
+
+fn heatmap(values: &[f32], width: usize, height: usize) -> Bitmap {
+    let max = values.iter().cloned().fold(0.0, f32::max);
+    let mut data = Vec::with_capacity(width * height * 3);
+    for val in values {
+        data.extend_from_slice(&heat_color(if max > 0.0 { val / max } else { 0.0 }));
+    }
+
+    return Bitmap::new(width, height, u8::MAX as usize, BitmapData::U8(data));
+}
+
+pub fn plane_image(plane: &Plane) -> Bitmap {
+    let data = plane
+        .data
+        .iter()
+        .flat_map(|val| [val.round().clamp(0.0, 255.0) as u8; 3])
+        .collect();
+
+    return Bitmap::new(plane.width, plane.height, u8::MAX as usize, BitmapData::U8(data));
+}
+
+// Every block drawn in place as its 8x8 coefficients, log scaled so the
+// small high frequency terms stay visible.
+pub fn coefficient_heatmap(component: &Component, quantized: bool) -> Bitmap {
+    let (width, height) = (component.blocks_wide * 8, component.blocks_high * 8);
+    let mut values = vec![0.0; width * height];
+    for block in 0..component.dct.len() {
+        let (block_x, block_y) = (block % component.blocks_wide, block / component.blocks_wide);
+        for i in 0..64 {
+            let val = if quantized {
+                component.quantized[block][i] as f32
+            } else {
+                component.dct[block][i]
+            };
+            values[(block_y * 8 + i / 8) * width + block_x * 8 + i % 8] = val.abs().ln_1p();
+        }
+    }
+
+    return heatmap(&values, width, height);
+}
+
+// Coded size of every block, blocks filled with a single colour.
+pub fn bit_cost_map(component: &Component) -> Bitmap {
+    let (width, height) = (component.blocks_wide * 8, component.blocks_high * 8);
+    let mut values = vec![0.0; width * height];
+    for (index, val) in values.iter_mut().enumerate() {
+        let (x, y) = (index % width, index / width);
+        *val = component.bits[(y / 8) * component.blocks_wide + x / 8] as f32;
+    }
+
+    return heatmap(&values, width, height);
+}
+
+// Absolute difference of the luma of both images.
+pub fn error_map(original: &dyn Image, decoded: &dyn Image) -> Bitmap {
+    let (width, height) = (
+        original.get_width().min(decoded.get_width()),
+        original.get_height().min(decoded.get_height()),
+    );
+    let luma = |image: &dyn Image, x: usize, y: usize| {
+        let (r, g, b) = image.get_pixel_value(x, y);
+        let scale = 255.0 / image.get_max_value().max(1) as f32;
+        (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32) * scale
+    };
+
+    let mut values = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            values.push((luma(original, x, y) - luma(decoded, x, y)).abs());
+        }
+    }
+
+    return heatmap(&values, width, height);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jpeg::JPEG;
+    use crate::metrics::psnr;
+
+    fn pattern(width: usize, height: usize) -> Bitmap {
+        let mut data = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                data.extend([(x * 4) % 256, (y * 6) % 256, ((x + y) * 2) % 256].map(|val| val as u8));
+            }
+        }
+
+        return Bitmap::new(width, height, 255, BitmapData::U8(data));
+    }
+
+    // Other decoders read the files, sizes that don't fill whole MCUs included.
+    #[test]
+    fn encoded_files_decode_elsewhere() {
+        for subsampling in [Subsampling::S444, Subsampling::S422, Subsampling::S420] {
+            for (width, height) in [(32, 32), (37, 21)] {
+                let image = pattern(width, height);
+                let encoded = encode(&image, 90, subsampling);
+
+                let mut decoded = JPEG::default();
+                decoded.populate_from_buffer(&encoded.bytes).unwrap();
+                assert_eq!((decoded.get_width(), decoded.get_height()), (width, height));
+                assert!(psnr(&image, &decoded).unwrap() > 30.0, "{:?} {}x{}", subsampling, width, height);
+
+                let ours = decode(&encoded.bytes).unwrap();
+                assert!(psnr(&ours, &decoded).unwrap() > 35.0, "{:?} {}x{}", subsampling, width, height);
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::error::Error;
+
+use image::{codecs::jpeg::JpegEncoder, ColorType};
+
+use ppm::codec::{bit_cost_map, coefficient_heatmap, decode, encode, error_map, plane_image, run_length, Component, Encoded, Symbol};
+use ppm::image::{load_from_buffer, Bitmap, Image};
+use ppm::jpeg::Subsampling;
+use ppm::metrics::{psnr, ssim};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Plane,
+    Dct,
+    Quantized,
+    BitCost,
+    Error,
+    Decoded,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Plane,
+        Stage::Dct,
+        Stage::Quantized,
+        Stage::BitCost,
+        Stage::Error,
+        Stage::Decoded,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Stage::Plane => "YCbCr plane",
+            Stage::Dct => "DCT coefficients",
+            Stage::Quantized => "Quantized coefficients",
+            Stage::BitCost => "Bits per block",
+            Stage::Error => "Quantization error",
+            Stage::Decoded => "Decoded",
+        }
+    }
+
+    // Stages shown per component rather than for the whole image.
+    pub fn per_component(&self) -> bool {
+        matches!(self, Stage::Plane | Stage::Dct | Stage::Quantized | Stage::BitCost)
+    }
+}
+
+// The current image run through the teaching codec and, for comparison,
+// through the image crate's encoder at the same quality.
+pub struct CodecLab {
+    pub encoded: Encoded,
+    pub decoded: Bitmap,
+    pub psnr: f64,
+    pub ssim: f64,
+    pub reference_size: usize,
+    pub reference_psnr: f64,
+    error_map: Bitmap,
+}
+
+impl CodecLab {
+    pub fn new(image: &dyn Image, quality: u8, subsampling: Subsampling) -> Result<Self, Box<dyn Error>> {
+        if image.get_width() > u16::MAX as usize || image.get_height() > u16::MAX as usize {
+            return Err("Image is too large for jpeg.".into());
+        }
+
+        let encoded = encode(image, quality, subsampling);
+        let decoded = decode(&encoded.bytes)?;
+
+        let max_value = image.get_max_value().max(1);
+        let mut rgb = Vec::with_capacity(image.get_width() * image.get_height() * 3);
+        for y in 0..image.get_height() {
+            for x in 0..image.get_width() {
+                let (r, g, b) = image.get_pixel_value(x, y);
+                rgb.extend([r, g, b].map(|val| (val as usize * 255 / max_value) as u8));
+            }
+        }
+        let mut reference = Vec::new();
+        JpegEncoder::new_with_quality(&mut reference, quality).encode(
+            &rgb,
+            image.get_width() as u32,
+            image.get_height() as u32,
+            ColorType::Rgb8,
+        )?;
+
+        return Ok(CodecLab {
+            psnr: psnr(image, &decoded)?,
+            ssim: ssim(image, &decoded)?,
+            reference_size: reference.len(),
+            reference_psnr: psnr(image, load_from_buffer(&reference)?.as_ref())?,
+            error_map: error_map(image, &decoded),
+            encoded,
+            decoded,
+        });
+    }
+
+    pub fn with_stage_image<T>(&self, stage: Stage, component: usize, f: impl FnOnce(&dyn Image) -> T) -> T {
+        let component = &self.encoded.components[component];
+        match stage {
+            Stage::Plane => f(&plane_image(&component.plane)),
+            Stage::Dct => f(&coefficient_heatmap(component, false)),
+            Stage::Quantized => f(&coefficient_heatmap(component, true)),
+            Stage::BitCost => f(&bit_cost_map(component)),
+            Stage::Error => f(&self.error_map),
+            Stage::Decoded => f(&self.decoded),
+        }
+    }
+
+    // Block under a point given as fractions of the shown stage image.
+    pub fn block_at(&self, stage: Stage, component: usize, pos: (f64, f64)) -> usize {
+        let component = &self.encoded.components[component];
+        let (width, height) = if stage.per_component() && stage != Stage::Plane {
+            (component.blocks_wide * 8, component.blocks_high * 8)
+        } else {
+            (component.plane.width, component.plane.height)
+        };
+
+        let x = ((pos.0.clamp(0.0, 1.0) * width as f64) as usize / 8).min(component.blocks_wide - 1);
+        let y = ((pos.1.clamp(0.0, 1.0) * height as f64) as usize / 8).min(component.blocks_high - 1);
+
+        return y * component.blocks_wide + x;
+    }
+
+    // Run-length symbols of a block as the entropy coder saw them.
+    pub fn block_symbols(&self, component: usize, block: usize) -> Vec<Symbol> {
+        let component = &self.encoded.components[component];
+        let previous_dc = previous_block(component, block)
+            .map(|previous| component.quantized[previous][0])
+            .unwrap_or(0);
+
+        return run_length(&component.quantized[block], previous_dc);
+    }
+}
+
+// Block coded just before the given one of the same component, blocks are
+// coded MCU by MCU.
+fn previous_block(component: &Component, block: usize) -> Option<usize> {
+    let (h, v) = component.sampling;
+    let mcus_wide = component.blocks_wide / h;
+    let order = |block: usize| {
+        let (x, y) = (block % component.blocks_wide, block / component.blocks_wide);
+        ((y / v) * mcus_wide + x / h) * h * v + (y % v) * h + x % h
+    };
+
+    let position = order(block).checked_sub(1)?;
+
+    return (0..component.dct.len()).find(|candidate| order(*candidate) == position);
+}
+
+pub fn symbol_label(symbol: &Symbol) -> String {
+    match symbol {
+        Symbol::Dc { size, value } => format!("DC diff {:+} ({} bits)", value, size),
+        Symbol::Ac { run, size, value } => format!("{}/{} {:+}", run, size, value),
+        Symbol::ZeroRun => "ZRL".to_string(),
+        Symbol::EndOfBlock => "EOB".to_string(),
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsCast;
+use web_sys::{window, Event, HtmlInputElement, HtmlTextAreaElement, KeyboardEvent};
+
+// Local storage key of the customised bindings.
+const STORAGE_KEY: &str = "ppm-keybindings";
+
+// Screen pixels moved by one press of an arrow key.
+pub const PAN_STEP: f64 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    Palette,
+    Keybindings,
+    Open,
+    SaveAsPpm,
+    ExportJpeg,
+    ExportRecipe,
+    NextDocument,
+    PreviousDocument,
+    CloseDocument,
+    DuplicateDocument,
+    Undo,
+    Redo,
+    ZoomIn,
+    ZoomOut,
+    FitToWindow,
+    ActualSize,
+    FillWindow,
+    PanLeft,
+    PanRight,
+    PanUp,
+    PanDown,
+    ToggleGrid,
+    PanTool,
+    RectangleTool,
+    EllipseTool,
+    FreehandTool,
+    ClearSelection,
+    CropToSelection,
+    Invert,
+    Grayscale,
+    Threshold,
+    FlipHorizontal,
+    FlipVertical,
+    RotateLeft,
+    RotateRight,
+    GaussianBlur,
+    BrightnessContrast,
+    CodecLab,
+}
+
+impl Command {
+    pub const ALL: [Command; 38] = [
+        Command::Palette,
+        Command::Keybindings,
+        Command::Open,
+        Command::SaveAsPpm,
+        Command::ExportJpeg,
+        Command::ExportRecipe,
+        Command::NextDocument,
+        Command::PreviousDocument,
+        Command::CloseDocument,
+        Command::DuplicateDocument,
+        Command::Undo,
+        Command::Redo,
+        Command::ZoomIn,
+        Command::ZoomOut,
+        Command::FitToWindow,
+        Command::ActualSize,
+        Command::FillWindow,
+        Command::PanLeft,
+        Command::PanRight,
+        Command::PanUp,
+        Command::PanDown,
+        Command::ToggleGrid,
+        Command::PanTool,
+        Command::RectangleTool,
+        Command::EllipseTool,
+        Command::FreehandTool,
+        Command::ClearSelection,
+        Command::CropToSelection,
+        Command::Invert,
+        Command::Grayscale,
+        Command::Threshold,
+        Command::FlipHorizontal,
+        Command::FlipVertical,
+        Command::RotateLeft,
+        Command::RotateRight,
+        Command::GaussianBlur,
+        Command::BrightnessContrast,
+        Command::CodecLab,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Command::Palette => "Command palette",
+            Command::Keybindings => "Keyboard shortcuts",
+            Command::Open => "Open files",
+            Command::SaveAsPpm => "Save as ppm",
+            Command::ExportJpeg => "Export jpeg",
+            Command::ExportRecipe => "Export recipe",
+            Command::NextDocument => "Next image",
+            Command::PreviousDocument => "Previous image",
+            Command::CloseDocument => "Close image",
+            Command::DuplicateDocument => "Duplicate image",
+            Command::Undo => "Undo",
+            Command::Redo => "Redo",
+            Command::ZoomIn => "Zoom in",
+            Command::ZoomOut => "Zoom out",
+            Command::FitToWindow => "Fit to window",
+            Command::ActualSize => "Actual size",
+            Command::FillWindow => "Fill window",
+            Command::PanLeft => "Pan left",
+            Command::PanRight => "Pan right",
+            Command::PanUp => "Pan up",
+            Command::PanDown => "Pan down",
+            Command::ToggleGrid => "Toggle pixel grid",
+            Command::PanTool => "Pan tool",
+            Command::RectangleTool => "Rectangle selection",
+            Command::EllipseTool => "Ellipse selection",
+            Command::FreehandTool => "Freehand selection",
+            Command::ClearSelection => "Clear selection",
+            Command::CropToSelection => "Crop to selection",
+            Command::Invert => "Invert",
+            Command::Grayscale => "Grayscale",
+            Command::Threshold => "Otsu threshold",
+            Command::FlipHorizontal => "Flip horizontally",
+            Command::FlipVertical => "Flip vertically",
+            Command::RotateLeft => "Rotate left",
+            Command::RotateRight => "Rotate right",
+            Command::GaussianBlur => "Gaussian blur",
+            Command::BrightnessContrast => "Adjust brightness and contrast",
+            Command::CodecLab => "Codec lab",
+        }
+    }
+
+    fn default_keys(&self) -> &'static [&'static str] {
+        match self {
+            Command::Palette => &["Ctrl+K", "F1"],
+            Command::Open => &["Ctrl+O"],
+            Command::SaveAsPpm => &["Ctrl+S"],
+            Command::ExportJpeg => &["Ctrl+E"],
+            Command::NextDocument => &["]"],
+            Command::PreviousDocument => &["["],
+            Command::CloseDocument => &["Alt+W"],
+            Command::DuplicateDocument => &["Alt+D"],
+            Command::Undo => &["Ctrl+Z"],
+            Command::Redo => &["Ctrl+Shift+Z", "Ctrl+Y"],
+            Command::ZoomIn => &["+", "="],
+            Command::ZoomOut => &["-"],
+            Command::FitToWindow => &["0"],
+            Command::ActualSize => &["1"],
+            Command::FillWindow => &["2"],
+            Command::PanLeft => &["ArrowLeft"],
+            Command::PanRight => &["ArrowRight"],
+            Command::PanUp => &["ArrowUp"],
+            Command::PanDown => &["ArrowDown"],
+            Command::ToggleGrid => &["G"],
+            Command::PanTool => &["V"],
+            Command::RectangleTool => &["M"],
+            Command::EllipseTool => &["E"],
+            Command::FreehandTool => &["L"],
+            Command::ClearSelection => &["Escape"],
+            _ => &[],
+        }
+    }
+}
+
+// Keys of every command, as combos like "Ctrl+Shift+Z".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keybindings {
+    keys: BTreeMap<Command, Vec<String>>,
+}
+
+impl Default for Keybindings {
+    fn default() -> Self {
+        let keys = Command::ALL
+            .iter()
+            .map(|command| (*command, command.default_keys().iter().map(|key| key.to_string()).collect()))
+            .collect();
+
+        Keybindings { keys }
+    }
+}
+
+impl Keybindings {
+    // Stored bindings over the defaults, commands added since keep theirs.
+    pub fn load() -> Self {
+        let mut bindings = Keybindings::default();
+        let stored = window()
+            .and_then(|window| window.local_storage().ok().flatten())
+            .and_then(|storage| storage.get_item(STORAGE_KEY).ok().flatten())
+            .and_then(|json| serde_json::from_str::<BTreeMap<Command, Vec<String>>>(&json).ok());
+        if let Some(stored) = stored {
+            bindings.keys.extend(stored);
+        }
+
+        return bindings;
+    }
+
+    pub fn save(&self) {
+        let storage = match window().and_then(|window| window.local_storage().ok().flatten()) {
+            Some(storage) => storage,
+            None => return,
+        };
+
+        if let Err(err) = storage.set_item(STORAGE_KEY, &serde_json::to_string(&self.keys).unwrap()) {
+            log::error!("Couldn't save keybindings: {:?}", err);
+        }
+    }
+
+    pub fn keys(&self, command: Command) -> &[String] {
+        return self.keys.get(&command).map(|keys| keys.as_slice()).unwrap_or(&[]);
+    }
+
+    pub fn command(&self, combo: &str) -> Option<Command> {
+        return self
+            .keys
+            .iter()
+            .find(|(_, keys)| keys.iter().any(|key| key == combo))
+            .map(|(command, _)| *command);
+    }
+
+    // Binds the combo to the command alone, taking it from any other.
+    pub fn bind(&mut self, command: Command, combo: String) {
+        for keys in self.keys.values_mut() {
+            keys.retain(|key| *key != combo);
+        }
+        self.keys.entry(command).or_default().push(combo);
+    }
+
+    pub fn clear(&mut self, command: Command) {
+        self.keys.insert(command, Vec::new());
+    }
+}
+
+// The pressed combo, None while only modifiers are down. Shift is left out
+// of printable keys other than letters, the character already reflects it.
+pub fn key_combo(event: &KeyboardEvent) -> Option<String> {
+    let key = event.key();
+    if matches!(key.as_str(), "Control" | "Shift" | "Alt" | "Meta" | "Dead" | "Unidentified") {
+        return None;
+    }
+
+    let printable = key.chars().count() == 1;
+    let key = match key.as_str() {
+        " " => "Space".to_string(),
+        _ if printable => key.to_uppercase(),
+        _ => key,
+    };
+    let shift = event.shift_key() && (!printable || key.chars().all(|c| c.is_alphabetic()));
+
+    let mut combo = String::new();
+    if event.ctrl_key() || event.meta_key() {
+        combo.push_str("Ctrl+");
+    }
+    if event.alt_key() {
+        combo.push_str("Alt+");
+    }
+    if shift {
+        combo.push_str("Shift+");
+    }
+    combo.push_str(&key);
+
+    return Some(combo);
+}
+
+// Every word of the query appears in the label, ignoring case.
+pub fn matches_query(label: &str, query: &str) -> bool {
+    let label = label.to_lowercase();
+
+    return query
+        .to_lowercase()
+        .split_whitespace()
+        .all(|word| label.contains(word));
+}
+
+// Shortcuts and pasting are left to fields that take text.
+pub fn is_text_entry(event: &Event) -> bool {
+    let target = match event.target() {
+        Some(target) => target,
+        None => return false,
+    };
+    if target.dyn_ref::<HtmlTextAreaElement>().is_some() {
+        return true;
+    }
+
+    return match target.dyn_ref::<HtmlInputElement>() {
+        Some(input) => !matches!(
+            input.type_().as_str(),
+            "button" | "checkbox" | "radio" | "range" | "file" | "submit" | "reset"
+        ),
+        None => false,
+    };
+}
@@ -0,0 +1,175 @@
+use wasm_bindgen::JsValue;
+use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement};
+
+use ppm::image::{Bitmap, Image};
+use ppm::metrics::{compare, difference_heatmap, Metrics};
+
+use crate::preview::offscreen_canvas;
+use crate::view::ViewState;
+
+// Distance in screen pixels within which the swipe divider can be grabbed.
+pub const DIVIDER_GRAB_DISTANCE: f64 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareMode {
+    SideBySide,
+    Swipe,
+    Onion,
+    Difference,
+}
+
+impl CompareMode {
+    pub const ALL: [CompareMode; 4] = [
+        CompareMode::SideBySide,
+        CompareMode::Swipe,
+        CompareMode::Onion,
+        CompareMode::Difference,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            CompareMode::SideBySide => "Side by side",
+            CompareMode::Swipe => "Swipe",
+            CompareMode::Onion => "Onion skin",
+            CompareMode::Difference => "Difference",
+        }
+    }
+}
+
+// The current image, the reference, compared against a candidate loaded from
+// a second file. Both share the view of the main canvas.
+pub struct Comparison {
+    pub candidate: Box<dyn Image>,
+    pub mode: CompareMode,
+    // Fraction of the viewport left of the swipe divider.
+    pub split: f64,
+    // Opacity of the candidate in onion skin mode.
+    pub opacity: f64,
+    pub amplification: f64,
+    pub metrics: Result<Metrics, String>,
+    // Images uploaded into offscreen canvases, rebuilt when cleared.
+    reference_canvas: Option<HtmlCanvasElement>,
+    candidate_canvas: Option<HtmlCanvasElement>,
+    difference: Option<Result<HtmlCanvasElement, String>>,
+}
+
+impl Comparison {
+    pub fn new(reference: &dyn Image, candidate: Box<dyn Image>) -> Self {
+        Comparison {
+            metrics: compare(reference, candidate.as_ref()).map_err(|err| err.to_string()),
+            candidate,
+            mode: CompareMode::SideBySide,
+            split: 0.5,
+            opacity: 0.5,
+            amplification: 4.0,
+            reference_canvas: None,
+            candidate_canvas: None,
+            difference: None,
+        }
+    }
+
+    // Call after the reference changed.
+    pub fn reference_changed(&mut self, reference: &dyn Image) {
+        self.metrics = compare(reference, self.candidate.as_ref()).map_err(|err| err.to_string());
+        self.reference_canvas = None;
+        self.difference = None;
+    }
+
+    pub fn set_amplification(&mut self, amplification: f64) {
+        self.amplification = amplification;
+        self.difference = None;
+    }
+
+    pub fn difference_error(&self) -> Option<&str> {
+        match &self.difference {
+            Some(Err(err)) => Some(err),
+            _ => None,
+        }
+    }
+
+    // Position in the coordinates the view is kept in. The right half of the
+    // side by side mode shows the same region as the left one.
+    pub fn view_pos(&self, pos: (f64, f64), viewport: (f64, f64)) -> (f64, f64) {
+        if self.mode == CompareMode::SideBySide && pos.0 > viewport.0 / 2.0 {
+            return (pos.0 - viewport.0 / 2.0, pos.1);
+        }
+
+        return pos;
+    }
+
+    pub fn near_divider(&self, pos: (f64, f64), viewport: (f64, f64)) -> bool {
+        self.mode == CompareMode::Swipe && (pos.0 - self.split * viewport.0).abs() <= DIVIDER_GRAB_DISTANCE
+    }
+
+    pub fn draw(&mut self, ctx: &CanvasRenderingContext2d, reference: &dyn Image, view: &ViewState, viewport: (f64, f64)) {
+        let reference_canvas = self.reference_canvas.get_or_insert_with(|| offscreen_canvas(reference)).clone();
+        let candidate_canvas = self.candidate_canvas.get_or_insert_with(|| offscreen_canvas(self.candidate.as_ref())).clone();
+
+        ctx.save();
+        ctx.set_image_smoothing_enabled(false);
+        match self.mode {
+            CompareMode::SideBySide => {
+                let half = viewport.0 / 2.0;
+                clipped(ctx, (0.0, half, viewport.1), |ctx| draw_image(ctx, &reference_canvas, view, 0.0));
+                clipped(ctx, (half, half, viewport.1), |ctx| draw_image(ctx, &candidate_canvas, view, half));
+                draw_divider(ctx, half, viewport.1);
+            }
+            CompareMode::Swipe => {
+                let divider = self.split * viewport.0;
+                draw_image(ctx, &candidate_canvas, view, 0.0);
+                clipped(ctx, (0.0, divider, viewport.1), |ctx| draw_image(ctx, &reference_canvas, view, 0.0));
+                draw_divider(ctx, divider, viewport.1);
+            }
+            CompareMode::Onion => {
+                draw_image(ctx, &reference_canvas, view, 0.0);
+                ctx.set_global_alpha(self.opacity);
+                draw_image(ctx, &candidate_canvas, view, 0.0);
+            }
+            CompareMode::Difference => {
+                let amplification = self.amplification;
+                let candidate = self.candidate.as_ref();
+                let difference = self.difference.get_or_insert_with(|| {
+                    difference_heatmap(reference, candidate, amplification)
+                        .map(|heatmap: Bitmap| offscreen_canvas(&heatmap))
+                        .map_err(|err| err.to_string())
+                });
+                if let Ok(canvas) = difference {
+                    draw_image(ctx, canvas, view, 0.0);
+                }
+            }
+        }
+        ctx.restore();
+    }
+}
+
+// Runs f with drawing limited to a full height strip of the canvas.
+fn clipped(ctx: &CanvasRenderingContext2d, (x, width, height): (f64, f64, f64), f: impl FnOnce(&CanvasRenderingContext2d)) {
+    ctx.save();
+    ctx.begin_path();
+    ctx.rect(x, 0.0, width, height);
+    ctx.clip();
+    f(ctx);
+    ctx.restore();
+}
+
+// Draws with the view transform, shifted right by offset screen pixels.
+fn draw_image(ctx: &CanvasRenderingContext2d, canvas: &HtmlCanvasElement, view: &ViewState, offset: f64) {
+    let (x, y) = view.image_to_screen((0.0, 0.0));
+    ctx.draw_image_with_html_canvas_element_and_dw_and_dh(
+        canvas,
+        x + offset,
+        y,
+        canvas.width() as f64 * view.scale,
+        canvas.height() as f64 * view.scale,
+    )
+    .unwrap();
+}
+
+fn draw_divider(ctx: &CanvasRenderingContext2d, x: f64, height: f64) {
+    ctx.set_stroke_style(&JsValue::from_str("red"));
+    ctx.set_line_width(2.0);
+    ctx.begin_path();
+    ctx.move_to(x, 0.0);
+    ctx.line_to(x, height);
+    ctx.stroke();
+}
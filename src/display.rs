@@ -0,0 +1,211 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::image::{Bitmap, BitmapData, Image};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMap {
+    Clip,
+    Reinhard,
+    Aces,
+}
+
+impl ToneMap {
+    pub const ALL: [ToneMap; 3] = [ToneMap::Clip, ToneMap::Reinhard, ToneMap::Aces];
+
+    // Matches u_tone_map in the viewer's fragment shader.
+    pub fn index(&self) -> i32 {
+        match self {
+            ToneMap::Clip => 0,
+            ToneMap::Reinhard => 1,
+            ToneMap::Aces => 2,
+        }
+    }
+
+    pub fn curve(&self, value: f64) -> f64 {
+        match self {
+            ToneMap::Clip => value,
+            ToneMap::Reinhard => value / (1.0 + value),
+            // Narkowicz's fit of the ACES filmic curve.
+            ToneMap::Aces => (value * (2.51 * value + 0.03)) / (value * (2.43 * value + 0.59) + 0.14),
+        }
+    }
+}
+
+impl fmt::Display for ToneMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ToneMap::Clip => write!(f, "Linear clip"),
+            ToneMap::Reinhard => write!(f, "Reinhard"),
+            ToneMap::Aces => write!(f, "ACES"),
+        }
+    }
+}
+
+// How samples are mapped to the screen. Only the display changes, the image
+// keeps its pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplaySettings {
+    // In stops, applied after the black and white points.
+    pub exposure: f64,
+    pub gamma: f64,
+    // Normalised samples mapped to 0 and 1.
+    pub black_point: f64,
+    pub white_point: f64,
+    pub tone_map: ToneMap,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        DisplaySettings {
+            exposure: 0.0,
+            gamma: 1.0,
+            black_point: 0.0,
+            white_point: 1.0,
+            tone_map: ToneMap::Clip,
+        }
+    }
+}
+
+impl DisplaySettings {
+    pub fn is_identity(&self) -> bool {
+        return *self == DisplaySettings::default();
+    }
+
+    // Maps a normalised sample to the displayed value in 0..=1. The fragment
+    // shader does the same, step for step.
+    pub fn apply(&self, value: f64) -> f64 {
+        let range = (self.white_point - self.black_point).max(1e-6);
+        let value = (value - self.black_point) / range * self.exposure.exp2();
+        let value = self.tone_map.curve(value.max(0.0)).clamp(0.0, 1.0);
+
+        return value.powf(1.0 / self.gamma.max(1e-6));
+    }
+
+    // The image as it's displayed, in 8 bits, for saving it as shown.
+    pub fn render(&self, image: &dyn Image) -> Bitmap {
+        let max_value = image.get_max_value().max(1) as f64;
+        let lookup = |val: u16| (self.apply(val as f64 / max_value) * 255.0).round() as u8;
+        let data = match image.get_buffer_ref() {
+            BitmapData::U8(data) => {
+                let table: Vec<u8> = (0..=255).map(lookup).collect();
+                data.iter().map(|val| table[*val as usize]).collect()
+            }
+            BitmapData::U16(data) => data.iter().map(|val| lookup(*val)).collect(),
+            BitmapData::None => Vec::new(),
+        };
+
+        return Bitmap::new(image.get_width(), image.get_height(), 255, BitmapData::U8(data));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        return (a - b).abs() < 1e-9;
+    }
+
+    #[test]
+    fn defaults_leave_values_unchanged() {
+        let settings = DisplaySettings::default();
+        assert!(settings.is_identity());
+        for value in [0.0, 0.1, 0.5, 0.9, 1.0] {
+            assert_eq!(settings.apply(value), value);
+        }
+        assert_eq!(settings.apply(-0.5), 0.0);
+        assert_eq!(settings.apply(1.5), 1.0);
+    }
+
+    #[test]
+    fn black_and_white_points_map_to_the_ends() {
+        let settings = DisplaySettings { black_point: 0.2, white_point: 0.6, ..Default::default() };
+        assert!(!settings.is_identity());
+        assert!(close(settings.apply(0.2), 0.0));
+        assert!(close(settings.apply(0.4), 0.5));
+        assert!(close(settings.apply(0.6), 1.0));
+        assert_eq!(settings.apply(0.1), 0.0);
+        assert_eq!(settings.apply(0.9), 1.0);
+    }
+
+    #[test]
+    fn one_stop_doubles_before_clipping() {
+        let settings = DisplaySettings { exposure: 1.0, ..Default::default() };
+        assert!(close(settings.apply(0.25), 0.5));
+        assert!(close(settings.apply(0.5), 1.0));
+        assert_eq!(settings.apply(0.75), 1.0);
+
+        let settings = DisplaySettings { exposure: -1.0, ..Default::default() };
+        assert!(close(settings.apply(1.0), 0.5));
+    }
+
+    #[test]
+    fn reinhard_halves_one() {
+        let settings = DisplaySettings { tone_map: ToneMap::Reinhard, ..Default::default() };
+        assert!(close(settings.apply(1.0), 0.5));
+        assert!(close(settings.apply(0.0), 0.0));
+        // Brighter values are compressed instead of clipped.
+        let settings = DisplaySettings { exposure: 3.0, ..settings };
+        assert!(close(settings.apply(1.0), 8.0 / 9.0));
+    }
+
+    #[test]
+    fn aces_starts_at_zero_and_saturates() {
+        assert_eq!(ToneMap::Aces.curve(0.0), 0.0);
+        assert!((ToneMap::Aces.curve(1e6) - 2.51 / 2.43).abs() < 1e-3);
+
+        let settings = DisplaySettings { tone_map: ToneMap::Aces, ..Default::default() };
+        assert_eq!(settings.apply(0.0), 0.0);
+        assert_eq!(settings.apply(100.0), 1.0);
+        assert!(settings.apply(0.5) < settings.apply(0.6));
+    }
+
+    #[test]
+    fn gamma_is_applied_last() {
+        let settings = DisplaySettings { gamma: 2.0, ..Default::default() };
+        assert!(close(settings.apply(0.25), 0.5));
+        assert_eq!(settings.apply(0.0), 0.0);
+        assert_eq!(settings.apply(1.0), 1.0);
+
+        let settings = DisplaySettings { exposure: 1.0, ..settings };
+        assert!(close(settings.apply(0.125), 0.5));
+    }
+
+    #[test]
+    fn render_matches_apply() {
+        let settings = DisplaySettings {
+            exposure: 0.5,
+            gamma: 2.2,
+            black_point: 0.1,
+            white_point: 0.8,
+            tone_map: ToneMap::Reinhard,
+        };
+        let expected = |value: f64| (settings.apply(value) * 255.0).round() as u8;
+
+        let samples: Vec<u16> = (0..12).map(|val| val * 90).collect();
+        let image = Bitmap::new(2, 2, 1000, BitmapData::U16(samples.clone()));
+        let rendered = settings.render(&image);
+        assert_eq!((rendered.get_width(), rendered.get_height(), rendered.get_max_value()), (2, 2, 255));
+        match rendered.get_buffer_ref() {
+            BitmapData::U8(data) => {
+                let wanted: Vec<u8> = samples.iter().map(|val| expected(*val as f64 / 1000.0)).collect();
+                assert_eq!(data, &wanted);
+            }
+            _ => panic!("Rendered image isn't 8-bit."),
+        }
+
+        let samples: Vec<u8> = (0..12).map(|val| val * 20).collect();
+        let image = Bitmap::new(2, 2, 255, BitmapData::U8(samples.clone()));
+        match settings.render(&image).get_buffer_ref() {
+            BitmapData::U8(data) => {
+                let wanted: Vec<u8> = samples.iter().map(|val| expected(*val as f64 / 255.0)).collect();
+                assert_eq!(data, &wanted);
+            }
+            _ => panic!("Rendered image isn't 8-bit."),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::mem::swap;
+
+use ppm::history::History;
+use ppm::image::Image;
+use ppm::info::ImageInfo;
+use ppm::markers::MarkerReport;
+use ppm::ops::copy;
+use ppm::selection::{RegionStats, Selection};
+
+use crate::inspector::Sample;
+use crate::view::ViewState;
+
+// Side of the square the tab thumbnails fit in.
+pub const THUMBNAIL_SIZE: usize = 64;
+
+// An open image and everything that belongs to it. The shown document's state
+// is swapped out into App::document, its tab keeps only the id, name and
+// thumbnail.
+#[derive(Default)]
+pub struct Document {
+    // Jobs running in the background find their document by it.
+    pub id: usize,
+    pub name: String,
+    pub thumbnail: Option<String>,
+    pub history: Option<History>,
+    pub metadata: Option<ImageInfo>,
+    // Segments of the opened JPEG, kept even when it fails to decode.
+    pub markers: Option<MarkerReport>,
+    pub load_error: Option<String>,
+    // Bytes of the opened JPEG for lossless transforms.
+    pub source: Option<Vec<u8>>,
+    pub view: ViewState,
+    pub selection: Option<Selection>,
+    pub region_stats: Option<RegionStats>,
+    pub pinned: Vec<Sample>,
+}
+
+impl Document {
+    pub fn new(id: usize, name: String) -> Self {
+        Document {
+            id,
+            name,
+            ..Default::default()
+        }
+    }
+
+    // A new document starting from the current result, without the edits
+    // that led to it.
+    pub fn duplicate(&self, id: usize, name: String) -> Self {
+        let history = self.history.as_ref().map(|history| History::new(Box::new(copy(history.current()))));
+        let edited = self.history.as_ref().is_some_and(|history| !history.steps().is_empty());
+
+        Document {
+            id,
+            name,
+            thumbnail: None,
+            history,
+            metadata: self.metadata.clone(),
+            markers: self.markers.clone(),
+            load_error: None,
+            source: if edited { None } else { self.source.clone() },
+            view: self.view,
+            selection: self.selection.clone(),
+            region_stats: self.region_stats.clone(),
+            pinned: self.pinned.clone(),
+        }
+    }
+
+    // Starts over from a newly opened image.
+    pub fn set_image(&mut self, image: Box<dyn Image>, viewport: (f64, f64)) {
+        self.view.reset((image.get_width(), image.get_height()), viewport);
+        self.history = Some(History::new(image));
+        self.pinned.clear();
+        self.selection = None;
+        self.region_stats = None;
+    }
+
+    // Exchanges everything but the id, name and thumbnail.
+    pub fn swap(&mut self, other: &mut Document) {
+        swap(&mut self.history, &mut other.history);
+        swap(&mut self.metadata, &mut other.metadata);
+        swap(&mut self.markers, &mut other.markers);
+        swap(&mut self.load_error, &mut other.load_error);
+        swap(&mut self.source, &mut other.source);
+        swap(&mut self.view, &mut other.view);
+        swap(&mut self.selection, &mut other.selection);
+        swap(&mut self.region_stats, &mut other.region_stats);
+        swap(&mut self.pinned, &mut other.pinned);
+    }
+}
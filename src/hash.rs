@@ -0,0 +1,254 @@
+use std::{error::Error, f64::consts::PI, fmt, str::FromStr};
+
+use serde::Serialize;
+
+use crate::image::Image;
+use crate::metrics::luma_plane;
+
+// Side of the grid the perceptual hash takes the DCT of.
+const PHASH_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashKind {
+    Average,
+    Difference,
+    Perceptual,
+}
+
+impl HashKind {
+    pub fn hash(&self, image: &dyn Image) -> u64 {
+        match self {
+            HashKind::Average => average_hash(image),
+            HashKind::Difference => difference_hash(image),
+            HashKind::Perceptual => perceptual_hash(image),
+        }
+    }
+}
+
+impl FromStr for HashKind {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "a" | "ahash" | "average" => Ok(HashKind::Average),
+            "d" | "dhash" | "difference" => Ok(HashKind::Difference),
+            "p" | "phash" | "perceptual" => Ok(HashKind::Perceptual),
+            _ => Err(format!("Unknown hash \"{}\", expected ahash, dhash or phash.", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for HashKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashKind::Average => write!(f, "ahash"),
+            HashKind::Difference => write!(f, "dhash"),
+            HashKind::Perceptual => write!(f, "phash"),
+        }
+    }
+}
+
+// Luma averaged down to a width x height grid. Sides smaller than the grid
+// repeat pixels.
+fn shrink(image: &dyn Image, width: usize, height: usize) -> Vec<f64> {
+    let (image_width, image_height) = (image.get_width(), image.get_height());
+    if image_width == 0 || image_height == 0 {
+        return vec![0.0; width * height];
+    }
+
+    let luma = luma_plane(image);
+    let span = |cell: usize, cells: usize, size: usize| {
+        let start = cell * size / cells;
+        let end = ((cell + 1) * size / cells).max(start + 1);
+        start..end
+    };
+
+    let mut grid = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (rows, columns) = (span(y, height, image_height), span(x, width, image_width));
+            let count = (rows.len() * columns.len()) as f64;
+            let sum: f64 = rows
+                .flat_map(|sy| columns.clone().map(move |sx| sy * image_width + sx))
+                .map(|i| luma[i])
+                .sum();
+            grid.push(sum / count);
+        }
+    }
+
+    return grid;
+}
+
+// First value is the most significant bit.
+fn to_bits(values: impl Iterator<Item = bool>) -> u64 {
+    return values.fold(0, |hash, bit| (hash << 1) | bit as u64);
+}
+
+// Bit set for each cell of an 8x8 grid brighter than the mean.
+pub fn average_hash(image: &dyn Image) -> u64 {
+    let grid = shrink(image, 8, 8);
+    let mean = grid.iter().sum::<f64>() / grid.len() as f64;
+
+    return to_bits(grid.iter().map(|val| *val > mean));
+}
+
+// Bit set where a cell of a 9x8 grid is brighter than its right neighbour.
+pub fn difference_hash(image: &dyn Image) -> u64 {
+    let grid = shrink(image, 9, 8);
+
+    return to_bits((0..8).flat_map(|y| {
+        let row = &grid[y * 9..(y + 1) * 9];
+        (0..8).map(move |x| row[x] > row[x + 1])
+    }));
+}
+
+// Bit set for each of the 8x8 lowest frequencies of a 32x32 DCT above their
+// median. The DC term is left out of the median, it only carries brightness.
+pub fn perceptual_hash(image: &dyn Image) -> u64 {
+    let grid = shrink(image, PHASH_SIZE, PHASH_SIZE);
+    let coefficients = dct_low_frequencies(&grid);
+
+    let mut sorted: Vec<f64> = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = (sorted[31] + sorted[32]) / 2.0;
+
+    return to_bits(coefficients.iter().map(|val| *val > median));
+}
+
+// The 8x8 lowest frequencies of the 2D DCT-II of a PHASH_SIZE square grid.
+fn dct_low_frequencies(grid: &[f64]) -> [f64; 64] {
+    let n = PHASH_SIZE;
+    let basis: Vec<f64> = (0..8)
+        .flat_map(|u| (0..n).map(move |x| ((2 * x + 1) as f64 * u as f64 * PI / (2 * n) as f64).cos()))
+        .collect();
+
+    // Rows first, then columns of the result.
+    let mut rows = vec![0.0; n * 8];
+    for y in 0..n {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..n).map(|x| grid[y * n + x] * basis[u * n + x]).sum();
+        }
+    }
+
+    let mut out = [0.0; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            out[v * 8 + u] = (0..n).map(|y| rows[y * 8 + u] * basis[v * n + y]).sum();
+        }
+    }
+
+    return out;
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    return (a ^ b).count_ones();
+}
+
+// Groups of indices whose hashes are at most max_distance bits apart, that
+// distance included, directly or through other members. Single images are
+// left out.
+pub fn group_duplicates(hashes: &[u64], max_distance: u32) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+
+        return i;
+    }
+
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if hamming_distance(hashes[i], hashes[j]) <= max_distance {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![Vec::new(); hashes.len()];
+    for i in 0..hashes.len() {
+        let group = root(&mut parent, i);
+        groups[group].push(i);
+    }
+
+    return groups.into_iter().filter(|group| group.len() > 1).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{Bitmap, BitmapData};
+    use crate::jpeg::{JpegOptions, JPEG};
+
+    const KINDS: [HashKind; 3] = [HashKind::Average, HashKind::Difference, HashKind::Perceptual];
+    // The duplicates command's default distance.
+    const DISTANCE: u32 = 8;
+
+    fn image(size: usize, pixel: impl Fn(f64, f64) -> f64) -> Bitmap {
+        let mut data = Vec::with_capacity(size * size * 3);
+        for y in 0..size {
+            for x in 0..size {
+                let val = (pixel(x as f64 / size as f64, y as f64 / size as f64).clamp(0.0, 1.0) * 255.0) as u8;
+                data.extend([val, val / 2, 255 - val]);
+            }
+        }
+
+        return Bitmap::new(size, size, 255, BitmapData::U8(data));
+    }
+
+    fn scene() -> Bitmap {
+        return image(96, |x, y| {
+            let disc = if (x - 0.3).powi(2) + (y - 0.6).powi(2) < 0.04 { 0.5 } else { 0.0 };
+            0.6 * x + 0.3 * (y * 7.0).sin().abs() + disc
+        });
+    }
+
+    fn other_scene() -> Bitmap {
+        return image(96, |x, y| {
+            let dark = ((x * 4.0) as usize + (y * 3.0) as usize).is_multiple_of(2);
+            if dark { 0.9 - y * 0.5 } else { 0.1 + x * 0.3 }
+        });
+    }
+
+    #[test]
+    fn reencoded_images_stay_close() {
+        let original = scene();
+        let mut encoded = Vec::new();
+        let options = JpegOptions { quality: 60, ..Default::default() };
+        original.write_to_jpeg(&mut encoded, &options).unwrap();
+        let mut decoded = JPEG::default();
+        decoded.populate_from_buffer(&encoded).unwrap();
+
+        for kind in KINDS {
+            let distance = hamming_distance(kind.hash(&original), kind.hash(&decoded));
+            assert!(distance <= DISTANCE, "{} is {} bits off", kind, distance);
+        }
+    }
+
+    #[test]
+    fn unrelated_images_differ() {
+        let (a, b) = (scene(), other_scene());
+        for kind in KINDS {
+            let distance = hamming_distance(kind.hash(&a), kind.hash(&b));
+            assert!(distance > DISTANCE, "{} is only {} bits off", kind, distance);
+        }
+    }
+
+    #[test]
+    fn groups_are_transitive() {
+        // Each is a bit away from the next, the ends are three bits apart.
+        let hashes = [0b0000, 0b0001, 0b0011, 0b0111, u64::MAX, u64::MAX - 1];
+        assert_eq!(group_duplicates(&hashes, 1), vec![vec![0, 1, 2, 3], vec![4, 5]]);
+        assert!(group_duplicates(&hashes, 0).is_empty());
+    }
+
+    #[test]
+    fn max_distance_is_inclusive() {
+        let hashes = [0, 0b1111];
+        assert_eq!(group_duplicates(&hashes, 4), vec![vec![0, 1]]);
+        assert!(group_duplicates(&hashes, 3).is_empty());
+    }
+}
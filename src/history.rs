@@ -0,0 +1,420 @@
+use serde::{Deserialize, Serialize};
+
+use crate::image::{Bitmap, BitmapData, Image};
+use crate::ops::Operation;
+use crate::selection::Selection;
+
+// Upper bound for cached intermediate results. The current result is always
+// kept, even when it alone is larger than this.
+pub const SNAPSHOT_BUDGET: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    #[serde(flatten)]
+    pub operation: Operation,
+    // Named apart from the selection of a crop, which shares the same table.
+    #[serde(rename = "roi", default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<Selection>,
+    // Disabled steps are left out of recipes instead of being stored.
+    #[serde(skip, default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Step {
+    pub fn new(operation: Operation, selection: Option<Selection>) -> Self {
+        Step {
+            operation,
+            selection,
+            enabled: true,
+        }
+    }
+}
+
+// An edit of the history, see History::change.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Undo,
+    Redo,
+    Toggle(usize),
+    // Adds the steps after the cursor, dropping anything that could be redone.
+    Push(Vec<Step>),
+}
+
+// Non-destructive edit history. Every step is replayed against the original
+// image; results are cached per step while they fit in the snapshot budget.
+pub struct History {
+    original: Box<dyn Image>,
+    steps: Vec<Step>,
+    // Steps before the cursor are applied, the ones after it can be redone.
+    cursor: usize,
+    snapshots: Vec<Option<Bitmap>>,
+    budget: usize,
+}
+
+impl History {
+    pub fn new(original: Box<dyn Image>) -> Self {
+        History {
+            original,
+            steps: Vec::new(),
+            cursor: 0,
+            snapshots: Vec::new(),
+            budget: SNAPSHOT_BUDGET,
+        }
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    pub fn original(&self) -> &dyn Image {
+        self.original.as_ref()
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.cursor > 0
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.cursor < self.steps.len()
+    }
+
+    // Steps that produce the current result, in order.
+    pub fn applied_steps(&self) -> Vec<Step> {
+        self.steps[..self.cursor]
+            .iter()
+            .filter(|step| step.enabled)
+            .cloned()
+            .collect()
+    }
+
+    // Result of all enabled steps before the cursor.
+    pub fn current(&self) -> &dyn Image {
+        match self.last_enabled(self.cursor) {
+            Some(index) => self.snapshots[index]
+                .as_ref()
+                .expect("Current snapshot missing."),
+            None => self.original.as_ref(),
+        }
+    }
+
+    // Adds a step after the cursor, dropping anything that could be redone.
+    pub fn push(&mut self, step: Step) {
+        self.change(Change::Push(vec![step]), None);
+    }
+
+    // Same as push, with the result of the step already computed elsewhere.
+    // It has to be the step applied to the current result.
+    pub fn push_applied(&mut self, step: Step, result: Bitmap) {
+        self.change(Change::Push(vec![step]), Some(result));
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.change(Change::Undo, None)
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.change(Change::Redo, None)
+    }
+
+    // Enables or disables a step; everything after it has to be replayed.
+    pub fn toggle(&mut self, index: usize) -> bool {
+        self.change(Change::Toggle(index), None)
+    }
+
+    // What the change leaves to compute: the cached result it starts from and
+    // the steps to apply to it, in order. None when the new current result is
+    // already known.
+    pub fn replay_for(&self, change: &Change) -> Option<(&dyn Image, Vec<Step>)> {
+        let (steps, cursor, cached) = self.changed(change)?;
+        let target = (0..cursor).rev().find(|index| steps[*index].enabled)?;
+        if cached[target] {
+            return None;
+        }
+
+        let start = (0..target).rev().find(|index| steps[*index].enabled && cached[*index]);
+        let source: &dyn Image = match start {
+            Some(index) => self.snapshots[index].as_ref().unwrap(),
+            None => self.original.as_ref(),
+        };
+        let replayed = steps[start.map_or(0, |index| index + 1)..=target]
+            .iter()
+            .filter(|step| step.enabled)
+            .cloned()
+            .collect();
+
+        return Some((source, replayed));
+    }
+
+    // Makes the change. The result of the replay_for steps can be passed in
+    // when it was computed elsewhere, otherwise they're replayed here.
+    pub fn change(&mut self, change: Change, result: Option<Bitmap>) -> bool {
+        let (steps, cursor, cached) = match self.changed(&change) {
+            Some(changed) => changed,
+            None => return false,
+        };
+        self.snapshots.resize_with(steps.len(), || None);
+        for (snapshot, cached) in self.snapshots.iter_mut().zip(cached) {
+            if !cached {
+                *snapshot = None;
+            }
+        }
+        self.steps = steps;
+        self.cursor = cursor;
+
+        if let (Some(result), Some(target)) = (result, self.last_enabled(self.cursor)) {
+            self.snapshots[target] = Some(result);
+        }
+        self.update();
+        return true;
+    }
+
+    // The steps, cursor and cached snapshots the change would leave, or None
+    // when it can't be made.
+    fn changed(&self, change: &Change) -> Option<(Vec<Step>, usize, Vec<bool>)> {
+        let mut steps = self.steps.clone();
+        let mut cursor = self.cursor;
+        let mut cached: Vec<bool> = self.snapshots.iter().map(|snapshot| snapshot.is_some()).collect();
+        match change {
+            Change::Undo if self.can_undo() => cursor -= 1,
+            Change::Redo if self.can_redo() => cursor += 1,
+            Change::Toggle(index) if *index < steps.len() => {
+                steps[*index].enabled = !steps[*index].enabled;
+                for snapshot in cached[*index..].iter_mut() {
+                    *snapshot = false;
+                }
+            }
+            Change::Push(pushed) => {
+                steps.truncate(cursor);
+                cached.truncate(cursor);
+                steps.extend(pushed.iter().cloned());
+                cached.resize(steps.len(), false);
+                cursor = steps.len();
+            }
+            _ => return None,
+        }
+
+        return Some((steps, cursor, cached));
+    }
+
+    fn last_enabled(&self, end: usize) -> Option<usize> {
+        (0..end).rev().find(|index| self.steps[*index].enabled)
+    }
+
+    // Makes sure the snapshot of the current result exists, replaying from the
+    // closest cached one.
+    fn update(&mut self) {
+        let target = match self.last_enabled(self.cursor) {
+            Some(target) => target,
+            None => {
+                self.evict();
+                return;
+            }
+        };
+
+        if self.snapshots[target].is_none() {
+            let mut start = 0;
+            for index in (0..target).rev() {
+                if self.steps[index].enabled && self.snapshots[index].is_some() {
+                    start = index + 1;
+                    break;
+                }
+            }
+
+            for index in start..=target {
+                if !self.steps[index].enabled {
+                    continue;
+                }
+
+                let step = &self.steps[index];
+                let source: &dyn Image = match self.last_enabled(index) {
+                    Some(previous) => self.snapshots[previous].as_ref().unwrap(),
+                    None => self.original.as_ref(),
+                };
+                let result = step.operation.apply_in(source, step.selection.as_ref());
+                self.snapshots[index] = Some(result);
+                self.evict_keeping(Some(index));
+            }
+        }
+
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        self.evict_keeping(self.last_enabled(self.cursor));
+    }
+
+    // Drops cached results furthest from the cursor until the budget is met.
+    fn evict_keeping(&mut self, current: Option<usize>) {
+        loop {
+            let used: usize = self.snapshots.iter().flatten().map(snapshot_size).sum();
+            if used <= self.budget {
+                return;
+            }
+
+            let furthest = (0..self.snapshots.len())
+                .filter(|index| self.snapshots[*index].is_some() && Some(*index) != current)
+                .max_by_key(|index| index.abs_diff(self.cursor));
+            match furthest {
+                Some(index) => self.snapshots[index] = None,
+                None => return,
+            }
+        }
+    }
+}
+
+fn snapshot_size(image: &Bitmap) -> usize {
+    let samples = image.get_width() * image.get_height() * 3;
+    match image.get_buffer_ref() {
+        BitmapData::U16(_) => samples * 2,
+        _ => samples,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops;
+
+    fn original() -> Bitmap {
+        return Bitmap::new(3, 2, 255, BitmapData::U8((0..18).map(|val| val * 13).collect()));
+    }
+
+    fn pixels(image: &dyn Image) -> (usize, usize, Vec<(u16, u16, u16)>) {
+        let (width, height) = (image.get_width(), image.get_height());
+        let values = (0..height).flat_map(|y| (0..width).map(move |x| (x, y)));
+
+        return (width, height, values.map(|(x, y)| image.get_pixel_value(x, y)).collect());
+    }
+
+    // The steps applied one after another, without any history.
+    fn expected(steps: &[Step]) -> (usize, usize, Vec<(u16, u16, u16)>) {
+        let mut result = original();
+        for step in steps {
+            result = step.operation.apply_in(&result, step.selection.as_ref());
+        }
+
+        return pixels(&result);
+    }
+
+    fn steps() -> Vec<Step> {
+        return vec![
+            Step::new(Operation::Invert, None),
+            Step::new(Operation::Rotate90, None),
+            Step::new(Operation::FlipHorizontal, None),
+        ];
+    }
+
+    #[test]
+    fn push_after_undo_drops_the_redo_tail() {
+        let steps = steps();
+        let mut history = History::new(Box::new(original()));
+        for step in steps.iter() {
+            history.push(step.clone());
+        }
+        assert!(history.undo());
+        assert!(history.undo());
+        assert!(history.can_redo());
+
+        let grayscale = Step::new(Operation::Grayscale, None);
+        history.push(grayscale.clone());
+        assert_eq!(history.steps(), &[steps[0].clone(), grayscale.clone()]);
+        assert_eq!(history.cursor(), 2);
+        assert!(!history.can_redo());
+        assert!(!history.redo());
+        assert_eq!(pixels(history.current()), expected(&[steps[0].clone(), grayscale]));
+    }
+
+    #[test]
+    fn toggling_replays_only_later_steps() {
+        let steps = steps();
+        let mut history = History::new(Box::new(original()));
+        for step in steps.iter() {
+            history.push(step.clone());
+        }
+
+        // Starts from the cached result of the first step.
+        let (source, replayed) = history.replay_for(&Change::Toggle(1)).unwrap();
+        assert_eq!(pixels(source), expected(&steps[..1]));
+        assert_eq!(replayed, vec![steps[2].clone()]);
+
+        assert!(history.toggle(1));
+        assert!(!history.steps()[1].enabled);
+        assert_eq!(history.applied_steps(), vec![steps[0].clone(), steps[2].clone()]);
+        assert_eq!(pixels(history.current()), expected(&[steps[0].clone(), steps[2].clone()]));
+
+        assert!(history.toggle(1));
+        assert_eq!(pixels(history.current()), expected(&steps));
+        assert!(!history.toggle(3));
+    }
+
+    #[test]
+    fn current_result_outlives_the_budget() {
+        let steps = steps();
+        let mut history = History::new(Box::new(original()));
+        for step in steps.iter() {
+            history.push(step.clone());
+        }
+
+        history.set_budget(0);
+        assert_eq!(pixels(history.current()), expected(&steps));
+        assert!(history.undo());
+        assert_eq!(pixels(history.current()), expected(&steps[..2]));
+        assert!(history.redo());
+        assert_eq!(pixels(history.current()), expected(&steps));
+        assert!(history.toggle(0));
+        assert_eq!(pixels(history.current()), expected(&steps[1..]));
+    }
+
+    // What the viewer does with results computed by its worker.
+    #[test]
+    fn replayed_results_match_local_replays() {
+        let steps = steps();
+        let changes = [
+            Change::Push(steps.clone()),
+            Change::Undo,
+            Change::Toggle(0),
+            Change::Redo,
+            Change::Toggle(2),
+            Change::Undo,
+            Change::Undo,
+            Change::Push(vec![Step::new(Operation::Grayscale, None)]),
+            Change::Toggle(0),
+            Change::Redo,
+        ];
+
+        for budget in [0, SNAPSHOT_BUDGET] {
+            let mut local = History::new(Box::new(original()));
+            let mut remote = History::new(Box::new(original()));
+            local.set_budget(budget);
+            remote.set_budget(budget);
+
+            for change in changes.iter() {
+                let result = remote.replay_for(change).map(|(source, steps)| {
+                    let mut result = ops::copy(source);
+                    for step in steps {
+                        result = step.operation.apply_in(&result, step.selection.as_ref());
+                    }
+                    result
+                });
+
+                assert_eq!(local.change(change.clone(), None), remote.change(change.clone(), result));
+                assert_eq!(local.steps(), remote.steps());
+                assert_eq!(local.cursor(), remote.cursor());
+                assert_eq!(pixels(local.current()), pixels(remote.current()), "{:?}", change);
+            }
+        }
+    }
+}
@@ -1,6 +1,15 @@
-use std::io::Cursor;
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, Read},
+};
 
-use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageBuffer, ImageResult};
+use jpeg_encoder::ColorType;
+
+use crate::{
+    jpeg::{write_segment, JpegOptions, APP1, COM, JPEG},
+    ppm::PPM,
+};
 
 pub enum BitmapData {
     U8(Vec<u8>),
@@ -12,6 +21,23 @@ pub trait Image {
     fn get_height(&self) -> usize;
     fn get_buffer_ref(&self) -> &BitmapData;
 
+    fn get_max_value(&self) -> usize {
+        match self.get_buffer_ref() {
+            BitmapData::U16(_) => u16::MAX as usize,
+            _ => u8::MAX as usize,
+        }
+    }
+
+    // Free form text stored alongside the pixels, e.g. PPM header comments.
+    fn get_comments(&self) -> &[String] {
+        &[]
+    }
+
+    // TIFF structure of the EXIF data, without the APP1 "Exif" header.
+    fn get_exif(&self) -> Option<&[u8]> {
+        None
+    }
+
     fn get_pixel_value(&self, x: usize, y: usize) -> (u16, u16, u16) {
         let index = (y * self.get_width() + x) * 3;
         // Guard
@@ -32,32 +58,249 @@ pub trait Image {
         return (0, 0, 0);
     }
 
-    fn write_to_jpeg(&self, vec: &mut Vec<u8>, quality: u8) -> ImageResult<()> {
-        let cursor = Cursor::new(vec);
-        let mut encoder = JpegEncoder::new_with_quality(cursor, quality);
-
-        let img = match self.get_buffer_ref() {
-            BitmapData::U8(data) => DynamicImage::ImageRgb8(
-                ImageBuffer::from_raw(
-                    self.get_width() as u32,
-                    self.get_height() as u32,
-                    data.clone(),
-                )
-                .unwrap(),
-            ),
-            BitmapData::U16(data) => DynamicImage::ImageRgb16(
-                ImageBuffer::from_raw(
-                    self.get_width() as u32,
-                    self.get_height() as u32,
-                    data.clone(),
-                )
-                .unwrap(),
-            ),
-            BitmapData::None => panic!("No data"),
-        };
+    // With keep_metadata the EXIF data and comments are stored in APP1 and COM
+    // segments.
+    fn write_to_jpeg(&self, vec: &mut Vec<u8>, options: &JpegOptions) -> Result<(), Box<dyn Error>> {
+        let width = u16::try_from(self.get_width()).map_err(|_| "Image too wide for jpeg.")?;
+        let height = u16::try_from(self.get_height()).map_err(|_| "Image too tall for jpeg.")?;
+
+        let mut encoded = Vec::new();
+        let encoder = options.encoder(&mut encoded)?;
+        encoder.encode(&to_rgb8(self), width, height, ColorType::Rgb)?;
 
-        encoder.encode_image(&img)?;
+        if !options.keep_metadata {
+            vec.extend_from_slice(&encoded);
+            return Ok(());
+        }
+
+        // Metadata goes after SOI and the JFIF segment written by the encoder.
+        let mut split = 2;
+        if encoded.get(2..4) == Some(&[0xFF, 0xE0]) {
+            split = 4 + u16::from_be_bytes([encoded[4], encoded[5]]) as usize;
+        }
+
+        vec.extend_from_slice(&encoded[..split]);
+        if let Some(exif) = self.get_exif() {
+            // Segments can't hold more than 64KB, EXIF data that big is dropped.
+            let payload = [b"Exif\0\0", exif].concat();
+            if payload.len() <= u16::MAX as usize - 2 {
+                write_segment(vec, APP1, &payload);
+            }
+        }
+        for comment in self.get_comments() {
+            let bytes = comment.as_bytes();
+            write_segment(vec, COM, &bytes[..bytes.len().min(u16::MAX as usize - 2)]);
+        }
+        vec.extend_from_slice(&encoded[split..]);
 
         Ok(())
     }
+
+    fn write_to_ppm(&self, vec: &mut Vec<u8>, binary: bool) {
+        let max_value = self.get_max_value();
+        let header = if binary { "P6" } else { "P3" };
+        write_header(vec, header, self, Some(max_value));
+
+        let samples: Box<dyn Iterator<Item = u16>> = match self.get_buffer_ref() {
+            BitmapData::U8(data) => Box::new(data.iter().map(|val| *val as u16)),
+            BitmapData::U16(data) => Box::new(data.iter().copied()),
+            BitmapData::None => Box::new(std::iter::empty()),
+        };
+        write_samples(vec, samples, max_value, binary);
+    }
+
+    // Single channel output, colour images are reduced to luma.
+    fn write_to_pgm(&self, vec: &mut Vec<u8>, binary: bool) {
+        let max_value = self.get_max_value();
+        let header = if binary { "P5" } else { "P2" };
+        write_header(vec, header, self, Some(max_value));
+
+        let samples = (0..self.get_height())
+            .flat_map(|y| (0..self.get_width()).map(move |x| (x, y)))
+            .map(|(x, y)| luma(self.get_pixel_value(x, y)).round() as u16);
+        write_samples(vec, Box::new(samples), max_value, binary);
+    }
+
+    // Pixels darker than half of the max value become black (1).
+    fn write_to_pbm(&self, vec: &mut Vec<u8>, binary: bool) {
+        let header = if binary { "P4" } else { "P1" };
+        write_header(vec, header, self, None);
+
+        let half = self.get_max_value() as f64 / 2.0;
+        let mut line_length = 0;
+        for y in 0..self.get_height() {
+            let mut byte = 0u8;
+            for x in 0..self.get_width() {
+                let black = luma(self.get_pixel_value(x, y)) < half;
+                if binary {
+                    byte |= (black as u8) << (7 - x % 8);
+                    if x % 8 == 7 || x + 1 == self.get_width() {
+                        vec.push(byte);
+                        byte = 0;
+                    }
+                } else {
+                    vec.push(if black { b'1' } else { b'0' });
+                    line_length += 1;
+                    if line_length == 70 {
+                        vec.push(b'\n');
+                        line_length = 0;
+                    }
+                }
+            }
+        }
+
+        if !binary && line_length > 0 {
+            vec.push(b'\n');
+        }
+    }
+}
+
+// Magic number, comments, dimensions and the max value if the format has one.
+fn write_header<I: Image + ?Sized>(vec: &mut Vec<u8>, magic: &str, image: &I, max_value: Option<usize>) {
+    vec.extend_from_slice(format!("{}\n", magic).as_bytes());
+    for comment in image.get_comments() {
+        // A line break would end the comment early.
+        let comment = comment.replace(['\r', '\n'], " ");
+        vec.extend_from_slice(format!("# {}\n", comment).as_bytes());
+    }
+
+    vec.extend_from_slice(format!("{} {}\n", image.get_width(), image.get_height()).as_bytes());
+    if let Some(max_value) = max_value {
+        vec.extend_from_slice(format!("{}\n", max_value).as_bytes());
+    }
+}
+
+// Samples rescaled from 0..=max_value to 0..=255.
+fn to_rgb8<I: Image + ?Sized>(image: &I) -> Vec<u8> {
+    let max_value = image.get_max_value().max(1);
+    match image.get_buffer_ref() {
+        BitmapData::U8(data) if max_value == u8::MAX as usize => data.clone(),
+        BitmapData::U8(data) => data
+            .iter()
+            .map(|val| (*val as usize * 255 / max_value).min(255) as u8)
+            .collect(),
+        BitmapData::U16(data) => data
+            .iter()
+            .map(|val| (*val as usize * 255 / max_value).min(255) as u8)
+            .collect(),
+        BitmapData::None => Vec::new(),
+    }
+}
+
+fn luma((r, g, b): (u16, u16, u16)) -> f64 {
+    0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64
+}
+
+// Raster for the netpbm formats: one byte per sample below 256, big endian
+// pairs above, or whitespace separated decimals kept under 70 columns.
+fn write_samples(vec: &mut Vec<u8>, samples: Box<dyn Iterator<Item = u16> + '_>, max_value: usize, binary: bool) {
+    if binary {
+        for val in samples {
+            if max_value <= u8::MAX as usize {
+                vec.push(val as u8);
+            } else {
+                vec.extend_from_slice(&val.to_be_bytes());
+            }
+        }
+        return;
+    }
+
+    let mut line_length = 0;
+    for val in samples {
+        let text = val.to_string();
+        if line_length > 0 && line_length + text.len() + 1 > 70 {
+            vec.push(b'\n');
+            line_length = 0;
+        } else if line_length > 0 {
+            vec.push(b' ');
+            line_length += 1;
+        }
+        vec.extend_from_slice(text.as_bytes());
+        line_length += text.len();
+    }
+    vec.push(b'\n');
+}
+
+// Decoded pixels that are not tied to a file format, e.g. the result of a crop.
+pub struct Bitmap {
+    width: usize,
+    height: usize,
+    max_value: usize,
+    data: BitmapData,
+    comments: Vec<String>,
+    exif: Option<Vec<u8>>,
+}
+
+impl Bitmap {
+    pub fn new(width: usize, height: usize, max_value: usize, data: BitmapData) -> Self {
+        Bitmap {
+            width,
+            height,
+            max_value,
+            data,
+            comments: Vec::new(),
+            exif: None,
+        }
+    }
+
+    // Carries the metadata of the image this one was derived from.
+    pub fn copy_metadata(&mut self, image: &dyn Image) {
+        self.set_metadata(image.get_comments().to_vec(), image.get_exif().map(|exif| exif.to_vec()));
+    }
+
+    pub fn set_metadata(&mut self, comments: Vec<String>, exif: Option<Vec<u8>>) {
+        self.comments = comments;
+        self.exif = exif;
+    }
+}
+
+impl Image for Bitmap {
+    fn get_width(&self) -> usize {
+        self.width
+    }
+
+    fn get_height(&self) -> usize {
+        self.height
+    }
+
+    fn get_buffer_ref(&self) -> &BitmapData {
+        &self.data
+    }
+
+    fn get_max_value(&self) -> usize {
+        self.max_value
+    }
+
+    fn get_comments(&self) -> &[String] {
+        &self.comments
+    }
+
+    fn get_exif(&self) -> Option<&[u8]> {
+        self.exif.as_deref()
+    }
+}
+
+// Picks the decoder from the file signature.
+pub fn load_from_buffer(buffer: &[u8]) -> Result<Box<dyn Image>, Box<dyn Error>> {
+    if buffer.starts_with(&[0xFF, 0xD8]) {
+        let mut jpeg = JPEG::default();
+        jpeg.populate_from_buffer(buffer)?;
+        return Ok(Box::new(jpeg));
+    }
+
+    let mut ppm = PPM::default();
+    ppm.populate_from_buffer(buffer)?;
+    return Ok(Box::new(ppm));
+}
+
+pub fn load_from_file(file_path: &str) -> Result<Box<dyn Image>, Box<dyn Error>> {
+    let mut buffer = Vec::new();
+
+    {
+        let file = File::open(file_path)?;
+        let mut reader = BufReader::new(file);
+        reader.read_to_end(&mut buffer)?;
+    }
+
+    return load_from_buffer(&buffer);
 }
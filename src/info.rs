@@ -0,0 +1,109 @@
+use std::{error::Error, fs};
+
+use serde::{Deserialize, Serialize};
+
+use crate::image::Image;
+use crate::jpeg::{ExifField, JPEG};
+use crate::ppm::{PPMVer, PPM};
+use crate::selection::{region_stats, RegionStats};
+
+// Summary of a file as stored on disk, not of the RGB data it decodes to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub format: String,
+    // PPM variant from the magic number, e.g. "P6".
+    pub variant: Option<String>,
+    pub width: usize,
+    pub height: usize,
+    pub max_value: usize,
+    pub bit_depth: u32,
+    pub channels: usize,
+    pub file_size: usize,
+    pub comments: Vec<String>,
+    pub exif: Vec<ExifField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<RegionStats>,
+}
+
+pub fn image_info(buffer: &[u8], with_stats: bool) -> Result<ImageInfo, Box<dyn Error>> {
+    let (info, _) = load_with_info(buffer, with_stats)?;
+
+    return Ok(info);
+}
+
+// Decodes the image and describes the file in one pass.
+pub fn load_with_info(
+    buffer: &[u8],
+    with_stats: bool,
+) -> Result<(ImageInfo, Box<dyn Image>), Box<dyn Error>> {
+    return load_with_info_progress(buffer, with_stats, &mut |_| {});
+}
+
+// Same as load_with_info, reporting how much of the pixel data was read.
+pub fn load_with_info_progress(
+    buffer: &[u8],
+    with_stats: bool,
+    progress: &mut dyn FnMut(f64),
+) -> Result<(ImageInfo, Box<dyn Image>), Box<dyn Error>> {
+    let (mut info, image): (ImageInfo, Box<dyn Image>) = if buffer.starts_with(&[0xFF, 0xD8]) {
+        let mut jpeg = JPEG::default();
+        jpeg.populate_from_buffer(buffer)?;
+
+        let info = ImageInfo {
+            format: String::from("JPEG"),
+            variant: None,
+            width: jpeg.get_width(),
+            height: jpeg.get_height(),
+            max_value: jpeg.get_max_value(),
+            bit_depth: 8,
+            channels: jpeg.get_channels(),
+            file_size: buffer.len(),
+            comments: jpeg.get_comments().to_vec(),
+            exif: jpeg.get_exif_fields().to_vec(),
+            stats: None,
+        };
+        (info, Box::new(jpeg))
+    } else {
+        let mut ppm = PPM::default();
+        ppm.populate_from_buffer_with_progress(buffer, progress)?;
+
+        let ver = *ppm.get_ver();
+        // Bitmaps are expanded to 0 and 255 when read.
+        let max_value = match ver {
+            PPMVer::P1 | PPMVer::P4 => 1,
+            _ => ppm.get_max_value(),
+        };
+        let format = match ver {
+            PPMVer::P1 | PPMVer::P4 => "PBM",
+            PPMVer::P2 | PPMVer::P5 => "PGM",
+            _ => "PPM",
+        };
+
+        let info = ImageInfo {
+            format: String::from(format),
+            variant: Some(format!("{:?}", ver)),
+            width: ppm.get_width(),
+            height: ppm.get_height(),
+            max_value,
+            bit_depth: usize::BITS - max_value.leading_zeros(),
+            channels: if matches!(ver, PPMVer::P3 | PPMVer::P6) { 3 } else { 1 },
+            file_size: buffer.len(),
+            comments: ppm.get_comments().to_vec(),
+            exif: Vec::new(),
+            stats: None,
+        };
+        (info, Box::new(ppm))
+    };
+
+    if with_stats {
+        info.stats = Some(region_stats(image.as_ref(), None));
+    }
+
+    return Ok((info, image));
+}
+
+pub fn file_info(file_path: &str, with_stats: bool) -> Result<ImageInfo, Box<dyn Error>> {
+    let buffer = fs::read(file_path)?;
+
+    return image_info(&buffer, with_stats);
+}
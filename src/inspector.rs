@@ -0,0 +1,137 @@
+use ppm::image::Image;
+
+pub const DEFAULT_NEIGHBOURHOOD: usize = 3;
+pub const MAX_NEIGHBOURHOOD: usize = 31;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub x: usize,
+    pub y: usize,
+    pub value: (u16, u16, u16),
+    pub max_value: usize,
+    pub neighbourhood: usize,
+    pub mean: [f64; 3],
+    pub stddev: [f64; 3],
+}
+
+impl Sample {
+    pub fn new(image: &dyn Image, x: usize, y: usize, neighbourhood: usize) -> Self {
+        let (mean, stddev) = neighbourhood_stats(image, x, y, neighbourhood);
+
+        return Sample {
+            x,
+            y,
+            value: image.get_pixel_value(x, y),
+            max_value: image.get_max_value(),
+            neighbourhood,
+            mean,
+            stddev,
+        };
+    }
+
+    pub fn channels(&self) -> [u16; 3] {
+        [self.value.0, self.value.1, self.value.2]
+    }
+
+    pub fn normalized(&self) -> [f64; 3] {
+        self.channels()
+            .map(|val| val as f64 / self.max_value.max(1) as f64)
+    }
+
+    // Two hex digits per channel for 8-bit data, four above that.
+    pub fn hex(&self) -> String {
+        let digits = if self.max_value <= u8::MAX as usize { 2 } else { 4 };
+        let channels: Vec<String> = self
+            .channels()
+            .iter()
+            .map(|val| format!("{:0width$X}", val, width = digits))
+            .collect();
+
+        return format!("#{}", channels.concat());
+    }
+
+    // CSS colour of the sample rescaled to 8 bits, for the swatch.
+    pub fn css_color(&self) -> String {
+        let [r, g, b] = self
+            .normalized()
+            .map(|val| (val.min(1.0) * u8::MAX as f64).round() as u8);
+
+        return format!("rgb({}, {}, {})", r, g, b);
+    }
+}
+
+// Mean and standard deviation per channel over the size x size window centred
+// on (x, y), clipped to the image bounds.
+pub fn neighbourhood_stats(
+    image: &dyn Image,
+    x: usize,
+    y: usize,
+    size: usize,
+) -> ([f64; 3], [f64; 3]) {
+    let radius = size / 2;
+    let x0 = x.saturating_sub(radius);
+    let y0 = y.saturating_sub(radius);
+    let x1 = (x + radius + 1).min(image.get_width());
+    let y1 = (y + radius + 1).min(image.get_height());
+
+    let mut sum = [0.0; 3];
+    let mut sum_sq = [0.0; 3];
+    let mut count = 0.0;
+    for sy in y0..y1 {
+        for sx in x0..x1 {
+            let (r, g, b) = image.get_pixel_value(sx, sy);
+            for (channel, val) in [r, g, b].iter().enumerate() {
+                sum[channel] += *val as f64;
+                sum_sq[channel] += (*val as f64) * (*val as f64);
+            }
+            count += 1.0;
+        }
+    }
+
+    if count == 0.0 {
+        return ([0.0; 3], [0.0; 3]);
+    }
+
+    let mean = sum.map(|val| val / count);
+    let mut stddev = [0.0; 3];
+    for channel in 0..3 {
+        let variance = sum_sq[channel] / count - mean[channel] * mean[channel];
+        stddev[channel] = variance.max(0.0).sqrt();
+    }
+
+    return (mean, stddev);
+}
+
+pub fn samples_to_csv(samples: &[Sample]) -> String {
+    let mut csv = String::from(
+        "x,y,r,g,b,max_value,r_norm,g_norm,b_norm,hex,neighbourhood,\
+         r_mean,g_mean,b_mean,r_stddev,g_stddev,b_stddev\n",
+    );
+
+    for sample in samples {
+        let [r, g, b] = sample.channels();
+        let norm = sample.normalized();
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{:.6},{:.6},{:.6},{},{},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n",
+            sample.x,
+            sample.y,
+            r,
+            g,
+            b,
+            sample.max_value,
+            norm[0],
+            norm[1],
+            norm[2],
+            sample.hex(),
+            sample.neighbourhood,
+            sample.mean[0],
+            sample.mean[1],
+            sample.mean[2],
+            sample.stddev[0],
+            sample.stddev[1],
+            sample.stddev[2],
+        ));
+    }
+
+    return csv;
+}
@@ -0,0 +1,140 @@
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::Step;
+use crate::image::{Bitmap, BitmapData, Image};
+use crate::info::{load_with_info_progress, ImageInfo};
+use crate::jpeg::{JpegOptions, JPEG};
+use crate::metrics::{psnr, ssim};
+
+// Work the viewer hands off to its worker. Jobs and replies travel as JSON,
+// the file or pixel data they go with is sent alongside as raw bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    // Data: the file.
+    Decode,
+    // Data: the pixels of the image. The steps are applied one after another.
+    Apply { image: ImageHeader, steps: Vec<Step> },
+    EncodeJpeg { image: ImageHeader, options: JpegOptions },
+    PreviewJpeg { image: ImageHeader, options: JpegOptions },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Reply {
+    Progress { fraction: f64 },
+    // Data: the pixels of the decoded image.
+    Decoded { image: ImageHeader, info: Box<ImageInfo> },
+    // Data: the pixels of the result.
+    Applied { image: ImageHeader },
+    // Data: the JPEG file.
+    Encoded,
+    // Data: the pixels of the JPEG decoded again.
+    Preview { image: ImageHeader, size: usize, psnr: f64, ssim: f64 },
+    Failed { error: String },
+}
+
+// Everything of a Bitmap but its samples. 16-bit samples are sent as little
+// endian pairs of bytes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageHeader {
+    pub width: usize,
+    pub height: usize,
+    pub max_value: usize,
+    pub sixteen_bit: bool,
+    pub comments: Vec<String>,
+    pub exif: Option<Vec<u8>>,
+}
+
+pub fn image_to_bytes(image: &dyn Image) -> (ImageHeader, Vec<u8>) {
+    let (sixteen_bit, data) = match image.get_buffer_ref() {
+        BitmapData::U8(data) => (false, data.clone()),
+        BitmapData::U16(data) => (true, data.iter().flat_map(|val| val.to_le_bytes()).collect()),
+        BitmapData::None => (false, Vec::new()),
+    };
+    let header = ImageHeader {
+        width: image.get_width(),
+        height: image.get_height(),
+        max_value: image.get_max_value(),
+        sixteen_bit,
+        comments: image.get_comments().to_vec(),
+        exif: image.get_exif().map(|exif| exif.to_vec()),
+    };
+
+    return (header, data);
+}
+
+pub fn image_from_bytes(header: &ImageHeader, data: &[u8]) -> Result<Bitmap, Box<dyn Error>> {
+    let samples = header.width * header.height * 3;
+    let buffer = if header.sixteen_bit {
+        if data.len() != samples * 2 {
+            return Err("Image data doesn't match its size.".into());
+        }
+        BitmapData::U16(data.chunks_exact(2).map(|val| u16::from_le_bytes([val[0], val[1]])).collect())
+    } else {
+        if data.len() != samples {
+            return Err("Image data doesn't match its size.".into());
+        }
+        BitmapData::U8(data.to_vec())
+    };
+
+    let mut image = Bitmap::new(header.width, header.height, header.max_value, buffer);
+    image.set_metadata(header.comments.clone(), header.exif.clone());
+
+    return Ok(image);
+}
+
+// Runs a job to the end, failures become Reply::Failed.
+pub fn run_job(job: &Job, data: &[u8], progress: &mut dyn FnMut(f64)) -> (Reply, Vec<u8>) {
+    match try_job(job, data, progress) {
+        Ok(result) => result,
+        Err(err) => (Reply::Failed { error: err.to_string() }, Vec::new()),
+    }
+}
+
+fn try_job(job: &Job, data: &[u8], progress: &mut dyn FnMut(f64)) -> Result<(Reply, Vec<u8>), Box<dyn Error>> {
+    match job {
+        Job::Decode => {
+            let (info, image) = load_with_info_progress(data, false, progress)?;
+            let (image, data) = image_to_bytes(image.as_ref());
+
+            return Ok((Reply::Decoded { image, info: Box::new(info) }, data));
+        }
+        Job::Apply { image, steps } => {
+            let mut result = image_from_bytes(image, data)?;
+            for (index, step) in steps.iter().enumerate() {
+                step.operation.validate()?;
+                result = step.operation.apply_in(&result, step.selection.as_ref());
+                progress((index + 1) as f64 / steps.len() as f64);
+            }
+            let (image, data) = image_to_bytes(&result);
+
+            return Ok((Reply::Applied { image }, data));
+        }
+        Job::EncodeJpeg { image, options } => {
+            let image = image_from_bytes(image, data)?;
+            let mut jpeg = Vec::new();
+            image.write_to_jpeg(&mut jpeg, options)?;
+
+            return Ok((Reply::Encoded, jpeg));
+        }
+        Job::PreviewJpeg { image, options } => {
+            let image = image_from_bytes(image, data)?;
+            let mut jpeg = Vec::new();
+            image.write_to_jpeg(&mut jpeg, options)?;
+
+            let mut decoded = JPEG::default();
+            decoded.populate_from_buffer(&jpeg)?;
+            let reply = Reply::Preview {
+                image: image_to_bytes(&decoded).0,
+                size: jpeg.len(),
+                psnr: psnr(&image, &decoded)?,
+                ssim: ssim(&image, &decoded)?,
+            };
+
+            return Ok((reply, image_to_bytes(&decoded).1));
+        }
+    }
+}
@@ -1,22 +1,206 @@
-use std::{error::Error, io::Cursor};
+use std::{error::Error, fmt, io::Cursor, str::FromStr};
 
-use image::{codecs::jpeg, io::Reader};
+use exif::{In, Tag};
+use image::{io::Reader, DynamicImage};
+use jpeg_encoder::{Encoder, QuantizationTableType, SamplingFactor};
+use serde::{Deserialize, Serialize};
 
 use crate::image::{BitmapData, Image};
 
+pub const APP1: u8 = 0xE1;
+pub const COM: u8 = 0xFE;
+const SOS: u8 = 0xDA;
+pub const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+
+// Example tables from Annex K of the standard, row-major, for quality 50.
+pub const STANDARD_LUMA_TABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+pub const STANDARD_CHROMA_TABLE: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
 pub struct JPEG {
     width: u32,
     height: u32,
+    // Colour components stored in the file, the data is always RGB.
+    channels: usize,
     data: BitmapData,
+    // TIFF structure of the APP1 segment. The orientation is reset to normal
+    // once it has been applied to the pixels.
+    exif: Option<Vec<u8>>,
+    exif_fields: Vec<ExifField>,
+    comments: Vec<String>,
 }
 
-impl JPEG {
-    pub fn from_buffer(buffer: &mut Vec<u8>) -> Self {
-        let mut jpeg = JPEG {
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExifField {
+    pub tag: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Subsampling {
+    #[serde(rename = "4:4:4")]
+    S444,
+    #[serde(rename = "4:2:2")]
+    S422,
+    #[serde(rename = "4:2:0")]
+    S420,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JpegOptions {
+    pub quality: u8,
+    pub subsampling: Subsampling,
+    pub progressive: bool,
+    pub optimize_huffman: bool,
+    // MCUs between restart markers, 0 leaves them out.
+    pub restart_interval: u16,
+    // 64 values in row-major order. Custom tables are used as given, the
+    // quality only scales the standard ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub luma_table: Option<Vec<u16>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chroma_table: Option<Vec<u16>>,
+    pub keep_metadata: bool,
+}
+
+impl Default for JpegOptions {
+    fn default() -> Self {
+        JpegOptions {
+            quality: 90,
+            subsampling: Subsampling::S420,
+            progressive: false,
+            optimize_huffman: false,
+            restart_interval: 0,
+            luma_table: None,
+            chroma_table: None,
+            keep_metadata: true,
+        }
+    }
+}
+
+impl JpegOptions {
+    pub fn encoder<'a>(&self, vec: &'a mut Vec<u8>) -> Result<Encoder<&'a mut Vec<u8>>, Box<dyn Error>> {
+        let mut encoder = Encoder::new(vec, self.quality.clamp(1, 100));
+        encoder.set_sampling_factor(match self.subsampling {
+            Subsampling::S444 => SamplingFactor::R_4_4_4,
+            Subsampling::S422 => SamplingFactor::R_4_2_2,
+            Subsampling::S420 => SamplingFactor::R_4_2_0,
+        });
+        encoder.set_progressive(self.progressive);
+        encoder.set_optimized_huffman_tables(self.optimize_huffman);
+        if self.restart_interval > 0 {
+            encoder.set_restart_interval(self.restart_interval);
+        }
+
+        if self.luma_table.is_some() || self.chroma_table.is_some() {
+            encoder.set_quantization_tables(
+                quantization_table(&self.luma_table)?,
+                quantization_table(&self.chroma_table)?,
+            );
+        }
+
+        return Ok(encoder);
+    }
+}
+
+fn quantization_table(table: &Option<Vec<u16>>) -> Result<QuantizationTableType, Box<dyn Error>> {
+    let table = match table {
+        Some(table) => table,
+        None => return Ok(QuantizationTableType::Default),
+    };
+
+    let table: [u16; 64] = table
+        .as_slice()
+        .try_into()
+        .map_err(|_| "Quantization tables need 64 values.")?;
+    if table.iter().any(|val| *val == 0 || *val > 255) {
+        return Err("Quantization table values must be between 1 and 255.".into());
+    }
+
+    return Ok(QuantizationTableType::Custom(Box::new(table)));
+}
+
+// Luma table and an optional chroma table.
+pub type QuantizationTables = (Vec<u16>, Option<Vec<u16>>);
+
+// 64 luma values, optionally followed by 64 chroma values, separated by
+// whitespace or commas.
+pub fn parse_quantization_tables(text: &str) -> Result<QuantizationTables, Box<dyn Error>> {
+    let mut values = Vec::new();
+    for val in text.split(|c: char| c.is_whitespace() || c == ',').filter(|val| !val.is_empty()) {
+        values.push(val.parse::<u16>()?);
+    }
+
+    let (luma, chroma) = match values.len() {
+        64 => (values, None),
+        128 => (values[..64].to_vec(), Some(values[64..].to_vec())),
+        _ => return Err("Quantization tables need 64 or 128 values.".into()),
+    };
+    quantization_table(&Some(luma.clone()))?;
+    quantization_table(&chroma)?;
+
+    return Ok((luma, chroma));
+}
+
+impl FromStr for Subsampling {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.replace(':', "").as_str() {
+            "444" => Ok(Subsampling::S444),
+            "422" => Ok(Subsampling::S422),
+            "420" => Ok(Subsampling::S420),
+            _ => Err(format!("Unknown chroma subsampling \"{}\", expected 4:4:4, 4:2:2 or 4:2:0.", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for Subsampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subsampling::S444 => write!(f, "4:4:4"),
+            Subsampling::S422 => write!(f, "4:2:2"),
+            Subsampling::S420 => write!(f, "4:2:0"),
+        }
+    }
+}
+
+impl Default for JPEG {
+    fn default() -> Self {
+        JPEG {
             width: 0,
             height: 0,
+            channels: 0,
             data: BitmapData::None,
-        };
+            exif: None,
+            exif_fields: Vec::new(),
+            comments: Vec::new(),
+        }
+    }
+}
+
+impl JPEG {
+    pub fn from_buffer(buffer: &[u8]) -> Self {
+        let mut jpeg = JPEG::default();
 
         jpeg.populate_from_buffer(buffer)
             .expect("Couldn't parse jpeg file.");
@@ -24,17 +208,51 @@ impl JPEG {
         return jpeg;
     }
 
-    pub fn populate_from_buffer(&mut self, buffer: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
-        let mut reader = Reader::new(Cursor::new(&buffer[..]));
+    pub fn populate_from_buffer(&mut self, buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.exif = None;
+        self.exif_fields.clear();
+        self.comments.clear();
+        for (marker, payload) in read_segments(buffer) {
+            match marker {
+                APP1 if payload.starts_with(EXIF_HEADER) && self.exif.is_none() => {
+                    self.exif = Some(payload[EXIF_HEADER.len()..].to_vec());
+                }
+                COM => self.comments.push(String::from_utf8_lossy(payload).into_owned()),
+                _ => {}
+            }
+        }
+
+        let mut orientation = 1;
+        if let Some(raw) = &self.exif {
+            // Broken EXIF data shouldn't keep the image from opening.
+            if let Ok(exif) = exif::Reader::new().read_raw(raw.clone()) {
+                self.exif_fields = exif_fields(&exif);
+                orientation = read_orientation(&exif);
+            }
+        }
+
+        let mut reader = Reader::new(Cursor::new(buffer));
         reader.set_format(image::ImageFormat::Jpeg);
-        let image = reader.decode()?;
+        let image = apply_orientation(reader.decode()?, orientation);
+        if orientation != 1 {
+            reset_orientation(self.exif.as_mut().unwrap());
+        }
 
         self.width = image.width();
         self.height = image.height();
+        self.channels = image.color().channel_count() as usize;
         self.data = BitmapData::U8(image.to_rgb8().into_raw());
 
         Ok(())
     }
+
+    pub fn get_channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn get_exif_fields(&self) -> &[ExifField] {
+        &self.exif_fields
+    }
 }
 
 impl Image for JPEG {
@@ -49,4 +267,116 @@ impl Image for JPEG {
     fn get_buffer_ref(&self) -> &BitmapData {
         &self.data
     }
+
+    fn get_comments(&self) -> &[String] {
+        &self.comments
+    }
+
+    fn get_exif(&self) -> Option<&[u8]> {
+        self.exif.as_deref()
+    }
+}
+
+// Marker and payload of every segment before the entropy coded data.
+pub fn read_segments(buffer: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= buffer.len() && buffer[pos] == 0xFF {
+        let marker = buffer[pos + 1];
+        // Fill bytes before a marker.
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+
+        let length = u16::from_be_bytes([buffer[pos + 2], buffer[pos + 3]]) as usize;
+        if length < 2 || pos + 2 + length > buffer.len() {
+            break;
+        }
+
+        segments.push((marker, &buffer[pos + 4..pos + 2 + length]));
+        if marker == SOS {
+            break;
+        }
+        pos += 2 + length;
+    }
+
+    return segments;
+}
+
+pub fn write_segment(vec: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+    vec.extend_from_slice(&[0xFF, marker]);
+    vec.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+    vec.extend_from_slice(payload);
+}
+
+// Readable fields of the main image, the thumbnail and maker notes are left out.
+fn exif_fields(exif: &exif::Exif) -> Vec<ExifField> {
+    const MAX_LENGTH: usize = 64;
+
+    exif.fields()
+        .filter(|field| field.ifd_num == In::PRIMARY && field.tag != Tag::MakerNote)
+        .map(|field| {
+            let mut value = field.display_value().with_unit(exif).to_string();
+            if value.chars().count() > MAX_LENGTH {
+                value = value.chars().take(MAX_LENGTH).collect::<String>() + "…";
+            }
+
+            ExifField {
+                tag: field.tag.to_string(),
+                value,
+            }
+        })
+        .collect()
+}
+
+pub fn read_orientation(exif: &exif::Exif) -> u32 {
+    exif.get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1)
+}
+
+// Turns the stored pixels upright according to the EXIF Orientation tag.
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+// Sets the Orientation entry of the first IFD to 1 in place, so metadata
+// written back with the rotated pixels isn't applied twice.
+pub fn reset_orientation(tiff: &mut [u8]) {
+    let big_endian = match tiff.get(..2) {
+        Some(b"MM") => true,
+        Some(b"II") => false,
+        _ => return,
+    };
+    let read_u16 = |data: &[u8], pos: usize| -> Option<u16> {
+        let bytes = [*data.get(pos)?, *data.get(pos + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let read_u32 = |data: &[u8], pos: usize| -> Option<u32> {
+        let bytes = [*data.get(pos)?, *data.get(pos + 1)?, *data.get(pos + 2)?, *data.get(pos + 3)?];
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+
+    let ifd = match read_u32(tiff, 4) {
+        Some(ifd) => ifd as usize,
+        None => return,
+    };
+    let count = read_u16(tiff, ifd).unwrap_or(0) as usize;
+    for entry in (0..count).map(|index| ifd + 2 + index * 12) {
+        if read_u16(tiff, entry) == Some(ORIENTATION_TAG) && entry + 10 <= tiff.len() {
+            let value = if big_endian { 1u16.to_be_bytes() } else { 1u16.to_le_bytes() };
+            tiff[entry + 8..entry + 10].copy_from_slice(&value);
+            return;
+        }
+    }
 }
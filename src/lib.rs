@@ -0,0 +1,17 @@
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+pub mod codec;
+pub mod display;
+pub mod hash;
+pub mod history;
+pub mod image;
+pub mod info;
+pub mod jobs;
+pub mod jpeg;
+pub mod lossless;
+pub mod markers;
+pub mod metrics;
+pub mod ops;
+pub mod ppm;
+pub mod recipe;
+pub mod selection;
@@ -0,0 +1,434 @@
+use std::{error::Error, fmt};
+
+use crate::codec::{read_coefficients, write_coefficients, CoefficientPlane, Coefficients};
+use crate::jpeg::{read_orientation, reset_orientation, APP1, EXIF_HEADER};
+
+// Rotations, flips and crops done on the quantised DCT blocks, the way
+// jpegtran does them, so the pixels don't go through another encode.
+// Partial MCUs at an edge that would end up at the left or top are trimmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,
+    // Origin is moved down to a multiple of the MCU size.
+    Crop {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transform::Rotate90 => write!(f, "Rotate 90°"),
+            Transform::Rotate180 => write!(f, "Rotate 180°"),
+            Transform::Rotate270 => write!(f, "Rotate 270°"),
+            Transform::FlipHorizontal => write!(f, "Flip horizontally"),
+            Transform::FlipVertical => write!(f, "Flip vertically"),
+            Transform::Transpose => write!(f, "Transpose"),
+            Transform::Crop { x, y, width, height } => write!(f, "Crop to {}x{} at {},{}", width, height, x, y),
+        }
+    }
+}
+
+fn map_planes(coefficients: &Coefficients, f: impl Fn(&CoefficientPlane) -> CoefficientPlane) -> Vec<CoefficientPlane> {
+    coefficients.planes.iter().map(f).collect()
+}
+
+// Mirrors the blocks within the first `columns` blocks of every row. Odd
+// horizontal frequencies change sign when a block is mirrored.
+fn flip_horizontal(coefficients: &Coefficients) -> Result<Coefficients, Box<dyn Error>> {
+    let mcu_width = 8 * coefficients.max_sampling().0;
+    let width = coefficients.width / mcu_width * mcu_width;
+    if width == 0 {
+        return Err("Image is narrower than one MCU.".into());
+    }
+
+    let planes = map_planes(coefficients, |plane| {
+        let columns = width / mcu_width * plane.sampling.0;
+        let mut blocks = Vec::with_capacity(columns * plane.blocks_high);
+        for block_y in 0..plane.blocks_high {
+            for block_x in 0..columns {
+                let source = &plane.blocks[block_y * plane.blocks_wide + columns - 1 - block_x];
+                let mut block = *source;
+                for (i, val) in block.iter_mut().enumerate() {
+                    if i % 2 == 1 {
+                        *val = -*val;
+                    }
+                }
+                blocks.push(block);
+            }
+        }
+
+        CoefficientPlane {
+            blocks_wide: columns,
+            blocks,
+            ..plane.clone()
+        }
+    });
+
+    return Ok(Coefficients {
+        width,
+        planes,
+        ..coefficients.clone()
+    });
+}
+
+fn flip_vertical(coefficients: &Coefficients) -> Result<Coefficients, Box<dyn Error>> {
+    let mcu_height = 8 * coefficients.max_sampling().1;
+    let height = coefficients.height / mcu_height * mcu_height;
+    if height == 0 {
+        return Err("Image is shorter than one MCU.".into());
+    }
+
+    let planes = map_planes(coefficients, |plane| {
+        let rows = height / mcu_height * plane.sampling.1;
+        let mut blocks = Vec::with_capacity(plane.blocks_wide * rows);
+        for block_y in 0..rows {
+            for block_x in 0..plane.blocks_wide {
+                let source = &plane.blocks[(rows - 1 - block_y) * plane.blocks_wide + block_x];
+                let mut block = *source;
+                for (i, val) in block.iter_mut().enumerate() {
+                    if (i / 8) % 2 == 1 {
+                        *val = -*val;
+                    }
+                }
+                blocks.push(block);
+            }
+        }
+
+        CoefficientPlane {
+            blocks_high: rows,
+            blocks,
+            ..plane.clone()
+        }
+    });
+
+    return Ok(Coefficients {
+        height,
+        planes,
+        ..coefficients.clone()
+    });
+}
+
+// Swaps rows and columns of the block grid, the blocks, the quantisation
+// tables and the sampling factors.
+fn transpose(coefficients: &Coefficients) -> Coefficients {
+    let transposed = |values: &[i16; 64]| {
+        let mut out = [0; 64];
+        for (i, val) in out.iter_mut().enumerate() {
+            *val = values[(i % 8) * 8 + i / 8];
+        }
+        out
+    };
+
+    let planes = map_planes(coefficients, |plane| {
+        let mut blocks = Vec::with_capacity(plane.blocks.len());
+        for block_y in 0..plane.blocks_wide {
+            for block_x in 0..plane.blocks_high {
+                blocks.push(transposed(&plane.blocks[block_x * plane.blocks_wide + block_y]));
+            }
+        }
+
+        let mut table = [0; 64];
+        for (i, val) in table.iter_mut().enumerate() {
+            *val = plane.table[(i % 8) * 8 + i / 8];
+        }
+
+        CoefficientPlane {
+            sampling: (plane.sampling.1, plane.sampling.0),
+            table,
+            blocks_wide: plane.blocks_high,
+            blocks_high: plane.blocks_wide,
+            blocks,
+            ..plane.clone()
+        }
+    });
+
+    return Coefficients {
+        width: coefficients.height,
+        height: coefficients.width,
+        planes,
+        ..coefficients.clone()
+    };
+}
+
+fn crop(coefficients: &Coefficients, x: usize, y: usize, width: usize, height: usize) -> Result<Coefficients, Box<dyn Error>> {
+    let (max_h, max_v) = coefficients.max_sampling();
+    let (mcu_width, mcu_height) = (8 * max_h, 8 * max_v);
+    let x0 = x / mcu_width * mcu_width;
+    let y0 = y / mcu_height * mcu_height;
+    let x1 = (x + width).min(coefficients.width);
+    let y1 = (y + height).min(coefficients.height);
+    if x1 <= x || y1 <= y {
+        return Err("Crop region is outside of the image.".into());
+    }
+
+    let (width, height) = (x1 - x0, y1 - y0);
+    let planes = map_planes(coefficients, |plane| {
+        let (h, v) = plane.sampling;
+        let (first_x, first_y) = (x0 / mcu_width * h, y0 / mcu_height * v);
+        let columns = width.div_ceil(mcu_width) * h;
+        let rows = height.div_ceil(mcu_height) * v;
+
+        let mut blocks = Vec::with_capacity(columns * rows);
+        for block_y in first_y..first_y + rows {
+            for block_x in first_x..first_x + columns {
+                blocks.push(plane.blocks[block_y * plane.blocks_wide + block_x]);
+            }
+        }
+
+        CoefficientPlane {
+            blocks_wide: columns,
+            blocks_high: rows,
+            blocks,
+            ..plane.clone()
+        }
+    });
+
+    return Ok(Coefficients {
+        width,
+        height,
+        planes,
+        ..coefficients.clone()
+    });
+}
+
+pub fn apply(coefficients: &Coefficients, transform: &Transform) -> Result<Coefficients, Box<dyn Error>> {
+    match *transform {
+        Transform::Rotate90 => flip_horizontal(&transpose(coefficients)),
+        Transform::Rotate180 => flip_vertical(&flip_horizontal(coefficients)?),
+        Transform::Rotate270 => flip_vertical(&transpose(coefficients)),
+        Transform::FlipHorizontal => flip_horizontal(coefficients),
+        Transform::FlipVertical => flip_vertical(coefficients),
+        Transform::Transpose => Ok(transpose(coefficients)),
+        Transform::Crop { x, y, width, height } => crop(coefficients, x, y, width, height),
+    }
+}
+
+// Transforms that turn stored pixels upright for an EXIF Orientation value.
+pub fn orientation_transforms(orientation: u32) -> Vec<Transform> {
+    match orientation {
+        2 => vec![Transform::FlipHorizontal],
+        3 => vec![Transform::Rotate180],
+        4 => vec![Transform::FlipVertical],
+        5 => vec![Transform::Transpose],
+        6 => vec![Transform::Rotate90],
+        7 => vec![Transform::Rotate90, Transform::FlipVertical],
+        8 => vec![Transform::Rotate270],
+        _ => Vec::new(),
+    }
+}
+
+// Applies the transforms to a baseline JPEG file. The image is first turned
+// upright by its EXIF orientation, so the transforms act on what viewers
+// show, and the orientation is reset afterwards.
+pub fn transform_jpeg(buffer: &[u8], transforms: &[Transform]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut coefficients = read_coefficients(buffer)?;
+
+    let mut orientation = 1;
+    for (marker, payload) in coefficients.segments.iter_mut() {
+        if *marker != APP1 || !payload.starts_with(EXIF_HEADER) {
+            continue;
+        }
+
+        let tiff = &mut payload[EXIF_HEADER.len()..];
+        if let Ok(exif) = exif::Reader::new().read_raw(tiff.to_vec()) {
+            orientation = read_orientation(&exif);
+            reset_orientation(tiff);
+        }
+        break;
+    }
+
+    for transform in orientation_transforms(orientation).iter().chain(transforms) {
+        coefficients = apply(&coefficients, transform)?;
+    }
+
+    let (vec, _) = write_coefficients(&coefficients);
+
+    return Ok(vec);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{decode, encode};
+    use crate::image::{Bitmap, BitmapData, Image};
+    use crate::jpeg::Subsampling;
+
+    const SUBSAMPLINGS: [Subsampling; 3] = [Subsampling::S444, Subsampling::S422, Subsampling::S420];
+    const TRANSFORMS: [Transform; 6] = [
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Transpose,
+    ];
+
+    fn pattern(width: usize, height: usize) -> Bitmap {
+        let mut data = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                data.extend([(x * 7 + y * 3) % 256, (x * y) % 256, ((x ^ y) * 4) % 256].map(|val| val as u8));
+            }
+        }
+
+        return Bitmap::new(width, height, 255, BitmapData::U8(data));
+    }
+
+    // For a position in the result, the position in the source it comes from.
+    type Source = Box<dyn Fn(usize, usize) -> (usize, usize)>;
+
+    // Size of the result and where its pixels come from.
+    fn mapping(transform: &Transform, width: usize, height: usize) -> ((usize, usize), Source) {
+        match *transform {
+            Transform::Rotate90 => ((height, width), Box::new(move |x, y| (y, height - 1 - x))),
+            Transform::Rotate180 => ((width, height), Box::new(move |x, y| (width - 1 - x, height - 1 - y))),
+            Transform::Rotate270 => ((height, width), Box::new(move |x, y| (width - 1 - y, x))),
+            Transform::FlipHorizontal => ((width, height), Box::new(move |x, y| (width - 1 - x, y))),
+            Transform::FlipVertical => ((width, height), Box::new(move |x, y| (x, height - 1 - y))),
+            Transform::Transpose => ((height, width), Box::new(|x, y| (y, x))),
+            Transform::Crop { x: left, y: top, width, height } => ((width, height), Box::new(move |x, y| (left + x, top + y))),
+        }
+    }
+
+    fn transformed(image: &dyn Image, transform: &Transform) -> Vec<(u16, u16, u16)> {
+        let ((width, height), source) = mapping(transform, image.get_width(), image.get_height());
+
+        return (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (sx, sy) = source(x, y);
+                image.get_pixel_value(sx, sy)
+            })
+            .collect();
+    }
+
+    fn pixels(image: &dyn Image) -> Vec<(u16, u16, u16)> {
+        return transformed(image, &Transform::Crop { x: 0, y: 0, width: image.get_width(), height: image.get_height() });
+    }
+
+    fn max_difference(a: &[(u16, u16, u16)], b: &[(u16, u16, u16)]) -> u16 {
+        assert_eq!(a.len(), b.len());
+
+        return a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| a.0.abs_diff(b.0).max(a.1.abs_diff(b.1)).max(a.2.abs_diff(b.2)))
+            .max()
+            .unwrap_or(0);
+    }
+
+    // The float inverse DCT of a mirrored block can round a sample the other
+    // way, so results may be off by one.
+    #[test]
+    fn transforms_match_pixel_space() {
+        for subsampling in SUBSAMPLINGS {
+            let jpeg = encode(&pattern(48, 32), 90, subsampling).bytes;
+            let original = decode(&jpeg).unwrap();
+            for transform in TRANSFORMS {
+                let result = decode(&transform_jpeg(&jpeg, &[transform]).unwrap()).unwrap();
+                let ((width, height), _) = mapping(&transform, 48, 32);
+                assert_eq!((result.get_width(), result.get_height()), (width, height), "{} {:?}", transform, subsampling);
+                let difference = max_difference(&pixels(&result), &transformed(&original, &transform));
+                assert!(difference <= 1, "{} {:?} differs by {}", transform, subsampling, difference);
+            }
+        }
+    }
+
+    #[test]
+    fn coefficients_round_trip() {
+        for subsampling in SUBSAMPLINGS {
+            let coefficients = read_coefficients(&encode(&pattern(40, 24), 75, subsampling).bytes).unwrap();
+            let (written, _) = write_coefficients(&coefficients);
+
+            assert_eq!(read_coefficients(&written).unwrap(), coefficients);
+        }
+    }
+
+    #[test]
+    fn flips_trim_partial_edge_mcus() {
+        let jpeg = encode(&pattern(40, 40), 90, Subsampling::S420).bytes;
+        let original = decode(&jpeg).unwrap();
+
+        let flipped = decode(&transform_jpeg(&jpeg, &[Transform::FlipHorizontal]).unwrap()).unwrap();
+        assert_eq!((flipped.get_width(), flipped.get_height()), (32, 40));
+        let trimmed = transformed(&original, &Transform::Crop { x: 0, y: 0, width: 32, height: 40 });
+        let trimmed = Bitmap::new(32, 40, 255, BitmapData::U8(trimmed.iter().flat_map(|(r, g, b)| [*r as u8, *g as u8, *b as u8]).collect()));
+        assert!(max_difference(&pixels(&flipped), &transformed(&trimmed, &Transform::FlipHorizontal)) <= 1);
+
+        let flipped = decode(&transform_jpeg(&jpeg, &[Transform::FlipVertical]).unwrap()).unwrap();
+        assert_eq!((flipped.get_width(), flipped.get_height()), (40, 32));
+    }
+
+    #[test]
+    fn crop_moves_origin_to_mcu() {
+        let jpeg = encode(&pattern(48, 48), 90, Subsampling::S420).bytes;
+        let original = decode(&jpeg).unwrap();
+
+        let crop = Transform::Crop { x: 21, y: 19, width: 20, height: 10 };
+        let cropped = decode(&transform_jpeg(&jpeg, &[crop]).unwrap()).unwrap();
+        // The origin goes down to 16,16, the far edge stays where asked.
+        assert_eq!((cropped.get_width(), cropped.get_height()), (25, 13));
+        assert_eq!(pixels(&cropped), transformed(&original, &Transform::Crop { x: 16, y: 16, width: 25, height: 13 }));
+
+        // Clipped to the image.
+        let cropped = decode(&transform_jpeg(&jpeg, &[Transform::Crop { x: 40, y: 0, width: 100, height: 8 }]).unwrap()).unwrap();
+        assert_eq!((cropped.get_width(), cropped.get_height()), (16, 8));
+
+        assert!(transform_jpeg(&jpeg, &[Transform::Crop { x: 48, y: 0, width: 8, height: 8 }]).is_err());
+    }
+
+    #[test]
+    fn narrower_than_one_mcu() {
+        let jpeg = encode(&pattern(8, 32), 90, Subsampling::S420).bytes;
+
+        let err = transform_jpeg(&jpeg, &[Transform::FlipHorizontal]).unwrap_err();
+        assert_eq!(err.to_string(), "Image is narrower than one MCU.");
+        assert!(transform_jpeg(&jpeg, &[Transform::FlipVertical]).is_ok());
+        // 4:4:4 MCUs are a single block wide.
+        assert!(transform_jpeg(&encode(&pattern(8, 32), 90, Subsampling::S444).bytes, &[Transform::FlipHorizontal]).is_ok());
+    }
+
+    #[test]
+    fn orientation_transforms_turn_images_upright() {
+        // Where the pixel shown at x,y of the upright image is stored, for
+        // each EXIF orientation.
+        let stored = |orientation: u32, x: usize, y: usize, width: usize, height: usize| match orientation {
+            1 => (x, y),
+            2 => (width - 1 - x, y),
+            3 => (width - 1 - x, height - 1 - y),
+            4 => (x, height - 1 - y),
+            5 => (y, x),
+            6 => (y, height - 1 - x),
+            7 => (width - 1 - y, height - 1 - x),
+            8 => (width - 1 - y, x),
+            _ => unreachable!(),
+        };
+
+        let image = pattern(6, 4);
+        for orientation in 1..=8 {
+            let mut result = pattern(6, 4);
+            for transform in orientation_transforms(orientation) {
+                let ((width, height), _) = mapping(&transform, result.get_width(), result.get_height());
+                let data = transformed(&result, &transform);
+                result = Bitmap::new(width, height, 255, BitmapData::U8(data.iter().flat_map(|(r, g, b)| [*r as u8, *g as u8, *b as u8]).collect()));
+            }
+
+            let (width, height) = (result.get_width(), result.get_height());
+            for y in 0..height {
+                for x in 0..width {
+                    let (sx, sy) = stored(orientation, x, y, 6, 4);
+                    assert_eq!(result.get_pixel_value(x, y), image.get_pixel_value(sx, sy), "orientation {}", orientation);
+                }
+            }
+        }
+    }
+}
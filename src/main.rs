@@ -1,36 +1,226 @@
-mod image;
-mod jpeg;
-mod ppm;
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+mod background;
+mod codec_lab;
+mod commands;
+mod comparison;
+mod document;
+mod inspector;
+mod overlay;
+mod preview;
+mod renderer;
+mod tiles;
+mod view;
 
 use gloo_events::EventListener;
-use jpeg::JPEG;
-use js_sys::{Uint8Array, Float32Array};
-use ppm::PPM;
-use wasm_bindgen::JsCast;
-use web_sys::{WebGl2RenderingContext as GL, HtmlElement, HtmlInputElement, Blob, Url};
+use gloo_timers::callback::Timeout;
+use js_sys::{Reflect, Uint8Array};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{Blob, DataTransfer, FileList, HtmlElement, HtmlInputElement, HtmlTextAreaElement, KeyboardEvent};
 use web_sys::{
-    window, CanvasRenderingContext2d, HtmlCanvasElement, WebGl2RenderingContext,
+    window, CanvasRenderingContext2d, HtmlCanvasElement,
 };
 use yew::prelude::*;
 
-use crate::image::{BitmapData, Image};
+use ppm::display::{DisplaySettings, ToneMap};
+use ppm::history::{Change, Step};
+use ppm::image::{load_from_buffer, Bitmap, Image};
+use ppm::info::ImageInfo;
+use ppm::jobs::{image_from_bytes, image_to_bytes, Job, Reply};
+use ppm::lossless::{transform_jpeg, Transform};
+use ppm::markers::{read_markers, Details};
+use ppm::metrics::{ChannelMetrics, Metrics};
+use ppm::jpeg::{parse_quantization_tables, JpegOptions, Subsampling, STANDARD_CHROMA_TABLE, STANDARD_LUMA_TABLE};
+use ppm::ops::Operation;
+use ppm::recipe::Recipe;
+use ppm::selection::{crop, region_stats, RegionStats, Selection, HISTOGRAM_BINS};
+
+use crate::background::{Background, JobReply};
+use crate::codec_lab::{symbol_label, CodecLab, Stage};
+use crate::commands::{is_text_entry, key_combo, matches_query, Command, Keybindings, PAN_STEP};
+use crate::comparison::{CompareMode, Comparison};
+use crate::document::{Document, THUMBNAIL_SIZE};
+use crate::preview::{draw_to_canvas, thumbnail_url, JpegPreview, PREVIEW_DELAY_MS};
+use crate::inspector::{samples_to_csv, Sample, DEFAULT_NEIGHBOURHOOD, MAX_NEIGHBOURHOOD};
+use crate::renderer::Renderer;
 
 struct App {
-    image: Option<Box<dyn Image>>,
-    scale: f64,
-    translate_pos: (f64, f64),
+    documents: Vec<Document>,
+    next_document_id: usize,
+    current_document: Option<usize>,
+    // State of the shown document.
+    document: Document,
+    drop_target: bool,
+    comparison: Option<Comparison>,
+    divider_drag: bool,
+    drag_pos: Option<(f64, f64)>,
+    drag_distance: f64,
+    tool: Tool,
+    selection_anchor: Option<(f64, f64)>,
+    show_grid: bool,
+    hover: Option<Sample>,
+    neighbourhood: usize,
     file_changed: bool,
-    quality: u8,
+    renderer: Option<Renderer>,
+    render_error: Option<String>,
+    display: DisplaySettings,
+    blur_sigma: f64,
+    brightness: f64,
+    contrast: f64,
+    jpeg_options: JpegOptions,
+    export_dialog: bool,
+    // Text of the custom quantisation tables, kept while it doesn't parse.
+    quant_tables: Option<String>,
+    quant_error: Option<String>,
+    preview: Option<Result<JpegPreview, String>>,
+    // Percentage of the preview width showing the original.
+    preview_split: f64,
+    preview_timeout: Option<Timeout>,
+    preview_dirty: bool,
+    codec_lab: bool,
+    codec_quality: u8,
+    codec_subsampling: Subsampling,
+    codec_stage: Stage,
+    codec_component: usize,
+    codec_block: usize,
+    codec: Option<Result<CodecLab, String>>,
+    codec_dirty: bool,
+    resize_listener: Option<EventListener>,
+    keydown_listener: Option<EventListener>,
+    keybindings: Keybindings,
+    // Command waiting for its new key in the shortcuts dialog.
+    capturing: Option<Command>,
+    keybindings_dialog: bool,
+    palette: bool,
+    palette_query: String,
+    palette_index: usize,
+    palette_focus: bool,
+    paste_listener: Option<EventListener>,
+    background: Background,
+    jobs: Vec<RunningJob>,
+}
+
+// Work handed to the background, with what's needed to use its result.
+// Documents are referred to by id, by the time the result is back they may
+// have been switched or closed.
+enum Pending {
+    Load { document: usize, value: Vec<u8> },
+    // The result of the steps the change replays.
+    Edit { document: usize, change: Change },
+    ExportJpeg,
+    Preview { raw_size: usize },
+}
+
+impl Pending {
+    fn document(&self) -> Option<usize> {
+        match self {
+            Pending::Load { document, .. } | Pending::Edit { document, .. } => Some(*document),
+            Pending::ExportJpeg | Pending::Preview { .. } => None,
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            Pending::Load { .. } => "Opening image".to_string(),
+            Pending::Edit { change, .. } => match change {
+                Change::Push(steps) if steps.len() == 1 => format!("Applying {}", steps[0].operation),
+                Change::Push(_) => "Applying recipe".to_string(),
+                Change::Undo => "Undoing".to_string(),
+                Change::Redo => "Redoing".to_string(),
+                Change::Toggle(_) => "Replaying steps".to_string(),
+            },
+            Pending::ExportJpeg => "Encoding jpeg".to_string(),
+            Pending::Preview { .. } => "Encoding preview".to_string(),
+        }
+    }
+}
+
+struct RunningJob {
+    id: usize,
+    pending: Pending,
+    progress: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tool {
+    Pan,
+    Rectangle,
+    Ellipse,
+    Freehand,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 enum Msg {
+    OpenFile { name: String, value: Vec<u8> },
+    SelectDocument { index: usize },
+    CloseDocument { index: usize },
+    DuplicateDocument,
+    DropTarget { active: bool },
     LoadFile { value: Vec<u8> },
+    SetTool { tool: Tool },
+    ClearSelection,
+    CropToSelection,
+    Apply { operation: Operation },
+    Undo,
+    Redo,
+    ToggleStep { index: usize },
+    ExportRecipe { toml: bool },
+    ImportRecipe { value: Vec<u8> },
+    BlurSigmaChange { value: f64 },
+    BrightnessChange { value: f64 },
+    ContrastChange { value: f64 },
     Zoom { pos: (f64, f64), y_delta: f64 },
+    Pan { delta: (f64, f64) },
+    FitToWindow,
+    ActualSize,
+    FillWindow,
+    ToggleGrid,
+    DisplayChange { settings: DisplaySettings },
+    SaveAsShown,
     Draw,
+    MouseDown { pos: (f64, f64) },
+    MouseUp,
+    MouseLeave,
     MouseOver { pos: (f64, f64) },
+    Click { pos: (f64, f64) },
+    NeighbourhoodChange { value: usize },
+    UnpinSample { index: usize },
+    ClearSamples,
+    ExportSamples,
     SaveAsJpeg,
-    QualityChange { value: u8 },
+    SaveAsPpm,
+    ShowExportDialog,
+    CloseExportDialog,
+    JpegOptionsChange { options: JpegOptions },
+    QuantTablesChange { text: Option<String> },
+    UpdatePreview,
+    PreviewSplitChange { value: f64 },
+    LoadCandidate { value: Vec<u8> },
+    CloseComparison,
+    CompareModeChange { mode: CompareMode },
+    CompareOpacityChange { value: f64 },
+    CompareAmplificationChange { value: f64 },
+    LosslessTransform { transform: Transform },
+    SaveLosslessJpeg,
+    ShowCodecLab,
+    CloseCodecLab,
+    CodecSettingsChange { quality: u8, subsampling: Subsampling },
+    CodecStageChange { stage: Stage, component: usize },
+    CodecBlockClick { pos: (f64, f64) },
+    RunCommand { command: Command },
+    ShowPalette,
+    ClosePalette,
+    PaletteQueryChange { query: String },
+    PaletteMove { delta: isize },
+    PaletteRun { index: usize },
+    ShowKeybindings,
+    CloseKeybindings,
+    CaptureKey { command: Command },
+    KeyCaptured { combo: String },
+    ClearKeys { command: Command },
+    ResetKeybindings,
+    JobReply { id: Option<usize>, reply: Reply, data: Vec<u8> },
+    CancelJobs,
     None,
 }
 
@@ -38,62 +228,163 @@ impl Component for App {
     type Message = Msg;
     type Properties = ();
 
-    fn create(_ctx: &Context<Self>) -> Self {
+    fn create(ctx: &Context<Self>) -> Self {
         Self {
-            image: None,
-            scale: 1.0,
-            translate_pos: (0.0, 0.0),
+            documents: Vec::new(),
+            next_document_id: 0,
+            current_document: None,
+            document: Document::default(),
+            drop_target: false,
+            comparison: None,
+            divider_drag: false,
+            drag_pos: None,
+            drag_distance: 0.0,
+            tool: Tool::Pan,
+            selection_anchor: None,
+            show_grid: true,
+            hover: None,
+            neighbourhood: DEFAULT_NEIGHBOURHOOD,
             file_changed: false,
-            quality: 100,
+            renderer: None,
+            render_error: None,
+            display: DisplaySettings::default(),
+            blur_sigma: 2.0,
+            brightness: 0.0,
+            contrast: 1.0,
+            jpeg_options: JpegOptions::default(),
+            export_dialog: false,
+            quant_tables: None,
+            quant_error: None,
+            preview: None,
+            preview_split: 50.0,
+            preview_timeout: None,
+            preview_dirty: false,
+            codec_lab: false,
+            codec_quality: 75,
+            codec_subsampling: Subsampling::S420,
+            codec_stage: Stage::Plane,
+            codec_component: 0,
+            codec_block: 0,
+            codec: None,
+            codec_dirty: false,
+            resize_listener: None,
+            keydown_listener: None,
+            keybindings: Keybindings::load(),
+            capturing: None,
+            keybindings_dialog: false,
+            palette: false,
+            palette_query: String::new(),
+            palette_index: 0,
+            palette_focus: false,
+            paste_listener: None,
+            background: Background::new(
+                ctx.link()
+                    .callback(|(id, reply, data): JobReply| Msg::JobReply { id, reply, data }),
+            ),
+            jobs: Vec::new(),
         }
     }
 
     fn view(&self, ctx: &Context<Self>) -> Html {
         let file_cb = ctx
             .link()
-            .callback(|value: Vec<u8>| Msg::LoadFile { value });
+            .callback(|(name, value): (String, Vec<u8>)| Msg::OpenFile { name, value });
+        let drop_cb = file_cb.clone();
+        let candidate_cb = ctx
+            .link()
+            .callback(|value: Vec<u8>| Msg::LoadCandidate { value });
         html! {
             <div>
                 <div>
-                    <input type="file" onchange={ctx.link().callback(move |event: Event| {
-                        let file_cb = file_cb.clone();
-                        let target = event.target().unwrap();
-                        let target: web_sys::HtmlInputElement = target.dyn_into().unwrap();
-                        let file = target.files().unwrap().get(0).unwrap();
-                        let file_reader = web_sys::FileReader::new().unwrap();
-                        file_reader.read_as_array_buffer(&file).unwrap();
-                        let listener = EventListener::new(&file_reader, "load", move |event| {
-                            let target = event.target().unwrap();
-                            let target: web_sys::FileReader = target.dyn_into().unwrap();
-                            let result = target.result().unwrap();
-                            let array = Uint8Array::new(&result);
-
-                            file_cb.emit(array.to_vec());
-                        });
-                        listener.forget();
+                    <input type="file" id="open-files" multiple=true onchange={ctx.link().callback(move |event: Event| {
+                        read_files(event, file_cb.clone());
+
+                        Msg::None
+                    })} />
+                    <input type="button" value="Export jpeg..." onclick={ctx.link().callback(|_| Msg::ShowExportDialog)} />
+                    <input type="button" value="Save as ppm" onclick={ctx.link().callback(|_| Msg::SaveAsPpm)} />
+                    <input type="button" value="Codec lab..." onclick={ctx.link().callback(|_| Msg::ShowCodecLab)} />
+                    <input type="button" value="Commands..." title={self.shortcut_hint(Command::Palette)}
+                        onclick={ctx.link().callback(|_| Msg::ShowPalette)} />
+                    <label>{" Compare with: "}</label>
+                    <input type="file" disabled={self.document.history.is_none()} onchange={ctx.link().callback(move |event: Event| {
+                        read_file(event, candidate_cb.clone());
 
                         Msg::None
                     })} />
-                    <label>{"Quality: "}</label>
-                    <input type="range" min="0" max="100" value={self.quality.to_string()} step="1" onchange={ctx.link().callback(|event: Event| {
-                        let quality = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap().value_as_number();
-
-                        Msg::QualityChange { value: quality as u8 }
-                    } )} />
-                    <span>{self.quality.to_string()}</span>
-                    <input type="button" value="Save as jpeg" onclick={ctx.link().callback(|_| Msg::SaveAsJpeg)} />
-                    <span id="prompt" style="display: none;" />
+                    <input type="button" value="Fit" onclick={ctx.link().callback(|_| Msg::FitToWindow)} />
+                    <input type="button" value="1:1" onclick={ctx.link().callback(|_| Msg::ActualSize)} />
+                    <input type="button" value="Fill" onclick={ctx.link().callback(|_| Msg::FillWindow)} />
+                    { for [(Tool::Pan, "Pan"), (Tool::Rectangle, "Rectangle"), (Tool::Ellipse, "Ellipse"), (Tool::Freehand, "Freehand")].into_iter().map(|(tool, label)| html! {
+                        <label>
+                            <input type="radio" name="tool" checked={self.tool == tool} onchange={ctx.link().callback(move |_| Msg::SetTool { tool })} />
+                            {label}
+                        </label>
+                    }) }
+                    <label>
+                        <input type="checkbox" checked={self.show_grid} onchange={ctx.link().callback(|_| Msg::ToggleGrid)} />
+                        {"Pixel grid"}
+                    </label>
                 </div>
-                <div style="overflow: auto; width: 95vw; height: 90vh;"
-                    onwheel={ctx.link().callback(|event: WheelEvent| {
-                    event.prevent_default();
+                { self.view_tabs(ctx) }
+                { self.view_progress(ctx) }
+                { self.view_edit_toolbar(ctx) }
+                { self.view_display_toolbar(ctx) }
+                { self.view_comparison_toolbar(ctx) }
+                { self.view_export_dialog(ctx) }
+                { self.view_codec_lab(ctx) }
+                { self.view_palette(ctx) }
+                { self.view_keybindings(ctx) }
+                if let Some(err) = &self.render_error {
+                    <p style="color: red;">{format!("Couldn't set up rendering: {}", err)}</p>
+                }
+                <div style="display: flex;">
+                <div id="viewport"
+                    style={format!("overflow: hidden; flex: 1; height: 90vh;{}", if self.drop_target { " outline: 2px dashed dodgerblue;" } else { "" })}
+                    ondragover={ctx.link().callback(|event: DragEvent| {
+                        // Needed for the drop event to fire.
+                        event.prevent_default();
+
+                        Msg::DropTarget { active: true }
+                    })}
+                    ondragleave={ctx.link().callback(|_| Msg::DropTarget { active: false })}
+                    ondrop={ctx.link().callback(move |event: DragEvent| {
+                        event.prevent_default();
+                        if let Some(files) = event.data_transfer().and_then(|data| data.files()) {
+                            read_file_list(&files, drop_cb.clone());
+                        }
 
-                    Msg::Zoom { pos: (event.offset_x() as f64, event.offset_y() as f64), y_delta: event.delta_y() }
-                })}>
+                        Msg::DropTarget { active: false }
+                    })}>
                     <canvas id="canvas" width="0" height="0"
+                        style={match (self.tool, self.drag_pos) {
+                            (Tool::Pan, Some(_)) => "display: block; cursor: grabbing;",
+                            (Tool::Pan, None) => "display: block; cursor: grab;",
+                            _ => "display: block; cursor: crosshair;",
+                        }}
+                        onwheel={ctx.link().callback(|event: WheelEvent| {
+                            event.prevent_default();
+
+                            Msg::Zoom { pos: (event.offset_x() as f64, event.offset_y() as f64), y_delta: event.delta_y() }
+                        })}
+                        onmousedown={ctx.link().callback(|event: MouseEvent|
+                            Msg::MouseDown { pos: (event.offset_x() as f64, event.offset_y() as f64) }
+                        )}
+                        onmouseup={ctx.link().callback(|_| Msg::MouseUp)}
+                        onmouseleave={ctx.link().callback(|_| Msg::MouseLeave)}
                         onmousemove={ctx.link().callback(|event: MouseEvent|
-                            Msg::MouseOver { pos: (event.offset_x() as f64,event.offset_y() as f64)
-                    })} />
+                            Msg::MouseOver { pos: (event.offset_x() as f64, event.offset_y() as f64) }
+                        )}
+                        onclick={ctx.link().callback(|event: MouseEvent|
+                            Msg::Click { pos: (event.offset_x() as f64, event.offset_y() as f64) }
+                        )} />
+                </div>
+                <div style="width: 300px; height: 90vh; overflow-y: auto;">
+                    { self.view_metadata() }
+                    { self.view_markers() }
+                    { self.view_history(ctx) }
+                    { self.view_inspector(ctx) }
+                </div>
                 </div>
             </div>
         }
@@ -117,304 +408,2284 @@ impl Component for App {
             .unwrap();
 
         match msg {
+            Msg::OpenFile { name, value } => {
+                self.documents.push(Document::new(self.next_document_id, name));
+                self.next_document_id += 1;
+                self.switch_document(ctx, Some(self.documents.len() - 1));
+                // Loaded right away, another file may be opened before a
+                // message would get here.
+                self.load_file(value);
+
+                true
+            }
+            Msg::SelectDocument { index } => {
+                if self.current_document == Some(index) || index >= self.documents.len() {
+                    return false;
+                }
+
+                self.switch_document(ctx, Some(index));
+
+                true
+            }
+            Msg::CloseDocument { index } => {
+                if index >= self.documents.len() {
+                    return false;
+                }
+
+                let id = self.documents[index].id;
+                self.jobs.retain(|job| job.pending.document() != Some(id));
+                match self.current_document {
+                    Some(current) if current == index => {
+                        self.switch_document(ctx, None);
+                        self.documents.remove(index);
+                        // The tab to the right takes its place, or the one to
+                        // the left when it was the last.
+                        if !self.documents.is_empty() {
+                            self.switch_document(ctx, Some(index.min(self.documents.len() - 1)));
+                        }
+                    }
+                    Some(current) => {
+                        self.documents.remove(index);
+                        if index < current {
+                            self.current_document = Some(current - 1);
+                        }
+                    }
+                    None => {
+                        self.documents.remove(index);
+                    }
+                }
+
+                true
+            }
+            Msg::RunCommand { command } => {
+                ctx.link().send_message(self.command_msg(command));
+
+                false
+            }
+            Msg::ShowPalette => {
+                self.palette = true;
+                self.palette_focus = true;
+                self.palette_query.clear();
+                self.palette_index = 0;
+
+                true
+            }
+            Msg::ClosePalette => {
+                self.palette = false;
+
+                true
+            }
+            Msg::PaletteQueryChange { query } => {
+                self.palette_query = query;
+                self.palette_index = 0;
+
+                true
+            }
+            Msg::PaletteMove { delta } => {
+                let count = self.palette_commands().len() as isize;
+                if count == 0 {
+                    return false;
+                }
+                self.palette_index = (self.palette_index as isize + delta).rem_euclid(count) as usize;
+
+                true
+            }
+            Msg::PaletteRun { index } => {
+                self.palette = false;
+                if let Some(command) = self.palette_commands().get(index) {
+                    ctx.link().send_message(self.command_msg(*command));
+                }
+
+                true
+            }
+            Msg::ShowKeybindings => {
+                self.palette = false;
+                self.keybindings_dialog = true;
+
+                true
+            }
+            Msg::CloseKeybindings => {
+                self.keybindings_dialog = false;
+                self.capturing = None;
+                self.listen_for_keys(ctx);
+
+                true
+            }
+            Msg::CaptureKey { command } => {
+                self.capturing = Some(command);
+                self.listen_for_keys(ctx);
+
+                true
+            }
+            Msg::KeyCaptured { combo } => {
+                let command = match self.capturing.take() {
+                    Some(command) => command,
+                    None => return false,
+                };
+
+                // Escape only cancels, it stays bound to what it was.
+                if combo != "Escape" {
+                    self.keybindings.bind(command, combo);
+                    self.keybindings.save();
+                }
+                self.listen_for_keys(ctx);
+
+                true
+            }
+            Msg::ClearKeys { command } => {
+                self.keybindings.clear(command);
+                self.keybindings.save();
+                self.listen_for_keys(ctx);
+
+                true
+            }
+            Msg::ResetKeybindings => {
+                self.keybindings = Keybindings::default();
+                self.keybindings.save();
+                self.listen_for_keys(ctx);
+
+                true
+            }
+            Msg::DuplicateDocument => {
+                let current = match self.current_document {
+                    Some(current) if self.document.history.is_some() => current,
+                    _ => return false,
+                };
+
+                let mut duplicate = self
+                    .document
+                    .duplicate(self.next_document_id, format!("{} (copy)", self.documents[current].name));
+                self.next_document_id += 1;
+                duplicate.thumbnail = Some(thumbnail_url(
+                    self.document.history.as_ref().unwrap().current(),
+                    THUMBNAIL_SIZE,
+                ));
+                self.documents.insert(current + 1, duplicate);
+                self.switch_document(ctx, Some(current + 1));
+
+                true
+            }
+            Msg::DropTarget { active } => {
+                let changed = self.drop_target != active;
+                self.drop_target = active;
+
+                changed
+            }
             Msg::LoadFile { value } => {
-                // Check if jpeg or ppm
-                if value[0] == 0xFF && value[1] == 0xD8 {
-                    self.image = Some(Box::new(JPEG::from_buffer(&mut value.clone())));
-                } else {
-                    self.image = Some(Box::new(PPM::from_buffer(&mut value.clone())));
+                self.load_file(value);
+
+                true
+            }
+            Msg::JobReply { id, reply, data } => {
+                let id = match id {
+                    Some(id) => id,
+                    None => {
+                        // The worker is gone along with everything it was doing.
+                        let error = match reply {
+                            Reply::Failed { error } => error,
+                            _ => "The worker failed.".to_string(),
+                        };
+                        self.background.cancel();
+                        for job in std::mem::take(&mut self.jobs) {
+                            self.job_failed(job.pending, error.clone());
+                        }
+
+                        return true;
+                    }
+                };
+
+                // Replies to cancelled or superseded jobs are dropped.
+                let index = match self.jobs.iter().position(|job| job.id == id) {
+                    Some(index) => index,
+                    None => return false,
+                };
+                if let Reply::Progress { fraction } = reply {
+                    self.jobs[index].progress = Some(fraction);
+                    return true;
+                }
+
+                let pending = self.jobs.remove(index).pending;
+                self.job_done(ctx, pending, reply, data);
+
+                true
+            }
+            Msg::CancelJobs => {
+                self.background.cancel();
+                for job in std::mem::take(&mut self.jobs) {
+                    self.job_failed(job.pending, "Cancelled.".to_string());
                 }
 
-                self.file_changed = true;
-                self.scale = 1.0;
+                true
+            }
+            Msg::SetTool { tool } => {
+                self.tool = tool;
+
+                true
+            }
+            Msg::ClearSelection => {
+                self.document.selection = None;
+                self.document.region_stats = None;
                 ctx.link().send_message(Msg::Draw);
 
                 true
             }
+            Msg::CropToSelection => {
+                let selection = match &self.document.selection {
+                    Some(selection) => selection.clone(),
+                    None => return false,
+                };
+
+                ctx.link().send_message(Msg::Apply { operation: Operation::Crop { selection } });
+
+                false
+            }
+            Msg::Apply { operation } => {
+                // Filters and adjustments only touch the selected region.
+                let selection = if operation.is_geometric() { None } else { self.document.selection.clone() };
+
+                self.edit(ctx, Change::Push(vec![Step::new(operation, selection)]))
+            }
+            Msg::Undo => self.edit(ctx, Change::Undo),
+            Msg::Redo => self.edit(ctx, Change::Redo),
+            Msg::ToggleStep { index } => self.edit(ctx, Change::Toggle(index)),
+            Msg::ExportRecipe { toml } => {
+                let history = match &self.document.history {
+                    Some(history) => history,
+                    None => return false,
+                };
+
+                let recipe = Recipe::new(history.applied_steps());
+                let exported = if toml { recipe.to_toml() } else { recipe.to_json() };
+                match exported {
+                    Ok(text) if toml => download(text.as_bytes(), "application/toml", "recipe.toml"),
+                    Ok(text) => download(text.as_bytes(), "application/json", "recipe.json"),
+                    Err(err) => log::error!("Couldn't export recipe: {}", err),
+                }
+
+                false
+            }
+            Msg::ImportRecipe { value } => {
+                if self.document.history.is_none() || self.applying() {
+                    return false;
+                }
+
+                let recipe = match String::from_utf8(value)
+                    .map_err(|err| err.into())
+                    .and_then(|text| Recipe::parse(&text))
+                {
+                    Ok(recipe) => recipe,
+                    Err(err) => {
+                        log::error!("Couldn't import recipe: {}", err);
+                        return false;
+                    }
+                };
+
+                self.edit(ctx, Change::Push(recipe.steps))
+            }
+            Msg::BlurSigmaChange { value } => {
+                self.blur_sigma = value.clamp(0.1, 50.0);
+
+                true
+            }
+            Msg::BrightnessChange { value } => {
+                self.brightness = value;
+
+                true
+            }
+            Msg::ContrastChange { value } => {
+                self.contrast = value;
+
+                true
+            }
             Msg::Zoom { pos, y_delta } => {
-                let scale = if y_delta > 0.0 {
-                    self.scale * 0.9
-                } else {
-                    self.scale * 1.1
+                let factor = if y_delta > 0.0 { 0.9 } else { 1.1 };
+                self.document.view.zoom_at(self.view_pos(pos), factor);
+
+                ctx.link().send_message(Msg::Draw);
+
+                true
+            }
+            Msg::Pan { delta } => {
+                if self.document.history.is_none() {
+                    return false;
+                }
+
+                self.document.view.pan(delta);
+                ctx.link().send_message(Msg::Draw);
+
+                false
+            }
+            Msg::FitToWindow | Msg::ActualSize | Msg::FillWindow => {
+                let image_size = match self.image_size() {
+                    Some(size) => size,
+                    None => return false,
                 };
+                let viewport = get_viewport_size();
+                match msg {
+                    Msg::FitToWindow => self.document.view.fit(image_size, viewport),
+                    Msg::ActualSize => self.document.view.actual_size(image_size, viewport),
+                    _ => self.document.view.fill(image_size, viewport),
+                }
 
-                let translate_pos = (
-                    self.translate_pos.0
-                        + (pos.0 - self.translate_pos.0) * (1.0 - scale / self.scale),
-                    self.translate_pos.1
-                        + (pos.1 - self.translate_pos.1) * (1.0 - scale / self.scale),
-                );
+                ctx.link().send_message(Msg::Draw);
 
-                self.scale = scale;
-                self.translate_pos = translate_pos;
+                true
+            }
+            Msg::ToggleGrid => {
+                self.show_grid = !self.show_grid;
+                ctx.link().send_message(Msg::Draw);
 
+                true
+            }
+            Msg::DisplayChange { settings } => {
+                self.display = settings;
                 ctx.link().send_message(Msg::Draw);
 
                 true
             }
+            Msg::SaveAsShown => {
+                if self.document.history.is_none() {
+                    return false;
+                }
+
+                let mut vec = Vec::new();
+                self.with_export_image(|image| self.display.render(image).write_to_ppm(&mut vec, true));
+                download(&vec, "image/x-portable-pixmap", "image-shown.ppm");
+
+                false
+            }
             Msg::Draw => {
-                if self.image.is_none() {
+                if self.document.history.is_none() {
+                    // Nothing left to show after the last document was closed.
+                    canvas.set_width(0);
+                    canvas.set_height(0);
                     return false;
                 }
 
-                let ppm = self.image.as_ref().unwrap();
-                canvas.set_width(ppm.get_width() as u32);
-                canvas.set_height(ppm.get_height() as u32);
+                let ppm = self.document.history.as_ref().unwrap().current();
 
-                let new_canvas = match window()
-                    .unwrap()
-                    .document()
-                    .unwrap()
-                    .query_selector("#new_canvas")
-                {
-                    Ok(Some(canvas)) => canvas,
-                    _ => {
-                        let canvas = window()
-                            .unwrap()
-                            .document()
-                            .unwrap()
-                            .create_element("canvas")
-                            .unwrap();
-                        canvas.set_attribute("id", "new_canvas").unwrap();
-                        canvas.set_attribute("style", "display: none;").unwrap();
-                        window()
-                            .unwrap()
-                            .document()
-                            .unwrap()
-                            .body()
-                            .unwrap()
-                            .append_child(&canvas)
-                            .unwrap();
-                        canvas
+                // Comparisons are drawn with the 2D context, both images side
+                // by side or on top of each other.
+                if let Some(comparison) = self.comparison.as_mut() {
+                    let viewport = get_viewport_size();
+                    canvas.set_width(viewport.0 as u32);
+                    canvas.set_height(viewport.1 as u32);
+                    comparison.draw(&rendering_context, ppm, &self.document.view, viewport);
+                    if let Some(selection) = &self.document.selection {
+                        overlay::draw_selection(&rendering_context, selection, &self.document.view);
                     }
+
+                    return true;
                 }
-                .dyn_into::<HtmlCanvasElement>()
-                .unwrap();
 
-                if self.file_changed {
-                    new_canvas.set_width(ppm.get_width() as u32);
-                    new_canvas.set_height(ppm.get_height() as u32);
-
-                    let glctx = new_canvas
-                        .get_context("webgl2")
-                        .unwrap()
-                        .unwrap()
-                        .dyn_into::<WebGl2RenderingContext>()
-                        .unwrap();
-                    glctx.viewport(0, 0, ppm.get_width() as i32, ppm.get_height() as i32);
-
-                    let texture = glctx.create_texture();
-                    glctx.bind_texture(GL::TEXTURE_2D, texture.as_ref());
-                    glctx.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_MIN_FILTER, GL::LINEAR as i32);
-                    glctx.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_MAG_FILTER, GL::LINEAR as i32);
-                    glctx.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_WRAP_S, GL::CLAMP_TO_EDGE as i32);
-                    glctx.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_WRAP_T, GL::CLAMP_TO_EDGE as i32);
-                    glctx.pixel_storei(GL::UNPACK_ALIGNMENT, 1);
-
-                    match &ppm.get_buffer_ref() {
-                        BitmapData::U8(data) => {
-                            glctx.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
-                                GL::TEXTURE_2D, 
-                                0, 
-                                GL::RGB8 as i32, 
-                                ppm.get_width() as i32, 
-                                ppm.get_height() as i32, 
-                                0, 
-                                GL::RGB, 
-                                GL::UNSIGNED_BYTE, 
-                                Some(&data))
-                            .expect("Couldn't load texture data.");
+                if self.renderer.is_none() && self.render_error.is_none() {
+                    match Renderer::new() {
+                        Ok(renderer) => self.renderer = Some(renderer),
+                        Err(err) => {
+                            log::error!("Couldn't set up rendering: {}", err);
+                            self.render_error = Some(err);
                         }
-                        BitmapData::U16(data) => {
-                            let data: Vec<f32> = data.iter().map(|val| (*val as f32) / u16::MAX as f32).collect();
-                            let array = Float32Array::from(data.as_slice());
-                            glctx.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_array_buffer_view(
-                                GL::TEXTURE_2D, 
-                                0, 
-                                GL::RGB16F as i32, 
-                                ppm.get_width() as i32, 
-                                ppm.get_height() as i32, 
-                                0, 
-                                GL::RGB, 
-                                GL::FLOAT, 
-                                Some(&array))
-                            .expect("Couldn't load texture data.");
-                        }
-                        BitmapData::None => {},                        
-                    };
+                    }
+                }
+                let renderer = match self.renderer.as_mut() {
+                    Some(renderer) => renderer,
+                    None => return true,
+                };
 
-                    let vertex_shader = glctx
-                        .create_shader(GL::VERTEX_SHADER)
-                        .expect("Unable to create vertex shader.");
-                    glctx.shader_source(
-                        &vertex_shader,
-                        r#"#version 300 es
-                        in vec2 a_position;
-                        in vec2 a_texcoord;
-                        out vec2 v_texcoord;
-                        uniform vec2 u_translate_pos;
-                        uniform float u_scale;
-                        void main() {
-                            gl_Position = vec4(a_position, 0.0, 1.0);
-                            v_texcoord = a_texcoord;
-                        }"#,
-                    );
-                    glctx.compile_shader(&vertex_shader);
-
-                    let fragment_shader = glctx
-                        .create_shader(GL::FRAGMENT_SHADER)
-                        .expect("Unable to create fragment shader.");
-                    glctx.shader_source(
-                        &fragment_shader,
-                        r#"#version 300 es
-                        precision highp float;
-                        in vec2 v_texcoord;
-                        out vec4 outColor;
-                        uniform sampler2D u_texture;
-                        void main() {
-                            outColor = texture(u_texture, v_texcoord);
-                        }"#,
-                    );
-                    glctx.compile_shader(&fragment_shader);
-
-                    let program = glctx
-                        .create_program()
-                        .expect("Unable to create shader program.");
-                    glctx.attach_shader(&program, &vertex_shader);
-                    glctx.attach_shader(&program, &fragment_shader);
-                    glctx.link_program(&program);
-
-                    let va = glctx.create_vertex_array();
-                    glctx.bind_vertex_array(va.as_ref());
-
-                    let buffer = glctx.create_buffer();
-                    glctx.bind_buffer(GL::ARRAY_BUFFER, buffer.as_ref());
-                    glctx.buffer_data_with_array_buffer_view(
-                        GL::ARRAY_BUFFER,
-                        &Float32Array::from([
-                            -1.0f32, -1.0f32,   0.0f32, 1.0f32, 
-                             1.0f32,  -1.0f32,  1.0f32, 1.0f32, 
-                            -1.0f32,  1.0f32,   0.0f32, 0.0f32, 
-                             1.0f32,   1.0f32,  1.0f32, 0.0f32,
-                        ].as_slice()),
-                        GL::STATIC_DRAW,
+                if self.file_changed {
+                    renderer.set_image(ppm);
+                    self.file_changed = false;
+                }
+
+                // The canvas only ever covers the viewport, the image is drawn
+                // into it tile by tile.
+                let (viewport_width, viewport_height) = get_viewport_size();
+                renderer.draw(ppm, &self.document.view, (viewport_width, viewport_height), &self.display);
+                canvas.set_width(viewport_width as u32);
+                canvas.set_height(viewport_height as u32);
+                rendering_context
+                    .draw_image_with_html_canvas_element(renderer.canvas(), 0.0, 0.0)
+                    .unwrap();
+
+                if self.show_grid {
+                    overlay::draw_pixel_grid(
+                        &rendering_context,
+                        ppm,
+                        &self.document.view,
+                        (viewport_width, viewport_height),
                     );
-                    glctx.vertex_attrib_pointer_with_i32(0, 2, GL::FLOAT, false, 16, 0);
-                    glctx.enable_vertex_attrib_array(0);
-                    glctx.vertex_attrib_pointer_with_i32(1, 2, GL::FLOAT, false, 16, 8);
-                    glctx.enable_vertex_attrib_array(1);
-
-                    glctx.clear_color(0.0, 0.0, 0.0, 1.0);
-                    glctx.clear(GL::COLOR_BUFFER_BIT);
-                    glctx.use_program(Some(&program));
-                    glctx.bind_vertex_array(va.as_ref());
-                    glctx.bind_texture(GL::TEXTURE_2D, texture.as_ref());
-                    glctx.draw_arrays(GL::TRIANGLE_STRIP, 0, 4);
+                }
 
-                    self.file_changed = false;
+                if let Some(selection) = &self.document.selection {
+                    overlay::draw_selection(&rendering_context, selection, &self.document.view);
                 }
 
-                let mut scaled_width = (ppm.get_width() as f64 * self.scale) as u32;
-                let mut scaled_height = (ppm.get_height() as f64 * self.scale) as u32;
-                const MAX_CANVAS: u32 = 19000;
-                scaled_width = if scaled_width > MAX_CANVAS { MAX_CANVAS } else { scaled_width };
-                scaled_height = if scaled_height > MAX_CANVAS { MAX_CANVAS } else { scaled_height };
-                let scale = if scaled_width == MAX_CANVAS || scaled_height == MAX_CANVAS {
-                    let scale_x = MAX_CANVAS as f64 / ppm.get_width() as f64;
-                    let scale_y = MAX_CANVAS as f64 / ppm.get_height() as f64;
-                    if scale_x < scale_y { scale_x } else { scale_y }
-                } else {
-                    self.scale
-                };
+                true
+            }
+            Msg::None => false,
+            Msg::MouseDown { pos } => {
+                if self.document.history.is_none() {
+                    return false;
+                }
+
+                if let Some(comparison) = &self.comparison {
+                    if comparison.near_divider(pos, get_viewport_size()) {
+                        self.divider_drag = true;
+                        return false;
+                    }
+                }
 
-                canvas.set_width((ppm.get_width() as f64 * scale) as u32);
-                canvas.set_height((ppm.get_height() as f64 * scale) as u32);
+                if self.tool == Tool::Pan {
+                    self.drag_pos = Some(pos);
+                    self.drag_distance = 0.0;
 
-                rendering_context.clear_rect(
-                    0.0,
-                    0.0,
-                    scaled_width as f64,
-                    scaled_height as f64,
-                );
+                    return true;
+                }
 
-                rendering_context.set_image_smoothing_enabled(false);
-                rendering_context.translate(0.0, 0.0);
-                rendering_context.scale(scale, scale);
-                rendering_context.draw_image_with_html_canvas_element(&new_canvas, 0.0, 0.0);
+                let anchor = self.document.view.screen_to_image(self.view_pos(pos));
+                self.selection_anchor = Some(anchor);
+                self.document.selection = Some(match self.tool {
+                    Tool::Ellipse => Selection::ellipse_from_corners(anchor, anchor),
+                    Tool::Freehand => Selection::Freehand { points: vec![anchor] },
+                    _ => Selection::rectangle_from_corners(anchor, anchor),
+                });
+                self.document.region_stats = None;
 
                 true
             }
-            Msg::None => false,
-            Msg::MouseOver { pos } => {
-                let prompt = window()
-                    .unwrap()
-                    .document()
-                    .unwrap()
-                    .query_selector("#prompt")
-                    .unwrap()
-                    .unwrap()
-                    .dyn_into::<HtmlElement>()
-                    .unwrap();
-                
-                let scaled_x = (pos.0 / self.scale).floor() as usize;
-                let scaled_y = (pos.1 / self.scale).floor() as usize;
-                log::info!("Mouse over: {}, {}", scaled_x, scaled_y);
-                let ppm = self.image.as_ref().unwrap();
-                // check if in bounds
-                if !(scaled_x < ppm.get_width() && scaled_y < ppm.get_height()) {
-                    prompt.set_attribute("style", &format!("display: none;"))
-                    .unwrap();
+            Msg::MouseUp | Msg::MouseLeave if self.divider_drag => {
+                self.divider_drag = false;
+
+                false
+            }
+            Msg::MouseOver { pos } if self.divider_drag => {
+                if let Some(comparison) = self.comparison.as_mut() {
+                    comparison.split = (pos.0 / get_viewport_size().0).clamp(0.0, 1.0);
                 }
 
-                let (r, g, b) = ppm.get_pixel_value(scaled_x, scaled_y);
-                let text = format!("r: {}, g: {}, b: {}", r, g, b);
-                prompt.set_inner_text(&text);
-                prompt.set_attribute("style", &format!("left: {}px; top: {}px; display: block-inline;", pos.0, pos.1))
-                    .unwrap();
+                ctx.link().send_message(Msg::Draw);
+
+                false
+            }
+            Msg::MouseUp | Msg::MouseLeave if self.selection_anchor.is_some() => {
+                self.selection_anchor = None;
+                self.finish_selection();
+                if msg == Msg::MouseLeave {
+                    self.hover = None;
+                }
+
+                ctx.link().send_message(Msg::Draw);
 
                 true
-            },
-            Msg::SaveAsJpeg => {
-                if self.image.is_none() {
+            }
+            Msg::MouseUp => {
+                if self.drag_pos.is_none() {
                     return false;
                 }
 
-                let image = self.image.as_ref().unwrap();
-                let mut vec = Vec::new();
-                image.write_to_jpeg(&mut vec, self.quality).expect("Unable to write to jpeg");
-
-                let a = window()
-                    .unwrap()
-                    .document()
-                    .unwrap()
-                    .create_element("a")
-                    .unwrap()
-                    .dyn_into::<HtmlElement>()
-                    .unwrap();
+                self.drag_pos = None;
 
-                let blob = Blob::new_with_u8_array_sequence(&Uint8Array::from(&vec[..])).unwrap();
-                a.set_attribute("href", &format!("data:image/jpeg;base64,{}", base64::encode(&vec[..])))
-                    .unwrap();
-                a.set_attribute("download", "image.jpeg").unwrap();
-                
-                a.click();
-                a.remove();
+                true
+            }
+            Msg::MouseLeave => {
+                self.drag_pos = None;
+                self.hover = None;
 
                 true
-            },
-            Msg::QualityChange { value } => {
-                self.quality = value;
+            }
+            Msg::MouseOver { pos } if self.selection_anchor.is_some() => {
+                let anchor = self.selection_anchor.unwrap();
+                let current = self.document.view.screen_to_image(self.view_pos(pos));
+                match &mut self.document.selection {
+                    Some(Selection::Freehand { points }) => points.push(current),
+                    Some(Selection::Ellipse { .. }) => {
+                        self.document.selection = Some(Selection::ellipse_from_corners(anchor, current))
+                    }
+                    _ => self.document.selection = Some(Selection::rectangle_from_corners(anchor, current)),
+                }
+                self.hover = self.sample_at(pos);
+
+                ctx.link().send_message(Msg::Draw);
 
                 true
-            },
-        }
-    }
+            }
+            Msg::MouseOver { pos } if self.drag_pos.is_some() => {
+                let last_pos = self.drag_pos.unwrap();
+                let delta = (pos.0 - last_pos.0, pos.1 - last_pos.1);
+                self.document.view.pan(delta);
+                self.drag_pos = Some(pos);
+                self.drag_distance += delta.0.abs() + delta.1.abs();
 
-    fn rendered(&mut self, _ctx: &Context<Self>, first_render: bool) {
-        if first_render {
-            return;
-        }
-    }
+                ctx.link().send_message(Msg::Draw);
+
+                false
+            }
+            Msg::MouseOver { pos } => {
+                self.hover = self.sample_at(pos);
+
+                true
+            }
+            Msg::Click { pos } => {
+                // A click that ends a drag only pans.
+                if self.tool != Tool::Pan || self.drag_distance > 3.0 {
+                    return false;
+                }
+
+                match self.sample_at(pos) {
+                    Some(sample) => {
+                        self.document.pinned.push(sample);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Msg::NeighbourhoodChange { value } => {
+                // Keep the window odd so it stays centred on the pixel.
+                self.neighbourhood = (value.clamp(1, MAX_NEIGHBOURHOOD) / 2) * 2 + 1;
+                let image = self.document.history.as_ref().map(|history| history.current());
+                self.hover = self.hover.as_ref().and_then(|sample| {
+                    image.map(|image| Sample::new(image, sample.x, sample.y, self.neighbourhood))
+                });
+
+                true
+            }
+            Msg::UnpinSample { index } => {
+                if index >= self.document.pinned.len() {
+                    return false;
+                }
+
+                self.document.pinned.remove(index);
+
+                true
+            }
+            Msg::ClearSamples => {
+                self.document.pinned.clear();
+
+                true
+            }
+            Msg::ExportSamples => {
+                if self.document.pinned.is_empty() {
+                    return false;
+                }
+
+                download(samples_to_csv(&self.document.pinned).as_bytes(), "text/csv", "samples.csv");
+
+                false
+            }
+            Msg::SaveAsJpeg => {
+                if self.document.history.is_none() {
+                    return false;
+                }
+
+                // Downloaded once encoded.
+                let (image, data) = self.with_export_image(image_to_bytes);
+                let options = self.jpeg_options.clone();
+                self.start_job(Job::EncodeJpeg { image, options }, &data, Pending::ExportJpeg);
+                ctx.link().send_message(Msg::CloseExportDialog);
+
+                true
+            },
+            Msg::SaveAsPpm => {
+                if self.document.history.is_none() {
+                    return false;
+                }
+
+                // Header comments of the opened file are written back.
+                let image = self.document.history.as_ref().unwrap().current();
+                let mut vec = Vec::new();
+                match self.document.selection.as_ref().and_then(|selection| crop(image, selection)) {
+                    Some(cropped) => cropped.write_to_ppm(&mut vec, true),
+                    None => image.write_to_ppm(&mut vec, true),
+                }
+
+                download(&vec, "image/x-portable-pixmap", "image.ppm");
+
+                false
+            },
+            Msg::ShowExportDialog => {
+                self.export_dialog = self.document.history.is_some();
+                self.schedule_preview(ctx);
+
+                true
+            },
+            Msg::CloseExportDialog => {
+                self.export_dialog = false;
+                self.preview = None;
+                self.preview_timeout = None;
+                self.jobs.retain(|job| !matches!(job.pending, Pending::Preview { .. }));
+
+                true
+            },
+            Msg::JpegOptionsChange { options } => {
+                self.jpeg_options = options;
+                self.schedule_preview(ctx);
+
+                true
+            },
+            Msg::UpdatePreview => {
+                self.preview_timeout = None;
+                if !self.export_dialog || self.document.history.is_none() {
+                    return false;
+                }
+
+                // Only the latest preview is waited for, the one shown stays
+                // until it's done.
+                self.jobs.retain(|job| !matches!(job.pending, Pending::Preview { .. }));
+                let (image, data) = self.with_export_image(image_to_bytes);
+                let raw_size = image.width * image.height * 3;
+                let options = self.jpeg_options.clone();
+                self.start_job(Job::PreviewJpeg { image, options }, &data, Pending::Preview { raw_size });
+
+                false
+            },
+            Msg::PreviewSplitChange { value } => {
+                self.preview_split = value.clamp(0.0, 100.0);
+
+                true
+            },
+            Msg::QuantTablesChange { text } => {
+                self.quant_error = None;
+                self.jpeg_options.luma_table = None;
+                self.jpeg_options.chroma_table = None;
+                if let Some(text) = &text {
+                    match parse_quantization_tables(text) {
+                        Ok((luma, chroma)) => {
+                            self.jpeg_options.luma_table = Some(luma);
+                            self.jpeg_options.chroma_table = chroma;
+                        }
+                        Err(err) => self.quant_error = Some(err.to_string()),
+                    }
+                }
+                self.quant_tables = text;
+                self.schedule_preview(ctx);
+
+                true
+            },
+            Msg::LoadCandidate { value } => {
+                let reference = match &self.document.history {
+                    Some(history) => history.current(),
+                    None => return false,
+                };
+                match load_from_buffer(&value) {
+                    Ok(candidate) => self.comparison = Some(Comparison::new(reference, candidate)),
+                    Err(err) => {
+                        log::error!("Couldn't open file: {}", err);
+                        self.document.load_error = Some(err.to_string());
+                        return true;
+                    }
+                }
+
+                ctx.link().send_message(Msg::Draw);
+
+                true
+            },
+            Msg::CloseComparison => {
+                self.comparison = None;
+                self.divider_drag = false;
+                ctx.link().send_message(Msg::Draw);
+
+                true
+            },
+            Msg::CompareModeChange { mode } => {
+                if let Some(comparison) = self.comparison.as_mut() {
+                    comparison.mode = mode;
+                }
+                ctx.link().send_message(Msg::Draw);
+
+                true
+            },
+            Msg::CompareOpacityChange { value } => {
+                if let Some(comparison) = self.comparison.as_mut() {
+                    comparison.opacity = value;
+                }
+                ctx.link().send_message(Msg::Draw);
+
+                true
+            },
+            Msg::CompareAmplificationChange { value } => {
+                if let Some(comparison) = self.comparison.as_mut() {
+                    comparison.set_amplification(value);
+                }
+                ctx.link().send_message(Msg::Draw);
+
+                true
+            },
+            Msg::LosslessTransform { transform } => {
+                let source = match &self.document.source {
+                    Some(source) => source,
+                    None => return false,
+                };
+
+                // The result is opened like a new file, which resets the history.
+                match transform_jpeg(source, &[transform]) {
+                    Ok(value) => ctx.link().send_message(Msg::LoadFile { value }),
+                    Err(err) => {
+                        log::error!("Couldn't transform jpeg: {}", err);
+                        self.document.load_error = Some(err.to_string());
+                        return true;
+                    }
+                }
+
+                false
+            },
+            Msg::SaveLosslessJpeg => {
+                if let Some(source) = &self.document.source {
+                    download(source, "image/jpeg", "image.jpeg");
+                }
+
+                false
+            },
+            Msg::ShowCodecLab => {
+                if self.document.history.is_none() {
+                    return false;
+                }
+
+                self.codec_lab = true;
+                ctx.link().send_message(Msg::CodecSettingsChange {
+                    quality: self.codec_quality,
+                    subsampling: self.codec_subsampling,
+                });
+
+                true
+            },
+            Msg::CloseCodecLab => {
+                self.codec_lab = false;
+                self.codec = None;
+
+                true
+            },
+            Msg::CodecSettingsChange { quality, subsampling } => {
+                if !self.codec_lab || self.document.history.is_none() {
+                    return false;
+                }
+
+                self.codec_quality = quality;
+                self.codec_subsampling = subsampling;
+                let lab = self.with_export_image(|image| CodecLab::new(image, quality, subsampling));
+                self.codec = Some(lab.map_err(|err| err.to_string()));
+                self.codec_block = 0;
+                self.codec_dirty = true;
+
+                true
+            },
+            Msg::CodecStageChange { stage, component } => {
+                if component != self.codec_component {
+                    self.codec_block = 0;
+                }
+                self.codec_stage = stage;
+                self.codec_component = component;
+                self.codec_dirty = true;
+
+                true
+            },
+            Msg::CodecBlockClick { pos } => {
+                let lab = match &self.codec {
+                    Some(Ok(lab)) => lab,
+                    _ => return false,
+                };
+
+                self.codec_block = lab.block_at(self.codec_stage, self.codec_component, pos);
+
+                true
+            },
+        }
+    }
+
+    fn rendered(&mut self, ctx: &Context<Self>, first_render: bool) {
+        if self.preview_dirty {
+            self.preview_dirty = false;
+            if let Some(Ok(preview)) = &self.preview {
+                self.with_export_image(|image| draw_to_canvas("preview-before", image));
+                draw_to_canvas("preview-after", &preview.decoded);
+            }
+        }
+
+        if self.palette_focus {
+            self.palette_focus = false;
+            if let Some(input) = window()
+                .and_then(|window| window.document())
+                .and_then(|document| document.get_element_by_id("palette-query"))
+                .and_then(|element| element.dyn_into::<HtmlElement>().ok())
+            {
+                input.focus().unwrap();
+            }
+        }
+
+        if self.codec_dirty {
+            self.codec_dirty = false;
+            if let Some(Ok(lab)) = &self.codec {
+                lab.with_stage_image(self.codec_stage, self.codec_component, |image| draw_to_canvas("codec-stage", image));
+            }
+        }
+
+        if !first_render {
+            return;
+        }
+
+        let link = ctx.link().clone();
+        self.resize_listener = Some(EventListener::new(&window().unwrap(), "resize", move |_| {
+            link.send_message(Msg::Draw);
+        }));
+
+        self.listen_for_keys(ctx);
+
+        let link = ctx.link().clone();
+        self.paste_listener = Some(EventListener::new(&window().unwrap(), "paste", move |event| {
+            if is_text_entry(event) {
+                return;
+            }
+
+            // ClipboardEvent is behind the unstable web-sys APIs.
+            let files = match Reflect::get(event, &JsValue::from_str("clipboardData"))
+                .ok()
+                .and_then(|data| data.dyn_into::<DataTransfer>().ok())
+                .and_then(|data| data.files())
+            {
+                Some(files) if files.length() > 0 => files,
+                _ => return,
+            };
+            event.prevent_default();
+            read_file_list(&files, link.callback(|(name, value): (String, Vec<u8>)| Msg::OpenFile { name, value }));
+        }));
+    }
+}
+
+impl App {
+    // The listener works on a copy of the bindings, it's replaced whenever
+    // they change or a key is being captured.
+    fn listen_for_keys(&mut self, ctx: &Context<Self>) {
+        let link = ctx.link().clone();
+        let keybindings = self.keybindings.clone();
+        let capturing = self.capturing.is_some();
+        self.keydown_listener = Some(EventListener::new(&window().unwrap(), "keydown", move |event| {
+            if is_text_entry(event) {
+                return;
+            }
+
+            let event = event.dyn_ref::<KeyboardEvent>().unwrap();
+            let combo = match key_combo(event) {
+                Some(combo) => combo,
+                None => return,
+            };
+
+            if capturing {
+                event.prevent_default();
+                link.send_message(Msg::KeyCaptured { combo });
+            } else if let Some(command) = keybindings.command(&combo) {
+                event.prevent_default();
+                link.send_message(Msg::RunCommand { command });
+            }
+        }));
+    }
+
+    fn command_msg(&self, command: Command) -> Msg {
+        let viewport = get_viewport_size();
+        let center = (viewport.0 / 2.0, viewport.1 / 2.0);
+        let document_count = self.documents.len();
+        let step = |delta: usize| match self.current_document {
+            Some(current) => Msg::SelectDocument { index: (current + delta) % document_count },
+            None => Msg::None,
+        };
+
+        match command {
+            Command::Palette => Msg::ShowPalette,
+            Command::Keybindings => Msg::ShowKeybindings,
+            Command::Open => {
+                if let Some(input) = window()
+                    .and_then(|window| window.document())
+                    .and_then(|document| document.get_element_by_id("open-files"))
+                    .and_then(|element| element.dyn_into::<HtmlElement>().ok())
+                {
+                    input.click();
+                }
+                Msg::None
+            }
+            Command::SaveAsPpm => Msg::SaveAsPpm,
+            Command::ExportJpeg => Msg::ShowExportDialog,
+            Command::ExportRecipe => Msg::ExportRecipe { toml: false },
+            Command::NextDocument => step(1),
+            Command::PreviousDocument => step(document_count.saturating_sub(1)),
+            Command::CloseDocument => match self.current_document {
+                Some(index) => Msg::CloseDocument { index },
+                None => Msg::None,
+            },
+            Command::DuplicateDocument => Msg::DuplicateDocument,
+            Command::Undo => Msg::Undo,
+            Command::Redo => Msg::Redo,
+            Command::ZoomIn => Msg::Zoom { pos: center, y_delta: -1.0 },
+            Command::ZoomOut => Msg::Zoom { pos: center, y_delta: 1.0 },
+            Command::FitToWindow => Msg::FitToWindow,
+            Command::ActualSize => Msg::ActualSize,
+            Command::FillWindow => Msg::FillWindow,
+            Command::PanLeft => Msg::Pan { delta: (PAN_STEP, 0.0) },
+            Command::PanRight => Msg::Pan { delta: (-PAN_STEP, 0.0) },
+            Command::PanUp => Msg::Pan { delta: (0.0, PAN_STEP) },
+            Command::PanDown => Msg::Pan { delta: (0.0, -PAN_STEP) },
+            Command::ToggleGrid => Msg::ToggleGrid,
+            Command::PanTool => Msg::SetTool { tool: Tool::Pan },
+            Command::RectangleTool => Msg::SetTool { tool: Tool::Rectangle },
+            Command::EllipseTool => Msg::SetTool { tool: Tool::Ellipse },
+            Command::FreehandTool => Msg::SetTool { tool: Tool::Freehand },
+            Command::ClearSelection => Msg::ClearSelection,
+            Command::CropToSelection => Msg::CropToSelection,
+            Command::Invert => Msg::Apply { operation: Operation::Invert },
+            Command::Grayscale => Msg::Apply { operation: Operation::Grayscale },
+            Command::Threshold => Msg::Apply { operation: Operation::Threshold { level: None } },
+            Command::FlipHorizontal => Msg::Apply { operation: Operation::FlipHorizontal },
+            Command::FlipVertical => Msg::Apply { operation: Operation::FlipVertical },
+            Command::RotateLeft => Msg::Apply { operation: Operation::Rotate270 },
+            Command::RotateRight => Msg::Apply { operation: Operation::Rotate90 },
+            Command::GaussianBlur => Msg::Apply { operation: Operation::GaussianBlur { sigma: self.blur_sigma } },
+            Command::BrightnessContrast => Msg::Apply {
+                operation: Operation::BrightnessContrast { brightness: self.brightness, contrast: self.contrast },
+            },
+            Command::CodecLab => Msg::ShowCodecLab,
+        }
+    }
+
+    // Commands whose label matches the palette query.
+    fn palette_commands(&self) -> Vec<Command> {
+        Command::ALL
+            .iter()
+            .copied()
+            .filter(|command| *command != Command::Palette && matches_query(command.label(), &self.palette_query))
+            .collect()
+    }
+
+    // Shows another document, or none. The state of the shown one goes back
+    // to its tab.
+    fn switch_document(&mut self, ctx: &Context<Self>, index: Option<usize>) {
+        if let Some(current) = self.current_document {
+            // Edits only show up in the thumbnail once the tab is left.
+            if let Some(history) = self.document.history.as_ref().filter(|history| !history.steps().is_empty()) {
+                self.documents[current].thumbnail = Some(thumbnail_url(history.current(), THUMBNAIL_SIZE));
+            }
+            self.document.swap(&mut self.documents[current]);
+        }
+
+        self.current_document = index;
+        if let Some(index) = index {
+            self.document.swap(&mut self.documents[index]);
+            // Stats of a region edited in the background are left until it's
+            // shown again.
+            if self.document.region_stats.is_none() {
+                self.finish_selection();
+            }
+        }
+
+        self.file_changed = true;
+        self.hover = None;
+        self.selection_anchor = None;
+        self.drag_pos = None;
+        if let (Some(comparison), Some(history)) = (self.comparison.as_mut(), &self.document.history) {
+            comparison.reference_changed(history.current());
+        }
+        ctx.link().send_message(Msg::Draw);
+    }
+
+    fn set_image(&mut self, image: Box<dyn Image>) {
+        self.document.set_image(image, get_viewport_size());
+        self.file_changed = true;
+        self.hover = None;
+        self.selection_anchor = None;
+        if let (Some(comparison), Some(history)) = (self.comparison.as_mut(), &self.document.history) {
+            comparison.reference_changed(history.current());
+        }
+    }
+
+    // Decodes the file in the background into the shown document.
+    fn load_file(&mut self, value: Vec<u8>) {
+        let current = match self.current_document {
+            Some(current) => current,
+            None => return,
+        };
+
+        self.document.markers = value.starts_with(&[0xFF, 0xD8]).then(|| read_markers(&value));
+        // Whatever was being done to the image it replaces is dropped.
+        let document = self.documents[current].id;
+        self.jobs.retain(|job| job.pending.document() != Some(document));
+        self.start_job(Job::Decode, &value, Pending::Load { document, value: value.clone() });
+    }
+
+    fn start_job(&mut self, job: Job, data: &[u8], pending: Pending) {
+        let id = self.background.start(&job, data);
+        self.jobs.push(RunningJob { id, pending, progress: None });
+    }
+
+    // Whether an edit of the shown document is still being computed.
+    fn applying(&self) -> bool {
+        let document = match self.current_document {
+            Some(current) => self.documents[current].id,
+            None => return false,
+        };
+
+        return self
+            .jobs
+            .iter()
+            .any(|job| matches!(job.pending, Pending::Edit { .. }) && job.pending.document() == Some(document));
+    }
+
+    // The state of a document, wherever it's kept at the moment.
+    fn document_mut(&mut self, index: usize) -> &mut Document {
+        if self.current_document == Some(index) {
+            return &mut self.document;
+        }
+
+        return &mut self.documents[index];
+    }
+
+    fn job_done(&mut self, ctx: &Context<Self>, pending: Pending, reply: Reply, data: Vec<u8>) {
+        match (pending, reply) {
+            (pending, Reply::Failed { error }) => self.job_failed(pending, error),
+            (Pending::Load { document, value }, Reply::Decoded { image, info }) => match image_from_bytes(&image, &data) {
+                Ok(image) => self.file_loaded(ctx, document, Box::new(image), *info, value),
+                Err(err) => self.job_failed(Pending::Load { document, value }, err.to_string()),
+            },
+            (Pending::Edit { document, change }, Reply::Applied { image }) => match image_from_bytes(&image, &data) {
+                Ok(image) => self.history_changed(ctx, document, change, image),
+                Err(err) => self.job_failed(Pending::Edit { document, change }, err.to_string()),
+            },
+            (Pending::ExportJpeg, Reply::Encoded) => download(&data, "image/jpeg", "image.jpeg"),
+            (Pending::Preview { raw_size }, Reply::Preview { image, size, psnr, ssim }) => {
+                let preview = image_from_bytes(&image, &data).map(|decoded| JpegPreview {
+                    size,
+                    raw_size,
+                    psnr,
+                    ssim,
+                    decoded,
+                });
+                self.preview = Some(preview.map_err(|err| err.to_string()));
+                self.preview_dirty = true;
+            }
+            (pending, _) => log::error!("Unexpected reply to a job: {}.", pending.label()),
+        }
+    }
+
+    fn job_failed(&mut self, pending: Pending, error: String) {
+        match pending {
+            Pending::Load { document, .. } => {
+                log::error!("Couldn't open file: {}", error);
+                if let Some(index) = self.documents.iter().position(|doc| doc.id == document) {
+                    let document = self.document_mut(index);
+                    document.metadata = None;
+                    document.load_error = Some(error);
+                    document.source = None;
+                }
+            }
+            pending @ Pending::Edit { .. } => log::error!("{} failed: {}", pending.label(), error),
+            Pending::ExportJpeg => log::error!("Couldn't encode jpeg: {}", error),
+            Pending::Preview { .. } => self.preview = Some(Err(error)),
+        }
+    }
+
+    fn file_loaded(&mut self, ctx: &Context<Self>, document: usize, image: Box<dyn Image>, info: ImageInfo, value: Vec<u8>) {
+        let index = match self.documents.iter().position(|doc| doc.id == document) {
+            Some(index) => index,
+            None => return,
+        };
+
+        self.documents[index].thumbnail = Some(thumbnail_url(image.as_ref(), THUMBNAIL_SIZE));
+        if self.current_document == Some(index) {
+            self.set_image(image);
+            ctx.link().send_message(Msg::Draw);
+        } else {
+            self.documents[index].set_image(image, get_viewport_size());
+        }
+
+        let document = self.document_mut(index);
+        document.metadata = Some(info);
+        document.load_error = None;
+        document.source = value.starts_with(&[0xFF, 0xD8]).then_some(value);
+    }
+
+    // Changes the history of the shown document. What has to be replayed for
+    // it runs in the background first.
+    fn edit(&mut self, ctx: &Context<Self>, change: Change) -> bool {
+        let current = match self.current_document {
+            Some(current) if self.document.history.is_some() => current,
+            _ => return false,
+        };
+        // Each edit starts from the result of the one before.
+        if self.applying() {
+            return false;
+        }
+
+        let history = self.document.history.as_ref().unwrap();
+        if let Some((image, steps)) = history.replay_for(&change) {
+            let (image, data) = image_to_bytes(image);
+            let document = self.documents[current].id;
+            self.start_job(Job::Apply { image, steps }, &data, Pending::Edit { document, change });
+            return true;
+        }
+
+        // Nothing to compute, the result is cached.
+        let size = self.image_size();
+        if !self.document.history.as_mut().unwrap().change(change, None) {
+            return false;
+        }
+        self.image_edited(size);
+        ctx.link().send_message(Msg::Draw);
+
+        return true;
+    }
+
+    fn history_changed(&mut self, ctx: &Context<Self>, document: usize, change: Change, image: Bitmap) {
+        let index = match self.documents.iter().position(|doc| doc.id == document) {
+            Some(index) => index,
+            None => return,
+        };
+
+        if self.current_document == Some(index) {
+            let size = self.image_size();
+            if let Some(history) = self.document.history.as_mut() {
+                history.change(change, Some(image));
+                self.image_edited(size);
+                ctx.link().send_message(Msg::Draw);
+            }
+            return;
+        }
+
+        // Switched away in the meantime, the rest is refreshed when it's
+        // shown again.
+        let document = &mut self.documents[index];
+        let history = match document.history.as_mut() {
+            Some(history) => history,
+            None => return,
+        };
+        let size = (history.current().get_width(), history.current().get_height());
+        history.change(change, Some(image));
+        let new_size = (history.current().get_width(), history.current().get_height());
+        document.thumbnail = Some(thumbnail_url(history.current(), THUMBNAIL_SIZE));
+        if new_size != size {
+            document.view.reset(new_size, get_viewport_size());
+            document.selection = None;
+        }
+        document.region_stats = None;
+    }
+
+    // Runs f on what gets exported: the selected region when there is one,
+    // the whole image otherwise.
+    fn with_export_image<T>(&self, f: impl FnOnce(&dyn Image) -> T) -> T {
+        let image = self.document.history.as_ref().expect("No image loaded.").current();
+        match self.document.selection.as_ref().and_then(|selection| crop(image, selection)) {
+            Some(cropped) => f(&cropped),
+            None => f(image),
+        }
+    }
+
+    // Encodes the preview again once the options stop changing.
+    fn schedule_preview(&mut self, ctx: &Context<Self>) {
+        if !self.export_dialog {
+            return;
+        }
+
+        let link = ctx.link().clone();
+        self.preview_timeout = Some(Timeout::new(PREVIEW_DELAY_MS, move || {
+            link.send_message(Msg::UpdatePreview);
+        }));
+    }
+
+    fn image_size(&self) -> Option<(usize, usize)> {
+        let image = self.document.history.as_ref()?.current();
+
+        return Some((image.get_width(), image.get_height()));
+    }
+
+    // Refreshes everything derived from the pixels after the history moved.
+    fn image_edited(&mut self, previous_size: Option<(usize, usize)>) {
+        self.file_changed = true;
+        self.hover = None;
+        if let (Some(comparison), Some(history)) = (self.comparison.as_mut(), &self.document.history) {
+            comparison.reference_changed(history.current());
+        }
+
+        let size = self.image_size();
+        if size != previous_size {
+            if let Some(size) = size {
+                self.document.view.reset(size, get_viewport_size());
+            }
+            self.document.selection = None;
+            self.document.region_stats = None;
+        }
+
+        self.finish_selection();
+    }
+
+    fn finish_selection(&mut self) {
+        let image = match &self.document.history {
+            Some(history) => history.current(),
+            None => return,
+        };
+
+        let selection = match &self.document.selection {
+            Some(selection) if !selection.is_empty(image.get_width(), image.get_height()) => selection,
+            _ => {
+                self.document.selection = None;
+                self.document.region_stats = None;
+                return;
+            }
+        };
+
+        self.document.region_stats = Some(region_stats(image, Some(selection)));
+    }
+
+    // Maps canvas positions into the left half while comparing side by side.
+    fn view_pos(&self, pos: (f64, f64)) -> (f64, f64) {
+        match &self.comparison {
+            Some(comparison) => comparison.view_pos(pos, get_viewport_size()),
+            None => pos,
+        }
+    }
+
+    fn sample_at(&self, pos: (f64, f64)) -> Option<Sample> {
+        let image = self.document.history.as_ref()?.current();
+        let (x, y) = self.document.view.pixel_at(self.view_pos(pos), (image.get_width(), image.get_height()))?;
+
+        return Some(Sample::new(image, x, y, self.neighbourhood));
+    }
+
+    // The first job in the queue, previews show up in the export dialog.
+    fn view_progress(&self, ctx: &Context<Self>) -> Html {
+        let mut jobs = self.jobs.iter().filter(|job| !matches!(job.pending, Pending::Preview { .. }));
+        let job = match jobs.next() {
+            Some(job) => job,
+            None => return html! {},
+        };
+        let queued = jobs.count();
+
+        html! {
+            <div>
+                <label>{format!("{}... ", job.pending.label())}</label>
+                if let Some(progress) = job.progress {
+                    <progress max="1" value={progress.to_string()} />
+                } else {
+                    // Decoding jpegs and most single steps don't report how
+                    // far they got.
+                    <progress />
+                    {" no estimate "}
+                }
+                if queued > 0 {
+                    {format!(" {} more queued ", queued)}
+                }
+                <input type="button" value="Cancel" onclick={ctx.link().callback(|_| Msg::CancelJobs)} />
+            </div>
+        }
+    }
+
+    fn view_edit_toolbar(&self, ctx: &Context<Self>) -> Html {
+        let applying = self.applying();
+        let button = |label: &str, operation: Operation| {
+            html! {
+                <input type="button" value={label.to_string()} disabled={self.document.history.is_none() || applying}
+                    onclick={ctx.link().callback(move |_| Msg::Apply { operation: operation.clone() })} />
+            }
+        };
+        let number = |event: Event| {
+            event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap().value_as_number()
+        };
+
+        html! {
+            <div>
+                { button("Invert", Operation::Invert) }
+                { button("Grayscale", Operation::Grayscale) }
+                { button("Otsu threshold", Operation::Threshold { level: None }) }
+                { button("Flip H", Operation::FlipHorizontal) }
+                { button("Flip V", Operation::FlipVertical) }
+                { button("Rotate left", Operation::Rotate270) }
+                { button("Rotate right", Operation::Rotate90) }
+                <label>{" σ: "}</label>
+                <input type="number" min="0.1" max="50" step="0.1" style="width: 4em;" value={self.blur_sigma.to_string()}
+                    onchange={ctx.link().callback(move |event: Event| Msg::BlurSigmaChange { value: number(event) })} />
+                { button("Gaussian blur", Operation::GaussianBlur { sigma: self.blur_sigma }) }
+                <label>{" Brightness: "}</label>
+                <input type="range" min="-1" max="1" step="0.01" value={self.brightness.to_string()}
+                    onchange={ctx.link().callback(move |event: Event| Msg::BrightnessChange { value: number(event) })} />
+                <label>{" Contrast: "}</label>
+                <input type="range" min="0" max="3" step="0.01" value={self.contrast.to_string()}
+                    onchange={ctx.link().callback(move |event: Event| Msg::ContrastChange { value: number(event) })} />
+                { button("Adjust", Operation::BrightnessContrast { brightness: self.brightness, contrast: self.contrast }) }
+                { self.view_lossless_toolbar(ctx) }
+            </div>
+        }
+    }
+
+    // Display only, exported and saved images are left as they are unless
+    // saved as shown.
+    fn view_display_toolbar(&self, ctx: &Context<Self>) -> Html {
+        if self.document.history.is_none() {
+            return html! {};
+        }
+
+        let display = self.display;
+        let slider = |label: &str, (min, max, step): (f64, f64, f64), value: f64, apply: fn(&mut DisplaySettings, f64)| {
+            html! {
+                <>
+                    <label>{format!(" {}: ", label)}</label>
+                    <input type="range" min={min.to_string()} max={max.to_string()} step={step.to_string()} value={value.to_string()}
+                        oninput={ctx.link().callback(move |event: InputEvent| {
+                            let input = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap();
+                            let mut settings = display;
+                            apply(&mut settings, input.value_as_number());
+                            Msg::DisplayChange { settings }
+                        })} />
+                    {format!("{:.2}", value)}
+                </>
+            }
+        };
+
+        html! {
+            <div>
+                <label title="Changes how the image is shown, not its pixels.">{"Display:"}</label>
+                { slider("Exposure", (-5.0, 5.0, 0.1), display.exposure, |settings, val| settings.exposure = val) }
+                { slider("Gamma", (0.2, 5.0, 0.05), display.gamma, |settings, val| settings.gamma = val) }
+                { slider("Black", (0.0, 1.0, 0.005), display.black_point, |settings, val| settings.black_point = val.min(settings.white_point - 0.005)) }
+                { slider("White", (0.0, 1.0, 0.005), display.white_point, |settings, val| settings.white_point = val.max(settings.black_point + 0.005)) }
+                <label>{" Tone map: "}</label>
+                { for ToneMap::ALL.iter().map(|&tone_map| html! {
+                    <label>
+                        <input type="radio" name="tone-map" checked={display.tone_map == tone_map}
+                            onchange={ctx.link().callback(move |_| Msg::DisplayChange { settings: DisplaySettings { tone_map, ..display } })} />
+                        {tone_map.to_string()}
+                    </label>
+                }) }
+                <input type="button" value="Reset" disabled={display.is_identity()}
+                    onclick={ctx.link().callback(|_| Msg::DisplayChange { settings: DisplaySettings::default() })} />
+                <input type="button" value="Save as shown" title="The image with these settings, in 8 bits."
+                    onclick={ctx.link().callback(|_| Msg::SaveAsShown)} />
+            </div>
+        }
+    }
+
+    // Only offered for JPEG files without edits, the transforms work on the
+    // file as it was opened.
+    fn view_lossless_toolbar(&self, ctx: &Context<Self>) -> Html {
+        if self.document.source.is_none() {
+            return html! {};
+        }
+
+        let edited = self.document.history.as_ref().is_some_and(|history| !history.steps().is_empty());
+        let button = |transform: Transform| {
+            html! {
+                <input type="button" value={transform.to_string()} disabled={edited}
+                    onclick={ctx.link().callback(move |_| Msg::LosslessTransform { transform })} />
+            }
+        };
+        let crop = self
+            .document
+            .selection
+            .as_ref()
+            .zip(self.image_size())
+            .and_then(|(selection, (width, height))| selection.bounds(width, height))
+            .map(|(x, y, width, height)| Transform::Crop { x, y, width, height });
+
+        html! {
+            <div>
+                <label title="Works on the DCT blocks of the file, without re-encoding. Undo all edits first.">{"Lossless jpeg: "}</label>
+                { button(Transform::Rotate270) }
+                { button(Transform::Rotate90) }
+                { button(Transform::Rotate180) }
+                { button(Transform::FlipHorizontal) }
+                { button(Transform::FlipVertical) }
+                <input type="button" value="Crop to selection (MCU aligned)" disabled={edited || crop.is_none()}
+                    onclick={ctx.link().callback(move |_| match crop {
+                        Some(transform) => Msg::LosslessTransform { transform },
+                        None => Msg::None,
+                    })} />
+                <input type="button" value="Save lossless jpeg" onclick={ctx.link().callback(|_| Msg::SaveLosslessJpeg)} />
+            </div>
+        }
+    }
+
+    fn view_comparison_toolbar(&self, ctx: &Context<Self>) -> Html {
+        let comparison = match &self.comparison {
+            Some(comparison) => comparison,
+            None => return html! {},
+        };
+
+        html! {
+            <div>
+                <label>{"Compare: "}</label>
+                { for CompareMode::ALL.iter().map(|&mode| html! {
+                    <label>
+                        <input type="radio" name="compare-mode" checked={comparison.mode == mode}
+                            onchange={ctx.link().callback(move |_| Msg::CompareModeChange { mode })} />
+                        {mode.label()}
+                    </label>
+                }) }
+                if comparison.mode == CompareMode::Onion {
+                    <label>{" Opacity: "}</label>
+                    <input type="range" min="0" max="1" step="0.01" value={comparison.opacity.to_string()}
+                        oninput={ctx.link().callback(|event: InputEvent| {
+                            let input = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap();
+                            Msg::CompareOpacityChange { value: input.value_as_number() }
+                        })} />
+                }
+                if comparison.mode == CompareMode::Difference {
+                    <label>{" Amplification: "}</label>
+                    <input type="range" min="1" max="64" step="1" value={comparison.amplification.to_string()}
+                        onchange={ctx.link().callback(|event: Event| {
+                            let input = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap();
+                            Msg::CompareAmplificationChange { value: input.value_as_number() }
+                        })} />
+                    <span>{format!("{}x", comparison.amplification)}</span>
+                }
+                if let Some(err) = comparison.difference_error() {
+                    <span style="color: red;">{format!(" {}", err)}</span>
+                }
+                <input type="button" value="Close comparison" onclick={ctx.link().callback(|_| Msg::CloseComparison)} />
+                { view_metrics(&comparison.metrics) }
+            </div>
+        }
+    }
+
+    fn shortcut_hint(&self, command: Command) -> String {
+        return self.keybindings.keys(command).join(", ");
+    }
+
+    fn view_palette(&self, ctx: &Context<Self>) -> Html {
+        if !self.palette {
+            return html! {};
+        }
+
+        let commands = self.palette_commands();
+        let selected = self.palette_index;
+        html! {
+            <div style="position: fixed; inset: 0; background: rgba(0, 0, 0, 0.4); z-index: 10;"
+                onclick={ctx.link().callback(|_| Msg::ClosePalette)}>
+                <div style="background: white; margin: 10vh auto; padding: 8px; width: 420px; font-family: monospace;"
+                    onclick={Callback::from(|event: MouseEvent| event.stop_propagation())}>
+                    <input id="palette-query" type="text" placeholder="Type a command" style="width: 100%; box-sizing: border-box;"
+                        value={self.palette_query.clone()}
+                        oninput={ctx.link().callback(|event: InputEvent| {
+                            let input = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap();
+                            Msg::PaletteQueryChange { query: input.value() }
+                        })}
+                        onkeydown={ctx.link().batch_callback(move |event: KeyboardEvent| {
+                            let msg = match event.key().as_str() {
+                                "ArrowDown" => Msg::PaletteMove { delta: 1 },
+                                "ArrowUp" => Msg::PaletteMove { delta: -1 },
+                                "Enter" => Msg::PaletteRun { index: selected },
+                                "Escape" => Msg::ClosePalette,
+                                _ => return None,
+                            };
+                            event.prevent_default();
+
+                            Some(msg)
+                        })} />
+                    <div style="max-height: 60vh; overflow-y: auto;">
+                        { for commands.iter().enumerate().map(|(index, command)| html! {
+                            <div style={format!("display: flex; justify-content: space-between; padding: 2px 4px; cursor: pointer;{}",
+                                    if index == selected { " background: #cde;" } else { "" })}
+                                onclick={ctx.link().callback(move |_| Msg::PaletteRun { index })}>
+                                <span>{command.label()}</span>
+                                <span style="color: gray;">{self.shortcut_hint(*command)}</span>
+                            </div>
+                        }) }
+                        if commands.is_empty() {
+                            <p>{"No matching commands."}</p>
+                        }
+                    </div>
+                    <input type="button" value="Keyboard shortcuts..." onclick={ctx.link().callback(|_| Msg::ShowKeybindings)} />
+                </div>
+            </div>
+        }
+    }
+
+    fn view_keybindings(&self, ctx: &Context<Self>) -> Html {
+        if !self.keybindings_dialog {
+            return html! {};
+        }
+
+        html! {
+            <div style="position: fixed; inset: 0; background: rgba(0, 0, 0, 0.4); z-index: 10;">
+                <div style="background: white; margin: 5vh auto; padding: 16px; width: 480px; max-height: 85vh; overflow-y: auto; font-family: monospace;">
+                    <h3>{"Keyboard shortcuts"}</h3>
+                    <table>
+                        { for Command::ALL.iter().map(|&command| html! {
+                            <tr>
+                                <td>{command.label()}</td>
+                                <td>
+                                    if self.capturing == Some(command) {
+                                        <em>{"Press a key, Escape cancels"}</em>
+                                    } else {
+                                        {self.shortcut_hint(command)}
+                                    }
+                                </td>
+                                <td>
+                                    <input type="button" value="Add" onclick={ctx.link().callback(move |_| Msg::CaptureKey { command })} />
+                                    <input type="button" value="Clear" disabled={self.keybindings.keys(command).is_empty()}
+                                        onclick={ctx.link().callback(move |_| Msg::ClearKeys { command })} />
+                                </td>
+                            </tr>
+                        }) }
+                    </table>
+                    <input type="button" value="Reset to defaults" onclick={ctx.link().callback(|_| Msg::ResetKeybindings)} />
+                    <input type="button" value="Close" onclick={ctx.link().callback(|_| Msg::CloseKeybindings)} />
+                </div>
+            </div>
+        }
+    }
+
+    fn view_tabs(&self, ctx: &Context<Self>) -> Html {
+        if self.documents.is_empty() {
+            return html! {};
+        }
+
+        html! {
+            <div style="display: flex; gap: 4px; overflow-x: auto; padding: 4px 0; align-items: flex-end;">
+                { for self.documents.iter().enumerate().map(|(index, document)| {
+                    let border = if self.current_document == Some(index) { "dodgerblue" } else { "transparent" };
+                    html! {
+                        <div title={document.name.clone()} onclick={ctx.link().callback(move |_| Msg::SelectDocument { index })}
+                            style={format!("position: relative; cursor: pointer; border: 2px solid {}; width: {}px; text-align: center; font-size: small;", border, THUMBNAIL_SIZE)}>
+                            <span title="Close" style="position: absolute; top: 0; right: 2px;"
+                                onclick={ctx.link().callback(move |event: MouseEvent| {
+                                    event.stop_propagation();
+
+                                    Msg::CloseDocument { index }
+                                })}>{"×"}</span>
+                            if let Some(thumbnail) = &document.thumbnail {
+                                <img src={thumbnail.clone()} style="display: block; margin: auto;" />
+                            } else {
+                                <div style={format!("height: {}px;", THUMBNAIL_SIZE)}>{"?"}</div>
+                            }
+                            <div style="overflow: hidden; text-overflow: ellipsis; white-space: nowrap;">{&document.name}</div>
+                        </div>
+                    }
+                }) }
+                <input type="button" value="Duplicate" disabled={self.document.history.is_none()}
+                    onclick={ctx.link().callback(|_| Msg::DuplicateDocument)} />
+            </div>
+        }
+    }
+
+    fn view_history(&self, ctx: &Context<Self>) -> Html {
+        let history = match &self.document.history {
+            Some(history) => history,
+            None => return html! {},
+        };
+        let recipe_cb = ctx
+            .link()
+            .callback(|value: Vec<u8>| Msg::ImportRecipe { value });
+
+        html! {
+            <div style="padding: 0 8px;">
+                <h4>{"History"}</h4>
+                <input type="button" value="Undo" disabled={!history.can_undo()} onclick={ctx.link().callback(|_| Msg::Undo)} />
+                <input type="button" value="Redo" disabled={!history.can_redo()} onclick={ctx.link().callback(|_| Msg::Redo)} />
+                <div>
+                    {"Recipe: "}
+                    <input type="button" value="Export JSON" onclick={ctx.link().callback(|_| Msg::ExportRecipe { toml: false })} />
+                    <input type="button" value="Export TOML" onclick={ctx.link().callback(|_| Msg::ExportRecipe { toml: true })} />
+                    <input type="file" accept=".json,.toml" onchange={ctx.link().callback(move |event: Event| {
+                        read_file(event, recipe_cb.clone());
+
+                        Msg::None
+                    })} />
+                </div>
+                <ol>
+                    { for history.steps().iter().enumerate().map(|(index, step)| {
+                        // Undone steps stay listed until something new is applied.
+                        let style = if index < history.cursor() { "" } else { "opacity: 0.5;" };
+                        let label = match &step.selection {
+                            Some(selection) => format!("{} in {}", step.operation, selection),
+                            None => step.operation.to_string(),
+                        };
+
+                        html! {
+                            <li style={style}>
+                                <label>
+                                    <input type="checkbox" checked={step.enabled}
+                                        onchange={ctx.link().callback(move |_| Msg::ToggleStep { index })} />
+                                    {label}
+                                </label>
+                            </li>
+                        }
+                    }) }
+                </ol>
+            </div>
+        }
+    }
+
+    fn view_export_dialog(&self, ctx: &Context<Self>) -> Html {
+        if !self.export_dialog {
+            return html! {};
+        }
+
+        let options = &self.jpeg_options;
+        let default_tables = [STANDARD_LUMA_TABLE, STANDARD_CHROMA_TABLE]
+            .iter()
+            .map(|table| {
+                table
+                    .chunks(8)
+                    .map(|row| row.iter().map(|val| format!("{:3}", val)).collect::<Vec<_>>().join(" "))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        html! {
+            <div style="position: fixed; inset: 0; background: rgba(0, 0, 0, 0.4); z-index: 10;">
+                <div style="background: white; margin: 5vh auto; padding: 16px; width: 480px; max-height: 85vh; overflow-y: auto; font-family: monospace;">
+                    <h3>{"Export jpeg"}</h3>
+                    <p>
+                        <label>{"Quality: "}</label>
+                        <input type="range" min="1" max="100" step="1" value={options.quality.to_string()}
+                            onchange={options_callback(ctx, options, |options, input| options.quality = input.value_as_number() as u8)} />
+                        <span>{options.quality}</span>
+                    </p>
+                    <p>
+                        {"Chroma subsampling: "}
+                        { for [Subsampling::S444, Subsampling::S422, Subsampling::S420].into_iter().map(|subsampling| html! {
+                            <label>
+                                <input type="radio" name="subsampling" checked={options.subsampling == subsampling}
+                                    onchange={options_callback(ctx, options, move |options, _| options.subsampling = subsampling)} />
+                                {subsampling.to_string()}
+                            </label>
+                        }) }
+                    </p>
+                    <p>
+                        <label>
+                            <input type="radio" name="mode" checked={!options.progressive}
+                                onchange={options_callback(ctx, options, |options, _| options.progressive = false)} />
+                            {"Baseline"}
+                        </label>
+                        <label>
+                            <input type="radio" name="mode" checked={options.progressive}
+                                onchange={options_callback(ctx, options, |options, _| options.progressive = true)} />
+                            {"Progressive"}
+                        </label>
+                    </p>
+                    <p>
+                        <label>
+                            <input type="checkbox" checked={options.optimize_huffman}
+                                onchange={options_callback(ctx, options, |options, input| options.optimize_huffman = input.checked())} />
+                            {"Optimized Huffman tables"}
+                        </label>
+                    </p>
+                    <p>
+                        <label>{"Restart interval (MCUs, 0 = off): "}</label>
+                        <input type="number" min="0" max="65535" value={options.restart_interval.to_string()}
+                            onchange={options_callback(ctx, options, |options, input| options.restart_interval = input.value_as_number().clamp(0.0, u16::MAX as f64) as u16)} />
+                    </p>
+                    <p>
+                        <label>
+                            <input type="checkbox" checked={self.quant_tables.is_some()}
+                                onchange={ctx.link().callback(move |event: Event| {
+                                    let checked = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap().checked();
+
+                                    Msg::QuantTablesChange { text: checked.then(|| default_tables.clone()) }
+                                })} />
+                            {"Custom quantization tables"}
+                        </label>
+                    </p>
+                    if let Some(text) = &self.quant_tables {
+                        <p>{"64 luma values, optionally followed by 64 chroma values, row by row. Quality doesn't scale custom tables."}</p>
+                        <textarea rows="18" cols="40" value={text.clone()}
+                            onchange={ctx.link().callback(|event: Event| {
+                                let text = event.target().unwrap().dyn_into::<HtmlTextAreaElement>().unwrap().value();
+
+                                Msg::QuantTablesChange { text: Some(text) }
+                            })} />
+                        if let Some(err) = &self.quant_error {
+                            <p style="color: red;">{err}</p>
+                        }
+                    }
+                    <p>
+                        <label>
+                            <input type="checkbox" checked={options.keep_metadata}
+                                onchange={options_callback(ctx, options, |options, input| options.keep_metadata = input.checked())} />
+                            {"Keep metadata"}
+                        </label>
+                    </p>
+                    { self.view_preview(ctx) }
+                    <input type="button" value="Save" disabled={self.quant_error.is_some()} onclick={ctx.link().callback(|_| Msg::SaveAsJpeg)} />
+                    <input type="button" value="Cancel" onclick={ctx.link().callback(|_| Msg::CloseExportDialog)} />
+                </div>
+            </div>
+        }
+    }
+
+    fn view_preview(&self, ctx: &Context<Self>) -> Html {
+        let preview = match &self.preview {
+            Some(Ok(preview)) => preview,
+            Some(Err(err)) => return html! { <p style="color: red;">{err}</p> },
+            None => return html! { <p>{"Encoding preview..."}</p> },
+        };
+
+        html! {
+            <div>
+                <h4>{"Preview"}</h4>
+                <p>
+                    {format!("{} bytes, {:.1}:1, {:.2} bits per pixel", preview.size, preview.ratio(), preview.bits_per_pixel())}
+                    <br />
+                    {format!("PSNR {:.2} dB, SSIM {:.4}", preview.psnr, preview.ssim)}
+                    if self.preview_timeout.is_some() {
+                        <br />
+                        {"Updating..."}
+                    }
+                </p>
+                <div style="position: relative;">
+                    <canvas id="preview-before" style="display: block; width: 100%; image-rendering: pixelated;" />
+                    <canvas id="preview-after" style={format!(
+                        "position: absolute; top: 0; left: 0; width: 100%; image-rendering: pixelated; clip-path: inset(0 0 0 {}%);",
+                        self.preview_split
+                    )} />
+                    <div style={format!(
+                        "position: absolute; top: 0; bottom: 0; left: {}%; border-left: 1px solid red;",
+                        self.preview_split
+                    )} />
+                </div>
+                <input type="range" min="0" max="100" step="0.5" style="width: 100%;" value={self.preview_split.to_string()}
+                    oninput={ctx.link().callback(|event: InputEvent| {
+                        let value = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap().value_as_number();
+
+                        Msg::PreviewSplitChange { value }
+                    })} />
+                <p>{"Original on the left, compressed on the right."}</p>
+            </div>
+        }
+    }
+
+    fn view_codec_lab(&self, ctx: &Context<Self>) -> Html {
+        if !self.codec_lab {
+            return html! {};
+        }
+
+        let (quality, subsampling) = (self.codec_quality, self.codec_subsampling);
+
+        html! {
+            <div style="position: fixed; inset: 0; background: rgba(0, 0, 0, 0.4); z-index: 10;">
+                <div style="background: white; margin: 5vh auto; padding: 16px; width: 720px; max-height: 85vh; overflow-y: auto; font-family: monospace;">
+                    <h3>{"Codec lab"}</h3>
+                    <p>{"A baseline jpeg codec written out step by step: colour conversion, chroma subsampling, DCT, quantization, zig-zag, run-length and Huffman coding."}</p>
+                    <p>
+                        <label>{"Quality: "}</label>
+                        <input type="range" min="1" max="100" step="1" value={quality.to_string()}
+                            onchange={ctx.link().callback(move |event: Event| {
+                                let quality = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap().value_as_number() as u8;
+
+                                Msg::CodecSettingsChange { quality, subsampling }
+                            })} />
+                        <span>{quality}</span>
+                    </p>
+                    <p>
+                        {"Chroma subsampling: "}
+                        { for [Subsampling::S444, Subsampling::S422, Subsampling::S420].into_iter().map(|option| html! {
+                            <label>
+                                <input type="radio" name="codec-subsampling" checked={subsampling == option}
+                                    onchange={ctx.link().callback(move |_| Msg::CodecSettingsChange { quality, subsampling: option })} />
+                                {option.to_string()}
+                            </label>
+                        }) }
+                    </p>
+                    { self.view_codec_result(ctx) }
+                    <input type="button" value="Close" onclick={ctx.link().callback(|_| Msg::CloseCodecLab)} />
+                </div>
+            </div>
+        }
+    }
+
+    fn view_codec_result(&self, ctx: &Context<Self>) -> Html {
+        let lab = match &self.codec {
+            Some(Ok(lab)) => lab,
+            Some(Err(err)) => return html! { <p style="color: red;">{err}</p> },
+            None => return html! { <p>{"Encoding..."}</p> },
+        };
+
+        let (stage, component) = (self.codec_stage, self.codec_component);
+        let block = self.codec_block;
+        let selected = &lab.encoded.components[component];
+        let coefficients = |values: Vec<String>| html! {
+            <table style="text-align: right;">
+                { for values.chunks(8).map(|row| html! {
+                    <tr>{ for row.iter().map(|val| html! { <td>{val}</td> }) }</tr>
+                }) }
+            </table>
+        };
+
+        html! {
+            <div>
+                <table>
+                    <tr><th></th><th>{"size"}</th><th>{"PSNR"}</th><th>{"SSIM"}</th></tr>
+                    <tr>
+                        <td>{"this codec"}</td>
+                        <td>{format!("{} bytes", lab.encoded.bytes.len())}</td>
+                        <td>{format!("{:.2} dB", lab.psnr)}</td>
+                        <td>{format!("{:.4}", lab.ssim)}</td>
+                    </tr>
+                    <tr>
+                        <td>{"image crate"}</td>
+                        <td>{format!("{} bytes", lab.reference_size)}</td>
+                        <td>{format!("{:.2} dB", lab.reference_psnr)}</td>
+                        <td></td>
+                    </tr>
+                </table>
+                <table>
+                    <tr><th>{"component"}</th><th>{"size"}</th><th>{"blocks"}</th><th>{"non-zero"}</th><th>{"bytes"}</th></tr>
+                    { for lab.encoded.components.iter().map(|component| {
+                        let non_zero: usize = component.quantized.iter().map(|block| block.iter().filter(|val| **val != 0).count()).sum();
+                        let bits: u32 = component.bits.iter().sum();
+
+                        html! {
+                            <tr>
+                                <td>{component.name}</td>
+                                <td>{format!("{}x{}", component.plane.width, component.plane.height)}</td>
+                                <td>{component.dct.len()}</td>
+                                <td>{non_zero}</td>
+                                <td>{bits / 8}</td>
+                            </tr>
+                        }
+                    }) }
+                </table>
+                <p>
+                    { for Stage::ALL.into_iter().map(|option| html! {
+                        <label>
+                            <input type="radio" name="codec-stage" checked={stage == option}
+                                onchange={ctx.link().callback(move |_| Msg::CodecStageChange { stage: option, component })} />
+                            {option.label()}
+                        </label>
+                    }) }
+                </p>
+                if stage.per_component() {
+                    <p>
+                        { for lab.encoded.components.iter().enumerate().map(|(index, option)| html! {
+                            <label>
+                                <input type="radio" name="codec-component" checked={component == index}
+                                    onchange={ctx.link().callback(move |_| Msg::CodecStageChange { stage, component: index })} />
+                                {option.name}
+                            </label>
+                        }) }
+                    </p>
+                }
+                <canvas id="codec-stage" style="display: block; width: 100%; image-rendering: pixelated; cursor: crosshair;"
+                    onclick={ctx.link().callback(|event: MouseEvent| {
+                        let canvas = event.target().unwrap().dyn_into::<HtmlElement>().unwrap();
+                        let pos = (
+                            event.offset_x() as f64 / canvas.client_width().max(1) as f64,
+                            event.offset_y() as f64 / canvas.client_height().max(1) as f64,
+                        );
+
+                        Msg::CodecBlockClick { pos }
+                    })} />
+                <p>{"Click the image to inspect a block."}</p>
+                <h4>{format!(
+                    "{} block {},{}: {} bits",
+                    selected.name,
+                    block % selected.blocks_wide,
+                    block / selected.blocks_wide,
+                    selected.bits[block]
+                )}</h4>
+                <div style="display: flex; gap: 16px;">
+                    <div>
+                        <p>{"DCT"}</p>
+                        { coefficients(selected.dct[block].iter().map(|val| format!("{:.0}", val)).collect()) }
+                    </div>
+                    <div>
+                        <p>{"Table"}</p>
+                        { coefficients(selected.table.iter().map(|val| val.to_string()).collect()) }
+                    </div>
+                    <div>
+                        <p>{"Quantized"}</p>
+                        { coefficients(selected.quantized[block].iter().map(|val| val.to_string()).collect()) }
+                    </div>
+                </div>
+                <p>
+                    {"Run-length symbols in zig-zag order: "}
+                    {lab.block_symbols(component, block).iter().map(symbol_label).collect::<Vec<_>>().join(", ")}
+                </p>
+            </div>
+        }
+    }
+
+    fn view_metadata(&self) -> Html {
+        let metadata = match &self.document.metadata {
+            Some(metadata) => metadata,
+            None => return html! {},
+        };
+
+        let format = match &metadata.variant {
+            Some(variant) => format!("{} ({})", metadata.format, variant),
+            None => metadata.format.clone(),
+        };
+
+        html! {
+            <div id="metadata" style="padding: 0 8px; font-family: monospace;">
+                <h4>{"Metadata"}</h4>
+                <table>
+                    <tr><td>{"format"}</td><td>{format}</td></tr>
+                    <tr><td>{"size"}</td><td>{format!("{}x{}", metadata.width, metadata.height)}</td></tr>
+                    <tr><td>{"max value"}</td><td>{metadata.max_value}</td></tr>
+                    <tr><td>{"bit depth"}</td><td>{metadata.bit_depth}</td></tr>
+                    <tr><td>{"channels"}</td><td>{metadata.channels}</td></tr>
+                    <tr><td>{"file size"}</td><td>{format!("{} bytes", metadata.file_size)}</td></tr>
+                </table>
+                if metadata.comments.is_empty() {
+                    <p>{"No comments."}</p>
+                } else {
+                    <ul style="padding-left: 1em; white-space: pre-wrap;">
+                        { for metadata.comments.iter().map(|comment| html! { <li>{comment}</li> }) }
+                    </ul>
+                }
+                if !metadata.exif.is_empty() {
+                    <h4>{"EXIF"}</h4>
+                    <table>
+                        { for metadata.exif.iter().map(|field| html! {
+                            <tr><td>{&field.tag}</td><td>{&field.value}</td></tr>
+                        }) }
+                    </table>
+                }
+            </div>
+        }
+    }
+
+    fn view_markers(&self) -> Html {
+        let report = match &self.document.markers {
+            Some(report) => report,
+            None => return html! {
+                if let Some(err) = &self.document.load_error {
+                    <p style="padding: 0 8px; color: red;">{format!("Couldn't open file: {}", err)}</p>
+                }
+            },
+        };
+
+        let table = |values: &[u16]| html! {
+            <table style="text-align: right;">
+                { for values.chunks(8).map(|row| html! {
+                    <tr>{ for row.iter().map(|val| html! { <td>{val}</td> }) }</tr>
+                }) }
+            </table>
+        };
+
+        html! {
+            <div id="markers" style="padding: 0 8px; font-family: monospace;">
+                <h4>{"JPEG markers"}</h4>
+                if let Some(err) = &self.document.load_error {
+                    <p style="color: red;">{format!("Couldn't open file: {}", err)}</p>
+                }
+                if let Some(quality) = report.estimated_quality {
+                    <p>{format!("Estimated quality: {}", quality)}</p>
+                }
+                { for report.segments.iter().map(|segment| {
+                    let summary = if segment.length > 0 {
+                        format!("{} {} ({} bytes)", segment.offset, segment.name, segment.length)
+                    } else {
+                        format!("{} {}", segment.offset, segment.name)
+                    };
+
+                    let details = match &segment.details {
+                        Details::None => return html! { <div style="padding-left: 1em;">{summary}</div> },
+                        Details::Application { identifier } => html! { <div>{format!("identifier \"{}\"", identifier)}</div> },
+                        Details::Comment { text } => html! { <div style="white-space: pre-wrap;">{text}</div> },
+                        Details::QuantizationTables { tables } => html! {
+                            { for tables.iter().map(|quantization| html! {
+                                <details>
+                                    <summary>{format!("table {}, {}-bit, quality {}", quantization.id, quantization.precision, quantization.estimated_quality)}</summary>
+                                    { table(&quantization.values) }
+                                </details>
+                            }) }
+                        },
+                        Details::HuffmanTables { tables } => html! {
+                            { for tables.iter().map(|huffman| html! {
+                                <details>
+                                    <summary>{format!("{} table {}, {} codes", huffman.class, huffman.id, huffman.symbols.len())}</summary>
+                                    <div>{"codes per length: "}{huffman.counts.iter().map(|val| val.to_string()).collect::<Vec<_>>().join(" ")}</div>
+                                    <div style="word-break: break-all;">{"symbols: "}{huffman.symbols.iter().map(|val| format!("{:02X}", val)).collect::<Vec<_>>().join(" ")}</div>
+                                </details>
+                            }) }
+                        },
+                        Details::Frame { process, precision, width, height, components } => html! {
+                            <>
+                                <div>{format!("{}, {}-bit, {}x{}", process, precision, width, height)}</div>
+                                <table>
+                                    <tr><td>{"id"}</td><td>{"sampling"}</td><td>{"table"}</td></tr>
+                                    { for components.iter().map(|component| html! {
+                                        <tr>
+                                            <td>{component.id}</td>
+                                            <td>{format!("{}x{}", component.horizontal, component.vertical)}</td>
+                                            <td>{component.table}</td>
+                                        </tr>
+                                    }) }
+                                </table>
+                            </>
+                        },
+                        Details::Scan { components, spectral_start, spectral_end, approximation_high, approximation_low, data_length, restart_markers } => html! {
+                            <>
+                                { for components.iter().map(|component| html! {
+                                    <div>{format!("component {}: DC table {}, AC table {}", component.id, component.dc_table, component.ac_table)}</div>
+                                }) }
+                                <div>{format!("spectral {}..{}, approximation {}/{}", spectral_start, spectral_end, approximation_high, approximation_low)}</div>
+                                <div>{format!("{} bytes of data, {} restart markers", data_length, restart_markers)}</div>
+                            </>
+                        },
+                        Details::RestartInterval { mcus } => html! { <div>{format!("every {} MCUs", mcus)}</div> },
+                    };
+
+                    html! {
+                        <details>
+                            <summary>{summary}</summary>
+                            <div style="padding-left: 1em;">{details}</div>
+                        </details>
+                    }
+                }) }
+                if !report.problems.is_empty() {
+                    <ul style="color: red; padding-left: 1em;">
+                        { for report.problems.iter().map(|problem| html! { <li>{problem}</li> }) }
+                    </ul>
+                }
+            </div>
+        }
+    }
+
+    fn view_region_stats(&self, ctx: &Context<Self>) -> Html {
+        let stats = match &self.document.region_stats {
+            Some(stats) => stats,
+            None => return html! {},
+        };
+
+        let row = |label: &str, values: [String; 3]| html! {
+            <tr><td>{label.to_string()}</td>{ for values.into_iter().map(|val| html! { <td>{val}</td> }) }</tr>
+        };
+
+        html! {
+            <div>
+                <h4>{"Selection"}</h4>
+                <p>{format!("{} pixels", stats.count)}</p>
+                <table>
+                    <tr><td></td><td>{"r"}</td><td>{"g"}</td><td>{"b"}</td></tr>
+                    { row("mean", stats.mean.map(|val| format!("{:.2}", val))) }
+                    { row("min", stats.min.map(|val| val.to_string())) }
+                    { row("max", stats.max.map(|val| val.to_string())) }
+                    { row("stddev", stats.stddev.map(|val| format!("{:.2}", val))) }
+                </table>
+                { view_histogram(stats) }
+                <input type="button" value="Crop to selection" onclick={ctx.link().callback(|_| Msg::CropToSelection)} />
+                <input type="button" value="Clear selection" onclick={ctx.link().callback(|_| Msg::ClearSelection)} />
+            </div>
+        }
+    }
+
+    fn view_inspector(&self, ctx: &Context<Self>) -> Html {
+        let hover = match &self.hover {
+            Some(sample) => {
+                let normalized = sample.normalized();
+                html! {
+                    <table>
+                        <tr><td>{"x, y"}</td><td colspan="3">{format!("{}, {}", sample.x, sample.y)}</td></tr>
+                        <tr><td></td><td>{"r"}</td><td>{"g"}</td><td>{"b"}</td></tr>
+                        <tr>
+                            <td>{"raw"}</td>
+                            <td>{sample.value.0}</td><td>{sample.value.1}</td><td>{sample.value.2}</td>
+                        </tr>
+                        <tr>
+                            <td>{"norm"}</td>
+                            { for normalized.iter().map(|val| html! { <td>{format!("{:.4}", val)}</td> }) }
+                        </tr>
+                        <tr>
+                            <td>{"mean"}</td>
+                            { for sample.mean.iter().map(|val| html! { <td>{format!("{:.2}", val)}</td> }) }
+                        </tr>
+                        <tr>
+                            <td>{"stddev"}</td>
+                            { for sample.stddev.iter().map(|val| html! { <td>{format!("{:.2}", val)}</td> }) }
+                        </tr>
+                        <tr>
+                            <td>{"hex"}</td>
+                            <td colspan="2">{sample.hex()}</td>
+                            <td><span style={format!("display: inline-block; width: 2em; height: 1em; border: 1px solid gray; background: {};", sample.css_color())} /></td>
+                        </tr>
+                        <tr><td>{"max"}</td><td colspan="3">{sample.max_value}</td></tr>
+                    </table>
+                }
+            }
+            None => html! { <p>{"Move the cursor over the image."}</p> },
+        };
+
+        html! {
+            <div id="inspector" style="padding: 0 8px; font-family: monospace;">
+                <label>{"Neighbourhood: "}</label>
+                <input type="number" min="1" max={MAX_NEIGHBOURHOOD.to_string()} step="2" value={self.neighbourhood.to_string()}
+                    onchange={ctx.link().callback(|event: Event| {
+                        let value = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap().value_as_number();
+
+                        Msg::NeighbourhoodChange { value: value as usize }
+                    })} />
+                <span>{format!(" {0}x{0}", self.neighbourhood)}</span>
+                { hover }
+                <p>
+                    {format!("Pinned samples: {} ", self.document.pinned.len())}
+                    <input type="button" value="Export CSV" disabled={self.document.pinned.is_empty()} onclick={ctx.link().callback(|_| Msg::ExportSamples)} />
+                    <input type="button" value="Clear" disabled={self.document.pinned.is_empty()} onclick={ctx.link().callback(|_| Msg::ClearSamples)} />
+                </p>
+                { self.view_region_stats(ctx) }
+                <table>
+                    { for self.document.pinned.iter().enumerate().map(|(index, sample)| html! {
+                        <tr>
+                            <td><span style={format!("display: inline-block; width: 1em; height: 1em; background: {};", sample.css_color())} /></td>
+                            <td>{format!("{}, {}", sample.x, sample.y)}</td>
+                            <td>{sample.hex()}</td>
+                            <td><input type="button" value="x" onclick={ctx.link().callback(move |_| Msg::UnpinSample { index })} /></td>
+                        </tr>
+                    }) }
+                </table>
+            </div>
+        }
+    }
+}
+
+fn view_histogram(stats: &RegionStats) -> Html {
+    const HEIGHT: f64 = 100.0;
+    let peak = stats
+        .histogram
+        .iter()
+        .flat_map(|channel| channel.iter())
+        .max()
+        .copied()
+        .unwrap_or(0)
+        .max(1) as f64;
+
+    let polyline = |channel: &Vec<u32>| -> String {
+        let points: Vec<String> = channel
+            .iter()
+            .enumerate()
+            .map(|(bin, count)| format!("{},{:.1}", bin, HEIGHT - *count as f64 / peak * HEIGHT))
+            .collect();
+
+        return points.join(" ");
+    };
+
+    html! {
+        <svg viewBox={format!("0 0 {} {}", HISTOGRAM_BINS, HEIGHT)} preserveAspectRatio="none"
+            style="width: 100%; height: 100px; background: #222;">
+            <polyline points={polyline(&stats.histogram[0])} fill="none" stroke="red" />
+            <polyline points={polyline(&stats.histogram[1])} fill="none" stroke="lime" />
+            <polyline points={polyline(&stats.histogram[2])} fill="none" stroke="dodgerblue" />
+        </svg>
+    }
+}
+
+fn view_metrics(metrics: &Result<Metrics, String>) -> Html {
+    let metrics = match metrics {
+        Ok(metrics) => metrics,
+        Err(err) => return html! { <p style="color: red;">{err}</p> },
+    };
+    let psnr = |psnr: f64| if psnr.is_infinite() { "inf".to_string() } else { format!("{:.2} dB", psnr) };
+    let row = |name: &str, channel: &ChannelMetrics| {
+        html! {
+            <tr>
+                <td>{name.to_string()}</td>
+                <td>{format!("{:.6}", channel.mse)}</td>
+                <td>{psnr(channel.psnr)}</td>
+                <td>{format!("{:.4}", channel.ssim)}</td>
+                <td>{channel.max_error}</td>
+            </tr>
+        }
+    };
+
+    html! {
+        <table style="text-align: right; font-family: monospace;">
+            <tr><th></th><th>{"MSE"}</th><th>{"PSNR"}</th><th>{"SSIM"}</th><th>{format!("Max error (of {})", metrics.max_value)}</th></tr>
+            { row("R", &metrics.channels[0]) }
+            { row("G", &metrics.channels[1]) }
+            { row("B", &metrics.channels[2]) }
+            { row("All", &metrics.overall) }
+            <tr><td>{"MS-SSIM"}</td><td colspan="4">{format!("{:.4}", metrics.ms_ssim)}</td></tr>
+        </table>
+    }
+}
+
+// Change handler that edits a copy of the export options.
+fn options_callback<F>(ctx: &Context<App>, options: &JpegOptions, apply: F) -> Callback<Event>
+where
+    F: Fn(&mut JpegOptions, &HtmlInputElement) + 'static,
+{
+    let options = options.clone();
+    ctx.link().callback(move |event: Event| {
+        let input = event.target().unwrap().dyn_into::<HtmlInputElement>().unwrap();
+        let mut options = options.clone();
+        apply(&mut options, &input);
+
+        Msg::JpegOptionsChange { options }
+    })
+}
+
+fn read_file(event: Event, callback: Callback<Vec<u8>>) {
+    let target = event.target().unwrap();
+    let target: web_sys::HtmlInputElement = target.dyn_into().unwrap();
+    let file = match target.files().and_then(|files| files.get(0)) {
+        Some(file) => file,
+        None => return,
+    };
+
+    read_blob(&file, callback);
+}
+
+// Every selected file with its name, in the order they finish loading.
+fn read_files(event: Event, callback: Callback<(String, Vec<u8>)>) {
+    let target = event.target().unwrap();
+    let target: web_sys::HtmlInputElement = target.dyn_into().unwrap();
+    if let Some(files) = target.files() {
+        read_file_list(&files, callback);
+    }
+    // Lets the same files be picked again.
+    target.set_value("");
+}
+
+fn read_file_list(files: &FileList, callback: Callback<(String, Vec<u8>)>) {
+    for index in 0..files.length() {
+        let file = files.get(index).unwrap();
+        let name = file.name();
+        let callback = callback.clone();
+        read_blob(&file, Callback::from(move |value: Vec<u8>| callback.emit((name.clone(), value))));
+    }
+}
+
+fn read_blob(blob: &Blob, callback: Callback<Vec<u8>>) {
+    let file_reader = web_sys::FileReader::new().unwrap();
+    file_reader.read_as_array_buffer(blob).unwrap();
+    let listener = EventListener::new(&file_reader, "load", move |event| {
+        let target = event.target().unwrap();
+        let target: web_sys::FileReader = target.dyn_into().unwrap();
+        let result = target.result().unwrap();
+        let array = Uint8Array::new(&result);
+
+        callback.emit(array.to_vec());
+    });
+    listener.forget();
+}
+
+fn download(data: &[u8], mime: &str, file_name: &str) {
+    let a = window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .create_element("a")
+        .unwrap()
+        .dyn_into::<HtmlElement>()
+        .unwrap();
+
+    a.set_attribute("href", &format!("data:{};base64,{}", mime, base64::encode(data)))
+        .unwrap();
+    a.set_attribute("download", file_name).unwrap();
+
+    a.click();
+    a.remove();
+}
+
+fn get_viewport_size() -> (f64, f64) {
+    let viewport = window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .query_selector("#viewport")
+        .unwrap()
+        .unwrap();
+
+    return (
+        viewport.client_width() as f64,
+        viewport.client_height() as f64,
+    );
 }
 
 fn main() {
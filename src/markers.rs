@@ -0,0 +1,571 @@
+use std::{error::Error, fs};
+
+use serde::Serialize;
+
+use crate::codec::{scaled_table, ZIGZAG};
+use crate::jpeg::{STANDARD_CHROMA_TABLE, STANDARD_LUMA_TABLE};
+
+// Walks the marker segments of a JPEG file without decoding it. Problems are
+// collected instead of stopping the walk, so files the decoder rejects can
+// still be looked at.
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MarkerReport {
+    pub segments: Vec<Segment>,
+    // Closest IJG quality for the luma quantisation table.
+    pub estimated_quality: Option<u8>,
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Segment {
+    pub offset: usize,
+    pub marker: u8,
+    pub name: String,
+    // Value of the length field, 0 for markers without a payload.
+    pub length: usize,
+    #[serde(flatten)]
+    pub details: Details,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Details {
+    None,
+    Application {
+        identifier: String,
+    },
+    Comment {
+        text: String,
+    },
+    QuantizationTables {
+        tables: Vec<QuantizationTable>,
+    },
+    HuffmanTables {
+        tables: Vec<HuffmanTable>,
+    },
+    Frame {
+        process: String,
+        precision: u8,
+        width: u16,
+        height: u16,
+        components: Vec<FrameComponent>,
+    },
+    Scan {
+        components: Vec<ScanComponent>,
+        spectral_start: u8,
+        spectral_end: u8,
+        approximation_high: u8,
+        approximation_low: u8,
+        // Entropy coded bytes following the header, restart markers included.
+        data_length: usize,
+        restart_markers: usize,
+    },
+    RestartInterval {
+        mcus: u16,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuantizationTable {
+    pub id: u8,
+    pub precision: u8,
+    // Row-major, converted from the zig-zag order in the file.
+    pub values: Vec<u16>,
+    pub estimated_quality: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HuffmanTable {
+    pub class: String,
+    pub id: u8,
+    // Number of codes of each length from 1 to 16 bits.
+    pub counts: Vec<u8>,
+    pub symbols: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FrameComponent {
+    pub id: u8,
+    pub horizontal: u8,
+    pub vertical: u8,
+    pub table: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanComponent {
+    pub id: u8,
+    pub dc_table: u8,
+    pub ac_table: u8,
+}
+
+pub fn marker_name(marker: u8) -> String {
+    match marker {
+        0x01 => String::from("TEM"),
+        0xC4 => String::from("DHT"),
+        0xC8 => String::from("JPG"),
+        0xCC => String::from("DAC"),
+        0xC0..=0xCF => format!("SOF{}", marker - 0xC0),
+        0xD0..=0xD7 => format!("RST{}", marker - 0xD0),
+        0xD8 => String::from("SOI"),
+        0xD9 => String::from("EOI"),
+        0xDA => String::from("SOS"),
+        0xDB => String::from("DQT"),
+        0xDC => String::from("DNL"),
+        0xDD => String::from("DRI"),
+        0xDE => String::from("DHP"),
+        0xDF => String::from("EXP"),
+        0xE0..=0xEF => format!("APP{}", marker - 0xE0),
+        0xF0..=0xFD => format!("JPG{}", marker - 0xF0),
+        0xFE => String::from("COM"),
+        _ => format!("0x{:02X}", marker),
+    }
+}
+
+fn frame_process(marker: u8) -> String {
+    let process = match marker & 0x03 {
+        0 => "baseline",
+        1 => "extended sequential",
+        2 => "progressive",
+        _ => "lossless",
+    };
+    let coding = if marker >= 0xC9 { "arithmetic" } else { "Huffman" };
+    let hierarchical = if matches!(marker, 0xC5..=0xC7 | 0xCD..=0xCF) { "hierarchical " } else { "" };
+
+    return format!("{}{}, {}", hierarchical, process, coding);
+}
+
+// Quality whose scaled standard table is closest to the given one.
+pub fn estimate_quality(values: &[u16], standard: &[u16; 64]) -> u8 {
+    (1..=100)
+        .min_by_key(|quality| {
+            scaled_table(standard, *quality)
+                .iter()
+                .zip(values)
+                .map(|(a, b)| a.abs_diff(*b) as u32)
+                .sum::<u32>()
+        })
+        .unwrap()
+}
+
+fn parse_quantization_tables(payload: &[u8], problems: &mut Vec<String>) -> Details {
+    let mut tables = Vec::new();
+    let mut pos = 0;
+    while pos < payload.len() {
+        let (precision, id) = (payload[pos] >> 4, payload[pos] & 0x0F);
+        let size = if precision == 0 { 1 } else { 2 };
+        let data = match payload.get(pos + 1..pos + 1 + 64 * size) {
+            Some(data) => data,
+            None => {
+                problems.push(format!("Quantization table {} is truncated.", id));
+                break;
+            }
+        };
+
+        let mut values = vec![0; 64];
+        for (i, index) in ZIGZAG.iter().enumerate() {
+            values[*index] = if size == 1 {
+                data[i] as u16
+            } else {
+                u16::from_be_bytes([data[2 * i], data[2 * i + 1]])
+            };
+        }
+        let standard = if id == 0 { &STANDARD_LUMA_TABLE } else { &STANDARD_CHROMA_TABLE };
+
+        tables.push(QuantizationTable {
+            id,
+            precision: 8 * size as u8,
+            estimated_quality: estimate_quality(&values, standard),
+            values,
+        });
+        pos += 1 + 64 * size;
+    }
+
+    return Details::QuantizationTables { tables };
+}
+
+fn parse_huffman_tables(payload: &[u8], problems: &mut Vec<String>) -> Details {
+    let mut tables = Vec::new();
+    let mut pos = 0;
+    while pos < payload.len() {
+        let (class, id) = (payload[pos] >> 4, payload[pos] & 0x0F);
+        let counts = match payload.get(pos + 1..pos + 17) {
+            Some(counts) => counts,
+            None => {
+                problems.push(format!("Huffman table {} is truncated.", id));
+                break;
+            }
+        };
+        let count: usize = counts.iter().map(|val| *val as usize).sum();
+        let symbols = match payload.get(pos + 17..pos + 17 + count) {
+            Some(symbols) => symbols,
+            None => {
+                problems.push(format!("Huffman table {} is truncated.", id));
+                break;
+            }
+        };
+
+        tables.push(HuffmanTable {
+            class: String::from(if class == 0 { "DC" } else { "AC" }),
+            id,
+            counts: counts.to_vec(),
+            symbols: symbols.to_vec(),
+        });
+        pos += 17 + count;
+    }
+
+    return Details::HuffmanTables { tables };
+}
+
+fn parse_frame(marker: u8, payload: &[u8], problems: &mut Vec<String>) -> Details {
+    if payload.len() < 6 {
+        problems.push(String::from("Frame header is truncated."));
+        return Details::None;
+    }
+
+    let count = payload[5] as usize;
+    let components: Vec<FrameComponent> = payload[6..]
+        .chunks_exact(3)
+        .take(count)
+        .map(|chunk| FrameComponent {
+            id: chunk[0],
+            horizontal: chunk[1] >> 4,
+            vertical: chunk[1] & 0x0F,
+            table: chunk[2],
+        })
+        .collect();
+    if components.len() < count {
+        problems.push(format!("Frame header lists {} components but holds {}.", count, components.len()));
+    }
+
+    return Details::Frame {
+        process: frame_process(marker),
+        precision: payload[0],
+        height: u16::from_be_bytes([payload[1], payload[2]]),
+        width: u16::from_be_bytes([payload[3], payload[4]]),
+        components,
+    };
+}
+
+// Entropy coded data runs up to the first marker other than a stuffed zero
+// or a restart marker. Returns its length and the number of restart markers.
+fn scan_data(buffer: &[u8], start: usize) -> (usize, usize) {
+    let mut pos = start;
+    let mut restart_markers = 0;
+    while pos + 1 < buffer.len() {
+        if buffer[pos] == 0xFF {
+            match buffer[pos + 1] {
+                0x00 => pos += 2,
+                0xD0..=0xD7 => {
+                    restart_markers += 1;
+                    pos += 2;
+                }
+                // Fill byte before a marker.
+                0xFF => pos += 1,
+                _ => break,
+            }
+            continue;
+        }
+        pos += 1;
+    }
+    if pos + 1 >= buffer.len() {
+        pos = buffer.len();
+    }
+
+    return (pos - start, restart_markers);
+}
+
+fn parse_scan(payload: &[u8], buffer: &[u8], data_start: usize, problems: &mut Vec<String>) -> Details {
+    let count = payload.first().copied().unwrap_or(0) as usize;
+    if payload.len() < 1 + 2 * count + 3 {
+        problems.push(String::from("Scan header is truncated."));
+        return Details::None;
+    }
+
+    let components = payload[1..1 + 2 * count]
+        .chunks_exact(2)
+        .map(|chunk| ScanComponent {
+            id: chunk[0],
+            dc_table: chunk[1] >> 4,
+            ac_table: chunk[1] & 0x0F,
+        })
+        .collect();
+    let tail = &payload[1 + 2 * count..];
+    let (data_length, restart_markers) = scan_data(buffer, data_start);
+
+    return Details::Scan {
+        components,
+        spectral_start: tail[0],
+        spectral_end: tail[1],
+        approximation_high: tail[2] >> 4,
+        approximation_low: tail[2] & 0x0F,
+        data_length,
+        restart_markers,
+    };
+}
+
+pub fn read_markers(buffer: &[u8]) -> MarkerReport {
+    let mut report = MarkerReport {
+        segments: Vec::new(),
+        estimated_quality: None,
+        problems: Vec::new(),
+    };
+
+    if !buffer.starts_with(&[0xFF, 0xD8]) {
+        report.problems.push(String::from("File doesn't start with an SOI marker."));
+    }
+
+    let mut pos = 0;
+    while pos < buffer.len() {
+        if buffer[pos] != 0xFF {
+            // Skip garbage up to the next marker.
+            let next = (pos..buffer.len() - 1).find(|i| buffer[*i] == 0xFF && !matches!(buffer[*i + 1], 0x00 | 0xFF));
+            report.problems.push(format!(
+                "Expected a marker at offset {}, skipped {} bytes.",
+                pos,
+                next.unwrap_or(buffer.len()) - pos
+            ));
+            match next {
+                Some(next) => pos = next,
+                None => break,
+            }
+            continue;
+        }
+
+        let marker = match buffer.get(pos + 1) {
+            Some(0xFF) => {
+                pos += 1;
+                continue;
+            }
+            Some(marker) => *marker,
+            None => {
+                report.problems.push(String::from("File ends inside a marker."));
+                break;
+            }
+        };
+
+        let mut segment = Segment {
+            offset: pos,
+            marker,
+            name: marker_name(marker),
+            length: 0,
+            details: Details::None,
+        };
+
+        // Markers that stand alone without a length field.
+        if matches!(marker, 0x01 | 0xD0..=0xD9) {
+            report.segments.push(segment);
+            pos += 2;
+            if marker == 0xD9 {
+                if pos < buffer.len() {
+                    report.problems.push(format!("{} bytes follow the EOI marker.", buffer.len() - pos));
+                }
+                break;
+            }
+            continue;
+        }
+
+        let length = match buffer.get(pos + 2..pos + 4) {
+            Some(bytes) => u16::from_be_bytes([bytes[0], bytes[1]]) as usize,
+            None => {
+                report.problems.push(format!("{} marker at offset {} has no length.", segment.name, pos));
+                break;
+            }
+        };
+        segment.length = length;
+        let end = pos + 2 + length;
+        if length < 2 || end > buffer.len() {
+            report.problems.push(format!("{} segment at offset {} runs past the end of the file.", segment.name, pos));
+            report.segments.push(segment);
+            break;
+        }
+
+        let payload = &buffer[pos + 4..end];
+        let problems = &mut report.problems;
+        segment.details = match marker {
+            0xDB => parse_quantization_tables(payload, problems),
+            0xC4 => parse_huffman_tables(payload, problems),
+            0xC0..=0xCF if marker != 0xC8 && marker != 0xCC => parse_frame(marker, payload, problems),
+            0xDA => parse_scan(payload, buffer, end, problems),
+            0xDD if payload.len() >= 2 => Details::RestartInterval {
+                mcus: u16::from_be_bytes([payload[0], payload[1]]),
+            },
+            0xE0..=0xEF => {
+                let identifier = payload.iter().take_while(|val| **val != 0).take(32).map(|val| *val as char).collect();
+                Details::Application { identifier }
+            }
+            0xFE => Details::Comment {
+                text: String::from_utf8_lossy(payload).to_string(),
+            },
+            _ => Details::None,
+        };
+
+        pos = match &segment.details {
+            Details::Scan { data_length, .. } => end + data_length,
+            _ => end,
+        };
+        report.segments.push(segment);
+    }
+
+    if !report.segments.iter().any(|segment| segment.marker == 0xD9) {
+        report.problems.push(String::from("Missing EOI marker."));
+    }
+    if !report.segments.iter().any(|segment| matches!(segment.details, Details::Frame { .. })) {
+        report.problems.push(String::from("Missing frame header."));
+    }
+
+    report.estimated_quality = report.segments.iter().find_map(|segment| match &segment.details {
+        Details::QuantizationTables { tables } => tables.iter().find(|table| table.id == 0).map(|table| table.estimated_quality),
+        _ => None,
+    });
+
+    return report;
+}
+
+pub fn file_markers(path: &str) -> Result<MarkerReport, Box<dyn Error>> {
+    let buffer = fs::read(path)?;
+
+    return Ok(read_markers(&buffer));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::{Bitmap, BitmapData, Image};
+    use crate::jpeg::JpegOptions;
+
+    const SOI: [u8; 2] = [0xFF, 0xD8];
+    const EOI: [u8; 2] = [0xFF, 0xD9];
+    // One 8x8 grey component and a scan of it.
+    const SOF0: [u8; 13] = [0xFF, 0xC0, 0x00, 0x0B, 0x08, 0x00, 0x08, 0x00, 0x08, 0x01, 0x01, 0x11, 0x00];
+    const SOS: [u8; 10] = [0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00];
+
+    fn encoded(restart_interval: u16) -> Vec<u8> {
+        let data = (0..48 * 32 * 3).map(|val| (val * 5 % 256) as u8).collect();
+        let image = Bitmap::new(48, 32, 255, BitmapData::U8(data));
+        let options = JpegOptions { restart_interval, ..Default::default() };
+        let mut vec = Vec::new();
+        image.write_to_jpeg(&mut vec, &options).unwrap();
+
+        return vec;
+    }
+
+    fn file(parts: &[&[u8]]) -> Vec<u8> {
+        return parts.concat();
+    }
+
+    fn names(report: &MarkerReport) -> Vec<String> {
+        return report.segments.iter().map(|segment| segment.name.clone()).collect();
+    }
+
+    fn scan(report: &MarkerReport) -> (usize, usize) {
+        return report
+            .segments
+            .iter()
+            .find_map(|segment| match segment.details {
+                Details::Scan { data_length, restart_markers, .. } => Some((data_length, restart_markers)),
+                _ => None,
+            })
+            .expect("No scan found.");
+    }
+
+    #[test]
+    fn encoded_files_have_no_problems() {
+        let report = read_markers(&encoded(1));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(names(&report).first().map(String::as_str), Some("SOI"));
+        assert_eq!(names(&report).last().map(String::as_str), Some("EOI"));
+        assert_eq!(report.estimated_quality, Some(90));
+        // Six MCUs of 16x16, a restart marker between each of them.
+        assert_eq!(scan(&report).1, 5);
+    }
+
+    #[test]
+    fn restart_markers_stay_inside_the_scan() {
+        let data: [u8; 9] = [0x12, 0xFF, 0x00, 0xFF, 0xD0, 0x34, 0xFF, 0xD1, 0x56];
+        let report = read_markers(&file(&[&SOI, &SOF0, &SOS, &data, &EOI]));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(names(&report), ["SOI", "SOF0", "SOS", "EOI"]);
+        assert_eq!(scan(&report), (9, 2));
+    }
+
+    #[test]
+    fn fill_bytes_are_skipped() {
+        let fill = [0xFF, 0xFF, 0xFF];
+        let data = [0x12, 0x34];
+        let report = read_markers(&file(&[&SOI, &fill, &SOF0, &SOS, &data, &fill, &EOI]));
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!(names(&report), ["SOI", "SOF0", "SOS", "EOI"]);
+        // Fill bytes before the EOI count as scan data.
+        assert_eq!(scan(&report).0, 5);
+    }
+
+    #[test]
+    fn missing_eoi_is_reported() {
+        let data = [0x12, 0x34, 0x56];
+        let report = read_markers(&file(&[&SOI, &SOF0, &SOS, &data]));
+        assert_eq!(report.problems, ["Missing EOI marker."]);
+        assert_eq!(scan(&report), (3, 0));
+
+        // Cut right after a stuffed byte's 0xFF.
+        let report = read_markers(&file(&[&SOI, &SOF0, &SOS, &data, &[0xFF]]));
+        assert_eq!(report.problems, ["Missing EOI marker."]);
+        assert_eq!(scan(&report), (4, 0));
+    }
+
+    #[test]
+    fn truncated_segments_are_reported() {
+        // The frame header claims more bytes than the file has.
+        let report = read_markers(&file(&[&SOI, &SOF0[..9]]));
+        assert_eq!(names(&report), ["SOI", "SOF0"]);
+        assert!(report.problems.iter().any(|problem| problem.contains("runs past the end")));
+        assert!(report.problems.contains(&String::from("Missing EOI marker.")));
+
+        // Lengths fit, but the tables inside don't.
+        let dqt = [0xFF, 0xDB, 0x00, 0x05, 0x00, 0x01, 0x02];
+        let dht = [0xFF, 0xC4, 0x00, 0x05, 0x00, 0x01, 0x00];
+        let short_frame = [0xFF, 0xC0, 0x00, 0x05, 0x08, 0x00, 0x08];
+        let short_scan = [0xFF, 0xDA, 0x00, 0x04, 0x02, 0x01];
+        let report = read_markers(&file(&[&SOI, &dqt, &dht, &short_frame, &short_scan, &EOI]));
+        for problem in [
+            "Quantization table 0 is truncated.",
+            "Huffman table 0 is truncated.",
+            "Frame header is truncated.",
+            "Scan header is truncated.",
+        ] {
+            assert!(report.problems.contains(&String::from(problem)), "{:?}", report.problems);
+        }
+
+        // Nothing but a marker's first byte, or a length cut in half.
+        assert!(read_markers(&[0xFF]).problems.contains(&String::from("File ends inside a marker.")));
+        let report = read_markers(&file(&[&SOI, &[0xFF, 0xDB, 0x00]]));
+        assert!(report.problems.iter().any(|problem| problem.contains("has no length")));
+    }
+
+    // Every prefix and a spread of corrupted bytes only end up as problems.
+    #[test]
+    fn damaged_files_dont_panic() {
+        let original = encoded(2);
+        for end in 0..original.len() {
+            read_markers(&original[..end]);
+        }
+
+        let mut seed: u32 = 1;
+        let mut random = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (seed >> 8) as usize
+        };
+        for _ in 0..2000 {
+            let mut damaged = original.clone();
+            for _ in 0..4 {
+                let index = random() % damaged.len();
+                damaged[index] = match random() % 3 {
+                    0 => 0xFF,
+                    1 => 0x00,
+                    _ => random() as u8,
+                };
+            }
+            read_markers(&damaged);
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use std::error::Error;
+
+use serde::Serialize;
+
+use crate::codec::heat_color;
+use crate::image::{Bitmap, BitmapData, Image};
+
+// SSIM constants for samples normalised to 0..=1.
+const SSIM_C1: f64 = 0.01 * 0.01;
+const SSIM_C2: f64 = 0.03 * 0.03;
+const SSIM_SIGMA: f64 = 1.5;
+
+// Weights of the MS-SSIM scales, from full resolution down.
+const MS_SSIM_WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+// Smallest side a scale is still computed for, the gaussian window is 11
+// pixels wide.
+const MS_SSIM_MIN_SIZE: usize = 11;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ChannelMetrics {
+    // On samples normalised to 0..=1.
+    pub mse: f64,
+    pub psnr: f64,
+    pub ssim: f64,
+    // Largest absolute difference of a sample, in the range of Metrics::max_value.
+    pub max_error: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Metrics {
+    // The larger max value of the two images.
+    pub max_value: usize,
+    pub channels: [ChannelMetrics; 3],
+    // SSIM of the overall metrics is computed on luma.
+    pub overall: ChannelMetrics,
+    pub ms_ssim: f64,
+}
+
+fn check_size(a: &dyn Image, b: &dyn Image) -> Result<(), Box<dyn Error>> {
+    if a.get_width() != b.get_width() || a.get_height() != b.get_height() {
+        return Err(format!(
+            "Images differ in size: {}x{} and {}x{}.",
+            a.get_width(),
+            a.get_height(),
+            b.get_width(),
+            b.get_height()
+        )
+        .into());
+    }
+
+    return Ok(());
+}
+
+// Samples of every channel divided by the max value, so images of different
+// bit depths can be compared.
+fn normalized(image: &dyn Image) -> Vec<[f64; 3]> {
+    let max_value = image.get_max_value().max(1) as f64;
+    let mut pixels = Vec::with_capacity(image.get_width() * image.get_height());
+    for y in 0..image.get_height() {
+        for x in 0..image.get_width() {
+            let (r, g, b) = image.get_pixel_value(x, y);
+            pixels.push([r as f64 / max_value, g as f64 / max_value, b as f64 / max_value]);
+        }
+    }
+
+    return pixels;
+}
+
+// Mean squared error over all channels, on normalised samples.
+pub fn mse(a: &dyn Image, b: &dyn Image) -> Result<f64, Box<dyn Error>> {
+    check_size(a, b)?;
+
+    let (a, b) = (normalized(a), normalized(b));
+    let count = (a.len() * 3).max(1) as f64;
+    let sum: f64 = a
+        .iter()
+        .zip(b.iter())
+        .flat_map(|(a, b)| (0..3).map(move |channel| (a[channel] - b[channel]).powi(2)))
+        .sum();
+
+    return Ok(sum / count);
+}
+
+// Peak signal to noise ratio in dB, infinite for identical images.
+pub fn psnr(a: &dyn Image, b: &dyn Image) -> Result<f64, Box<dyn Error>> {
+    return Ok(psnr_from_mse(mse(a, b)?));
+}
+
+fn psnr_from_mse(mse: f64) -> f64 {
+    if mse == 0.0 {
+        return f64::INFINITY;
+    }
+
+    return 10.0 * (1.0 / mse).log10();
+}
+
+// Everything at once, per channel and for the whole image. Images of
+// different bit depths are compared on normalised samples.
+pub fn compare(a: &dyn Image, b: &dyn Image) -> Result<Metrics, Box<dyn Error>> {
+    check_size(a, b)?;
+
+    let (width, height) = (a.get_width(), a.get_height());
+    let max_value = a.get_max_value().max(b.get_max_value()).max(1);
+    let (pixels_a, pixels_b) = (normalized(a), normalized(b));
+
+    let channels = [0, 1, 2].map(|channel| {
+        let plane_a: Vec<f64> = pixels_a.iter().map(|pixel| pixel[channel]).collect();
+        let plane_b: Vec<f64> = pixels_b.iter().map(|pixel| pixel[channel]).collect();
+        let count = plane_a.len().max(1) as f64;
+
+        let mut sum = 0.0;
+        let mut largest: f64 = 0.0;
+        for (a, b) in plane_a.iter().zip(plane_b.iter()) {
+            sum += (a - b).powi(2);
+            largest = largest.max((a - b).abs());
+        }
+
+        ChannelMetrics {
+            mse: sum / count,
+            psnr: psnr_from_mse(sum / count),
+            ssim: ssim_planes(&plane_a, &plane_b, width, height),
+            max_error: (largest * max_value as f64).round() as usize,
+        }
+    });
+
+    let luma_a = luma_of(&pixels_a);
+    let luma_b = luma_of(&pixels_b);
+    let mse = channels.iter().map(|channel| channel.mse).sum::<f64>() / 3.0;
+
+    return Ok(Metrics {
+        max_value,
+        channels,
+        overall: ChannelMetrics {
+            mse,
+            psnr: psnr_from_mse(mse),
+            ssim: ssim_planes(&luma_a, &luma_b, width, height),
+            max_error: channels.iter().map(|channel| channel.max_error).max().unwrap_or(0),
+        },
+        ms_ssim: ms_ssim_planes(&luma_a, &luma_b, width, height),
+    });
+}
+
+// Mean structural similarity of the luma planes with a gaussian window.
+pub fn ssim(a: &dyn Image, b: &dyn Image) -> Result<f64, Box<dyn Error>> {
+    check_size(a, b)?;
+
+    let (width, height) = (a.get_width(), a.get_height());
+    return Ok(ssim_planes(&luma_plane(a), &luma_plane(b), width, height));
+}
+
+// Multi-scale SSIM of the luma planes, with as many of the five scales as
+// the image size allows.
+pub fn ms_ssim(a: &dyn Image, b: &dyn Image) -> Result<f64, Box<dyn Error>> {
+    check_size(a, b)?;
+
+    let (width, height) = (a.get_width(), a.get_height());
+    return Ok(ms_ssim_planes(&luma_plane(a), &luma_plane(b), width, height));
+}
+
+pub fn luma_plane(image: &dyn Image) -> Vec<f64> {
+    return luma_of(&normalized(image));
+}
+
+fn luma_of(pixels: &[[f64; 3]]) -> Vec<f64> {
+    pixels
+        .iter()
+        .map(|[r, g, b]| 0.299 * r + 0.587 * g + 0.114 * b)
+        .collect()
+}
+
+pub fn ssim_planes(a: &[f64], b: &[f64], width: usize, height: usize) -> f64 {
+    return ssim_terms(a, b, width, height).0;
+}
+
+pub fn ms_ssim_planes(a: &[f64], b: &[f64], width: usize, height: usize) -> f64 {
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+
+    let mut scales = 1;
+    while scales < MS_SSIM_WEIGHTS.len() && (width.min(height) >> scales) >= MS_SSIM_MIN_SIZE {
+        scales += 1;
+    }
+    let total: f64 = MS_SSIM_WEIGHTS[..scales].iter().sum();
+
+    let (mut a, mut b) = (a.to_vec(), b.to_vec());
+    let (mut width, mut height) = (width, height);
+    let mut result = 1.0;
+    for (scale, weight) in MS_SSIM_WEIGHTS[..scales].iter().enumerate() {
+        let (ssim, contrast_structure) = ssim_terms(&a, &b, width, height);
+        // Luminance only counts at the coarsest scale. Negative terms would
+        // make the fractional powers undefined.
+        let term = if scale + 1 == scales { ssim } else { contrast_structure };
+        result *= term.max(0.0).powf(weight / total);
+
+        if scale + 1 < scales {
+            a = downsample(&a, width, height);
+            b = downsample(&b, width, height);
+            width /= 2;
+            height /= 2;
+        }
+    }
+
+    return result;
+}
+
+// Halves both sides by averaging 2x2 blocks, an odd last row or column is dropped.
+fn downsample(plane: &[f64], width: usize, height: usize) -> Vec<f64> {
+    let (half_width, half_height) = (width / 2, height / 2);
+    let mut out = Vec::with_capacity(half_width * half_height);
+    for y in 0..half_height {
+        for x in 0..half_width {
+            let i = 2 * y * width + 2 * x;
+            out.push((plane[i] + plane[i + 1] + plane[i + width] + plane[i + width + 1]) / 4.0);
+        }
+    }
+
+    return out;
+}
+
+// Mean SSIM and mean contrast-structure term, which is SSIM without the
+// luminance comparison.
+fn ssim_terms(a: &[f64], b: &[f64], width: usize, height: usize) -> (f64, f64) {
+    if width == 0 || height == 0 {
+        return (1.0, 1.0);
+    }
+
+    let product = |x: &[f64], y: &[f64]| -> Vec<f64> { x.iter().zip(y).map(|(x, y)| x * y).collect() };
+    let mean_a = blur_plane(a, width, height);
+    let mean_b = blur_plane(b, width, height);
+    let mean_aa = blur_plane(&product(a, a), width, height);
+    let mean_bb = blur_plane(&product(b, b), width, height);
+    let mean_ab = blur_plane(&product(a, b), width, height);
+
+    let mut sum = 0.0;
+    let mut contrast_structure = 0.0;
+    for i in 0..a.len() {
+        let (mu_a, mu_b) = (mean_a[i], mean_b[i]);
+        let var_a = mean_aa[i] - mu_a * mu_a;
+        let var_b = mean_bb[i] - mu_b * mu_b;
+        let covariance = mean_ab[i] - mu_a * mu_b;
+
+        let cs = (2.0 * covariance + SSIM_C2) / (var_a + var_b + SSIM_C2);
+        sum += (2.0 * mu_a * mu_b + SSIM_C1) / (mu_a * mu_a + mu_b * mu_b + SSIM_C1) * cs;
+        contrast_structure += cs;
+    }
+
+    return (sum / a.len() as f64, contrast_structure / a.len() as f64);
+}
+
+// Separable gaussian blur of a single plane, edges are clamped.
+fn blur_plane(plane: &[f64], width: usize, height: usize) -> Vec<f64> {
+    let radius = (SSIM_SIGMA * 3.0).ceil() as isize;
+    let kernel: Vec<f64> = (-radius..=radius)
+        .map(|i| (-((i * i) as f64) / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp())
+        .collect();
+    let total: f64 = kernel.iter().sum();
+
+    let mut horizontal = vec![0.0; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            for (k, weight) in kernel.iter().enumerate() {
+                let sx = (x as isize + k as isize - radius).clamp(0, width as isize - 1) as usize;
+                sum += plane[y * width + sx] * weight;
+            }
+            horizontal[y * width + x] = sum / total;
+        }
+    }
+
+    let mut out = vec![0.0; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            for (k, weight) in kernel.iter().enumerate() {
+                let sy = (y as isize + k as isize - radius).clamp(0, height as isize - 1) as usize;
+                sum += horizontal[sy * width + x] * weight;
+            }
+            out[y * width + x] = sum / total;
+        }
+    }
+
+    return out;
+}
+
+// Absolute difference of every channel, in the range of the first image.
+pub fn difference(a: &dyn Image, b: &dyn Image) -> Result<Bitmap, Box<dyn Error>> {
+    check_size(a, b)?;
+
+    let max_value = a.get_max_value().max(1);
+    let samples = normalized(a)
+        .iter()
+        .zip(normalized(b).iter())
+        .flat_map(|(a, b)| (0..3).map(move |channel| (a[channel] - b[channel]).abs()))
+        .map(|val| (val * max_value as f64).round())
+        .collect::<Vec<_>>();
+    let data = if max_value > u8::MAX as usize {
+        BitmapData::U16(samples.iter().map(|val| *val as u16).collect())
+    } else {
+        BitmapData::U8(samples.iter().map(|val| *val as u8).collect())
+    };
+
+    return Ok(Bitmap::new(a.get_width(), a.get_height(), max_value, data));
+}
+
+// Largest channel difference of every pixel as a heatmap. At an
+// amplification of 1 only a difference over the full range reaches the top
+// of the scale.
+pub fn difference_heatmap(a: &dyn Image, b: &dyn Image, amplification: f64) -> Result<Bitmap, Box<dyn Error>> {
+    check_size(a, b)?;
+
+    let data = normalized(a)
+        .iter()
+        .zip(normalized(b).iter())
+        .flat_map(|(a, b)| {
+            let largest = (0..3).map(|channel| (a[channel] - b[channel]).abs()).fold(0.0, f64::max);
+            heat_color((largest * amplification) as f32)
+        })
+        .collect();
+
+    return Ok(Bitmap::new(a.get_width(), a.get_height(), u8::MAX as usize, BitmapData::U8(data)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        return (0..width * height * 3).map(|val| (val * 7 % 256) as u8).collect();
+    }
+
+    fn eight_bit(width: usize, height: usize, data: Vec<u8>) -> Bitmap {
+        return Bitmap::new(width, height, 255, BitmapData::U8(data));
+    }
+
+    // The same samples stretched over 16 bits.
+    fn sixteen_bit(width: usize, height: usize, data: &[u8]) -> Bitmap {
+        let data = data.iter().map(|val| *val as u16 * 257).collect();
+        return Bitmap::new(width, height, u16::MAX as usize, BitmapData::U16(data));
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        return (a - b).abs() < 1e-9;
+    }
+
+    #[test]
+    fn identical_images_match_perfectly() {
+        let image = eight_bit(16, 16, gradient(16, 16));
+        assert_eq!(mse(&image, &image).unwrap(), 0.0);
+        assert_eq!(psnr(&image, &image).unwrap(), f64::INFINITY);
+        assert!(close(ssim(&image, &image).unwrap(), 1.0));
+        assert!(close(ms_ssim(&image, &image).unwrap(), 1.0));
+
+        let metrics = compare(&image, &image).unwrap();
+        assert_eq!(metrics.overall.mse, 0.0);
+        assert_eq!(metrics.overall.psnr, f64::INFINITY);
+        assert_eq!(metrics.overall.max_error, 0);
+        assert!(close(metrics.overall.ssim, 1.0));
+    }
+
+    // One grey pixel of 51 out of four black ones: three samples off by 0.2
+    // of twelve, so the MSE is 0.12 / 12 = 0.01 and the PSNR 20 dB.
+    #[test]
+    fn known_difference() {
+        let black = eight_bit(2, 2, vec![0; 12]);
+        let mut data = vec![0; 12];
+        data[9..12].copy_from_slice(&[51, 51, 51]);
+        let grey = eight_bit(2, 2, data);
+
+        assert!(close(mse(&black, &grey).unwrap(), 0.01));
+        assert!(close(psnr(&black, &grey).unwrap(), 20.0));
+
+        let metrics = compare(&black, &grey).unwrap();
+        assert!(close(metrics.overall.mse, 0.01));
+        assert!(close(metrics.overall.psnr, 20.0));
+        for channel in metrics.channels {
+            assert!(close(channel.mse, 0.01));
+            assert_eq!(channel.max_error, 51);
+        }
+    }
+
+    #[test]
+    fn bit_depths_are_normalised_alike() {
+        let (width, height) = (16, 12);
+        let a = gradient(width, height);
+        let b: Vec<u8> = a.iter().map(|val| val.saturating_add(9)).collect();
+
+        // The same picture at both depths is identical.
+        assert_eq!(mse(&eight_bit(width, height, a.clone()), &sixteen_bit(width, height, &a)).unwrap(), 0.0);
+
+        let low = compare(&eight_bit(width, height, a.clone()), &eight_bit(width, height, b.clone())).unwrap();
+        let high = compare(&sixteen_bit(width, height, &a), &sixteen_bit(width, height, &b)).unwrap();
+        assert!(close(low.overall.mse, high.overall.mse));
+        assert!(close(low.overall.psnr, high.overall.psnr));
+        assert!(close(low.overall.ssim, high.overall.ssim));
+        assert!(close(low.ms_ssim, high.ms_ssim));
+        assert_eq!((low.max_value, high.max_value), (255, 65535));
+        assert_eq!((low.overall.max_error, high.overall.max_error), (9, 9 * 257));
+    }
+
+    #[test]
+    fn sizes_must_match() {
+        let a = eight_bit(4, 2, gradient(4, 2));
+        let b = eight_bit(2, 4, gradient(2, 4));
+        assert!(mse(&a, &b).is_err());
+        assert!(psnr(&a, &b).is_err());
+        assert!(ssim(&a, &b).is_err());
+        assert!(ms_ssim(&a, &b).is_err());
+        assert!(compare(&a, &b).is_err());
+        assert!(difference(&a, &b).is_err());
+    }
+}
@@ -0,0 +1,651 @@
+use std::{error::Error, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::image::{Bitmap, BitmapData, Image};
+use crate::selection::{crop, Selection};
+
+// Largest result a resize may produce, 1.5 GiB of 16-bit samples.
+pub const MAX_RESIZE_PIXELS: usize = 1 << 28;
+
+// Applies a generic function to whichever sample type the bitmap holds.
+macro_rules! map_bitmap {
+    ($data:expr, $func:ident ( $($arg:expr),* )) => {
+        match $data {
+            BitmapData::U8(data) => BitmapData::U8($func(data, $($arg),*)),
+            BitmapData::U16(data) => BitmapData::U16($func(data, $($arg),*)),
+            BitmapData::None => BitmapData::None,
+        }
+    };
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Invert,
+    Grayscale,
+    // Brightness is an offset relative to the max value, contrast a factor
+    // around mid grey.
+    BrightnessContrast { brightness: f64, contrast: f64 },
+    GaussianBlur { sigma: f64 },
+    // Without a level the threshold is picked with Otsu's method.
+    Threshold { level: Option<u16> },
+    FlipHorizontal,
+    FlipVertical,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    Crop { selection: Selection },
+    Resize { width: usize, height: usize },
+}
+
+impl Operation {
+    // Geometric operations change the pixel layout, so they can't be limited
+    // to a selection.
+    pub fn is_geometric(&self) -> bool {
+        matches!(
+            self,
+            Operation::FlipHorizontal
+                | Operation::FlipVertical
+                | Operation::Rotate90
+                | Operation::Rotate180
+                | Operation::Rotate270
+                | Operation::Crop { .. }
+                | Operation::Resize { .. }
+        )
+    }
+
+    // Rejects parameters the operation can't run with. Steps from outside,
+    // recipes and worker jobs, are checked before they're applied.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Operation::GaussianBlur { sigma } if !sigma.is_finite() || *sigma <= 0.0 => {
+                Err(format!("Blur sigma must be a positive number, not {}.", sigma).into())
+            }
+            Operation::Resize { width, height } => {
+                let samples = width.checked_mul(*height).and_then(|val| val.checked_mul(3));
+                match samples {
+                    Some(samples) if samples <= MAX_RESIZE_PIXELS * 3 => Ok(()),
+                    _ => Err(format!("Can't resize to {}x{}, the result would be too large.", width, height).into()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn apply(&self, image: &dyn Image) -> Bitmap {
+        let (width, height) = (image.get_width(), image.get_height());
+        let max_value = image.get_max_value();
+        let max = max_value as f32;
+        let data = image.get_buffer_ref();
+
+        let (width, height, data) = match self {
+            Operation::Invert => (width, height, map_bitmap!(data, invert(max))),
+            Operation::Grayscale => (width, height, map_bitmap!(data, grayscale())),
+            Operation::BrightnessContrast {
+                brightness,
+                contrast,
+            } => (
+                width,
+                height,
+                map_bitmap!(data, brightness_contrast(max, *brightness as f32, *contrast as f32)),
+            ),
+            Operation::GaussianBlur { sigma } => (
+                width,
+                height,
+                map_bitmap!(data, gaussian_blur(width, height, *sigma as f32)),
+            ),
+            Operation::Threshold { level } => {
+                let level = match level {
+                    Some(level) => *level as f32,
+                    None => otsu_level(image, None) as f32,
+                };
+                (width, height, map_bitmap!(data, threshold(max, level)))
+            }
+            Operation::FlipHorizontal => (width, height, map_bitmap!(data, flip_horizontal(width))),
+            Operation::FlipVertical => (width, height, map_bitmap!(data, flip_vertical(width))),
+            Operation::Rotate90 => (height, width, map_bitmap!(data, rotate90(width, height))),
+            Operation::Rotate180 => (width, height, map_bitmap!(data, rotate180())),
+            Operation::Rotate270 => (height, width, map_bitmap!(data, rotate270(width, height))),
+            Operation::Crop { selection } => {
+                return match crop(image, selection) {
+                    Some(cropped) => cropped,
+                    None => copy(image),
+                }
+            }
+            Operation::Resize {
+                width: new_width,
+                height: new_height,
+            } => (
+                *new_width,
+                *new_height,
+                map_bitmap!(data, resize(width, height, *new_width, *new_height)),
+            ),
+        };
+
+        let mut result = Bitmap::new(width, height, max_value, data);
+        result.copy_metadata(image);
+
+        return result;
+    }
+
+    // Applies the operation, keeping pixels outside the selection untouched.
+    // Only the selection's bounding box is processed, with the pixels a blur
+    // reads around it; Otsu's level comes from the selected pixels alone.
+    pub fn apply_in(&self, image: &dyn Image, selection: Option<&Selection>) -> Bitmap {
+        let selection = match selection {
+            Some(selection) if !self.is_geometric() => selection,
+            _ => return self.apply(image),
+        };
+
+        let (width, height) = (image.get_width(), image.get_height());
+        let (x, y, selected_width, selected_height) = match selection.bounds(width, height) {
+            Some(bounds) => bounds,
+            None => return copy(image),
+        };
+        let margin = match self {
+            Operation::GaussianBlur { sigma } => blur_radius(*sigma as f32, width, height),
+            _ => 0,
+        };
+        let (x0, y0) = (x.saturating_sub(margin), y.saturating_sub(margin));
+        let (x1, y1) = ((x + selected_width + margin).min(width), (y + selected_height + margin).min(height));
+        let region = (x0, y0, x1 - x0, y1 - y0);
+
+        let operation = match self {
+            Operation::Threshold { level: None } => Operation::Threshold {
+                level: Some(otsu_level(image, Some(selection))),
+            },
+            _ => self.clone(),
+        };
+        let max_value = image.get_max_value();
+        let data = match image.get_buffer_ref() {
+            BitmapData::U8(original) => {
+                let source = Bitmap::new(region.2, region.3, max_value, BitmapData::U8(extract(original, width, region)));
+                match operation.apply(&source).get_buffer_ref() {
+                    BitmapData::U8(changed) => BitmapData::U8(mask(original, changed, width, region, selection)),
+                    _ => return copy(image),
+                }
+            }
+            BitmapData::U16(original) => {
+                let source = Bitmap::new(region.2, region.3, max_value, BitmapData::U16(extract(original, width, region)));
+                match operation.apply(&source).get_buffer_ref() {
+                    BitmapData::U16(changed) => BitmapData::U16(mask(original, changed, width, region, selection)),
+                    _ => return copy(image),
+                }
+            }
+            BitmapData::None => return copy(image),
+        };
+
+        let mut masked = Bitmap::new(width, image.get_height(), image.get_max_value(), data);
+        masked.copy_metadata(image);
+
+        return masked;
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Invert => write!(f, "Invert"),
+            Operation::Grayscale => write!(f, "Grayscale"),
+            Operation::BrightnessContrast {
+                brightness,
+                contrast,
+            } => write!(f, "Brightness {:+.2}, contrast {:.2}", brightness, contrast),
+            Operation::GaussianBlur { sigma } => write!(f, "Gaussian blur σ={}", sigma),
+            Operation::Threshold { level: Some(level) } => write!(f, "Threshold {}", level),
+            Operation::Threshold { level: None } => write!(f, "Threshold (Otsu)"),
+            Operation::FlipHorizontal => write!(f, "Flip horizontal"),
+            Operation::FlipVertical => write!(f, "Flip vertical"),
+            Operation::Rotate90 => write!(f, "Rotate 90°"),
+            Operation::Rotate180 => write!(f, "Rotate 180°"),
+            Operation::Rotate270 => write!(f, "Rotate 270°"),
+            Operation::Crop { selection } => write!(f, "Crop {}", selection),
+            Operation::Resize { width, height } => write!(f, "Resize to {}x{}", width, height),
+        }
+    }
+}
+
+pub trait Channel: Copy + Default {
+    fn to_f32(self) -> f32;
+    fn from_f32(val: f32) -> Self;
+}
+
+impl Channel for u8 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        val.round().clamp(0.0, u8::MAX as f32) as u8
+    }
+}
+
+impl Channel for u16 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(val: f32) -> Self {
+        val.round().clamp(0.0, u16::MAX as f32) as u16
+    }
+}
+
+pub fn copy(image: &dyn Image) -> Bitmap {
+    let data = match image.get_buffer_ref() {
+        BitmapData::U8(data) => BitmapData::U8(data.clone()),
+        BitmapData::U16(data) => BitmapData::U16(data.clone()),
+        BitmapData::None => BitmapData::None,
+    };
+
+    let mut copied = Bitmap::new(image.get_width(), image.get_height(), image.get_max_value(), data);
+    copied.copy_metadata(image);
+
+    return copied;
+}
+
+// Pixels of a rectangle given as (x, y, width, height).
+fn extract<T: Copy>(
+    data: &[T],
+    width: usize,
+    (x0, y0, region_width, region_height): (usize, usize, usize, usize),
+) -> Vec<T> {
+    let mut out = Vec::with_capacity(region_width * region_height * 3);
+    for y in y0..y0 + region_height {
+        let start = (y * width + x0) * 3;
+        out.extend_from_slice(&data[start..start + region_width * 3]);
+    }
+
+    return out;
+}
+
+// The original with the selected pixels taken from the processed region.
+fn mask<T: Copy>(
+    original: &[T],
+    changed: &[T],
+    width: usize,
+    (x0, y0, region_width, region_height): (usize, usize, usize, usize),
+    selection: &Selection,
+) -> Vec<T> {
+    let mut out = original.to_vec();
+    for y in y0..y0 + region_height {
+        for x in x0..x0 + region_width {
+            if selection.contains(x, y) {
+                let from = ((y - y0) * region_width + x - x0) * 3;
+                let to = (y * width + x) * 3;
+                out[to..to + 3].copy_from_slice(&changed[from..from + 3]);
+            }
+        }
+    }
+
+    return out;
+}
+
+fn luma<T: Channel>(pixel: &[T]) -> f32 {
+    0.299 * pixel[0].to_f32() + 0.587 * pixel[1].to_f32() + 0.114 * pixel[2].to_f32()
+}
+
+fn invert<T: Channel>(data: &[T], max: f32) -> Vec<T> {
+    data.iter().map(|val| T::from_f32(max - val.to_f32())).collect()
+}
+
+fn grayscale<T: Channel>(data: &[T]) -> Vec<T> {
+    let mut out = Vec::with_capacity(data.len());
+    for pixel in data.chunks(3) {
+        let val = T::from_f32(luma(pixel));
+        out.extend_from_slice(&[val, val, val]);
+    }
+
+    return out;
+}
+
+fn brightness_contrast<T: Channel>(data: &[T], max: f32, brightness: f32, contrast: f32) -> Vec<T> {
+    let mid = max / 2.0;
+    data.iter()
+        .map(|val| T::from_f32(((val.to_f32() - mid) * contrast + mid + brightness * max).clamp(0.0, max)))
+        .collect()
+}
+
+fn threshold<T: Channel>(data: &[T], max: f32, level: f32) -> Vec<T> {
+    let mut out = Vec::with_capacity(data.len());
+    for pixel in data.chunks(3) {
+        let val = if luma(pixel) >= level { T::from_f32(max) } else { T::default() };
+        out.extend_from_slice(&[val, val, val]);
+    }
+
+    return out;
+}
+
+// The kernel is cut off at three sigma. One wider than the image only adds
+// more clamped edge samples.
+fn blur_radius(sigma: f32, width: usize, height: usize) -> usize {
+    return ((sigma * 3.0).ceil() as usize).min(width.max(height));
+}
+
+// Separable blur with the kernel cut off at three sigma, edges are clamped.
+fn gaussian_blur<T: Channel>(data: &[T], width: usize, height: usize, sigma: f32) -> Vec<T> {
+    if !sigma.is_finite() || sigma <= 0.0 || width == 0 || height == 0 {
+        return data.to_vec();
+    }
+
+    let radius = blur_radius(sigma, width, height) as isize;
+    let kernel: Vec<f32> = (-radius..=radius)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = kernel.iter().sum();
+    let kernel: Vec<f32> = kernel.iter().map(|val| val / total).collect();
+
+    let mut horizontal = vec![0.0f32; data.len()];
+    for y in 0..height {
+        for x in 0..width {
+            for channel in 0..3 {
+                let mut sum = 0.0;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let sx = (x as isize + k as isize - radius).clamp(0, width as isize - 1) as usize;
+                    sum += data[(y * width + sx) * 3 + channel].to_f32() * weight;
+                }
+                horizontal[(y * width + x) * 3 + channel] = sum;
+            }
+        }
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    for y in 0..height {
+        for x in 0..width {
+            for channel in 0..3 {
+                let mut sum = 0.0;
+                for (k, weight) in kernel.iter().enumerate() {
+                    let sy = (y as isize + k as isize - radius).clamp(0, height as isize - 1) as usize;
+                    sum += horizontal[(sy * width + x) * 3 + channel] * weight;
+                }
+                out.push(T::from_f32(sum));
+            }
+        }
+    }
+
+    return out;
+}
+
+// Level maximising the between-class variance of the luma histogram, of the
+// selected pixels when there's a selection.
+pub fn otsu_level(image: &dyn Image, selection: Option<&Selection>) -> u16 {
+    let max_value = image.get_max_value().max(1);
+    let mut histogram = vec![0u64; max_value + 1];
+    let (width, height) = (image.get_width(), image.get_height());
+    let (x0, y0, region_width, region_height) = match selection {
+        Some(selection) => selection.bounds(width, height).unwrap_or_default(),
+        None => (0, 0, width, height),
+    };
+    let mut total = 0.0;
+    for y in y0..y0 + region_height {
+        for x in x0..x0 + region_width {
+            if selection.is_some_and(|selection| !selection.contains(x, y)) {
+                continue;
+            }
+
+            let (r, g, b) = image.get_pixel_value(x, y);
+            let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+            histogram[(luma.round() as usize).min(max_value)] += 1;
+            total += 1.0;
+        }
+    }
+
+    let sum: f64 = histogram.iter().enumerate().map(|(level, count)| level as f64 * *count as f64).sum();
+    let mut sum_background = 0.0;
+    let mut weight_background = 0.0;
+    let mut best = (0, 0.0);
+    for (level, count) in histogram.iter().enumerate() {
+        weight_background += *count as f64;
+        if weight_background == 0.0 {
+            continue;
+        }
+
+        let weight_foreground = total - weight_background;
+        if weight_foreground == 0.0 {
+            break;
+        }
+
+        sum_background += level as f64 * *count as f64;
+        let mean_background = sum_background / weight_background;
+        let mean_foreground = (sum - sum_background) / weight_foreground;
+        let variance = weight_background * weight_foreground * (mean_background - mean_foreground).powi(2);
+        if variance > best.1 {
+            best = (level, variance);
+        }
+    }
+
+    // Pixels above the best split are foreground.
+    return (best.0 + 1).min(max_value) as u16;
+}
+
+fn flip_horizontal<T: Copy>(data: &[T], width: usize) -> Vec<T> {
+    // An image without columns has no pixels to move.
+    if width == 0 {
+        return data.to_vec();
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    for row in data.chunks(width * 3) {
+        for pixel in row.chunks(3).rev() {
+            out.extend_from_slice(pixel);
+        }
+    }
+
+    return out;
+}
+
+fn flip_vertical<T: Copy>(data: &[T], width: usize) -> Vec<T> {
+    // An image without columns has no pixels to move.
+    if width == 0 {
+        return data.to_vec();
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    for row in data.chunks(width * 3).rev() {
+        out.extend_from_slice(row);
+    }
+
+    return out;
+}
+
+fn rotate180<T: Copy>(data: &[T]) -> Vec<T> {
+    let mut out = Vec::with_capacity(data.len());
+    for pixel in data.chunks(3).rev() {
+        out.extend_from_slice(pixel);
+    }
+
+    return out;
+}
+
+// Clockwise.
+fn rotate90<T: Copy>(data: &[T], width: usize, height: usize) -> Vec<T> {
+    let mut out = Vec::with_capacity(data.len());
+    for x in 0..width {
+        for y in (0..height).rev() {
+            let index = (y * width + x) * 3;
+            out.extend_from_slice(&data[index..index + 3]);
+        }
+    }
+
+    return out;
+}
+
+fn rotate270<T: Copy>(data: &[T], width: usize, height: usize) -> Vec<T> {
+    let mut out = Vec::with_capacity(data.len());
+    for x in (0..width).rev() {
+        for y in 0..height {
+            let index = (y * width + x) * 3;
+            out.extend_from_slice(&data[index..index + 3]);
+        }
+    }
+
+    return out;
+}
+
+// Source range and normalised weights for every output sample along one axis.
+// The triangle filter is widened when shrinking so no source pixel is skipped.
+fn resample_weights(size: usize, new_size: usize) -> Vec<(usize, Vec<f32>)> {
+    let scale = size as f32 / new_size as f32;
+    let support = scale.max(1.0);
+
+    return (0..new_size)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale;
+            let start = ((center - support).floor().max(0.0) as usize).min(size - 1);
+            let end = ((center + support).ceil() as usize).clamp(start + 1, size);
+            let mut weights: Vec<f32> = (start..end)
+                .map(|j| (1.0 - ((j as f32 + 0.5 - center) / support).abs()).max(0.0))
+                .collect();
+
+            let total: f32 = weights.iter().sum();
+            if total > 0.0 {
+                weights.iter_mut().for_each(|weight| *weight /= total);
+            } else {
+                weights = vec![0.0; end - start];
+                weights[0] = 1.0;
+            }
+
+            (start, weights)
+        })
+        .collect();
+}
+
+fn resize<T: Channel>(
+    data: &[T],
+    width: usize,
+    height: usize,
+    new_width: usize,
+    new_height: usize,
+) -> Vec<T> {
+    if width == 0 || height == 0 || new_width == 0 || new_height == 0 {
+        return vec![T::default(); new_width * new_height * 3];
+    }
+
+    let columns = resample_weights(width, new_width);
+    let mut horizontal = vec![0.0f32; new_width * height * 3];
+    for y in 0..height {
+        for (x, (start, weights)) in columns.iter().enumerate() {
+            for channel in 0..3 {
+                let mut sum = 0.0;
+                for (k, weight) in weights.iter().enumerate() {
+                    sum += data[(y * width + start + k) * 3 + channel].to_f32() * weight;
+                }
+                horizontal[(y * new_width + x) * 3 + channel] = sum;
+            }
+        }
+    }
+
+    let rows = resample_weights(height, new_height);
+    let mut out = Vec::with_capacity(new_width * new_height * 3);
+    for (start, weights) in rows.iter() {
+        for x in 0..new_width {
+            for channel in 0..3 {
+                let mut sum = 0.0;
+                for (k, weight) in weights.iter().enumerate() {
+                    sum += horizontal[((start + k) * new_width + x) * 3 + channel] * weight;
+                }
+                out.push(T::from_f32(sum));
+            }
+        }
+    }
+
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: usize, height: usize, data: Vec<u8>) -> Bitmap {
+        return Bitmap::new(width, height, 255, BitmapData::U8(data));
+    }
+
+    #[test]
+    fn blur_sigma_is_validated() {
+        for sigma in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(Operation::GaussianBlur { sigma }.validate().is_err());
+        }
+        assert!(Operation::GaussianBlur { sigma: 1.5 }.validate().is_ok());
+    }
+
+    #[test]
+    fn resize_size_is_validated() {
+        assert!(Operation::Resize { width: 100_000_000, height: 100_000_000 }.validate().is_err());
+        assert!(Operation::Resize { width: usize::MAX, height: 2 }.validate().is_err());
+        assert!(Operation::Resize { width: 1 << 14, height: 1 << 14 }.validate().is_ok());
+    }
+
+    fn grey(width: usize, height: usize, values: &[u8]) -> Bitmap {
+        return image(width, height, values.iter().flat_map(|val| [*val; 3]).collect());
+    }
+
+    fn samples(image: &Bitmap) -> Vec<u8> {
+        match image.get_buffer_ref() {
+            BitmapData::U8(data) => data.clone(),
+            _ => panic!("Not an 8-bit image."),
+        }
+    }
+
+    // The whole row splits between 120 and 255, the selection between 100
+    // and 120.
+    #[test]
+    fn otsu_level_comes_from_the_selection() {
+        let source = grey(6, 1, &[0, 0, 100, 120, 255, 255]);
+        let selection = Selection::Rectangle { x: 2, y: 0, width: 2, height: 1 };
+        assert_eq!(otsu_level(&source, None), 121);
+        assert_eq!(otsu_level(&source, Some(&selection)), 101);
+
+        let result = Operation::Threshold { level: None }.apply_in(&source, Some(&selection));
+        assert_eq!(samples(&result), samples(&grey(6, 1, &[0, 0, 0, 255, 255, 255])));
+        let result = Operation::Threshold { level: None }.apply(&source);
+        assert_eq!(samples(&result), samples(&grey(6, 1, &[0, 0, 0, 0, 255, 255])));
+    }
+
+    // Processing just the bounding box gives the selected pixels the values
+    // of the whole frame processed, the rest keeps its own.
+    #[test]
+    fn selections_match_the_whole_frame() {
+        let (width, height) = (12, 10);
+        let source = image(width, height, (0..width * height * 3).map(|val| (val * 37 % 256) as u8).collect());
+        let selections = [
+            Selection::Rectangle { x: 3, y: 2, width: 4, height: 5 },
+            Selection::Ellipse { cx: 6.0, cy: 5.0, rx: 3.5, ry: 2.5 },
+            Selection::Freehand { points: vec![(0.0, 0.0), (9.0, 1.0), (2.0, 8.0)] },
+            Selection::Rectangle { x: 8, y: 6, width: 100, height: 100 },
+        ];
+        let operations = [
+            Operation::GaussianBlur { sigma: 1.2 },
+            Operation::Invert,
+            Operation::BrightnessContrast { brightness: 0.1, contrast: 1.5 },
+            Operation::Threshold { level: Some(90) },
+        ];
+
+        for selection in selections.iter() {
+            for operation in operations.iter() {
+                let whole = samples(&operation.apply(&source));
+                let result = samples(&operation.apply_in(&source, Some(selection)));
+                let original = samples(&source);
+                for pixel in 0..width * height {
+                    let expected = if selection.contains(pixel % width, pixel / width) { &whole } else { &original };
+                    let samples = pixel * 3..pixel * 3 + 3;
+                    assert_eq!(result[samples.clone()], expected[samples], "{} in {}", operation, selection);
+                }
+            }
+        }
+
+        let outside = Selection::Rectangle { x: 20, y: 20, width: 2, height: 2 };
+        assert_eq!(samples(&Operation::Invert.apply_in(&source, Some(&outside))), samples(&source));
+    }
+
+    // The kernel is cut to the image size, a huge sigma is a flat box over
+    // the clamped edges.
+    #[test]
+    fn huge_blur_is_bounded_by_the_image() {
+        let source = image(2, 1, vec![0, 0, 0, 200, 200, 200]);
+        let blurred = Operation::GaussianBlur { sigma: 1e12 }.apply(&source);
+        match blurred.get_buffer_ref() {
+            BitmapData::U8(data) => assert_eq!(data, &vec![80, 80, 80, 120, 120, 120]),
+            _ => panic!("Blur changed the sample type."),
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use wasm_bindgen::JsValue;
+use web_sys::CanvasRenderingContext2d;
+
+use ppm::image::Image;
+use ppm::selection::Selection;
+use crate::view::ViewState;
+
+// Pixel size on screen above which the grid, and later the values, are shown.
+pub const GRID_MIN_SCALE: f64 = 8.0;
+pub const LABEL_MIN_SCALE: f64 = 40.0;
+
+pub fn labels_visible(view: &ViewState) -> bool {
+    view.scale >= LABEL_MIN_SCALE
+}
+
+// Range of image pixels intersecting the viewport, as (x0, y0, x1, y1) with
+// exclusive upper bounds.
+fn visible_pixels(
+    view: &ViewState,
+    viewport: (f64, f64),
+    image: (usize, usize),
+) -> Option<(usize, usize, usize, usize)> {
+    let (x0, y0) = view.screen_to_image((0.0, 0.0));
+    let (x1, y1) = view.screen_to_image(viewport);
+
+    let x0 = x0.floor().max(0.0) as usize;
+    let y0 = y0.floor().max(0.0) as usize;
+    let x1 = (x1.ceil().max(0.0) as usize).min(image.0);
+    let y1 = (y1.ceil().max(0.0) as usize).min(image.1);
+    if x0 >= x1 || y0 >= y1 {
+        return None;
+    }
+
+    return Some((x0, y0, x1, y1));
+}
+
+pub fn draw_pixel_grid(
+    ctx: &CanvasRenderingContext2d,
+    image: &dyn Image,
+    view: &ViewState,
+    viewport: (f64, f64),
+) {
+    if view.scale < GRID_MIN_SCALE {
+        return;
+    }
+
+    let (x0, y0, x1, y1) =
+        match visible_pixels(view, viewport, (image.get_width(), image.get_height())) {
+            Some(range) => range,
+            None => return,
+        };
+
+    let (left, top) = view.image_to_screen((x0 as f64, y0 as f64));
+    let (right, bottom) = view.image_to_screen((x1 as f64, y1 as f64));
+
+    ctx.save();
+    ctx.set_stroke_style(&JsValue::from_str("rgba(128, 128, 128, 0.6)"));
+    ctx.set_line_width(1.0);
+    ctx.begin_path();
+    for x in x0..=x1 {
+        let screen_x = view.image_to_screen((x as f64, 0.0)).0.round() + 0.5;
+        ctx.move_to(screen_x, top);
+        ctx.line_to(screen_x, bottom);
+    }
+    for y in y0..=y1 {
+        let screen_y = view.image_to_screen((0.0, y as f64)).1.round() + 0.5;
+        ctx.move_to(left, screen_y);
+        ctx.line_to(right, screen_y);
+    }
+    ctx.stroke();
+
+    if labels_visible(view) {
+        draw_pixel_labels(ctx, image, view, (x0, y0, x1, y1));
+    }
+
+    ctx.restore();
+}
+
+fn draw_pixel_labels(
+    ctx: &CanvasRenderingContext2d,
+    image: &dyn Image,
+    view: &ViewState,
+    range: (usize, usize, usize, usize),
+) {
+    let (x0, y0, x1, y1) = range;
+    // Three rows of up to five digits have to fit into a cell.
+    let font_size = (view.scale / 5.0).min(16.0).floor();
+    let line_height = font_size * 1.1;
+
+    ctx.set_font(&format!("{}px monospace", font_size));
+    ctx.set_text_align("center");
+    ctx.set_text_baseline("middle");
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let (r, g, b) = image.get_pixel_value(x, y);
+            let (center_x, center_y) = view.image_to_screen((x as f64 + 0.5, y as f64 + 0.5));
+
+            // Pick a text colour that stays readable on top of the pixel.
+            let luminance = (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64)
+                / image.get_max_value().max(1) as f64;
+            ctx.set_fill_style(&JsValue::from_str(if luminance > 0.5 {
+                "black"
+            } else {
+                "white"
+            }));
+
+            for (line, value) in [r, g, b].iter().enumerate() {
+                let offset = (line as f64 - 1.0) * line_height;
+                ctx.fill_text(&value.to_string(), center_x, center_y + offset)
+                    .unwrap();
+            }
+        }
+    }
+}
+
+pub fn draw_selection(ctx: &CanvasRenderingContext2d, selection: &Selection, view: &ViewState) {
+    ctx.save();
+    ctx.begin_path();
+    match selection {
+        Selection::Rectangle {
+            x,
+            y,
+            width,
+            height,
+        } => {
+            let (left, top) = view.image_to_screen((*x as f64, *y as f64));
+            ctx.rect(left, top, *width as f64 * view.scale, *height as f64 * view.scale);
+        }
+        Selection::Ellipse { cx, cy, rx, ry } => {
+            let (center_x, center_y) = view.image_to_screen((*cx, *cy));
+            ctx.ellipse(
+                center_x,
+                center_y,
+                rx * view.scale,
+                ry * view.scale,
+                0.0,
+                0.0,
+                std::f64::consts::TAU,
+            )
+            .unwrap();
+        }
+        Selection::Freehand { points } => {
+            for (index, point) in points.iter().enumerate() {
+                let (screen_x, screen_y) = view.image_to_screen(*point);
+                if index == 0 {
+                    ctx.move_to(screen_x, screen_y);
+                } else {
+                    ctx.line_to(screen_x, screen_y);
+                }
+            }
+            ctx.close_path();
+        }
+    }
+
+    // Black dashes over a white line stay visible on any background.
+    ctx.set_line_width(1.0);
+    ctx.set_stroke_style(&JsValue::from_str("white"));
+    ctx.stroke();
+    ctx.set_line_dash(&js_sys::Array::of2(&JsValue::from(4.0), &JsValue::from(4.0)))
+        .unwrap();
+    ctx.set_stroke_style(&JsValue::from_str("black"));
+    ctx.stroke();
+    ctx.restore();
+}
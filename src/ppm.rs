@@ -12,24 +12,36 @@ pub struct PPM {
     max_value: usize,
     ver: PPMVer,
     buffer: BitmapData,
+    comments: Vec<String>,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PPMVer {
+    P1,
+    P2,
     P3,
+    P4,
+    P5,
     P6,
     None,
 }
 
-impl PPM {
-    pub fn from_file(file_path: &str) -> Self {
-        let mut ppm = PPM {
+impl Default for PPM {
+    fn default() -> Self {
+        PPM {
             width: 0,
             height: 0,
             max_value: 0,
             ver: PPMVer::None,
             buffer: BitmapData::None,
-        };
+            comments: Vec::new(),
+        }
+    }
+}
+
+impl PPM {
+    pub fn from_file(file_path: &str) -> Self {
+        let mut ppm = PPM::default();
 
         ppm.populate_from_file(file_path)
             .expect("Couldn't parse ppm file.");
@@ -46,19 +58,13 @@ impl PPM {
             reader.read_to_end(&mut buffer)?;
         }
 
-        self.populate_from_buffer(&mut buffer)?;
+        self.populate_from_buffer(&buffer)?;
 
         return Ok(());
     }
 
-    pub fn from_buffer(buffer: &mut Vec<u8>) -> Self {
-        let mut ppm = PPM {
-            width: 0,
-            height: 0,
-            max_value: 0,
-            ver: PPMVer::None,
-            buffer: BitmapData::None,
-        };
+    pub fn from_buffer(buffer: &[u8]) -> Self {
+        let mut ppm = PPM::default();
 
         ppm.populate_from_buffer(buffer)
             .expect("Couldn't parse ppm file.");
@@ -66,97 +72,162 @@ impl PPM {
         return ppm;
     }
 
-    fn populate_from_buffer(&mut self, buffer: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
-        let mut is_commented = false;
-        let mut is_multiple_whitespace = false;
-        let mut is_last_whitespace = false;
-        let ver_buf = &buffer[0..2];
-        let ver_str = std::str::from_utf8(ver_buf).expect("Couldn't parse header.");
-        let is_p6 = ver_str == "P6";
-        let mut div_count = 0;
-        const HEADER_DIVS: i32 = 5;
-
-        buffer.retain(|val| {
-            if is_p6 && div_count >= HEADER_DIVS {
-                return true;
-            }
-
-            if *val == b'#' {
-                is_commented = true;
-            }
-
-            if (*val as char).is_whitespace() {
-                if !is_last_whitespace && !is_commented {
-                    div_count += 1;
-                }
-
-                if is_last_whitespace {
-                    is_multiple_whitespace = true;
-                }
-
-                is_last_whitespace = true;
-            } else {
-                is_last_whitespace = false;
-                is_multiple_whitespace = false;
-            }
-
-            let should_retain = (!is_commented.clone() && !is_multiple_whitespace.clone())
-                || (is_p6 && div_count >= HEADER_DIVS);
-            if *val == b'\n' {
-                is_commented = false;
-            }
-
-            return should_retain;
-        });
+    // Reads any of the netpbm formats. Grey and bitmap images are expanded to
+    // RGB; bitmaps use 0 and 255 for their two levels.
+    pub fn populate_from_buffer(&mut self, buffer: &[u8]) -> Result<(), Box<dyn Error>> {
+        return self.populate_from_buffer_with_progress(buffer, &mut |_| {});
+    }
 
-        let header_string = get_header_string(buffer);
+    // Reports the fraction of the pixel data read so far. Only the text
+    // formats are slow enough to report along the way.
+    pub fn populate_from_buffer_with_progress(
+        &mut self,
+        buffer: &[u8],
+        progress: &mut dyn FnMut(f64),
+    ) -> Result<(), Box<dyn Error>> {
+        let mut pos = 0;
+        self.comments.clear();
+
+        let header_string =
+            next_token(buffer, &mut pos, &mut self.comments).ok_or("Invalid ppm header.")?;
         self.ver = match header_string.as_str() {
+            "P1" => Ok(PPMVer::P1),
+            "P2" => Ok(PPMVer::P2),
             "P3" => Ok(PPMVer::P3),
+            "P4" => Ok(PPMVer::P4),
+            "P5" => Ok(PPMVer::P5),
             "P6" => Ok(PPMVer::P6),
             _ => Err("Invalid ppm header version."),
         }?;
 
-        let width_string = get_header_string(buffer);
-        self.width = width_string.parse().expect("Invalid width parameter.");
-
-        let height_string = get_header_string(buffer);
-        self.height = height_string.parse().expect("Invalid height parameter.");
-
-        let max_value_string = get_header_string(buffer);
-        self.max_value = max_value_string
-            .parse()
-            .expect("Invalid max value parameter.");
-
-        let mut u16_buffer = Vec::new();
-        if self.ver == PPMVer::P3 {
-            let mut num_string = String::new();
-            for val in buffer {
-                if (*val as char).is_whitespace() {
-                    if num_string.len() > 0 {
-                        let num: u16 = num_string.parse().expect("Invalid number.");
-                        u16_buffer.push(num);
-                        num_string.clear();
+        self.width = next_token(buffer, &mut pos, &mut self.comments)
+            .and_then(|val| val.parse().ok())
+            .ok_or("Invalid width parameter.")?;
+        self.height = next_token(buffer, &mut pos, &mut self.comments)
+            .and_then(|val| val.parse().ok())
+            .ok_or("Invalid height parameter.")?;
+
+        let is_bitmap = matches!(self.ver, PPMVer::P1 | PPMVer::P4);
+        self.max_value = if is_bitmap {
+            1
+        } else {
+            next_token(buffer, &mut pos, &mut self.comments)
+                .and_then(|val| val.parse().ok())
+                .filter(|val| *val > 0 && *val <= u16::MAX as usize)
+                .ok_or("Invalid max value parameter.")?
+        };
+
+        // A single whitespace character separates the header from binary data.
+        pos += 1;
+
+        let samples_per_pixel = match self.ver {
+            PPMVer::P3 | PPMVer::P6 => 3,
+            _ => 1,
+        };
+        let count = self
+            .width
+            .checked_mul(self.height)
+            .and_then(|val| val.checked_mul(samples_per_pixel))
+            .ok_or("Image dimensions are too large.")?;
+        let data = buffer.get(pos.min(buffer.len())..).unwrap_or_default();
+
+        let samples: Vec<u16> = match self.ver {
+            PPMVer::P1 => {
+                // Digits need no whitespace between them, comments may still
+                // come in between.
+                let mut samples = Vec::with_capacity(count.min(data.len()));
+                let mut bytes = data.iter();
+                while samples.len() < count {
+                    match bytes.next() {
+                        Some(b'#') => {
+                            bytes.by_ref().find(|val| **val == b'\n');
+                        }
+                        Some(val @ (b'0' | b'1')) => samples.push((*val - b'0') as u16),
+                        Some(val) if val.is_ascii_whitespace() => {}
+                        Some(_) => return Err("Invalid bitmap value.".into()),
+                        None => break,
                     }
-                } else {
-                    num_string.push(*val as char);
                 }
+                samples
             }
-        } else {
-            u16_buffer = buffer.iter().map(|val| *val as u16).collect();
+            PPMVer::P2 | PPMVer::P3 => {
+                pos -= 1;
+                // Sizes come from the header, every sample takes at least a byte.
+                let mut samples = Vec::with_capacity(count.min(data.len()));
+                let step = (count / 100).max(1);
+                while samples.len() < count {
+                    if samples.len() % step == 0 {
+                        progress(samples.len() as f64 / count as f64);
+                    }
+                    match next_token(buffer, &mut pos, &mut self.comments) {
+                        Some(val) => samples.push(val.parse().map_err(|_| "Invalid number.")?),
+                        None => break,
+                    }
+                }
+                samples
+            }
+            PPMVer::P4 => {
+                // Rows are padded to whole bytes.
+                let row_bytes = self.width.div_ceil(8);
+                let mut samples = Vec::with_capacity(count.min(data.len().saturating_mul(8)));
+                for row in data.chunks(row_bytes).take(self.height) {
+                    for x in 0..self.width.min(row.len() * 8) {
+                        samples.push(((row[x / 8] >> (7 - x % 8)) & 1) as u16);
+                    }
+                }
+                samples
+            }
+            PPMVer::P5 | PPMVer::P6 => {
+                let bytes = if self.max_value > u8::MAX as usize { 2 } else { 1 };
+                let mut samples = Vec::with_capacity(count.min(data.len() / bytes));
+                // Copied a hundredth at a time to report how far it got.
+                let step = (count / 100).max(1);
+                for chunk in data.chunks(step * bytes).take(count.div_ceil(step)) {
+                    progress(samples.len() as f64 / count as f64);
+                    samples.extend(chunk.chunks_exact(bytes).map(|val| match val {
+                        [high, low] => u16::from_be_bytes([*high, *low]),
+                        _ => val[0] as u16,
+                    }));
+                }
+                samples.truncate(count);
+                samples
+            }
+            PPMVer::None => Vec::new(),
+        };
+
+        if samples.len() < count {
+            return Err("Unexpected end of image data.".into());
+        }
+        if samples.iter().any(|val| *val as usize > self.max_value) {
+            return Err("Sample larger than the max value.".into());
         }
 
+        let samples: Vec<u16> = match self.ver {
+            PPMVer::P1 | PPMVer::P4 => {
+                self.max_value = u8::MAX as usize;
+                samples
+                    .iter()
+                    .flat_map(|val| [if *val == 1 { 0 } else { u8::MAX as u16 }; 3])
+                    .collect()
+            }
+            PPMVer::P2 | PPMVer::P5 => samples.iter().flat_map(|val| [*val; 3]).collect(),
+            _ => samples,
+        };
+
         if self.max_value <= u8::MAX.into() {
-            self.buffer = BitmapData::U8(u16_buffer.iter().map(|val| *val as u8).collect());
+            self.buffer = BitmapData::U8(samples.iter().map(|val| *val as u8).collect());
         } else {
-            self.buffer = BitmapData::U16(u16_buffer);
+            self.buffer = BitmapData::U16(samples);
         }
+        progress(1.0);
 
         return Ok(());
     }
 
-    pub fn get_max_value(&self) -> usize {
-        self.max_value
+    pub fn get_ver(&self) -> &PPMVer {
+        &self.ver
     }
+
 }
 
 impl Image for PPM {
@@ -171,14 +242,91 @@ impl Image for PPM {
     fn get_height(&self) -> usize {
         self.height
     }
+
+    fn get_max_value(&self) -> usize {
+        self.max_value
+    }
+
+    // Text of the `#` comments in file order, without the leading `# `.
+    fn get_comments(&self) -> &[String] {
+        &self.comments
+    }
+}
+
+// Next whitespace separated token of the header, collecting the comments
+// skipped on the way.
+fn next_token(buffer: &[u8], pos: &mut usize, comments: &mut Vec<String>) -> Option<String> {
+    while *pos < buffer.len() {
+        if buffer[*pos] == b'#' {
+            let start = *pos + 1;
+            while *pos < buffer.len() && buffer[*pos] != b'\n' {
+                *pos += 1;
+            }
+
+            let comment = String::from_utf8_lossy(&buffer[start..*pos]);
+            let comment = comment.trim_end_matches('\r');
+            comments.push(comment.strip_prefix(' ').unwrap_or(comment).to_string());
+        } else if buffer[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        } else {
+            break;
+        }
+    }
+
+    let start = *pos;
+    while *pos < buffer.len() && !buffer[*pos].is_ascii_whitespace() && buffer[*pos] != b'#' {
+        *pos += 1;
+    }
+    if start == *pos {
+        return None;
+    }
+
+    return Some(String::from_utf8_lossy(&buffer[start..*pos]).into_owned());
 }
 
-fn get_header_string(vec: &mut Vec<u8>) -> String {
-    let header_end = vec
-        .iter()
-        .position(|val| (*val as char).is_whitespace())
-        .expect("Invalid ppm header.");
-    let header: Vec<u8> = vec.drain(..header_end + 1).take(header_end).collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(buffer: &[u8]) -> Result<PPM, Box<dyn Error>> {
+        let mut ppm = PPM::default();
+        ppm.populate_from_buffer(buffer)?;
 
-    return String::from(std::str::from_utf8(&header).expect("Invalid ppm header characters."));
+        return Ok(ppm);
+    }
+
+    fn samples(ppm: &PPM) -> Vec<u16> {
+        match ppm.get_buffer_ref() {
+            BitmapData::U8(data) => data.iter().map(|val| *val as u16).collect(),
+            BitmapData::U16(data) => data.clone(),
+            BitmapData::None => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn samples_above_the_max_value_are_rejected() {
+        assert!(decode(b"P2\n2 1\n255\n10 300\n").is_err());
+        assert!(decode(b"P3\n1 1\n1000\n0 1001 0\n").is_err());
+        assert!(decode(b"P5\n2 1\n100\n\x10\x80").is_err());
+        assert!(decode(b"P6\n1 1\n1000\n\x00\x00\x03\xE9\x00\x00").is_err());
+
+        let ppm = decode(b"P2\n2 1\n255\n10 255\n").unwrap();
+        assert_eq!(samples(&ppm), vec![10, 10, 10, 255, 255, 255]);
+        let ppm = decode(b"P3\n1 1\n1000\n0 1000 7\n").unwrap();
+        assert_eq!(samples(&ppm), vec![0, 1000, 7]);
+        assert!(matches!(ppm.get_buffer_ref(), BitmapData::U16(_)));
+    }
+
+    #[test]
+    fn comments_in_plain_bitmaps_are_skipped() {
+        let ppm = decode(b"P1\n# size\n3 1\n1 # 0011\n01\n").unwrap();
+        assert_eq!(samples(&ppm), vec![0, 0, 0, 255, 255, 255, 0, 0, 0]);
+
+        // Digits without whitespace between them.
+        let ppm = decode(b"P1\n3 1\n101").unwrap();
+        assert_eq!(samples(&ppm), vec![0, 0, 0, 255, 255, 255, 0, 0, 0]);
+
+        assert!(decode(b"P1\n3 1\n1 2 1\n").is_err());
+        assert!(decode(b"P1\n3 1\n1 # 0\n").is_err());
+    }
 }
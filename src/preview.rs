@@ -0,0 +1,108 @@
+use wasm_bindgen::{Clamped, JsCast};
+use web_sys::{window, CanvasRenderingContext2d, HtmlCanvasElement, ImageData};
+
+use ppm::image::{Bitmap, Image};
+use ppm::ops::Operation;
+
+// Delay after the last option change before the preview is encoded again.
+pub const PREVIEW_DELAY_MS: u32 = 250;
+
+// The current image run through the encoder with the export options, done
+// by a Job::PreviewJpeg.
+pub struct JpegPreview {
+    pub size: usize,
+    // Size of the image as 8-bit RGB.
+    pub raw_size: usize,
+    pub psnr: f64,
+    pub ssim: f64,
+    pub decoded: Bitmap,
+}
+
+impl JpegPreview {
+    pub fn ratio(&self) -> f64 {
+        self.raw_size as f64 / self.size.max(1) as f64
+    }
+
+    pub fn bits_per_pixel(&self) -> f64 {
+        self.size as f64 * 8.0 * 3.0 / self.raw_size.max(1) as f64
+    }
+}
+
+// Puts the image into the canvas at its native size, CSS scales it to fit.
+pub fn draw_to_canvas(id: &str, image: &dyn Image) {
+    let canvas = match window()
+        .and_then(|window| window.document())
+        .and_then(|document| document.get_element_by_id(id))
+        .and_then(|element| element.dyn_into::<HtmlCanvasElement>().ok())
+    {
+        Some(canvas) => canvas,
+        None => return,
+    };
+
+    let (width, height) = (image.get_width() as u32, image.get_height() as u32);
+    canvas.set_width(width);
+    canvas.set_height(height);
+    let ctx = canvas
+        .get_context("2d")
+        .unwrap()
+        .unwrap()
+        .dyn_into::<CanvasRenderingContext2d>()
+        .unwrap();
+
+    ctx.put_image_data(&image_data(image), 0.0, 0.0).unwrap();
+}
+
+// RGBA pixels for a 2D canvas, samples scaled to 0..=255.
+pub fn image_data(image: &dyn Image) -> ImageData {
+    let (width, height) = (image.get_width() as u32, image.get_height() as u32);
+    let max_value = image.get_max_value().max(1);
+    let mut rgba = Vec::with_capacity(width as usize * height as usize * 4);
+    for y in 0..image.get_height() {
+        for x in 0..image.get_width() {
+            let (r, g, b) = image.get_pixel_value(x, y);
+            for val in [r, g, b] {
+                rgba.push((val as usize * 255 / max_value).min(255) as u8);
+            }
+            rgba.push(255);
+        }
+    }
+
+    return ImageData::new_with_u8_clamped_array_and_sh(Clamped(&rgba), width, height).unwrap();
+}
+
+// A detached canvas holding the image at its native size.
+pub fn offscreen_canvas(image: &dyn Image) -> HtmlCanvasElement {
+    let canvas = window()
+        .unwrap()
+        .document()
+        .unwrap()
+        .create_element("canvas")
+        .unwrap()
+        .dyn_into::<HtmlCanvasElement>()
+        .unwrap();
+    canvas.set_width(image.get_width() as u32);
+    canvas.set_height(image.get_height() as u32);
+
+    let ctx = canvas
+        .get_context("2d")
+        .unwrap()
+        .unwrap()
+        .dyn_into::<CanvasRenderingContext2d>()
+        .unwrap();
+    ctx.put_image_data(&image_data(image), 0.0, 0.0).unwrap();
+
+    return canvas;
+}
+
+// PNG data URL of the image scaled down to fit a size x size square.
+pub fn thumbnail_url(image: &dyn Image, size: usize) -> String {
+    let (width, height) = (image.get_width().max(1), image.get_height().max(1));
+    let scale = (size as f64 / width.max(height) as f64).min(1.0);
+    let thumbnail = Operation::Resize {
+        width: ((width as f64 * scale).round() as usize).max(1),
+        height: ((height as f64 * scale).round() as usize).max(1),
+    }
+    .apply(image);
+
+    return offscreen_canvas(&thumbnail).to_data_url().unwrap();
+}
@@ -0,0 +1,224 @@
+use std::{error::Error, path::Path};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::history::Step;
+use crate::image::{Bitmap, Image};
+use crate::jpeg::JpegOptions;
+use crate::ops;
+
+// A processing pipeline that can be saved from the viewer and replayed by the
+// CLI. Stored as JSON or TOML, e.g.
+//
+//     [[steps]]
+//     op = "gaussian_blur"
+//     sigma = 2.0
+//     roi = { shape = "rect", x = 0, y = 0, width = 64, height = 64 }
+//
+//     [[steps]]
+//     op = "threshold"
+//
+//     [output]
+//     format = "pbm"
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Recipe {
+    #[serde(default, serialize_with = "serialize_enabled")]
+    pub steps: Vec<Step>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<OutputFormat>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum OutputFormat {
+    Ppm {
+        #[serde(default = "binary_by_default")]
+        binary: bool,
+    },
+    Pgm {
+        #[serde(default = "binary_by_default")]
+        binary: bool,
+    },
+    Pbm {
+        #[serde(default = "binary_by_default")]
+        binary: bool,
+    },
+    Jpeg(JpegOptions),
+}
+
+fn binary_by_default() -> bool {
+    true
+}
+
+// Disabled steps are left out, they'd come back enabled.
+fn serialize_enabled<S: Serializer>(steps: &[Step], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(steps.iter().filter(|step| step.enabled))
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Ppm { .. } => "ppm",
+            OutputFormat::Pgm { .. } => "pgm",
+            OutputFormat::Pbm { .. } => "pbm",
+            OutputFormat::Jpeg(_) => "jpeg",
+        }
+    }
+
+    pub fn encode(&self, image: &dyn Image, vec: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+        match self {
+            OutputFormat::Ppm { binary } => image.write_to_ppm(vec, *binary),
+            OutputFormat::Pgm { binary } => image.write_to_pgm(vec, *binary),
+            OutputFormat::Pbm { binary } => image.write_to_pbm(vec, *binary),
+            OutputFormat::Jpeg(options) => image.write_to_jpeg(vec, options)?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Recipe {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Recipe {
+            steps,
+            output: None,
+        }
+    }
+
+    // JSON when the text starts with an object, TOML otherwise.
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        if text.trim_start().starts_with('{') {
+            return Self::from_json(text);
+        }
+
+        return Self::from_toml(text);
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)?;
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&text),
+            Some("toml") => Self::from_toml(&text),
+            _ => Self::parse(&text),
+        }
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, Box<dyn Error>> {
+        return serde_json::from_str::<Recipe>(text)?.validated();
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, Box<dyn Error>> {
+        return toml::from_str::<Recipe>(text)?.validated();
+    }
+
+    pub fn to_json(&self) -> Result<String, Box<dyn Error>> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String, Box<dyn Error>> {
+        Ok(toml::to_string(self)?)
+    }
+
+    fn validated(self) -> Result<Self, Box<dyn Error>> {
+        for (index, step) in self.steps.iter().enumerate() {
+            step.operation
+                .validate()
+                .map_err(|err| format!("Step {}: {}", index + 1, err))?;
+        }
+
+        return Ok(self);
+    }
+
+    pub fn apply(&self, image: &dyn Image) -> Bitmap {
+        let mut result: Option<Bitmap> = None;
+        for step in self.steps.iter().filter(|step| step.enabled) {
+            let source: &dyn Image = match &result {
+                Some(result) => result,
+                None => image,
+            };
+            let next = step.operation.apply_in(source, step.selection.as_ref());
+            result = Some(next);
+        }
+
+        return result.unwrap_or_else(|| ops::copy(image));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::Operation;
+    use crate::selection::Selection;
+
+    fn recipe() -> Recipe {
+        let mut disabled = Step::new(Operation::Invert, None);
+        disabled.enabled = false;
+        let steps = vec![
+            Step::new(
+                Operation::GaussianBlur { sigma: 2.5 },
+                Some(Selection::Rectangle { x: 1, y: 2, width: 30, height: 40 }),
+            ),
+            disabled,
+            Step::new(
+                Operation::Threshold { level: None },
+                Some(Selection::Freehand { points: vec![(0.0, 0.0), (8.0, 0.5), (4.0, 6.0)] }),
+            ),
+            Step::new(Operation::Rotate90, None),
+        ];
+
+        return Recipe {
+            steps,
+            output: Some(OutputFormat::Pgm { binary: false }),
+        };
+    }
+
+    fn enabled_only(mut recipe: Recipe) -> Recipe {
+        recipe.steps.retain(|step| step.enabled);
+        return recipe;
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let text = recipe().to_json().unwrap();
+        assert!(!text.contains("invert"));
+        assert_eq!(Recipe::from_json(&text).unwrap(), enabled_only(recipe()));
+        assert_eq!(Recipe::parse(&text).unwrap(), enabled_only(recipe()));
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let text = recipe().to_toml().unwrap();
+        assert!(!text.contains("invert"));
+        assert_eq!(Recipe::from_toml(&text).unwrap(), enabled_only(recipe()));
+        assert_eq!(Recipe::parse(&text).unwrap(), enabled_only(recipe()));
+    }
+
+    // The example of the doc comment, with the command line's shape names.
+    #[test]
+    fn reads_handwritten_recipes() {
+        let text = r#"
+            [[steps]]
+            op = "gaussian_blur"
+            sigma = 2.0
+            roi = { shape = "rect", x = 0, y = 0, width = 64, height = 64 }
+
+            [[steps]]
+            op = "threshold"
+            roi = { shape = "poly", points = [[0, 0], [4, 0], [0, 4]] }
+
+            [output]
+            format = "pbm"
+        "#;
+        let recipe = Recipe::parse(text).unwrap();
+        assert_eq!(recipe.steps[0].selection, Some(Selection::Rectangle { x: 0, y: 0, width: 64, height: 64 }));
+        assert_eq!(
+            recipe.steps[1].selection,
+            Some(Selection::Freehand { points: vec![(0.0, 0.0), (4.0, 0.0), (0.0, 4.0)] })
+        );
+        assert!(recipe.steps.iter().all(|step| step.enabled));
+        assert_eq!(recipe.output, Some(OutputFormat::Pbm { binary: true }));
+
+        assert!(Recipe::parse(r#"{"steps": [{"op": "gaussian_blur", "sigma": -1}]}"#).is_err());
+        assert!(Recipe::parse(r#"{"steps": [{"op": "invert", "roi": {"shape": "circle"}}]}"#).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use js_sys::Float32Array;
+use wasm_bindgen::JsCast;
+use web_sys::{
+    window, HtmlCanvasElement, WebGl2RenderingContext as GL, WebGlBuffer, WebGlProgram, WebGlShader,
+    WebGlTexture, WebGlUniformLocation, WebGlVertexArrayObject,
+};
+
+use ppm::display::DisplaySettings;
+use ppm::image::{BitmapData, Image};
+
+use crate::tiles::{extract_rect, Lookup, Pyramid, TileCache, MAX_CACHED_TILES};
+use crate::view::ViewState;
+
+const VERTEX_SHADER: &str = r#"#version 300 es
+in vec2 a_position;
+out vec2 v_texcoord;
+uniform vec2 u_viewport;
+uniform vec4 u_rect;
+void main() {
+    vec2 pos = (u_rect.xy + a_position * u_rect.zw) / u_viewport * 2.0 - 1.0;
+    gl_Position = vec4(pos.x, -pos.y, 0.0, 1.0);
+    v_texcoord = a_position;
+}"#;
+
+// DisplaySettings::apply, with samples scaled from the texture's range to
+// the image's maximum value.
+const FRAGMENT_SHADER: &str = r#"#version 300 es
+precision highp float;
+in vec2 v_texcoord;
+out vec4 outColor;
+uniform sampler2D u_texture;
+uniform float u_scale;
+uniform float u_black;
+uniform float u_white;
+uniform float u_exposure;
+uniform float u_gamma;
+uniform int u_tone_map;
+void main() {
+    vec3 color = texture(u_texture, v_texcoord).rgb * u_scale;
+    color = (color - u_black) / max(u_white - u_black, 1e-6) * exp2(u_exposure);
+    color = max(color, 0.0);
+    if (u_tone_map == 1) {
+        color = color / (1.0 + color);
+    } else if (u_tone_map == 2) {
+        color = (color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14);
+    }
+    color = pow(clamp(color, 0.0, 1.0), vec3(1.0 / max(u_gamma, 1e-6)));
+    outColor = vec4(color, 1.0);
+}"#;
+
+// Draws the image tile by tile into a canvas of its own, which is then copied
+// onto the visible one. Made once, the GL objects live as long as it does.
+pub struct Renderer {
+    canvas: HtmlCanvasElement,
+    glctx: GL,
+    program: WebGlProgram,
+    vao: Option<WebGlVertexArrayObject>,
+    buffer: Option<WebGlBuffer>,
+    viewport_location: Option<WebGlUniformLocation>,
+    rect_location: Option<WebGlUniformLocation>,
+    // Scale, black point, white point, exposure, gamma and tone map.
+    display_locations: [Option<WebGlUniformLocation>; 6],
+    pyramid: Option<Pyramid>,
+    tiles: TileCache<WebGlTexture>,
+}
+
+impl Renderer {
+    pub fn new() -> Result<Self, String> {
+        let canvas = window()
+            .and_then(|window| window.document())
+            .and_then(|document| document.create_element("canvas").ok())
+            .and_then(|element| element.dyn_into::<HtmlCanvasElement>().ok())
+            .ok_or("Couldn't create canvas.")?;
+        let glctx = canvas
+            .get_context("webgl2")
+            .ok()
+            .flatten()
+            .and_then(|context| context.dyn_into::<GL>().ok())
+            .ok_or("WebGL2 isn't available.")?;
+
+        let program = link_program(&glctx)?;
+
+        let vao = glctx.create_vertex_array();
+        glctx.bind_vertex_array(vao.as_ref());
+        let buffer = glctx.create_buffer();
+        glctx.bind_buffer(GL::ARRAY_BUFFER, buffer.as_ref());
+        glctx.buffer_data_with_array_buffer_view(
+            GL::ARRAY_BUFFER,
+            &Float32Array::from([
+                0.0f32, 0.0f32,
+                1.0f32, 0.0f32,
+                0.0f32, 1.0f32,
+                1.0f32, 1.0f32,
+            ].as_slice()),
+            GL::STATIC_DRAW,
+        );
+        glctx.vertex_attrib_pointer_with_i32(0, 2, GL::FLOAT, false, 8, 0);
+        glctx.enable_vertex_attrib_array(0);
+
+        return Ok(Renderer {
+            viewport_location: glctx.get_uniform_location(&program, "u_viewport"),
+            rect_location: glctx.get_uniform_location(&program, "u_rect"),
+            display_locations: ["u_scale", "u_black", "u_white", "u_exposure", "u_gamma", "u_tone_map"]
+                .map(|name| glctx.get_uniform_location(&program, name)),
+            canvas,
+            glctx,
+            program,
+            vao,
+            buffer,
+            pyramid: None,
+            tiles: TileCache::new(MAX_CACHED_TILES),
+        });
+    }
+
+    pub fn canvas(&self) -> &HtmlCanvasElement {
+        &self.canvas
+    }
+
+    // Takes a new or edited image. Uploaded tiles stay until they turn out to
+    // differ from the new pixels.
+    pub fn set_image(&mut self, image: &dyn Image) {
+        self.pyramid = Some(Pyramid::new(image));
+        self.tiles.invalidate();
+    }
+
+    pub fn draw(&mut self, image: &dyn Image, view: &ViewState, viewport: (f64, f64), display: &DisplaySettings) {
+        let (viewport_width, viewport_height) = viewport;
+        if self.canvas.width() != viewport_width as u32 || self.canvas.height() != viewport_height as u32 {
+            self.canvas.set_width(viewport_width as u32);
+            self.canvas.set_height(viewport_height as u32);
+        }
+
+        let glctx = &self.glctx;
+        glctx.viewport(0, 0, viewport_width as i32, viewport_height as i32);
+        glctx.clear_color(0.0, 0.0, 0.0, 0.0);
+        glctx.clear(GL::COLOR_BUFFER_BIT);
+
+        let pyramid = match &self.pyramid {
+            Some(pyramid) => pyramid,
+            None => return,
+        };
+
+        glctx.use_program(Some(&self.program));
+        glctx.bind_vertex_array(self.vao.as_ref());
+        glctx.uniform2f(self.viewport_location.as_ref(), viewport_width as f32, viewport_height as f32);
+
+        let range = match image.get_buffer_ref() {
+            BitmapData::U16(_) => u16::MAX as f32,
+            _ => u8::MAX as f32,
+        };
+        let [scale, black, white, exposure, gamma, tone_map] = &self.display_locations;
+        glctx.uniform1f(scale.as_ref(), range / image.get_max_value().max(1) as f32);
+        glctx.uniform1f(black.as_ref(), display.black_point as f32);
+        glctx.uniform1f(white.as_ref(), display.white_point as f32);
+        glctx.uniform1f(exposure.as_ref(), display.exposure as f32);
+        glctx.uniform1f(gamma.as_ref(), display.gamma as f32);
+        glctx.uniform1i(tone_map.as_ref(), display.tone_map.index());
+
+        let level = pyramid.level_for_scale(view.scale);
+        let level_width = pyramid.level_size(level).0;
+        let level_data = pyramid.level_data(image, level);
+        for tile in pyramid.visible_tiles(view, viewport, level) {
+            let rect = pyramid.tile_rect(&tile);
+            let (_, _, width, height) = rect;
+            let texture = match self.tiles.lookup(&tile, || (extract_rect(level_data, level_width, rect), width, height)) {
+                Lookup::Fresh(texture) => texture,
+                Lookup::Changed(texture, data) => {
+                    // Uploaded into the same texture, nothing is evicted.
+                    upload_texture(glctx, &texture, &data, width, height);
+                    self.tiles.insert(tile, texture.clone(), &data, width, height);
+                    texture
+                }
+                Lookup::Missing(data) => {
+                    let texture = glctx.create_texture().expect("Unable to create texture.");
+                    upload_texture(glctx, &texture, &data, width, height);
+                    for evicted in self.tiles.insert(tile, texture.clone(), &data, width, height) {
+                        glctx.delete_texture(Some(&evicted));
+                    }
+                    texture
+                }
+            };
+
+            let (x, y, width, height) = pyramid.tile_image_rect(&tile);
+            let (screen_x, screen_y) = view.image_to_screen((x, y));
+            glctx.uniform4f(
+                self.rect_location.as_ref(),
+                screen_x as f32,
+                screen_y as f32,
+                (width * view.scale) as f32,
+                (height * view.scale) as f32,
+            );
+            glctx.bind_texture(GL::TEXTURE_2D, Some(&texture));
+            glctx.draw_arrays(GL::TRIANGLE_STRIP, 0, 4);
+        }
+    }
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        for texture in self.tiles.clear() {
+            self.glctx.delete_texture(Some(&texture));
+        }
+        self.glctx.delete_buffer(self.buffer.as_ref());
+        self.glctx.delete_vertex_array(self.vao.as_ref());
+        self.glctx.delete_program(Some(&self.program));
+    }
+}
+
+fn compile_shader(glctx: &GL, kind: u32, source: &str) -> Result<WebGlShader, String> {
+    let shader = glctx.create_shader(kind).ok_or("Unable to create shader.")?;
+    glctx.shader_source(&shader, source);
+    glctx.compile_shader(&shader);
+
+    if !glctx.get_shader_parameter(&shader, GL::COMPILE_STATUS).as_bool().unwrap_or(false) {
+        let log = glctx.get_shader_info_log(&shader).unwrap_or_default();
+        glctx.delete_shader(Some(&shader));
+        return Err(format!("Couldn't compile shader: {}", log));
+    }
+
+    return Ok(shader);
+}
+
+fn link_program(glctx: &GL) -> Result<WebGlProgram, String> {
+    let vertex_shader = compile_shader(glctx, GL::VERTEX_SHADER, VERTEX_SHADER)?;
+    let fragment_shader = match compile_shader(glctx, GL::FRAGMENT_SHADER, FRAGMENT_SHADER) {
+        Ok(shader) => shader,
+        Err(err) => {
+            glctx.delete_shader(Some(&vertex_shader));
+            return Err(err);
+        }
+    };
+
+    let program = glctx.create_program().ok_or("Unable to create shader program.")?;
+    glctx.attach_shader(&program, &vertex_shader);
+    glctx.attach_shader(&program, &fragment_shader);
+    glctx.bind_attrib_location(&program, 0, "a_position");
+    glctx.link_program(&program);
+
+    // The program keeps what it needs once linked.
+    for shader in [vertex_shader, fragment_shader] {
+        glctx.detach_shader(&program, &shader);
+        glctx.delete_shader(Some(&shader));
+    }
+
+    if !glctx.get_program_parameter(&program, GL::LINK_STATUS).as_bool().unwrap_or(false) {
+        let log = glctx.get_program_info_log(&program).unwrap_or_default();
+        glctx.delete_program(Some(&program));
+        return Err(format!("Couldn't link shader program: {}", log));
+    }
+
+    return Ok(program);
+}
+
+// Replaces the texture's contents, its size and format included.
+fn upload_texture(glctx: &GL, texture: &WebGlTexture, data: &BitmapData, width: usize, height: usize) {
+    glctx.bind_texture(GL::TEXTURE_2D, Some(texture));
+    glctx.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_MIN_FILTER, GL::LINEAR as i32);
+    glctx.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_MAG_FILTER, GL::NEAREST as i32);
+    glctx.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_WRAP_S, GL::CLAMP_TO_EDGE as i32);
+    glctx.tex_parameteri(GL::TEXTURE_2D, GL::TEXTURE_WRAP_T, GL::CLAMP_TO_EDGE as i32);
+    glctx.pixel_storei(GL::UNPACK_ALIGNMENT, 1);
+
+    match data {
+        BitmapData::U8(data) => {
+            glctx.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                GL::TEXTURE_2D,
+                0,
+                GL::RGB8 as i32,
+                width as i32,
+                height as i32,
+                0,
+                GL::RGB,
+                GL::UNSIGNED_BYTE,
+                Some(data))
+            .expect("Couldn't load texture data.");
+        }
+        BitmapData::U16(data) => {
+            let data: Vec<f32> = data.iter().map(|val| (*val as f32) / u16::MAX as f32).collect();
+            let array = Float32Array::from(data.as_slice());
+            glctx.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_array_buffer_view(
+                GL::TEXTURE_2D,
+                0,
+                GL::RGB16F as i32,
+                width as i32,
+                height as i32,
+                0,
+                GL::RGB,
+                GL::FLOAT,
+                Some(&array))
+            .expect("Couldn't load texture data.");
+        }
+        BitmapData::None => {},
+    };
+}
@@ -0,0 +1,412 @@
+use std::{error::Error, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::image::{Bitmap, BitmapData, Image};
+
+pub const HISTOGRAM_BINS: usize = 256;
+
+// Region of interest in image coordinates. A pixel belongs to the selection
+// when its centre lies inside the shape. Recipes also take the shape names
+// of the command line, rect and poly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum Selection {
+    #[serde(alias = "rect")]
+    Rectangle {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+    Ellipse {
+        cx: f64,
+        cy: f64,
+        rx: f64,
+        ry: f64,
+    },
+    #[serde(alias = "poly")]
+    Freehand {
+        points: Vec<(f64, f64)>,
+    },
+}
+
+impl Selection {
+    // Rectangle spanning two corners given in (fractional) image coordinates.
+    pub fn rectangle_from_corners(a: (f64, f64), b: (f64, f64)) -> Self {
+        let x0 = a.0.min(b.0).floor().max(0.0);
+        let y0 = a.1.min(b.1).floor().max(0.0);
+        let x1 = a.0.max(b.0).ceil().max(0.0);
+        let y1 = a.1.max(b.1).ceil().max(0.0);
+
+        return Selection::Rectangle {
+            x: x0 as usize,
+            y: y0 as usize,
+            width: (x1 - x0) as usize,
+            height: (y1 - y0) as usize,
+        };
+    }
+
+    // Ellipse inscribed in the box spanned by two corners.
+    pub fn ellipse_from_corners(a: (f64, f64), b: (f64, f64)) -> Self {
+        return Selection::Ellipse {
+            cx: (a.0 + b.0) / 2.0,
+            cy: (a.1 + b.1) / 2.0,
+            rx: (a.0 - b.0).abs() / 2.0,
+            ry: (a.1 - b.1).abs() / 2.0,
+        };
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+
+        match self {
+            Selection::Rectangle {
+                x: rx,
+                y: ry,
+                width,
+                height,
+            } => x >= *rx && y >= *ry && x < rx.saturating_add(*width) && y < ry.saturating_add(*height),
+            Selection::Ellipse { cx, cy, rx, ry } => {
+                if *rx <= 0.0 || *ry <= 0.0 {
+                    return false;
+                }
+
+                let dx = (px - cx) / rx;
+                let dy = (py - cy) / ry;
+                dx * dx + dy * dy <= 1.0
+            }
+            Selection::Freehand { points } => {
+                // Even-odd rule.
+                let mut inside = false;
+                let mut j = points.len().wrapping_sub(1);
+                for i in 0..points.len() {
+                    let (xi, yi) = points[i];
+                    let (xj, yj) = points[j];
+                    if (yi > py) != (yj > py) && px < (xj - xi) * (py - yi) / (yj - yi) + xi {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+
+                inside
+            }
+        }
+    }
+
+    // Bounding box clipped to the image as (x, y, width, height), None when
+    // the selection does not overlap it.
+    pub fn bounds(&self, width: usize, height: usize) -> Option<(usize, usize, usize, usize)> {
+        let (x0, y0, x1, y1) = match self {
+            Selection::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => (*x as f64, *y as f64, *x as f64 + *width as f64, *y as f64 + *height as f64),
+            Selection::Ellipse { cx, cy, rx, ry } => (cx - rx, cy - ry, cx + rx, cy + ry),
+            Selection::Freehand { points } => points.iter().fold(
+                (f64::MAX, f64::MAX, f64::MIN, f64::MIN),
+                |(x0, y0, x1, y1), (x, y)| (x0.min(*x), y0.min(*y), x1.max(*x), y1.max(*y)),
+            ),
+        };
+
+        let x0 = x0.floor().max(0.0) as usize;
+        let y0 = y0.floor().max(0.0) as usize;
+        let x1 = (x1.ceil().max(0.0) as usize).min(width);
+        let y1 = (y1.ceil().max(0.0) as usize).min(height);
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+
+        return Some((x0, y0, x1 - x0, y1 - y0));
+    }
+
+    pub fn is_empty(&self, width: usize, height: usize) -> bool {
+        match self.bounds(width, height) {
+            Some((x, y, w, h)) => !(y..y + h).any(|py| (x..x + w).any(|px| self.contains(px, py))),
+            None => true,
+        }
+    }
+}
+
+// Parses "rect:x,y,w,h", "ellipse:cx,cy,rx,ry" and "poly:x,y;x,y;...".
+impl FromStr for Selection {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, args) = s
+            .split_once(':')
+            .ok_or("Selection must look like <shape>:<arguments>.")?;
+
+        let numbers = |args: &str| -> Result<Vec<f64>, Box<dyn Error>> {
+            let mut values = Vec::new();
+            for val in args.split(',') {
+                values.push(val.trim().parse::<f64>()?);
+            }
+
+            return Ok(values);
+        };
+
+        match kind {
+            "rect" => match numbers(args)?[..] {
+                [x, y, width, height] if x >= 0.0 && y >= 0.0 && width >= 0.0 && height >= 0.0 => {
+                    Ok(Selection::Rectangle {
+                        x: x as usize,
+                        y: y as usize,
+                        width: width as usize,
+                        height: height as usize,
+                    })
+                }
+                _ => Err("Rectangle selection needs x,y,width,height.".into()),
+            },
+            "ellipse" => match numbers(args)?[..] {
+                [cx, cy, rx, ry] => Ok(Selection::Ellipse { cx, cy, rx, ry }),
+                _ => Err("Ellipse selection needs cx,cy,rx,ry.".into()),
+            },
+            "poly" => {
+                let mut points = Vec::new();
+                for point in args.split(';') {
+                    match numbers(point)?[..] {
+                        [x, y] => points.push((x, y)),
+                        _ => return Err("Polygon points must be x,y pairs.".into()),
+                    }
+                }
+
+                if points.len() < 3 {
+                    return Err("Polygon selection needs at least three points.".into());
+                }
+
+                Ok(Selection::Freehand { points })
+            }
+            _ => Err(format!("Unknown selection shape \"{}\".", kind).into()),
+        }
+    }
+}
+
+impl fmt::Display for Selection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selection::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "rect:{},{},{},{}", x, y, width, height),
+            Selection::Ellipse { cx, cy, rx, ry } => write!(f, "ellipse:{},{},{},{}", cx, cy, rx, ry),
+            Selection::Freehand { points } => {
+                let points: Vec<String> = points.iter().map(|(x, y)| format!("{},{}", x, y)).collect();
+                write!(f, "poly:{}", points.join(";"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionStats {
+    pub count: usize,
+    pub mean: [f64; 3],
+    pub min: [u16; 3],
+    pub max: [u16; 3],
+    pub stddev: [f64; 3],
+    pub max_value: usize,
+    // HISTOGRAM_BINS bins per channel spanning 0..=max_value.
+    #[serde(skip)]
+    pub histogram: [Vec<u32>; 3],
+}
+
+// Statistics over the selected pixels, or the whole image without a selection.
+pub fn region_stats(image: &dyn Image, selection: Option<&Selection>) -> RegionStats {
+    let (width, height) = (image.get_width(), image.get_height());
+    let max_value = image.get_max_value().max(1);
+    let mut stats = RegionStats {
+        count: 0,
+        mean: [0.0; 3],
+        min: [u16::MAX; 3],
+        max: [0; 3],
+        stddev: [0.0; 3],
+        max_value,
+        histogram: [
+            vec![0; HISTOGRAM_BINS],
+            vec![0; HISTOGRAM_BINS],
+            vec![0; HISTOGRAM_BINS],
+        ],
+    };
+
+    let (x0, y0, region_width, region_height) = match selection {
+        Some(selection) => selection.bounds(width, height).unwrap_or_default(),
+        None => (0, 0, width, height),
+    };
+
+    let mut sum = [0.0; 3];
+    let mut sum_sq = [0.0; 3];
+    for y in y0..y0 + region_height {
+        for x in x0..x0 + region_width {
+            if let Some(selection) = selection {
+                if !selection.contains(x, y) {
+                    continue;
+                }
+            }
+
+            let (r, g, b) = image.get_pixel_value(x, y);
+            for (channel, val) in [r, g, b].into_iter().enumerate() {
+                sum[channel] += val as f64;
+                sum_sq[channel] += val as f64 * val as f64;
+                stats.min[channel] = stats.min[channel].min(val);
+                stats.max[channel] = stats.max[channel].max(val);
+
+                let bin = (val as usize * HISTOGRAM_BINS / (max_value + 1)).min(HISTOGRAM_BINS - 1);
+                stats.histogram[channel][bin] += 1;
+            }
+            stats.count += 1;
+        }
+    }
+
+    if stats.count == 0 {
+        stats.min = [0; 3];
+        return stats;
+    }
+
+    let count = stats.count as f64;
+    for channel in 0..3 {
+        stats.mean[channel] = sum[channel] / count;
+        let variance = sum_sq[channel] / count - stats.mean[channel] * stats.mean[channel];
+        stats.stddev[channel] = variance.max(0.0).sqrt();
+    }
+
+    return stats;
+}
+
+// Copies the bounding box of the selection. Pixels of the box that are not
+// part of the selection are cleared to black.
+pub fn crop(image: &dyn Image, selection: &Selection) -> Option<Bitmap> {
+    let (x0, y0, width, height) = selection.bounds(image.get_width(), image.get_height())?;
+
+    fn copy<T: Copy + Default>(
+        data: &[T],
+        image_width: usize,
+        selection: &Selection,
+        (x0, y0, width, height): (usize, usize, usize, usize),
+    ) -> Vec<T> {
+        let mut out = Vec::with_capacity(width * height * 3);
+        for y in y0..y0 + height {
+            for x in x0..x0 + width {
+                let index = (y * image_width + x) * 3;
+                if selection.contains(x, y) {
+                    out.extend_from_slice(&data[index..index + 3]);
+                } else {
+                    out.extend_from_slice(&[T::default(); 3]);
+                }
+            }
+        }
+
+        return out;
+    }
+
+    let bounds = (x0, y0, width, height);
+    let data = match image.get_buffer_ref() {
+        BitmapData::U8(data) => BitmapData::U8(copy(data, image.get_width(), selection, bounds)),
+        BitmapData::U16(data) => BitmapData::U16(copy(data, image.get_width(), selection, bounds)),
+        BitmapData::None => return None,
+    };
+
+    let mut cropped = Bitmap::new(width, height, image.get_max_value(), data);
+    cropped.copy_metadata(image);
+
+    return Some(cropped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inside(selection: &Selection, pixels: &[(usize, usize)]) -> Vec<bool> {
+        return pixels.iter().map(|(x, y)| selection.contains(*x, *y)).collect();
+    }
+
+    #[test]
+    fn rectangle_edges() {
+        let rect = Selection::Rectangle { x: 2, y: 3, width: 4, height: 2 };
+        let pixels = [(2, 3), (5, 4), (6, 4), (5, 5), (1, 3), (2, 2)];
+        assert_eq!(inside(&rect, &pixels), [true, true, false, false, false, false]);
+        assert_eq!(rect.bounds(10, 10), Some((2, 3, 4, 2)));
+        assert_eq!(rect.bounds(4, 4), Some((2, 3, 2, 1)));
+        assert_eq!(rect.bounds(2, 10), None);
+        assert!(Selection::Rectangle { x: 2, y: 3, width: 0, height: 2 }.is_empty(10, 10));
+
+        // Edges past usize::MAX neither overflow nor wrap around.
+        let huge = Selection::Rectangle { x: usize::MAX - 1, y: 0, width: usize::MAX, height: 1 };
+        assert!(huge.contains(usize::MAX - 1, 0));
+        assert!(!huge.contains(0, 0));
+        assert_eq!(huge.bounds(10, 10), None);
+    }
+
+    // Pixel centres on the outline belong to the ellipse.
+    #[test]
+    fn ellipse_edges() {
+        let ellipse = Selection::Ellipse { cx: 4.5, cy: 4.5, rx: 2.0, ry: 1.0 };
+        let pixels = [(4, 4), (6, 4), (7, 4), (2, 4), (1, 4), (4, 5), (4, 6), (6, 5)];
+        assert_eq!(inside(&ellipse, &pixels), [true, true, false, true, false, true, false, false]);
+        assert_eq!(ellipse.bounds(10, 10), Some((2, 3, 5, 3)));
+        assert_eq!(ellipse.bounds(4, 4), Some((2, 3, 2, 1)));
+
+        let flat = Selection::Ellipse { cx: 4.5, cy: 4.5, rx: 2.0, ry: 0.0 };
+        assert!(!flat.contains(4, 4));
+        assert!(flat.is_empty(10, 10));
+    }
+
+    // Pixel centres on the right edge of a polygon are outside, like the
+    // right and bottom edges of a rectangle.
+    #[test]
+    fn polygon_edges() {
+        let square = Selection::Freehand { points: vec![(1.0, 1.0), (3.0, 1.0), (3.0, 3.0), (1.0, 3.0)] };
+        assert_eq!(inside(&square, &[(1, 1), (2, 2), (3, 1), (0, 1), (1, 3)]), [true, true, false, false, false]);
+        assert_eq!(square.bounds(10, 10), Some((1, 1, 2, 2)));
+
+        let triangle = Selection::Freehand { points: vec![(0.0, 0.0), (4.0, 0.0), (0.0, 4.0)] };
+        let pixels = [(0, 0), (2, 0), (3, 0), (1, 1), (2, 1), (0, 2), (0, 3)];
+        assert_eq!(inside(&triangle, &pixels), [true, true, false, true, false, true, false]);
+        assert_eq!(triangle.bounds(2, 10), Some((0, 0, 2, 4)));
+
+        let empty = Selection::Freehand { points: Vec::new() };
+        assert!(!empty.contains(0, 0));
+        assert_eq!(empty.bounds(10, 10), None);
+    }
+
+    #[test]
+    fn parses_every_shape() {
+        let parsed: Selection = "rect:1,2,3,4".parse().unwrap();
+        assert_eq!(parsed, Selection::Rectangle { x: 1, y: 2, width: 3, height: 4 });
+        let parsed: Selection = "ellipse:1.5, 2, 3, 4".parse().unwrap();
+        assert_eq!(parsed, Selection::Ellipse { cx: 1.5, cy: 2.0, rx: 3.0, ry: 4.0 });
+        let parsed: Selection = "poly:0,0;4,0;0,4.5".parse().unwrap();
+        assert_eq!(parsed, Selection::Freehand { points: vec![(0.0, 0.0), (4.0, 0.0), (0.0, 4.5)] });
+
+        for selection in [
+            Selection::Rectangle { x: 1, y: 2, width: 3, height: 4 },
+            Selection::Ellipse { cx: 1.5, cy: 2.0, rx: 3.0, ry: 4.0 },
+            Selection::Freehand { points: vec![(0.0, 0.0), (4.0, 0.0), (0.0, 4.5)] },
+        ] {
+            assert_eq!(selection.to_string().parse::<Selection>().unwrap(), selection);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_selections() {
+        for text in [
+            "rect",
+            "rect:",
+            "rect:1,2,3",
+            "rect:1,2,3,4,5",
+            "rect:-1,2,3,4",
+            "rect:a,b,c,d",
+            "ellipse:1,2",
+            "poly:0,0;4,0",
+            "poly:0,0;4;0,4",
+            "poly:0,0,1;4,0;0,4",
+            "circle:1,2,3",
+        ] {
+            assert!(text.parse::<Selection>().is_err(), "{} was accepted", text);
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+use ppm::image::{BitmapData, Image};
+use crate::view::ViewState;
+
+// Small enough to stay below MAX_TEXTURE_SIZE on any WebGL2 implementation.
+pub const TILE_SIZE: usize = 512;
+pub const MAX_CACHED_TILES: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileId {
+    pub level: usize,
+    pub col: usize,
+    pub row: usize,
+}
+
+pub struct Level {
+    pub width: usize,
+    pub height: usize,
+    pub data: BitmapData,
+}
+
+// Level of detail pyramid. Level 0 is the image itself and is not copied,
+// every further level halves both dimensions until the image fits one tile.
+pub struct Pyramid {
+    width: usize,
+    height: usize,
+    levels: Vec<Level>,
+}
+
+impl Pyramid {
+    pub fn new(image: &dyn Image) -> Self {
+        let mut levels: Vec<Level> = Vec::new();
+        let (mut width, mut height) = (image.get_width(), image.get_height());
+
+        while width > TILE_SIZE || height > TILE_SIZE {
+            let data = match levels.last() {
+                Some(level) => &level.data,
+                None => image.get_buffer_ref(),
+            };
+
+            let level = downsample(data, width, height);
+            width = level.width;
+            height = level.height;
+            levels.push(level);
+        }
+
+        return Pyramid {
+            width: image.get_width(),
+            height: image.get_height(),
+            levels,
+        };
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.levels.len() + 1
+    }
+
+    pub fn level_size(&self, level: usize) -> (usize, usize) {
+        if level == 0 {
+            return (self.width, self.height);
+        }
+
+        let level = &self.levels[level - 1];
+        return (level.width, level.height);
+    }
+
+    pub fn level_data<'a>(&'a self, image: &'a dyn Image, level: usize) -> &'a BitmapData {
+        if level == 0 {
+            return image.get_buffer_ref();
+        }
+
+        return &self.levels[level - 1].data;
+    }
+
+    // Level whose resolution is closest to, but not below, the on-screen size.
+    pub fn level_for_scale(&self, scale: f64) -> usize {
+        if scale >= 1.0 {
+            return 0;
+        }
+
+        let level = (1.0 / scale).log2().floor() as usize;
+        return level.min(self.level_count() - 1);
+    }
+
+    pub fn visible_tiles(
+        &self,
+        view: &ViewState,
+        viewport: (f64, f64),
+        level: usize,
+    ) -> Vec<TileId> {
+        let level_scale = (1 << level) as f64;
+        let (level_width, level_height) = self.level_size(level);
+        let (x0, y0) = view.screen_to_image((0.0, 0.0));
+        let (x1, y1) = view.screen_to_image(viewport);
+
+        let x0 = (x0 / level_scale).max(0.0);
+        let y0 = (y0 / level_scale).max(0.0);
+        let x1 = (x1 / level_scale).min(level_width as f64);
+        let y1 = (y1 / level_scale).min(level_height as f64);
+        if x0 >= x1 || y0 >= y1 {
+            return Vec::new();
+        }
+
+        let tile = TILE_SIZE as f64;
+        let (col0, col1) = ((x0 / tile).floor() as usize, (x1 / tile).ceil() as usize);
+        let (row0, row1) = ((y0 / tile).floor() as usize, (y1 / tile).ceil() as usize);
+
+        let mut tiles = Vec::with_capacity((col1 - col0) * (row1 - row0));
+        for row in row0..row1 {
+            for col in col0..col1 {
+                tiles.push(TileId { level, col, row });
+            }
+        }
+
+        return tiles;
+    }
+
+    // Tile bounds in pixels of its own level.
+    pub fn tile_rect(&self, tile: &TileId) -> (usize, usize, usize, usize) {
+        let (level_width, level_height) = self.level_size(tile.level);
+        let x = tile.col * TILE_SIZE;
+        let y = tile.row * TILE_SIZE;
+
+        return (
+            x,
+            y,
+            TILE_SIZE.min(level_width - x),
+            TILE_SIZE.min(level_height - y),
+        );
+    }
+
+    // Tile bounds in image (level 0) coordinates.
+    pub fn tile_image_rect(&self, tile: &TileId) -> (f64, f64, f64, f64) {
+        let level_scale = (1 << tile.level) as f64;
+        let (x, y, width, height) = self.tile_rect(tile);
+        let x0 = x as f64 * level_scale;
+        let y0 = y as f64 * level_scale;
+        let x1 = ((x + width) as f64 * level_scale).min(self.width as f64);
+        let y1 = ((y + height) as f64 * level_scale).min(self.height as f64);
+
+        return (x0, y0, x1 - x0, y1 - y0);
+    }
+}
+
+// Copies a rectangle of an interleaved RGB buffer, ready for texture upload.
+pub fn extract_rect(
+    data: &BitmapData,
+    width: usize,
+    rect: (usize, usize, usize, usize),
+) -> BitmapData {
+    let (x, y, rect_width, rect_height) = rect;
+
+    fn copy<T: Copy>(
+        data: &[T],
+        width: usize,
+        x: usize,
+        y: usize,
+        rect_width: usize,
+        rect_height: usize,
+    ) -> Vec<T> {
+        let mut out = Vec::with_capacity(rect_width * rect_height * 3);
+        for row in y..y + rect_height {
+            let start = (row * width + x) * 3;
+            out.extend_from_slice(&data[start..start + rect_width * 3]);
+        }
+
+        return out;
+    }
+
+    match data {
+        BitmapData::U8(data) => BitmapData::U8(copy(data, width, x, y, rect_width, rect_height)),
+        BitmapData::U16(data) => BitmapData::U16(copy(data, width, x, y, rect_width, rect_height)),
+        BitmapData::None => BitmapData::None,
+    }
+}
+
+fn downsample(data: &BitmapData, width: usize, height: usize) -> Level {
+    let half_width = width.div_ceil(2);
+    let half_height = height.div_ceil(2);
+
+    fn average<T: Copy + Into<u32>>(
+        data: &[T],
+        width: usize,
+        height: usize,
+        convert: fn(u32) -> T,
+    ) -> Vec<T> {
+        let half_width = width.div_ceil(2);
+        let half_height = height.div_ceil(2);
+        let mut out = Vec::with_capacity(half_width * half_height * 3);
+
+        for y in 0..half_height {
+            let rows = if y * 2 + 1 < height { 2 } else { 1 };
+            for x in 0..half_width {
+                let cols = if x * 2 + 1 < width { 2 } else { 1 };
+                for channel in 0..3 {
+                    let mut sum = 0;
+                    for dy in 0..rows {
+                        for dx in 0..cols {
+                            sum += data[((y * 2 + dy) * width + x * 2 + dx) * 3 + channel].into();
+                        }
+                    }
+                    out.push(convert(sum / (rows * cols) as u32));
+                }
+            }
+        }
+
+        return out;
+    }
+
+    let data = match data {
+        BitmapData::U8(data) => BitmapData::U8(average(data, width, height, |val| val as u8)),
+        BitmapData::U16(data) => BitmapData::U16(average(data, width, height, |val| val as u16)),
+        BitmapData::None => BitmapData::None,
+    };
+
+    return Level {
+        width: half_width,
+        height: half_height,
+        data,
+    };
+}
+
+// Hash of a tile's pixels, telling whether an uploaded copy is still current.
+pub fn fingerprint(data: &BitmapData, width: usize, height: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (width, height).hash(&mut hasher);
+    match data {
+        BitmapData::U8(data) => (8u8, data).hash(&mut hasher),
+        BitmapData::U16(data) => (16u8, data).hash(&mut hasher),
+        BitmapData::None => 0u8.hash(&mut hasher),
+    }
+
+    return hasher.finish();
+}
+
+pub enum Lookup<T> {
+    // Holds the tile's current pixels.
+    Fresh(T),
+    // Was uploaded for an earlier image and differs now, the value can be
+    // reused for the new pixels.
+    Changed(T, BitmapData),
+    Missing(BitmapData),
+}
+
+struct Entry<T> {
+    value: T,
+    fingerprint: u64,
+    generation: usize,
+}
+
+// Least recently used cache for uploaded tiles. Evicted values are handed
+// back so the caller can release the GPU resources behind them. Entries are
+// kept across image changes and only replaced once their pixels differ.
+pub struct TileCache<T> {
+    capacity: usize,
+    entries: HashMap<TileId, Entry<T>>,
+    order: VecDeque<TileId>,
+    generation: usize,
+}
+
+impl<T: Clone> TileCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        TileCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+        }
+    }
+
+    // The image changed, every entry has to be checked before it's used again.
+    pub fn invalidate(&mut self) {
+        self.generation += 1;
+    }
+
+    // Looks the tile up, `data` gives its current pixels and size. It's only
+    // called for tiles that aren't known to be current.
+    pub fn lookup(&mut self, tile: &TileId, data: impl FnOnce() -> (BitmapData, usize, usize)) -> Lookup<T> {
+        let generation = self.generation;
+        let entry = match self.entries.get_mut(tile) {
+            Some(entry) => entry,
+            None => return Lookup::Missing(data().0),
+        };
+
+        if entry.generation != generation {
+            let (data, width, height) = data();
+            if fingerprint(&data, width, height) != entry.fingerprint {
+                return Lookup::Changed(entry.value.clone(), data);
+            }
+            entry.generation = generation;
+        }
+        let value = entry.value.clone();
+        self.touch(tile);
+
+        return Lookup::Fresh(value);
+    }
+
+    // Stores the value holding the given pixels. Returns the values that no
+    // longer have a place in the cache, a replaced one included when it's
+    // not the same.
+    pub fn insert(&mut self, tile: TileId, value: T, data: &BitmapData, width: usize, height: usize) -> Vec<T>
+    where
+        T: PartialEq,
+    {
+        let entry = Entry {
+            value: value.clone(),
+            fingerprint: fingerprint(data, width, height),
+            generation: self.generation,
+        };
+
+        let mut evicted = Vec::new();
+        if let Some(old) = self.entries.insert(tile, entry) {
+            if old.value != value {
+                evicted.push(old.value);
+            }
+        }
+        self.touch(&tile);
+
+        while self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            if let Some(entry) = self.entries.remove(&oldest) {
+                evicted.push(entry.value);
+            }
+        }
+
+        return evicted;
+    }
+
+    pub fn clear(&mut self) -> Vec<T> {
+        self.order.clear();
+        return self.entries.drain().map(|(_, entry)| entry.value).collect();
+    }
+
+    fn touch(&mut self, tile: &TileId) {
+        if let Some(index) = self.order.iter().position(|val| val == tile) {
+            self.order.remove(index);
+        }
+        self.order.push_back(*tile);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TILE: TileId = TileId { level: 0, col: 0, row: 0 };
+
+    fn pixels(val: u8) -> BitmapData {
+        return BitmapData::U8(vec![val; 2 * 2 * 3]);
+    }
+
+    fn lookup(cache: &mut TileCache<u32>, tile: &TileId, val: u8) -> Lookup<u32> {
+        return cache.lookup(tile, || (pixels(val), 2, 2));
+    }
+
+    #[test]
+    fn inserted_tiles_are_fresh() {
+        let mut cache = TileCache::new(4);
+        assert!(matches!(lookup(&mut cache, &TILE, 1), Lookup::Missing(_)));
+        assert!(cache.insert(TILE, 7, &pixels(1), 2, 2).is_empty());
+        assert!(matches!(lookup(&mut cache, &TILE, 1), Lookup::Fresh(7)));
+
+        // Pixels are only compared after the image changed.
+        assert!(matches!(lookup(&mut cache, &TILE, 2), Lookup::Fresh(7)));
+    }
+
+    #[test]
+    fn unchanged_pixels_stay_fresh() {
+        let mut cache = TileCache::new(4);
+        cache.insert(TILE, 7, &pixels(1), 2, 2);
+        cache.invalidate();
+        assert!(matches!(lookup(&mut cache, &TILE, 1), Lookup::Fresh(7)));
+    }
+
+    #[test]
+    fn changed_pixels_reuse_the_value() {
+        let mut cache = TileCache::new(4);
+        cache.insert(TILE, 7, &pixels(1), 2, 2);
+        cache.invalidate();
+        assert!(matches!(lookup(&mut cache, &TILE, 2), Lookup::Changed(7, BitmapData::U8(_))));
+
+        // Refilled in place, nothing to release.
+        assert!(cache.insert(TILE, 7, &pixels(2), 2, 2).is_empty());
+        assert!(matches!(lookup(&mut cache, &TILE, 2), Lookup::Fresh(7)));
+
+        // A different value replacing it is handed back.
+        cache.invalidate();
+        assert!(matches!(lookup(&mut cache, &TILE, 3), Lookup::Changed(7, _)));
+        assert_eq!(cache.insert(TILE, 8, &pixels(3), 2, 2), vec![7]);
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let tiles: Vec<TileId> = (0..3).map(|col| TileId { level: 0, col, row: 0 }).collect();
+        let mut cache = TileCache::new(2);
+        assert!(cache.insert(tiles[0], 0, &pixels(0), 2, 2).is_empty());
+        assert!(cache.insert(tiles[1], 1, &pixels(1), 2, 2).is_empty());
+
+        // Using the first tile makes the second the oldest.
+        assert!(matches!(lookup(&mut cache, &tiles[0], 0), Lookup::Fresh(0)));
+        assert_eq!(cache.insert(tiles[2], 2, &pixels(2), 2, 2), vec![1]);
+        assert!(matches!(lookup(&mut cache, &tiles[1], 1), Lookup::Missing(_)));
+        assert_eq!(cache.insert(tiles[1], 1, &pixels(1), 2, 2), vec![0]);
+    }
+
+    #[test]
+    fn clear_returns_every_value() {
+        let mut cache = TileCache::new(4);
+        for col in 0..3 {
+            cache.insert(TileId { level: 0, col, row: 0 }, col as u32, &pixels(col as u8), 2, 2);
+        }
+
+        let mut cleared = cache.clear();
+        cleared.sort();
+        assert_eq!(cleared, vec![0, 1, 2]);
+        assert!(cache.clear().is_empty());
+        assert!(matches!(lookup(&mut cache, &TILE, 0), Lookup::Missing(_)));
+    }
+
+    #[test]
+    fn fingerprints_tell_sizes_and_depths_apart() {
+        let data = pixels(1);
+        assert_eq!(fingerprint(&data, 2, 2), fingerprint(&pixels(1), 2, 2));
+        assert_ne!(fingerprint(&data, 2, 2), fingerprint(&pixels(2), 2, 2));
+        assert_ne!(fingerprint(&data, 2, 2), fingerprint(&data, 4, 1));
+        assert_ne!(fingerprint(&data, 2, 2), fingerprint(&BitmapData::U16(vec![1; 12]), 2, 2));
+    }
+}
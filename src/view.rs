@@ -0,0 +1,103 @@
+pub const MIN_SCALE: f64 = 0.01;
+pub const MAX_SCALE: f64 = 256.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ViewState {
+    pub scale: f64,
+    pub translate_pos: (f64, f64),
+}
+
+impl Default for ViewState {
+    fn default() -> Self {
+        ViewState {
+            scale: 1.0,
+            translate_pos: (0.0, 0.0),
+        }
+    }
+}
+
+impl ViewState {
+    // Zooms keeping the image point under `pos` (canvas coordinates) in place.
+    pub fn zoom_at(&mut self, pos: (f64, f64), factor: f64) {
+        let scale = (self.scale * factor).clamp(MIN_SCALE, MAX_SCALE);
+
+        let translate_pos = (
+            self.translate_pos.0 + (pos.0 - self.translate_pos.0) * (1.0 - scale / self.scale),
+            self.translate_pos.1 + (pos.1 - self.translate_pos.1) * (1.0 - scale / self.scale),
+        );
+
+        self.scale = scale;
+        self.translate_pos = translate_pos;
+    }
+
+    pub fn pan(&mut self, delta: (f64, f64)) {
+        self.translate_pos.0 += delta.0;
+        self.translate_pos.1 += delta.1;
+    }
+
+    pub fn fit(&mut self, image: (usize, usize), viewport: (f64, f64)) {
+        let scale_x = viewport.0 / image.0 as f64;
+        let scale_y = viewport.1 / image.1 as f64;
+        self.set_centered(image, viewport, scale_x.min(scale_y));
+    }
+
+    pub fn fill(&mut self, image: (usize, usize), viewport: (f64, f64)) {
+        let scale_x = viewport.0 / image.0 as f64;
+        let scale_y = viewport.1 / image.1 as f64;
+        self.set_centered(image, viewport, scale_x.max(scale_y));
+    }
+
+    pub fn actual_size(&mut self, image: (usize, usize), viewport: (f64, f64)) {
+        self.set_centered(image, viewport, 1.0);
+    }
+
+    // Fits images larger than the viewport, shows smaller ones at 1:1.
+    pub fn reset(&mut self, image: (usize, usize), viewport: (f64, f64)) {
+        if image.0 as f64 > viewport.0 || image.1 as f64 > viewport.1 {
+            self.fit(image, viewport);
+        } else {
+            self.actual_size(image, viewport);
+        }
+    }
+
+    fn set_centered(&mut self, image: (usize, usize), viewport: (f64, f64), scale: f64) {
+        if image.0 == 0 || image.1 == 0 || !scale.is_finite() {
+            return;
+        }
+
+        self.scale = scale.clamp(MIN_SCALE, MAX_SCALE);
+        self.translate_pos = (
+            ((viewport.0 - image.0 as f64 * self.scale) / 2.0).round(),
+            ((viewport.1 - image.1 as f64 * self.scale) / 2.0).round(),
+        );
+    }
+
+    pub fn screen_to_image(&self, pos: (f64, f64)) -> (f64, f64) {
+        return (
+            (pos.0 - self.translate_pos.0) / self.scale,
+            (pos.1 - self.translate_pos.1) / self.scale,
+        );
+    }
+
+    pub fn image_to_screen(&self, pos: (f64, f64)) -> (f64, f64) {
+        return (
+            pos.0 * self.scale + self.translate_pos.0,
+            pos.1 * self.scale + self.translate_pos.1,
+        );
+    }
+
+    // Image pixel under the canvas position, if it lies inside the image.
+    pub fn pixel_at(&self, pos: (f64, f64), image: (usize, usize)) -> Option<(usize, usize)> {
+        let (x, y) = self.screen_to_image(pos);
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+
+        let (x, y) = (x.floor() as usize, y.floor() as usize);
+        if x >= image.0 || y >= image.1 {
+            return None;
+        }
+
+        return Some((x, y));
+    }
+}